@@ -5,7 +5,8 @@ use ruma::events::room::message::RoomMessageEventContent;
 use crate::{
 	appservice, appservice::AppserviceCommand, check, check::CheckCommand, command::Command, debug,
 	debug::DebugCommand, federation, federation::FederationCommand, media, media::MediaCommand, query,
-	query::QueryCommand, room, room::RoomCommand, server, server::ServerCommand, user, user::UserCommand,
+	query::QueryCommand, room, room::RoomCommand, server, server::ServerCommand, token, token::TokenCommand, user,
+	user::UserCommand,
 };
 
 #[derive(Debug, Parser)]
@@ -19,6 +20,10 @@ pub(super) enum AdminCommand {
 	/// - Commands for managing local users
 	Users(UserCommand),
 
+	#[command(subcommand)]
+	/// - Commands for managing registration tokens
+	Token(TokenCommand),
+
 	#[command(subcommand)]
 	/// - Commands for managing rooms
 	Rooms(RoomCommand),
@@ -56,6 +61,7 @@ pub(super) async fn process(command: AdminCommand, context: &Command<'_>) -> Res
 		Appservices(command) => appservice::process(command, context).await?,
 		Media(command) => media::process(command, context).await?,
 		Users(command) => user::process(command, context).await?,
+		Token(command) => token::process(command, context).await?,
 		Rooms(command) => room::process(command, context).await?,
 		Federation(command) => federation::process(command, context).await?,
 		Server(command) => server::process(command, context).await?,
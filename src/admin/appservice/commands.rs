@@ -1,4 +1,5 @@
 use ruma::{api::appservice::Registration, events::room::message::RoomMessageEventContent};
+use service::appservice::RegistrationExtensions;
 
 use crate::{admin_command, Result};
 
@@ -12,9 +13,16 @@ pub(super) async fn register(&self) -> Result<RoomMessageEventContent> {
 	}
 
 	let appservice_config = self.body[1..self.body.len().checked_sub(1).unwrap()].join("\n");
-	let parsed_config = serde_yaml::from_str::<Registration>(&appservice_config);
+	let parsed_config = serde_yaml::from_str::<Registration>(&appservice_config).and_then(|yaml| {
+		serde_yaml::from_str::<RegistrationExtensions>(&appservice_config).map(|extensions| (yaml, extensions))
+	});
 	match parsed_config {
-		Ok(yaml) => match self.services.appservice.register_appservice(yaml).await {
+		Ok((yaml, extensions)) => match self
+			.services
+			.appservice
+			.register_appservice(yaml, extensions)
+			.await
+		{
 			Ok(id) => Ok(RoomMessageEventContent::text_plain(format!(
 				"Appservice registered with ID: {id}."
 			))),
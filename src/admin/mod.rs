@@ -16,6 +16,7 @@ pub(crate) mod media;
 pub(crate) mod query;
 pub(crate) mod room;
 pub(crate) mod server;
+pub(crate) mod token;
 pub(crate) mod user;
 
 extern crate conduit_api as api;
@@ -37,25 +38,40 @@ conduit::mod_dtor! {}
 conduit::rustc_flags_capture! {}
 
 /// Install the admin command processor
-pub async fn init(admin_service: &service::admin::Service) {
-	_ = admin_service
+pub async fn init(services: &service::Services) {
+	_ = services
+		.admin
 		.complete
 		.write()
 		.expect("locked for writing")
 		.insert(processor::complete);
-	_ = admin_service
+	_ = services
+		.admin
 		.handle
 		.write()
 		.await
 		.insert(processor::dispatch);
+	_ = services
+		.account_validity
+		.deactivate
+		.write()
+		.expect("locked for writing")
+		.insert(user::deactivate_expired);
 }
 
 /// Uninstall the admin command handler
-pub async fn fini(admin_service: &service::admin::Service) {
-	_ = admin_service.handle.write().await.take();
-	_ = admin_service
+pub async fn fini(services: &service::Services) {
+	_ = services.admin.handle.write().await.take();
+	_ = services
+		.admin
 		.complete
 		.write()
 		.expect("locked for writing")
 		.take();
+	_ = services
+		.account_validity
+		.deactivate
+		.write()
+		.expect("locked for writing")
+		.take();
 }
@@ -1,5 +1,11 @@
-use conduit::Result;
-use ruma::events::room::message::RoomMessageEventContent;
+use std::time::{Duration, UNIX_EPOCH};
+
+use conduit::{
+	utils::{self, time},
+	Result,
+};
+use ruma::{events::room::message::RoomMessageEventContent, OwnedEventId, OwnedRoomId};
+use service::rooms::timeline::HistoryCutoff;
 
 use crate::{admin_command, get_room_info, PAGE_SIZE};
 
@@ -74,3 +80,89 @@ pub(super) async fn list_rooms(
 
 	Ok(RoomMessageEventContent::notice_markdown(output_plain))
 }
+
+#[admin_command]
+pub(super) async fn purge(&self, room_id: OwnedRoomId) -> Result<RoomMessageEventContent> {
+	if self
+		.services
+		.rooms
+		.short
+		.get_shortroomid(&room_id)?
+		.is_none()
+	{
+		return Ok(RoomMessageEventContent::text_plain("Room does not exist in the database."));
+	}
+
+	let job = self.services.rooms.purge.purge(room_id.clone()).await?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Queued {room_id} for purging with federation disabled, {} rooms left in the job. A message will be sent here \
+		 once it is gone.",
+		job.rooms.len()
+	)))
+}
+
+#[admin_command]
+pub(super) async fn purge_status(&self) -> Result<RoomMessageEventContent> {
+	let Some(job) = self.services.rooms.purge.purge_job()? else {
+		return Ok(RoomMessageEventContent::text_plain("No room purge is running."));
+	};
+
+	let elapsed = utils::millis_since_unix_epoch().saturating_sub(job.started);
+	let elapsed = time::pretty(Duration::from_millis(elapsed));
+	let current = job
+		.rooms
+		.front()
+		.map_or_else(String::new, ToString::to_string);
+
+	let failed: String = job
+		.rooms_failed
+		.iter()
+		.map(|(room_id, error)| format!("\n- {room_id}: {error}"))
+		.collect();
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Purging: {}/{} rooms done, {} failed, {} events deleted in {elapsed}, currently {current} ({:?}){failed}",
+		job.rooms_done,
+		job.rooms_total,
+		job.rooms_failed.len(),
+		job.events_purged,
+		job.stage,
+	)))
+}
+
+#[admin_command]
+pub(super) async fn purge_history(
+	&self, room_id: OwnedRoomId, before_event: Option<OwnedEventId>, older_than: Option<String>,
+) -> Result<RoomMessageEventContent> {
+	let timeline = &self.services.rooms.timeline;
+	let cutoff = if let Some(event_id) = before_event {
+		let count = timeline
+			.get_pdu(&event_id)?
+			.filter(|pdu| pdu.room_id == room_id)
+			.and_then(|_| timeline.get_pdu_count(&event_id).transpose())
+			.transpose()?;
+
+		let Some(count) = count else {
+			return Ok(RoomMessageEventContent::text_plain("Event is not in the timeline of the room."));
+		};
+
+		HistoryCutoff::Event(count)
+	} else if let Some(older_than) = older_than {
+		let before = time::parse_timepoint_ago(&older_than)?
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+
+		HistoryCutoff::Timestamp(u64::try_from(before.as_millis()).unwrap_or(u64::MAX))
+	} else {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Please pass either --before-event or --older-than.",
+		));
+	};
+
+	let purged = timeline.purge_history(&room_id, cutoff, |_| {}).await?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Deleted {purged} messages from {room_id}."
+	)))
+}
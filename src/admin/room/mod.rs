@@ -3,12 +3,15 @@ mod commands;
 mod directory;
 mod info;
 mod moderation;
+mod search;
 
 use clap::Subcommand;
 use conduit::Result;
+use ruma::{OwnedEventId, OwnedRoomId};
 
 use self::{
 	alias::RoomAliasCommand, directory::RoomDirectoryCommand, info::RoomInfoCommand, moderation::RoomModerationCommand,
+	search::RoomSearchCommand,
 };
 use crate::admin_command_dispatch;
 
@@ -34,6 +37,37 @@ pub(super) enum RoomCommand {
 		no_details: bool,
 	},
 
+	/// - Deletes a room and all its events from the database
+	///
+	/// Local users have to leave the room first, for example by banning it
+	/// with `rooms moderation ban-room`. Federation is disabled for the room
+	/// right away. Runs in the background and resumes after a restart; see
+	/// `purge-status` for the progress.
+	Purge {
+		room_id: OwnedRoomId,
+	},
+
+	/// - Shows the progress of a running room purge
+	PurgeStatus,
+
+	/// - Deletes the messages of a room before an event or older than a
+	///   duration
+	///
+	/// State events and the latest events of the room are kept. Messages up to
+	/// the purged ones are not backfilled anymore afterwards, so they are not
+	/// fetched again from other servers.
+	PurgeHistory {
+		room_id: OwnedRoomId,
+
+		/// Deletes the messages before this event
+		#[arg(long, required_unless_present = "older_than")]
+		before_event: Option<OwnedEventId>,
+
+		/// Deletes the messages older than this duration, e.g. "90d"
+		#[arg(long, conflicts_with = "before_event")]
+		older_than: Option<String>,
+	},
+
 	#[command(subcommand)]
 	/// - View information about a room we know about
 	Info(RoomInfoCommand),
@@ -49,4 +83,8 @@ pub(super) enum RoomCommand {
 	#[command(subcommand)]
 	/// - Manage the room directory
 	Directory(RoomDirectoryCommand),
+
+	#[command(subcommand)]
+	/// - Manage the message search index of rooms
+	Search(RoomSearchCommand),
 }
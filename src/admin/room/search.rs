@@ -0,0 +1,153 @@
+use std::{fmt::Write, time::Duration};
+
+use clap::Subcommand;
+use conduit::{
+	utils::{self, time},
+	Result,
+};
+use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, RoomId};
+
+use crate::{admin_command, admin_command_dispatch};
+
+#[admin_command_dispatch]
+#[derive(Debug, Subcommand)]
+pub(crate) enum RoomSearchCommand {
+	/// - Rebuilds the message search index of a room, or of every room with
+	///   "all"
+	///
+	/// Needed after the tokenizer changed, or to index messages the index
+	/// missed. Runs in the background and
+	/// resumes after a restart; see `search stats` for the progress.
+	Reindex {
+		room: String,
+	},
+
+	/// - Shows the progress of a running reindex and the size of the search
+	///   index of a room, or of every room
+	Stats {
+		room_id: Option<OwnedRoomId>,
+	},
+
+	/// - Shows or sets the language words of a room are stemmed in for search
+	///
+	/// Takes an ISO 639-1 code such as "en" or "de". The room is reindexed in
+	/// the background afterwards.
+	Language {
+		room_id: OwnedRoomId,
+
+		language: Option<String>,
+
+		/// Unsets the language of the room, using the server default
+		#[arg(long, conflicts_with = "language")]
+		reset: bool,
+	},
+}
+
+#[admin_command]
+async fn reindex(&self, room: String) -> Result<RoomMessageEventContent> {
+	let rooms: Vec<OwnedRoomId> = if room == "all" {
+		self.services
+			.rooms
+			.metadata
+			.iter_ids()
+			.filter_map(Result::ok)
+			.collect()
+	} else {
+		let room_id = RoomId::parse(&room)?;
+		if !self.services.rooms.metadata.exists(&room_id)? {
+			return Ok(RoomMessageEventContent::text_plain("Room does not exist in the database."));
+		}
+
+		vec![room_id]
+	};
+
+	let queued = rooms.len();
+	let job = self.services.rooms.search.reindex(rooms).await?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Queued {queued} rooms for reindexing, {} rooms left in the job.",
+		job.rooms.len()
+	)))
+}
+
+#[admin_command]
+async fn stats(&self, room_id: Option<OwnedRoomId>) -> Result<RoomMessageEventContent> {
+	let search = &self.services.rooms.search;
+	let mut out = String::new();
+
+	if let Some(job) = search.reindex_job()? {
+		let elapsed = utils::millis_since_unix_epoch().saturating_sub(job.started);
+		let elapsed = time::pretty(Duration::from_millis(elapsed));
+		let current = job
+			.rooms
+			.front()
+			.map_or_else(String::new, ToString::to_string);
+
+		writeln!(
+			out,
+			"Reindexing: {}/{} rooms done, {} messages indexed in {elapsed}, currently {current}\n",
+			job.rooms_done, job.rooms_total, job.events_indexed,
+		)?;
+	}
+
+	let mut rooms = match room_id {
+		Some(room_id) => {
+			let stats = search.index_stats(&room_id)?;
+			vec![(room_id, stats)]
+		},
+		None => search.all_index_stats()?,
+	};
+
+	rooms.sort_by(|(_, a), (_, b)| b.bytes.cmp(&a.bytes));
+
+	let mibs = |input: usize| f64::from(u32::try_from(input / 1024).unwrap_or(0)) / 1024.0;
+
+	writeln!(out, "| Room | Messages | Tokens | Token occurrences | Entries | Size (MiB) |")?;
+	writeln!(out, "| ---- | --------:| ------:| -----------------:| -------:| ----------:|")?;
+	for (room_id, stats) in &rooms {
+		writeln!(
+			out,
+			"| {room_id} | {} | {} | {} | {} | {:.2} |",
+			stats.events,
+			stats.tokens,
+			stats.occurrences,
+			stats.entries,
+			mibs(stats.bytes),
+		)?;
+	}
+
+	let total_bytes = rooms.iter().map(|(_, stats)| stats.bytes).sum::<usize>();
+	let total_entries = rooms.iter().map(|(_, stats)| stats.entries).sum::<usize>();
+	writeln!(
+		out,
+		"\n{} rooms, {total_entries} entries, {:.2} MiB",
+		rooms.len(),
+		mibs(total_bytes)
+	)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(out))
+}
+
+#[admin_command]
+async fn language(
+	&self, room_id: OwnedRoomId, language: Option<String>, reset: bool,
+) -> Result<RoomMessageEventContent> {
+	let search = &self.services.rooms.search;
+	if language.is_none() && !reset {
+		let current = search.room_language(&room_id)?;
+		let current = current.as_deref().unwrap_or("not set (server default)");
+
+		return Ok(RoomMessageEventContent::notice_markdown(format!(
+			"Search language of {room_id}: {current}"
+		)));
+	}
+
+	search
+		.set_room_language(&room_id, language.as_deref())
+		.await?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Search language of {room_id} updated, its messages are being reindexed. See `!admin rooms search stats` for \
+		 the progress."
+	)))
+}
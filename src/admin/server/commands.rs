@@ -130,6 +130,13 @@ pub(super) async fn admin_notice(&self, message: Vec<String>) -> Result<RoomMess
 	Ok(RoomMessageEventContent::notice_plain("Notice was sent to #admins"))
 }
 
+#[admin_command]
+pub(super) async fn reload_spam_checker(&self) -> Result<RoomMessageEventContent> {
+	self.services.spam_checker.reload()?;
+
+	Ok(RoomMessageEventContent::notice_plain("Reloaded the spam checker rules."))
+}
+
 #[admin_command]
 pub(super) async fn reload_mods(&self) -> Result<RoomMessageEventContent> {
 	self.services.server.reload()?;
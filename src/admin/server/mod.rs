@@ -47,6 +47,9 @@ pub(super) enum ServerCommand {
 		message: Vec<String>,
 	},
 
+	/// - Reload the rules of the spam checkers
+	ReloadSpamChecker,
+
 	/// - Hot-reload the server
 	#[clap(alias = "reload")]
 	ReloadMods,
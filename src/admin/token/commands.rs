@@ -0,0 +1,74 @@
+use std::{
+	fmt::Write,
+	time::{Duration, UNIX_EPOCH},
+};
+
+use conduit::{
+	utils::{self, time},
+	Result,
+};
+use ruma::events::room::message::RoomMessageEventContent;
+
+use crate::admin_command;
+
+#[admin_command]
+pub(super) async fn create(
+	&self, token: Option<String>, uses_allowed: Option<u64>, expires_in: Option<String>,
+) -> Result<RoomMessageEventContent> {
+	let expires_in = expires_in
+		.as_deref()
+		.map(time::parse_duration)
+		.transpose()?;
+
+	let token = self
+		.services
+		.registration_tokens
+		.create(token, uses_allowed, expires_in)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Created registration token `{token}`"
+	)))
+}
+
+#[admin_command]
+pub(super) async fn list(&self) -> Result<RoomMessageEventContent> {
+	let tokens = self.services.registration_tokens.list()?;
+	let now = utils::millis_since_unix_epoch();
+
+	let mut msg = format!("Found {} registration token(s):\n```\n", tokens.len());
+	for (token, info) in tokens {
+		let uses_allowed = info
+			.uses_allowed
+			.map_or_else(|| "unlimited".to_owned(), |uses_allowed| uses_allowed.to_string());
+
+		let expiry = match info.expiry_time {
+			None => "never expires".to_owned(),
+			Some(expiry_time) => {
+				let formatted = time::format(UNIX_EPOCH + Duration::from_millis(expiry_time), "%Y-%m-%d %H:%M:%S UTC");
+				if expiry_time < now {
+					format!("expired {formatted}")
+				} else {
+					format!("expires {formatted}")
+				}
+			},
+		};
+
+		writeln!(
+			msg,
+			"{token}: {} completed, {} pending, {uses_allowed} allowed, {expiry}",
+			info.completed, info.pending
+		)?;
+	}
+	msg += "```";
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+#[admin_command]
+pub(super) async fn revoke(&self, token: String) -> Result<RoomMessageEventContent> {
+	self.services.registration_tokens.revoke(&token)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Revoked registration token `{token}`"
+	)))
+}
@@ -0,0 +1,39 @@
+mod commands;
+
+use clap::Subcommand;
+use conduit::Result;
+
+use crate::admin_command_dispatch;
+
+#[admin_command_dispatch]
+#[derive(Debug, Subcommand)]
+pub(super) enum TokenCommand {
+	/// - Create a new registration token
+	///
+	/// A random token is generated unless one is given.
+	Create {
+		/// The token to create
+		#[arg(long)]
+		token: Option<String>,
+
+		/// How many users can register with the token, unlimited if unspecified
+		#[arg(long)]
+		uses_allowed: Option<u64>,
+
+		/// How long the token stays valid (e.g. 30m, 7d), forever if
+		/// unspecified
+		#[arg(long)]
+		expires_in: Option<String>,
+	},
+
+	/// - List all registration tokens with their usage and expiry
+	List,
+
+	/// - Revoke a registration token
+	///
+	/// Registrations which already validated the token can still finish.
+	Revoke {
+		/// The token to revoke
+		token: String,
+	},
+}
@@ -1,7 +1,15 @@
-use std::{collections::BTreeMap, fmt::Write as _};
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	time::{Duration, UNIX_EPOCH},
+};
 
 use api::client::{full_user_deactivate, join_room_by_id_helper, leave_room};
-use conduit::{error, info, utils, warn, PduBuilder, Result};
+use conduit::{
+	error, info,
+	utils::{self, time},
+	warn, PduBuilder, Result,
+};
 use ruma::{
 	events::{
 		room::{
@@ -59,6 +67,8 @@ pub(super) async fn create_user(&self, username: String, password: Option<String
 		.users
 		.create(&user_id, Some(password.as_str()))?;
 
+	self.services.account_validity.start(&user_id)?;
+
 	// Default to pretty displayname
 	let mut displayname = user_id.localpart().to_owned();
 
@@ -193,6 +203,90 @@ pub(super) async fn deactivate(&self, no_leave_rooms: bool, user_id: String) ->
 	)))
 }
 
+#[admin_command]
+pub(super) async fn lock(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_active_local_user_id(self.services, &user_id)?;
+
+	if user_id == self.services.globals.server_user {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Not allowed to lock the server service account.",
+		));
+	}
+
+	self.services.users.set_locked(&user_id, true)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!("User {user_id} has been locked")))
+}
+
+#[admin_command]
+pub(super) async fn unlock(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(self.services, &user_id)?;
+
+	self.services.users.set_locked(&user_id, false)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!("User {user_id} has been unlocked")))
+}
+
+#[admin_command]
+pub(super) async fn suspend(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_active_local_user_id(self.services, &user_id)?;
+
+	if user_id == self.services.globals.server_user {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Not allowed to suspend the server service account.",
+		));
+	}
+
+	self.services.users.set_suspended(&user_id, true)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"User {user_id} has been suspended"
+	)))
+}
+
+#[admin_command]
+pub(super) async fn unsuspend(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(self.services, &user_id)?;
+
+	self.services.users.set_suspended(&user_id, false)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"User {user_id} has been unsuspended"
+	)))
+}
+
+#[admin_command]
+pub(super) async fn shadow_ban(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_active_local_user_id(self.services, &user_id)?;
+
+	if user_id == self.services.globals.server_user {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Not allowed to shadow-ban the server service account.",
+		));
+	}
+
+	self.services
+		.spam_checker
+		.set_shadow_banned(&user_id, true)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"User {user_id} has been shadow-banned"
+	)))
+}
+
+#[admin_command]
+pub(super) async fn unshadowban(&self, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(self.services, &user_id)?;
+
+	self.services
+		.spam_checker
+		.set_shadow_banned(&user_id, false)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"User {user_id} is no longer shadow-banned"
+	)))
+}
+
 #[admin_command]
 pub(super) async fn reset_password(&self, username: String) -> Result<RoomMessageEventContent> {
 	let user_id = parse_local_user_id(self.services, &username)?;
@@ -210,9 +304,19 @@ pub(super) async fn reset_password(&self, username: String) -> Result<RoomMessag
 		.users
 		.set_password(&user_id, Some(new_password.as_str()))
 	{
-		Ok(()) => Ok(RoomMessageEventContent::text_plain(format!(
-			"Successfully reset the password for user {user_id}: `{new_password}`"
-		))),
+		Ok(()) => {
+			// revoke refresh tokens so existing sessions end once their access tokens
+			// expire
+			for device_id in self.services.users.all_device_ids(&user_id).flatten() {
+				self.services
+					.users
+					.remove_refresh_token(&user_id, &device_id)?;
+			}
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Successfully reset the password for user {user_id}: `{new_password}`"
+			)))
+		},
 		Err(e) => Ok(RoomMessageEventContent::text_plain(format!(
 			"Couldn't reset the password for user {user_id}: {e}"
 		))),
@@ -622,3 +726,47 @@ pub(super) async fn redact_event(&self, event_id: Box<EventId>) -> Result<RoomMe
 		"Successfully redacted event. Redaction event ID: {redaction_event_id}"
 	)))
 }
+
+#[admin_command]
+pub(super) async fn extend_validity(&self, user_id: String, by: Option<String>) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(self.services, &user_id)?;
+	let by = by.as_deref().map(time::parse_duration).transpose()?;
+
+	let expires_at = self.services.account_validity.extend(&user_id, by)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Account {user_id} now expires on {}",
+		time::format(UNIX_EPOCH + Duration::from_millis(expires_at), "%Y-%m-%d %H:%M:%S UTC")
+	)))
+}
+
+#[admin_command]
+pub(super) async fn list_expiring(&self, within: Option<String>) -> Result<RoomMessageEventContent> {
+	let within = within
+		.as_deref()
+		.map(time::parse_duration)
+		.transpose()?
+		.map(|within| u64::try_from(within.as_millis()).unwrap_or(u64::MAX));
+	let now = utils::millis_since_unix_epoch();
+
+	let accounts: Vec<_> = self
+		.services
+		.account_validity
+		.list()?
+		.into_iter()
+		.filter(|(_, expires_at)| within.map_or(true, |within| *expires_at <= now.saturating_add(within)))
+		.collect();
+
+	let mut msg = format!("Found {} account(s) with an expiry date:\n```\n", accounts.len());
+	for (user_id, expires_at) in accounts {
+		let formatted = time::format(UNIX_EPOCH + Duration::from_millis(expires_at), "%Y-%m-%d %H:%M:%S UTC");
+		if expires_at <= now {
+			writeln!(msg, "{user_id}: expired {formatted}")?;
+		} else {
+			writeln!(msg, "{user_id}: expires {formatted}")?;
+		}
+	}
+	msg += "```";
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
@@ -1,8 +1,12 @@
 mod commands;
 
+use std::sync::Arc;
+
+use api::client::full_user_deactivate;
 use clap::Subcommand;
 use conduit::Result;
-use ruma::{EventId, OwnedRoomOrAliasId, RoomId};
+use ruma::{EventId, OwnedRoomOrAliasId, OwnedUserId, RoomId};
+use service::{account_validity::DeactivatorFuture, Services};
 
 use crate::admin_command_dispatch;
 
@@ -34,6 +38,45 @@ pub(super) enum UserCommand {
 		user_id: String,
 	},
 
+	/// - Lock a user's account (MSC3939)
+	///
+	/// Locked users cannot use their access tokens or log in until unlocked.
+	/// Unlike deactivation this keeps their rooms, profile and devices.
+	Lock {
+		user_id: String,
+	},
+
+	/// - Unlock a locked user's account
+	Unlock {
+		user_id: String,
+	},
+
+	/// - Suspend a user's account (MSC3823)
+	///
+	/// Suspended users can still read, but cannot send events, join rooms or
+	/// invite others until unsuspended. They can still leave rooms.
+	Suspend {
+		user_id: String,
+	},
+
+	/// - Unsuspend a suspended user's account
+	Unsuspend {
+		user_id: String,
+	},
+
+	/// - Shadow-ban a user's account
+	///
+	/// Shadow-banned users' events, invites and uploads seem to succeed but
+	/// are not visible to anyone else, until the ban is lifted.
+	ShadowBan {
+		user_id: String,
+	},
+
+	/// - Lift the shadow-ban of a user's account
+	Unshadowban {
+		user_id: String,
+	},
+
 	/// - Deactivate a list of users
 	///
 	/// Recommended to use in conjunction with list-local-users.
@@ -124,4 +167,38 @@ pub(super) enum UserCommand {
 	RedactEvent {
 		event_id: Box<EventId>,
 	},
+
+	/// - Extends the validity of a local user's account
+	///
+	/// Expired accounts are extended from now, which also unlocks them.
+	ExtendValidity {
+		user_id: String,
+
+		/// How long to extend the account by (e.g. 30d), defaults to
+		/// `account_validity.period`
+		#[arg(long)]
+		by: Option<String>,
+	},
+
+	/// - Lists accounts with an expiry date, soonest first
+	ListExpiring {
+		/// Only list accounts expiring within this long (e.g. 7d), including
+		/// already expired ones
+		#[arg(long)]
+		within: Option<String>,
+	},
+}
+
+/// Deactivates an expired account like a deactivation requested by its user.
+pub(crate) fn deactivate_expired(services: Arc<Services>, user_id: OwnedUserId) -> DeactivatorFuture {
+	Box::pin(async move {
+		let all_joined_rooms = services
+			.rooms
+			.state_cache
+			.rooms_joined(&user_id)
+			.filter_map(Result::ok)
+			.collect();
+
+		full_user_deactivate(&services, &user_id, all_joined_rooms).await
+	})
 }
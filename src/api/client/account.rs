@@ -1,15 +1,18 @@
 use std::fmt::Write;
 
-use axum::extract::State;
+use axum::{extract::State, response::IntoResponse};
 use axum_client_ip::InsecureClientIp;
-use conduit::{debug_info, error, info, utils, warn, Error, PduBuilder, Result};
+use conduit::{debug_info, err, error, info, utils, warn, Err, Error, PduBuilder, Result};
+use http::Uri;
 use register::RegistrationKind;
 use ruma::{
 	api::client::{
 		account::{
-			change_password, check_registration_token_validity, deactivate, get_3pids, get_username_availability,
+			add_3pid, change_password, check_registration_token_validity, deactivate, delete_3pid, get_3pids,
+			get_username_availability,
 			register::{self, LoginType},
-			request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn, whoami,
+			request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
+			request_password_change_token_via_email, request_registration_token_via_email, whoami,
 			ThirdPartyIdRemovalStatus,
 		},
 		error::ErrorKind,
@@ -22,10 +25,17 @@ use ruma::{
 		},
 		GlobalAccountDataEventType, StateEventType, TimelineEventType,
 	},
-	push, OwnedRoomId, UserId,
+	push,
+	thirdparty::{Medium, ThirdPartyIdentifier},
+	MilliSecondsSinceUnixEpoch, OwnedClientSecret, OwnedRoomId, OwnedSessionId, UserId,
 };
+use serde::Deserialize;
 use serde_json::value::to_raw_value;
-use service::Services;
+use service::{
+	spam_checker::Verdict,
+	threepid::{normalize_email, Purpose},
+	Services,
+};
 
 use super::{join_room_by_id_helper, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
 use crate::Ruma;
@@ -110,7 +120,7 @@ pub(crate) async fn register_route(
 
 	if is_guest
 		&& (!services.globals.allow_guest_registration()
-			|| (services.globals.allow_registration() && services.globals.config.registration_token.is_some()))
+			|| (services.globals.allow_registration() && services.registration_tokens.required()))
 	{
 		info!(
 			"Guest registration disabled / registration enabled with token configured, rejecting guest registration \
@@ -180,9 +190,22 @@ pub(crate) async fn register_route(
 		return Err(Error::BadRequest(ErrorKind::Exclusive, "User ID reserved by appservice."));
 	}
 
+	let shadow_banned = if body.appservice_info.is_none() {
+		match services
+			.spam_checker
+			.check_registration(user_id.localpart())
+		{
+			Verdict::Allow => false,
+			Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+			Verdict::ShadowBan => true,
+		}
+	} else {
+		false
+	};
+
 	// UIAA
 	let mut uiaainfo;
-	let skip_auth = if services.globals.config.registration_token.is_some() {
+	let skip_auth = if services.registration_tokens.required() {
 		// Registration token required
 		uiaainfo = UiaaInfo {
 			flows: vec![AuthFlow {
@@ -208,6 +231,15 @@ pub(crate) async fn register_route(
 		body.appservice_info.is_some() || is_guest
 	};
 
+	if services.globals.config.smtp.require_email_for_registration {
+		// Users must validate an email address, which gets bound to the new account
+		for flow in &mut uiaainfo.flows {
+			flow.stages.retain(|stage| *stage != AuthType::Dummy);
+			flow.stages.push(AuthType::EmailIdentity);
+		}
+	}
+
+	let mut uiaa_session = None;
 	if !skip_auth {
 		if let Some(auth) = &body.auth {
 			let (worked, uiaainfo) = services.uiaa.try_auth(
@@ -219,7 +251,8 @@ pub(crate) async fn register_route(
 			if !worked {
 				return Err(Error::Uiaa(uiaainfo));
 			}
-		// Success!
+			// Success!
+			uiaa_session = uiaainfo.session;
 		} else if let Some(json) = body.json_body {
 			uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
 			services.uiaa.create(
@@ -234,6 +267,29 @@ pub(crate) async fn register_route(
 		}
 	}
 
+	let email = uiaa_session.as_deref().and_then(|session| {
+		services
+			.threepid
+			.take_uiaa_email(session, Purpose::Registration)
+	});
+
+	if email.is_none() && !skip_auth && services.globals.config.smtp.require_email_for_registration {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidAuthFailed,
+			"No email address was validated for registration.",
+		));
+	}
+
+	if let Some(email) = &email {
+		if services
+			.users
+			.find_from_threepid(&Medium::Email, email)?
+			.is_some()
+		{
+			return Err(Error::BadRequest(ErrorKind::ThreepidInUse, "Email address is already in use."));
+		}
+	}
+
 	let password = if is_guest {
 		None
 	} else {
@@ -243,6 +299,34 @@ pub(crate) async fn register_route(
 	// Create user
 	services.users.create(&user_id, password)?;
 
+	if shadow_banned {
+		info!("New user {user_id} is shadow-banned by the spam checker");
+		services.spam_checker.set_shadow_banned(&user_id, true)?;
+	}
+
+	// Appservice users are managed by their appservice and never expire
+	if body.appservice_info.is_none() {
+		services.account_validity.start(&user_id)?;
+	}
+
+	if let Some(email) = email {
+		let now = MilliSecondsSinceUnixEpoch::now();
+		services.users.add_threepid(
+			&user_id,
+			&ThirdPartyIdentifier {
+				address: email,
+				medium: Medium::Email,
+				validated_at: now,
+				added_at: now,
+			},
+		)?;
+	}
+
+	// Count the registration against the token used for it
+	if let Some(session) = &uiaa_session {
+		services.registration_tokens.complete(session)?;
+	}
+
 	// Default to pretty displayname
 	let mut displayname = user_id.localpart().to_owned();
 
@@ -302,6 +386,18 @@ pub(crate) async fn register_route(
 		Some(client.to_string()),
 	)?;
 
+	// Clients supporting refresh tokens get an expiring access token
+	let (refresh_token, expires_in) = if body.refresh_token {
+		let refresh_token = utils::random_string(TOKEN_LENGTH);
+		let expires_in = services
+			.users
+			.set_refresh_token(&user_id, &device_id, &token, &refresh_token)?;
+
+		(Some(refresh_token), Some(expires_in))
+	} else {
+		(None, None)
+	};
+
 	debug_info!(%user_id, %device_id, "User account was created");
 
 	let device_display_name = body.initial_device_display_name.clone().unwrap_or_default();
@@ -413,8 +509,8 @@ pub(crate) async fn register_route(
 		access_token: Some(token),
 		user_id,
 		device_id: Some(device_id),
-		refresh_token: None,
-		expires_in: None,
+		refresh_token,
+		expires_in,
 	})
 }
 
@@ -435,17 +531,19 @@ pub(crate) async fn register_route(
 ///   last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
+///
+/// Otherwise the refresh tokens of the other devices are revoked.
+///
+/// Without an access token, users who forgot their password reset it by
+/// validating an email address bound to their account.
 #[tracing::instrument(skip_all, fields(%client), name = "change_password")]
 pub(crate) async fn change_password_route(
 	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp,
 	body: Ruma<change_password::v3::Request>,
 ) -> Result<change_password::v3::Response> {
-	// Authentication for this endpoint was made optional, but we need
-	// authentication currently
-	let sender_user = body
-		.sender_user
-		.as_ref()
-		.ok_or_else(|| Error::BadRequest(ErrorKind::MissingToken, "Missing access token."))?;
+	let Some(sender_user) = body.sender_user.as_ref() else {
+		return reset_password_with_email(&services, body).await;
+	};
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
 	let mut uiaainfo = UiaaInfo {
@@ -480,15 +578,18 @@ pub(crate) async fn change_password_route(
 		.users
 		.set_password(sender_user, Some(&body.new_password))?;
 
-	if body.logout_devices {
-		// Logout all devices except the current one
-		for id in services
-			.users
-			.all_device_ids(sender_user)
-			.filter_map(Result::ok)
-			.filter(|id| id != sender_device)
-		{
+	// Logout all devices except the current one, or at least revoke their refresh
+	// tokens so they cannot renew their access once it expires
+	for id in services
+		.users
+		.all_device_ids(sender_user)
+		.filter_map(Result::ok)
+		.filter(|id| id != sender_device)
+	{
+		if body.logout_devices {
 			services.users.remove_device(sender_user, &id)?;
+		} else {
+			services.users.remove_refresh_token(sender_user, &id)?;
 		}
 	}
 
@@ -506,6 +607,98 @@ pub(crate) async fn change_password_route(
 	Ok(change_password::v3::Response {})
 }
 
+/// Resets the password of the user an email address validated through UIAA is
+/// bound to.
+async fn reset_password_with_email(
+	services: &Services, body: Ruma<change_password::v3::Request>,
+) -> Result<change_password::v3::Response> {
+	if !services.threepid.email_enabled() {
+		return Err(Error::BadRequest(ErrorKind::MissingToken, "Missing access token."));
+	}
+
+	let mut uiaainfo = UiaaInfo {
+		flows: vec![AuthFlow {
+			stages: vec![AuthType::EmailIdentity],
+		}],
+		completed: Vec::new(),
+		params: Box::default(),
+		session: None,
+		auth_error: None,
+	};
+
+	let anonymous = UserId::parse_with_server_name("", services.globals.server_name()).expect("we know this is valid");
+	let email = if let Some(auth) = &body.auth {
+		let (worked, uiaainfo) = services
+			.uiaa
+			.try_auth(&anonymous, "".into(), auth, &uiaainfo)?;
+		if !worked {
+			return Err(Error::Uiaa(uiaainfo));
+		}
+
+		uiaainfo
+			.session
+			.as_deref()
+			.and_then(|session| {
+				services
+					.threepid
+					.take_uiaa_email(session, Purpose::PasswordReset)
+			})
+			.ok_or(Error::BadRequest(
+				ErrorKind::ThreepidAuthFailed,
+				"No email address was validated.",
+			))?
+	} else if let Some(json) = body.json_body {
+		uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+		services
+			.uiaa
+			.create(&anonymous, "".into(), &uiaainfo, &json)?;
+		return Err(Error::Uiaa(uiaainfo));
+	} else {
+		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+	};
+
+	let user_id = services
+		.users
+		.find_from_threepid(&Medium::Email, &email)?
+		.ok_or(Error::BadRequest(
+			ErrorKind::ThreepidNotFound,
+			"Email address is not bound to any account.",
+		))?;
+
+	if services.users.is_deactivated(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserDeactivated, "The user has been deactivated"));
+	}
+
+	services
+		.users
+		.set_password(&user_id, Some(&body.new_password))?;
+
+	for id in services
+		.users
+		.all_device_ids(&user_id)
+		.filter_map(Result::ok)
+	{
+		if body.logout_devices {
+			services.users.remove_device(&user_id, &id)?;
+		} else {
+			services.users.remove_refresh_token(&user_id, &id)?;
+		}
+	}
+
+	info!("User {user_id} reset their password by email.");
+
+	if services.globals.config.admin_room_notices {
+		services
+			.admin
+			.send_message(RoomMessageEventContent::notice_plain(format!(
+				"User {user_id} reset their password by email."
+			)))
+			.await;
+	}
+
+	Ok(change_password::v3::Response {})
+}
+
 /// # `GET _matrix/client/r0/account/whoami`
 ///
 /// Get `user_id` of the sender user.
@@ -605,12 +798,17 @@ pub(crate) async fn deactivate_route(
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
-pub(crate) async fn third_party_route(body: Ruma<get_3pids::v3::Request>) -> Result<get_3pids::v3::Response> {
-	let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+pub(crate) async fn third_party_route(
+	State(services): State<crate::State>, body: Ruma<get_3pids::v3::Request>,
+) -> Result<get_3pids::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-	Ok(get_3pids::v3::Response::new(Vec::new()))
+	let threepids = services
+		.users
+		.threepids(sender_user)
+		.collect::<Result<_>>()?;
+
+	Ok(get_3pids::v3::Response::new(threepids))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
@@ -621,12 +819,214 @@ pub(crate) async fn third_party_route(body: Ruma<get_3pids::v3::Request>) -> Res
 /// - 403 signals that The homeserver does not allow the third party identifier
 ///   as a contact option.
 pub(crate) async fn request_3pid_management_token_via_email_route(
-	_body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+	State(services): State<crate::State>, body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-	Err(Error::BadRequest(
-		ErrorKind::ThreepidDenied,
-		"Third party identifier is not allowed",
-	))
+	if !services.threepid.email_enabled() {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidDenied,
+			"Third party identifier is not allowed",
+		));
+	}
+
+	if services
+		.users
+		.find_from_threepid(&Medium::Email, &normalize_email(&body.email))?
+		.is_some()
+	{
+		return Err(Error::BadRequest(ErrorKind::ThreepidInUse, "Email address is already in use."));
+	}
+
+	let sid = services
+		.threepid
+		.request_email_token(&body.client_secret, &body.email, body.send_attempt, Purpose::AddThreepid)
+		.await?;
+
+	Ok(request_3pid_management_token_via_email::v3::Response::new(sid))
+}
+
+/// # `POST /_matrix/client/v3/register/email/requestToken`
+///
+/// Sends a validation link to an email address which is then bound to an
+/// account during registration.
+pub(crate) async fn request_registration_token_via_email_route(
+	State(services): State<crate::State>, body: Ruma<request_registration_token_via_email::v3::Request>,
+) -> Result<request_registration_token_via_email::v3::Response> {
+	if !services.globals.allow_registration() || !services.threepid.email_enabled() {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidDenied,
+			"Third party identifier is not allowed",
+		));
+	}
+
+	if services
+		.users
+		.find_from_threepid(&Medium::Email, &normalize_email(&body.email))?
+		.is_some()
+	{
+		return Err(Error::BadRequest(ErrorKind::ThreepidInUse, "Email address is already in use."));
+	}
+
+	let sid = services
+		.threepid
+		.request_email_token(&body.client_secret, &body.email, body.send_attempt, Purpose::Registration)
+		.await?;
+
+	Ok(request_registration_token_via_email::v3::Response::new(sid))
+}
+
+/// # `POST /_matrix/client/v3/account/password/email/requestToken`
+///
+/// Sends a validation link to an email address bound to an account, which
+/// allows resetting its password.
+pub(crate) async fn request_password_change_token_via_email_route(
+	State(services): State<crate::State>, body: Ruma<request_password_change_token_via_email::v3::Request>,
+) -> Result<request_password_change_token_via_email::v3::Response> {
+	if !services.threepid.email_enabled() {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidDenied,
+			"Third party identifier is not allowed",
+		));
+	}
+
+	if services
+		.users
+		.find_from_threepid(&Medium::Email, &normalize_email(&body.email))?
+		.is_none()
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidNotFound,
+			"Email address is not bound to any account.",
+		));
+	}
+
+	let sid = services
+		.threepid
+		.request_email_token(&body.client_secret, &body.email, body.send_attempt, Purpose::PasswordReset)
+		.await?;
+
+	Ok(request_password_change_token_via_email::v3::Response::new(sid))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Binds an email address the user validated to their account.
+///
+/// - Requires UIAA to verify user password
+pub(crate) async fn add_3pid_route(
+	State(services): State<crate::State>, body: Ruma<add_3pid::v3::Request>,
+) -> Result<add_3pid::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+	let mut uiaainfo = UiaaInfo {
+		flows: vec![AuthFlow {
+			stages: vec![AuthType::Password],
+		}],
+		completed: Vec::new(),
+		params: Box::default(),
+		session: None,
+		auth_error: None,
+	};
+
+	if let Some(auth) = &body.auth {
+		let (worked, uiaainfo) = services
+			.uiaa
+			.try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+		if !worked {
+			return Err(Error::Uiaa(uiaainfo));
+		}
+	// Success!
+	} else if let Some(json) = body.json_body {
+		uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+		services
+			.uiaa
+			.create(sender_user, sender_device, &uiaainfo, &json)?;
+		return Err(Error::Uiaa(uiaainfo));
+	} else {
+		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+	}
+
+	let email = services
+		.threepid
+		.take_validated_email(&body.sid, &body.client_secret, Purpose::AddThreepid)
+		.ok_or(Error::BadRequest(
+			ErrorKind::ThreepidAuthFailed,
+			"Email address has not been validated.",
+		))?;
+
+	match services.users.find_from_threepid(&Medium::Email, &email)? {
+		Some(user_id) if user_id == *sender_user => return Ok(add_3pid::v3::Response::new()),
+		Some(_) => return Err(Error::BadRequest(ErrorKind::ThreepidInUse, "Email address is already in use.")),
+		None => {},
+	}
+
+	let now = MilliSecondsSinceUnixEpoch::now();
+	services.users.add_threepid(
+		sender_user,
+		&ThirdPartyIdentifier {
+			address: email,
+			medium: Medium::Email,
+			validated_at: now,
+			added_at: now,
+		},
+	)?;
+
+	info!("User {sender_user} added an email address to their account.");
+
+	Ok(add_3pid::v3::Response::new())
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from the user's account.
+///
+/// - Identity servers are not supported, so nothing is unbound from them
+pub(crate) async fn delete_3pid_route(
+	State(services): State<crate::State>, body: Ruma<delete_3pid::v3::Request>,
+) -> Result<delete_3pid::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let address = normalize_email(&body.address);
+	if services
+		.users
+		.find_from_threepid(&body.medium, &address)?
+		.as_ref()
+		!= Some(sender_user)
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidNotFound,
+			"Third party identifier is not bound to this account.",
+		));
+	}
+
+	services
+		.users
+		.remove_threepid(sender_user, &body.medium, &address)?;
+
+	Ok(delete_3pid::v3::Response::new(ThirdPartyIdRemovalStatus::NoSupport))
+}
+
+/// Query parameters of the link sent in validation emails.
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailValidation {
+	sid: OwnedSessionId,
+	client_secret: OwnedClientSecret,
+	token: String,
+}
+
+/// # `GET /_conduwuit/email/validate`
+///
+/// Validates the email address of a session when its owner follows the link
+/// sent to them.
+pub(crate) async fn validate_email_route(State(services): State<crate::State>, uri: Uri) -> Result<impl IntoResponse> {
+	let validation: EmailValidation = serde_html_form::from_str(uri.query().unwrap_or_default())
+		.map_err(|e| err!(Request(InvalidParam("Invalid validation link: {e}"))))?;
+
+	services
+		.threepid
+		.validate(&validation.sid, &validation.client_secret, &validation.token)?;
+
+	Ok("Your email address has been validated. You can now return to your client.")
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/msisdn/requestToken`
@@ -649,20 +1049,19 @@ pub(crate) async fn request_3pid_management_token_via_msisdn_route(
 ///
 /// Checks if the provided registration token is valid at the time of checking
 ///
-/// Currently does not have any ratelimiting, and this isn't very practical as
-/// there is only one registration token allowed.
+/// Currently does not have any ratelimiting.
 pub(crate) async fn check_registration_token_validity(
 	State(services): State<crate::State>, body: Ruma<check_registration_token_validity::v1::Request>,
 ) -> Result<check_registration_token_validity::v1::Response> {
-	let Some(reg_token) = services.globals.config.registration_token.clone() else {
+	if !services.registration_tokens.required() {
 		return Err(Error::BadRequest(
 			ErrorKind::forbidden(),
 			"Server does not allow token registration.",
 		));
-	};
+	}
 
 	Ok(check_registration_token_validity::v1::Response {
-		valid: reg_token == body.token,
+		valid: services.registration_tokens.is_valid(&body.token)?,
 	})
 }
 
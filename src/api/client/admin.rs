@@ -0,0 +1,131 @@
+use axum::extract::State;
+use conduit::{err, info, Err, Result};
+use service::rooms::timeline::HistoryCutoff;
+
+use crate::Ruma;
+
+/// # `POST /_conduwuit/admin/v1/rooms/{roomId}/purge_history`
+///
+/// Deletes the non-state events of a room before an event or a timestamp.
+/// State events and the latest events of the room are kept. Only server
+/// admins can use it.
+pub(crate) async fn purge_history_route(
+	State(services): State<crate::State>, body: Ruma<purge_history::v1::Request>,
+) -> Result<purge_history::v1::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	if !services.admin.user_is_admin(sender_user).await? {
+		return Err!(Request(Forbidden("Only server admins can use the admin API.")));
+	}
+
+	let room_id = &body.room_id;
+	let cutoff = match (&body.purge_up_to_event_id, body.purge_up_to_ts) {
+		(Some(event_id), None) => {
+			let Some(pdu) = services.rooms.timeline.get_pdu(event_id)? else {
+				return Err!(Request(NotFound("Event not found.")));
+			};
+
+			let count = services
+				.rooms
+				.timeline
+				.get_pdu_count(event_id)?
+				.filter(|_| pdu.room_id == *room_id)
+				.ok_or_else(|| err!(Request(NotFound("Event is not in the timeline of the room."))))?;
+
+			HistoryCutoff::Event(count)
+		},
+		(None, Some(ts)) => HistoryCutoff::Timestamp(ts),
+		_ => {
+			return Err!(Request(InvalidParam(
+				"Exactly one of purge_up_to_event_id and purge_up_to_ts is required."
+			)))
+		},
+	};
+
+	info!("{sender_user} is purging the history of {room_id} up to {cutoff:?}");
+	let purged = services
+		.rooms
+		.timeline
+		.purge_history(room_id, cutoff, |_| {})
+		.await?;
+
+	Ok(purge_history::v1::Response {
+		purged,
+	})
+}
+
+/// The admin API endpoint, laid out like the endpoints of ruma so it is served
+/// with `ruma_route` and authenticated like any other client request.
+pub(crate) mod purge_history {
+	pub(crate) mod v1 {
+		use bytes::BufMut;
+		use ruma::{
+			api::{
+				client::error::Error,
+				error::{FromHttpRequestError, IntoHttpError},
+				metadata, IncomingRequest, Metadata, OutgoingResponse,
+			},
+			OwnedEventId, OwnedRoomId,
+		};
+		use serde::{Deserialize, Serialize};
+
+		const METADATA: Metadata = metadata! {
+			method: POST,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				unstable => "/_conduwuit/admin/v1/rooms/:room_id/purge_history",
+			}
+		};
+
+		pub(crate) struct Request {
+			pub(crate) room_id: OwnedRoomId,
+			pub(crate) purge_up_to_event_id: Option<OwnedEventId>,
+			/// Milliseconds since the unix epoch
+			pub(crate) purge_up_to_ts: Option<u64>,
+		}
+
+		#[derive(Deserialize)]
+		struct RequestBody {
+			purge_up_to_event_id: Option<OwnedEventId>,
+			purge_up_to_ts: Option<u64>,
+		}
+
+		#[derive(Serialize)]
+		pub(crate) struct Response {
+			/// Number of deleted events
+			pub(crate) purged: usize,
+		}
+
+		impl IncomingRequest for Request {
+			type EndpointError = Error;
+			type OutgoingResponse = Response;
+
+			const METADATA: Metadata = METADATA;
+
+			fn try_from_http_request<B, S>(
+				request: http::Request<B>, path_args: &[S],
+			) -> Result<Self, FromHttpRequestError>
+			where
+				B: AsRef<[u8]>,
+				S: AsRef<str>,
+			{
+				let room_id = OwnedRoomId::try_from(path_args.first().map_or("", AsRef::as_ref))?;
+				let body: RequestBody = serde_json::from_slice(request.body().as_ref())?;
+
+				Ok(Self {
+					room_id,
+					purge_up_to_event_id: body.purge_up_to_event_id,
+					purge_up_to_ts: body.purge_up_to_ts,
+				})
+			}
+		}
+
+		impl OutgoingResponse for Response {
+			fn try_into_http_response<T: Default + BufMut>(self) -> Result<http::Response<T>, IntoHttpError> {
+				Ok(http::Response::builder()
+					.header(http::header::CONTENT_TYPE, "application/json")
+					.body(ruma::serde::json_to_buf(&self)?)?)
+			}
+		}
+	}
+}
@@ -60,6 +60,7 @@ pub(crate) async fn get_context_route(
 	if !services.rooms.lazy_loading.lazy_load_was_sent_before(
 		sender_user,
 		sender_device,
+		body.stream_id.as_deref(),
 		&room_id,
 		&base_event.sender,
 	)? || lazy_load_send_redundant
@@ -91,6 +92,7 @@ pub(crate) async fn get_context_route(
 		if !services.rooms.lazy_loading.lazy_load_was_sent_before(
 			sender_user,
 			sender_device,
+			body.stream_id.as_deref(),
 			&room_id,
 			&event.sender,
 		)? || lazy_load_send_redundant
@@ -127,6 +129,7 @@ pub(crate) async fn get_context_route(
 		if !services.rooms.lazy_loading.lazy_load_was_sent_before(
 			sender_user,
 			sender_device,
+			body.stream_id.as_deref(),
 			&room_id,
 			&event.sender,
 		)? || lazy_load_send_redundant
@@ -9,6 +9,7 @@ use conduit::{
 };
 use conduit_service::{
 	media::{Dim, FileMeta, CACHE_CONTROL_IMMUTABLE, CORP_CROSS_ORIGIN, MXC_LENGTH},
+	spam_checker::Verdict,
 	Services,
 };
 use ruma::{
@@ -53,6 +54,21 @@ pub(crate) async fn create_content_route(
 		media_id: &utils::random_string(MXC_LENGTH),
 	};
 
+	match services
+		.spam_checker
+		.check_media_upload(user, content_type, filename)?
+	{
+		Verdict::Allow => {},
+		Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+		Verdict::ShadowBan => {
+			// the upload is dropped, so the returned mxc never resolves
+			return Ok(create_content::v3::Response {
+				content_uri: mxc.to_string().into(),
+				blurhash: None,
+			});
+		},
+	}
+
 	services
 		.media
 		.create(&mxc, Some(user), Some(&content_disposition), content_type, &body.file)
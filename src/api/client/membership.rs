@@ -18,6 +18,7 @@ use ruma::{
 	api::{
 		client::{
 			error::ErrorKind,
+			knock::knock_room,
 			membership::{
 				ban_user, forget_room, get_member_events, invite_user, join_room_by_id, join_room_by_id_or_alias,
 				joined_members::{self, v3::RoomMember},
@@ -40,7 +41,7 @@ use ruma::{
 	OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
-use service::{appservice::RegistrationInfo, rooms::state::RoomMutexGuard, Services};
+use service::{appservice::RegistrationInfo, rooms::state::RoomMutexGuard, spam_checker::Verdict, Services};
 use tokio::sync::RwLock;
 
 use crate::{client::full_user_deactivate, Ruma};
@@ -56,6 +57,23 @@ async fn banned_room_check(
 	client_ip: IpAddr,
 ) -> Result<()> {
 	if !services.users.is_admin(user_id)? {
+		if let Some(ban) = services
+			.policy
+			.user_ban(user_id)
+			.or_else(|| room_id.and_then(|room_id| services.policy.room_ban(room_id)))
+			.or_else(|| server_name.and_then(|server_name| services.policy.server_ban(server_name)))
+		{
+			services
+				.policy
+				.report(&format!("Refused {user_id} joining or inviting to a room"), &ban)
+				.await;
+
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"This room or user is banned by a policy list of this server.",
+			));
+		}
+
 		if let Some(room_id) = room_id {
 			if services.rooms.metadata.is_banned(room_id)?
 				|| services
@@ -312,6 +330,54 @@ pub(crate) async fn join_room_by_id_or_alias_route(
 	})
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Tries to knock on a room to ask for an invite.
+///
+/// - If the server knowns about this room: creates the knock event and does
+///   auth rules locally
+/// - If the server does not know about the room: asks the servers in `via`, the
+///   room alias server name and the room ID server name over federation
+#[tracing::instrument(skip_all, fields(%client), name = "knock")]
+pub(crate) async fn knock_room_route(
+	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp,
+	body: Ruma<knock_room::v3::Request>,
+) -> Result<knock_room::v3::Response> {
+	let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+	let body = body.body;
+
+	let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
+		Ok(room_id) => {
+			banned_room_check(&services, sender_user, Some(&room_id), room_id.server_name(), client).await?;
+
+			let mut servers = body.via;
+			if let Some(server) = room_id.server_name() {
+				servers.push(server.to_owned());
+			}
+
+			(servers, room_id)
+		},
+		Err(room_alias) => {
+			let (room_id, pre_servers) = services
+				.rooms
+				.alias
+				.resolve_alias(&room_alias, Some(&body.via.clone()))
+				.await?;
+
+			banned_room_check(&services, sender_user, Some(&room_id), Some(room_alias.server_name()), client).await?;
+
+			let mut servers = body.via;
+			servers.extend(pre_servers.unwrap_or_default());
+
+			(servers, room_id)
+		},
+	};
+
+	knock_room_helper(&services, sender_user, &room_id, body.reason, &servers).await?;
+
+	Ok(knock_room::v3::Response::new(room_id))
+}
+
 /// # `POST /_matrix/client/v3/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -354,6 +420,11 @@ pub(crate) async fn invite_user_route(
 		user_id,
 	} = &body.recipient
 	{
+		services
+			.policy
+			.check_membership(user_id, &body.room_id, &MembershipState::Invite)
+			.await?;
+
 		invite_helper(&services, sender_user, user_id, &body.room_id, body.reason.clone(), false).await?;
 		Ok(invite_user::v3::Response {})
 	} else {
@@ -656,6 +727,9 @@ pub async fn join_room_by_id_helper(
 	services: &Services, sender_user: &UserId, room_id: &RoomId, reason: Option<String>, servers: &[OwnedServerName],
 	third_party_signed: Option<&ThirdPartySigned>, appservice_info: &Option<RegistrationInfo>,
 ) -> Result<join_room_by_id::v3::Response> {
+	// Joins over federation never build a local event refused in the timeline
+	services.users.check_suspended(sender_user)?;
+
 	let state_lock = services.rooms.state.mutex.lock(room_id).await;
 
 	let user_is_guest = services.users.is_deactivated(sender_user).unwrap_or(false) && appservice_info.is_none();
@@ -671,6 +745,17 @@ pub async fn join_room_by_id_helper(
 		});
 	}
 
+	match services.spam_checker.check_join(sender_user, room_id)? {
+		Verdict::Allow => {},
+		Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+		Verdict::ShadowBan => {
+			debug!("Pretending {sender_user} shadow-banned by the spam checker joined {room_id}");
+			return Ok(join_room_by_id::v3::Response {
+				room_id: room_id.into(),
+			});
+		},
+	}
+
 	if services
 		.rooms
 		.state_cache
@@ -1307,6 +1392,253 @@ async fn make_join_request(
 	make_join_response_and_server
 }
 
+async fn knock_room_helper(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, reason: Option<String>, servers: &[OwnedServerName],
+) -> Result<()> {
+	services.users.check_suspended(sender_user)?;
+
+	let state_lock = services.rooms.state.mutex.lock(room_id).await;
+
+	if services.rooms.state_cache.is_joined(sender_user, room_id)? {
+		return Err!(Request(Forbidden("You are already joined to this room.")));
+	}
+
+	if services
+		.rooms
+		.state_cache
+		.is_invited(sender_user, room_id)?
+	{
+		return Err!(Request(Forbidden("You are already invited to this room, join it instead.")));
+	}
+
+	if services
+		.rooms
+		.state_cache
+		.is_knocked(sender_user, room_id)?
+	{
+		debug_warn!("{sender_user} already knocked on {room_id}");
+		return Ok(());
+	}
+
+	if services
+		.rooms
+		.state_cache
+		.server_in_room(services.globals.server_name(), room_id)?
+		|| servers.is_empty()
+		|| (servers.len() == 1 && services.globals.server_is_ours(&servers[0]))
+	{
+		knock_room_helper_local(services, sender_user, room_id, reason, state_lock).await
+	} else {
+		// Ask a remote server if we are not participating in this room
+		knock_room_helper_remote(services, sender_user, room_id, reason, servers, state_lock).await
+	}
+}
+
+async fn knock_room_helper_local(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, reason: Option<String>, state_lock: RoomMutexGuard,
+) -> Result<()> {
+	debug!("We can knock locally");
+
+	let room_version_id = services.rooms.state.get_room_version(room_id)?;
+	if !room_version_supports_knocking(&room_version_id) {
+		return Err!(Request(Forbidden("This room does not support knocking.")));
+	}
+
+	let content = RoomMemberEventContent {
+		displayname: services.users.displayname(sender_user)?,
+		avatar_url: services.users.avatar_url(sender_user)?,
+		blurhash: services.users.blurhash(sender_user)?,
+		reason,
+		..RoomMemberEventContent::new(MembershipState::Knock)
+	};
+
+	services
+		.rooms
+		.timeline
+		.build_and_append_pdu(
+			PduBuilder {
+				event_type: TimelineEventType::RoomMember,
+				content: to_raw_value(&content).expect("event is valid, we just created it"),
+				unsigned: None,
+				state_key: Some(sender_user.to_string()),
+				redacts: None,
+				timestamp: None,
+			},
+			sender_user,
+			room_id,
+			&state_lock,
+		)
+		.await?;
+
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(%sender_user, %room_id), name = "knock_remote")]
+async fn knock_room_helper_remote(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, reason: Option<String>, servers: &[OwnedServerName],
+	state_lock: RoomMutexGuard,
+) -> Result<()> {
+	info!("Knocking on {room_id} over federation.");
+
+	let (make_knock_response, remote_server) = make_knock_request(services, sender_user, room_id, servers).await?;
+
+	info!("make_knock finished");
+
+	let room_version_id = make_knock_response.room_version;
+	if !services
+		.globals
+		.supported_room_versions()
+		.contains(&room_version_id)
+	{
+		return Err!(BadServerResponse("Room version is not supported"));
+	}
+
+	if !room_version_supports_knocking(&room_version_id) {
+		return Err!(Request(Forbidden("This room does not support knocking.")));
+	}
+
+	let mut knock_event_stub = serde_json::from_str::<CanonicalJsonObject>(make_knock_response.event.get())
+		.map_err(|e| err!(BadServerResponse("Invalid make_knock event json received from server: {e:?}")))?;
+
+	knock_event_stub.insert(
+		"origin".to_owned(),
+		CanonicalJsonValue::String(services.globals.server_name().as_str().to_owned()),
+	);
+	knock_event_stub.insert(
+		"origin_server_ts".to_owned(),
+		CanonicalJsonValue::Integer(
+			utils::millis_since_unix_epoch()
+				.try_into()
+				.expect("Timestamp is valid js_int value"),
+		),
+	);
+	knock_event_stub.insert(
+		"content".to_owned(),
+		to_canonical_value(RoomMemberEventContent {
+			displayname: services.users.displayname(sender_user)?,
+			avatar_url: services.users.avatar_url(sender_user)?,
+			blurhash: services.users.blurhash(sender_user)?,
+			reason,
+			..RoomMemberEventContent::new(MembershipState::Knock)
+		})
+		.expect("event is valid, we just created it"),
+	);
+
+	// room v3 and above removed the "event_id" field from remote PDU format
+	knock_event_stub.remove("event_id");
+
+	// In order to create a compatible ref hash (EventID) the `hashes` field needs
+	// to be present
+	ruma::signatures::hash_and_sign_event(
+		services.globals.server_name().as_str(),
+		services.globals.keypair(),
+		&mut knock_event_stub,
+		&room_version_id,
+	)
+	.expect("event is valid, we just created it");
+
+	// Generate event id
+	let event_id = EventId::parse(format!(
+		"${}",
+		ruma::signatures::reference_hash(&knock_event_stub, &room_version_id)
+			.expect("ruma can calculate reference hashes")
+	))
+	.expect("ruma's reference hashes are valid event ids");
+
+	// Add event_id back
+	knock_event_stub.insert("event_id".to_owned(), CanonicalJsonValue::String(event_id.as_str().to_owned()));
+
+	// It has enough fields to be called a proper event now
+	let knock_event = knock_event_stub;
+
+	info!("Asking {remote_server} for send_knock");
+	let send_knock_response = services
+		.sending
+		.send_federation_request(
+			&remote_server,
+			federation::knock::send_knock::v1::Request {
+				room_id: room_id.to_owned(),
+				event_id,
+				pdu: services
+					.sending
+					.convert_to_outgoing_federation_event(knock_event),
+			},
+		)
+		.await?;
+
+	info!("send_knock finished");
+
+	// We are not in the room, so remember who to ask when rescinding the knock
+	services
+		.rooms
+		.state_cache
+		.add_servers_invite_via(room_id, &[remote_server])?;
+
+	services.rooms.state_cache.update_membership(
+		room_id,
+		sender_user,
+		RoomMemberEventContent::new(MembershipState::Knock),
+		sender_user,
+		Some(send_knock_response.knock_room_state),
+		None,
+		false,
+	)?;
+
+	drop(state_lock);
+
+	Ok(())
+}
+
+async fn make_knock_request(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, servers: &[OwnedServerName],
+) -> Result<(federation::knock::create_knock_event_template::v1::Response, OwnedServerName)> {
+	let mut make_knock_response_and_server = Err!(BadServerResponse("No server available to assist in knocking."));
+
+	let mut make_knock_counter: u16 = 0;
+
+	for remote_server in servers {
+		if services.globals.server_is_ours(remote_server) {
+			continue;
+		}
+
+		info!("Asking {remote_server} for make_knock ({make_knock_counter})");
+		let make_knock_response = services
+			.sending
+			.send_federation_request(
+				remote_server,
+				federation::knock::create_knock_event_template::v1::Request {
+					room_id: room_id.to_owned(),
+					user_id: sender_user.to_owned(),
+					ver: services.globals.supported_room_versions(),
+				},
+			)
+			.await;
+
+		trace!("make_knock response: {make_knock_response:?}");
+		make_knock_counter = make_knock_counter.saturating_add(1);
+
+		make_knock_response_and_server = make_knock_response.map(|r| (r, remote_server.clone()));
+
+		if make_knock_response_and_server.is_ok() {
+			break;
+		}
+
+		if make_knock_counter > 40 {
+			warn!("40 servers failed to provide valid make_knock response, assuming no server can assist in knocking.");
+			break;
+		}
+	}
+
+	make_knock_response_and_server
+}
+
+/// Knocking was introduced in room version 7.
+fn room_version_supports_knocking(room_version_id: &RoomVersionId) -> bool {
+	use RoomVersionId::*;
+
+	!matches!(room_version_id, V1 | V2 | V3 | V4 | V5 | V6)
+}
+
 pub async fn validate_and_add_event_id(
 	services: &Services, pdu: &RawJsonValue, room_version: &RoomVersionId,
 	pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
@@ -1369,6 +1701,9 @@ pub(crate) async fn invite_helper(
 	services: &Services, sender_user: &UserId, user_id: &UserId, room_id: &RoomId, reason: Option<String>,
 	is_direct: bool,
 ) -> Result<()> {
+	// Invites of remote users are signed by their server before any local event
+	services.users.check_suspended(sender_user)?;
+
 	if !services.users.is_admin(user_id)? && services.globals.block_non_admin_invites() {
 		info!("User {sender_user} is not an admin and attempted to send an invite to room {room_id}");
 		return Err(Error::BadRequest(
@@ -1377,6 +1712,18 @@ pub(crate) async fn invite_helper(
 		));
 	}
 
+	match services
+		.spam_checker
+		.check_invite(sender_user, user_id, room_id)?
+	{
+		Verdict::Allow => {},
+		Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+		Verdict::ShadowBan => {
+			debug!("Dropping invite of {user_id} to {room_id} by shadow-banned {sender_user}");
+			return Ok(());
+		},
+	}
+
 	if !services.globals.user_is_local(user_id) {
 		let (pdu, pdu_json, invite_room_state) = {
 			let state_lock = services.rooms.state.mutex.lock(room_id).await;
@@ -1571,9 +1918,10 @@ pub async fn leave_room(services: &Services, user_id: &UserId, room_id: &RoomId,
 			.rooms
 			.state_cache
 			.invite_state(user_id, room_id)?
+			.map_or_else(|| services.rooms.state_cache.knock_state(user_id, room_id), |s| Ok(Some(s)))?
 			.map_or_else(|| services.rooms.state_cache.left_state(user_id, room_id), |s| Ok(Some(s)))?;
 
-		// We always drop the invite, we can't rely on other servers
+		// We always drop the invite or knock, we can't rely on other servers
 		services.rooms.state_cache.update_membership(
 			room_id,
 			user_id,
@@ -1648,7 +1996,8 @@ async fn remote_leave_room(services: &Services, user_id: &UserId, room_id: &Room
 		.rooms
 		.state_cache
 		.invite_state(user_id, room_id)?
-		.ok_or(Error::BadRequest(ErrorKind::BadState, "User is not invited."))?;
+		.map_or_else(|| services.rooms.state_cache.knock_state(user_id, room_id), |s| Ok(Some(s)))?
+		.ok_or(Error::BadRequest(ErrorKind::BadState, "User is not invited or knocking."))?;
 
 	let mut servers: HashSet<OwnedServerName> = services
 		.rooms
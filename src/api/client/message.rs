@@ -133,7 +133,7 @@ pub(crate) async fn get_message_events_route(
 	services
 		.rooms
 		.lazy_loading
-		.lazy_load_confirm_delivery(sender_user, sender_device, &body.room_id, from)
+		.lazy_load_confirm_delivery(sender_user, sender_device, body.stream_id.as_deref(), &body.room_id, from)
 		.await?;
 
 	let limit = usize::try_from(body.limit).unwrap_or(10).min(100);
@@ -167,6 +167,7 @@ pub(crate) async fn get_message_events_route(
 					&& !services.rooms.lazy_loading.lazy_load_was_sent_before(
 						sender_user,
 						sender_device,
+						body.stream_id.as_deref(),
 						&body.room_id,
 						&event.sender,
 					)? {
@@ -212,6 +213,7 @@ pub(crate) async fn get_message_events_route(
 					&& !services.rooms.lazy_loading.lazy_load_was_sent_before(
 						sender_user,
 						sender_device,
+						body.stream_id.as_deref(),
 						&body.room_id,
 						&event.sender,
 					)? {
@@ -252,7 +254,14 @@ pub(crate) async fn get_message_events_route(
 			services
 				.rooms
 				.lazy_loading
-				.lazy_load_mark_sent(sender_user, sender_device, &body.room_id, lazy_loaded, next_token)
+				.lazy_load_mark_sent(
+					sender_user,
+					sender_device,
+					body.stream_id.as_deref(),
+					&body.room_id,
+					lazy_loaded,
+					next_token,
+				)
 				.await;
 		}
 	}
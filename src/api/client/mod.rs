@@ -1,4 +1,5 @@
 pub(super) mod account;
+pub(super) mod admin;
 pub(super) mod alias;
 pub(super) mod appservice;
 pub(super) mod backup;
@@ -39,6 +40,7 @@ pub(super) mod voip;
 
 pub use account::full_user_deactivate;
 pub(super) use account::*;
+pub(super) use admin::*;
 pub(super) use alias::*;
 pub(super) use appservice::*;
 pub(super) use backup::*;
@@ -83,7 +85,7 @@ pub(super) use voip::*;
 const DEVICE_ID_LENGTH: usize = 10;
 
 /// generated user access token length
-const TOKEN_LENGTH: usize = 32;
+pub(crate) const TOKEN_LENGTH: usize = 32;
 
 /// generated user session ID length
 const SESSION_ID_LENGTH: usize = service::uiaa::SESSION_ID_LENGTH;
@@ -5,7 +5,7 @@ use ruma::{
 		error::ErrorKind,
 		push::{
 			delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled, get_pushrules_all,
-			set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, RuleScope,
+			set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, PusherKind, RuleScope,
 		},
 	},
 	events::{
@@ -13,9 +13,10 @@ use ruma::{
 		GlobalAccountDataEventType,
 	},
 	push::{InsertPushRuleError, RemovePushRuleError, Ruleset},
+	thirdparty::Medium,
 	CanonicalJsonObject,
 };
-use service::Services;
+use service::{threepid::normalize_email, Services};
 
 use crate::{Error, Result, Ruma};
 
@@ -390,6 +391,27 @@ pub(crate) async fn set_pushers_route(
 ) -> Result<set_pusher::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	if let set_pusher::v3::PusherAction::Post(data) = &body.action {
+		if matches!(data.pusher.kind, PusherKind::Email(_)) {
+			if !services.mailer.enabled() {
+				return Err(Error::BadRequest(
+					ErrorKind::Unrecognized,
+					"Email notifications are not supported by this server.",
+				));
+			}
+
+			let owner = services
+				.users
+				.find_from_threepid(&Medium::Email, &normalize_email(&data.pusher.ids.pushkey))?;
+			if owner.as_ref() != Some(sender_user) {
+				return Err(Error::BadRequest(
+					ErrorKind::InvalidParam,
+					"Email pushers can only be added for email addresses bound to your account.",
+				));
+			}
+		}
+	}
+
 	services.pusher.set_pusher(sender_user, &body.action)?;
 
 	Ok(set_pusher::v3::Response::default())
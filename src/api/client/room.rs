@@ -31,7 +31,7 @@ use tracing::{error, info, warn};
 
 use super::invite_helper;
 use crate::{
-	service::{appservice::RegistrationInfo, pdu::PduBuilder, Services},
+	service::{appservice::RegistrationInfo, pdu::PduBuilder, spam_checker::Verdict, Services},
 	Error, Result, Ruma,
 };
 
@@ -72,6 +72,8 @@ pub(crate) async fn create_room_route(
 
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	services.users.check_suspended(sender_user)?;
+
 	if !services.globals.allow_room_creation()
 		&& body.appservice_info.is_none()
 		&& !services.users.is_admin(sender_user)?
@@ -79,6 +81,17 @@ pub(crate) async fn create_room_route(
 		return Err(Error::BadRequest(ErrorKind::forbidden(), "Room creation has been disabled."));
 	}
 
+	// every event of a shadow-banned user is dropped, so the room is only pretended
+	let shadow_banned =
+		match services
+			.spam_checker
+			.check_room_creation(sender_user, body.name.as_deref(), body.topic.as_deref())?
+		{
+			Verdict::Allow => false,
+			Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+			Verdict::ShadowBan => true,
+		};
+
 	let room_id: OwnedRoomId = if let Some(custom_room_id) = &body.room_id {
 		custom_room_id_check(&services, custom_room_id)?
 	} else {
@@ -93,6 +106,11 @@ pub(crate) async fn create_room_route(
 		));
 	}
 
+	if shadow_banned {
+		debug_info!("Pretending to create {room_id} for shadow-banned {sender_user}");
+		return Ok(create_room::v3::Response::new(room_id));
+	}
+
 	if body.visibility == room::Visibility::Public
 		&& services.globals.config.lockdown_public_room_directory
 		&& !services.users.is_admin(sender_user)?
@@ -1,28 +1,45 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
 use axum::extract::State;
+use conduit::{utils, PduCount, PduEvent};
 use ruma::{
 	api::client::{
 		error::ErrorKind,
 		search::search_events::{
 			self,
-			v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+			v3::{
+				EventContextResult, GroupingKey, OwnedRoomIdOrUserId, ResultCategories, ResultGroup, ResultRoomEvents,
+				SearchResult,
+			},
 		},
 	},
-	events::AnyStateEvent,
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		AnyStateEvent, StateEventType,
+	},
 	serde::Raw,
-	uint, OwnedRoomId,
+	uint, OwnedRoomId, RoomId, UInt, UserId,
 };
+use service::Services;
 use tracing::debug;
 
-use crate::{Error, Result, Ruma};
+use crate::{
+	service::rooms::search::{PaginationToken, Query, RankedPdu, SearchCriteria, SearchResults, Tokenizer},
+	Error, Result, Ruma,
+};
 
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect
-///   history visibility)
+/// - Supports quoted phrases, `prefix*` wildcards, `-excluded` terms and `OR`
+/// - Words are stemmed in the search language of each room
+/// - Results are sorted by rank when `order_by` is `rank`, newest first
+///   otherwise
+/// - Searches rooms the user is joined to or has left, only returning events
+///   their history visibility allowed them to see, up to when they left
+/// - The state of rooms the user left is returned as of their leave event
+/// - Results can be grouped by `room_id` and `sender`
 pub(crate) async fn search_events_route(
 	State(services): State<crate::State>, body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
@@ -31,15 +48,52 @@ pub(crate) async fn search_events_route(
 	let search_criteria = body.search_categories.room_events.as_ref().unwrap();
 	let filter = &search_criteria.filter;
 	let include_state = &search_criteria.include_state;
+	let order_by = search_criteria.order_by.as_ref();
 
-	let room_ids = filter.rooms.clone().unwrap_or_else(|| {
-		services
+	// Events of rooms the user left are searched up to their leave event.
+	let mut left_rooms = BTreeMap::new();
+	let room_ids = if let Some(rooms) = &filter.rooms {
+		for room_id in rooms {
+			if services.rooms.state_cache.is_joined(sender_user, room_id)? {
+				continue;
+			}
+
+			let Some(leave) = leave_event(&services, sender_user, room_id)? else {
+				return Err(Error::BadRequest(
+					ErrorKind::forbidden(),
+					"You don't have permission to view this room.",
+				));
+			};
+
+			left_rooms.insert(room_id.clone(), leave);
+		}
+
+		rooms.clone()
+	} else {
+		let mut room_ids: Vec<OwnedRoomId> = services
 			.rooms
 			.state_cache
 			.rooms_joined(sender_user)
 			.filter_map(Result::ok)
-			.collect()
-	});
+			.collect();
+
+		for room_id in services
+			.rooms
+			.state_cache
+			.rooms_left(sender_user)
+			.filter_map(Result::ok)
+			.map(|(room_id, _)| room_id)
+		{
+			// Rooms only left by leaving or being banned have an event to search up
+			// to, not those the user is still invited to or knocking on.
+			if let Some(leave) = leave_event(&services, sender_user, &room_id)? {
+				left_rooms.insert(room_id.clone(), leave);
+				room_ids.push(room_id);
+			}
+		}
+
+		room_ids
+	};
 
 	// Use limit or else 10, with maximum 100
 	let limit: usize = filter
@@ -53,130 +107,196 @@ pub(crate) async fn search_events_route(
 
 	if include_state.is_some_and(|include_state| include_state) {
 		for room_id in &room_ids {
-			if !services.rooms.state_cache.is_joined(sender_user, room_id)? {
-				return Err(Error::BadRequest(
-					ErrorKind::forbidden(),
-					"You don't have permission to view this room.",
-				));
-			}
+			let room_state = if let Some((_, leave)) = left_rooms.get(room_id) {
+				// The state as of when the user left the room
+				let shortstatehash = services
+					.rooms
+					.state_accessor
+					.pdu_shortstatehash(&leave.event_id)?
+					.ok_or_else(|| Error::bad_database("Leave event has no state."))?;
 
-			// check if sender_user can see state events
-			if services
+				let mut room_state = services
+					.rooms
+					.state_accessor
+					.state_full(shortstatehash)
+					.await?;
+
+				room_state.insert((StateEventType::RoomMember, sender_user.to_string()), leave.clone());
+				room_state
+			} else if services
 				.rooms
 				.state_accessor
 				.user_can_see_state_events(sender_user, room_id)?
 			{
-				let room_state = services
+				services
 					.rooms
 					.state_accessor
 					.room_state_full(room_id)
 					.await?
-					.values()
-					.map(|pdu| pdu.to_state_event())
-					.collect::<Vec<_>>();
-
-				debug!("Room state: {:?}", room_state);
-
-				room_states.insert(room_id.clone(), room_state);
 			} else {
 				return Err(Error::BadRequest(
 					ErrorKind::forbidden(),
 					"You don't have permission to view this room.",
 				));
-			}
-		}
-	}
+			};
 
-	let mut searches = Vec::new();
+			let room_state = room_state
+				.values()
+				.map(|pdu| pdu.to_state_event())
+				.collect::<Vec<_>>();
 
-	for room_id in &room_ids {
-		if !services.rooms.state_cache.is_joined(sender_user, room_id)? {
-			return Err(Error::BadRequest(
-				ErrorKind::forbidden(),
-				"You don't have permission to view this room.",
-			));
-		}
+			debug!("Room state: {:?}", room_state);
 
-		if let Some(search) = services
-			.rooms
-			.search
-			.search_pdus(room_id, &search_criteria.search_term)?
-		{
-			searches.push(search.0.peekable());
+			room_states.insert(room_id.clone(), room_state);
 		}
 	}
 
-	let skip: usize = match body.next_batch.as_ref().map(|s| s.parse()) {
-		Some(Ok(s)) => s,
-		Some(Err(_)) => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token.")),
-		None => 0, // Default to the start
-	};
+	let token = body
+		.next_batch
+		.as_deref()
+		.map(str::parse::<PaginationToken>)
+		.transpose()?;
 
-	let mut results = Vec::new();
-	let next_batch = skip.saturating_add(limit);
+	// Ranks depend on the age of events; keep computing them as of the first page
+	// so the order is the same on every page.
+	let now = token
+		.as_ref()
+		.map_or_else(utils::millis_since_unix_epoch, |token| token.now);
 
-	for _ in 0..next_batch {
-		if let Some(s) = searches
-			.iter_mut()
-			.map(|s| (s.peek().cloned(), s))
-			.max_by_key(|(peek, _)| peek.clone())
-			.and_then(|(_, i)| i.next())
-		{
-			results.push(s);
-		}
-	}
+	let search_term = &search_criteria.search_term;
+	let SearchResults {
+		results: page,
+		count,
+		more,
+	} = services.rooms.search.search_pdus(
+		&SearchCriteria {
+			room_ids: &room_ids,
+			search_term,
+			order_by,
+			from: token.as_ref(),
+			now,
+			limit,
+		},
+		|pdu| {
+			if pdu.is_redacted() {
+				return false;
+			}
+
+			let Some((left, _)) = left_rooms.get(&pdu.room_id) else {
+				return services
+					.rooms
+					.state_accessor
+					.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+					.unwrap_or(false);
+			};
 
-	let results: Vec<_> = results
-		.iter()
-		.skip(skip)
-		.filter_map(|result| {
 			services
 				.rooms
 				.timeline
-				.get_pdu_from_id(result)
-				.ok()?
-				.filter(|pdu| {
-					!pdu.is_redacted()
-						&& services
-							.rooms
-							.state_accessor
-							.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-							.unwrap_or(false)
-				})
-				.map(|pdu| pdu.to_room_event())
-		})
-		.map(|result| {
-			Ok::<_, Error>(SearchResult {
-				context: EventContextResult {
-					end: None,
-					events_after: Vec::new(),
-					events_before: Vec::new(),
-					profile_info: BTreeMap::new(),
-					start: None,
-				},
-				rank: None,
-				result: Some(result),
-			})
+				.get_pdu_count(&pdu.event_id)
+				.ok()
+				.flatten()
+				.is_some_and(|count| count < *left)
+				&& services
+					.rooms
+					.state_accessor
+					.user_could_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+					.unwrap_or(false)
+		},
+	)?;
+
+	let next_batch = page
+		.last()
+		.filter(|_| more)
+		.map(|last| PaginationToken::new(now, last, order_by).to_string());
+
+	let mut groups = BTreeMap::new();
+	for grouping in &search_criteria.groupings.group_by {
+		let Some(key) = &grouping.key else {
+			continue;
+		};
+
+		let Some(group_id) = group_of(key) else {
+			continue;
+		};
+
+		let mut group_results = BTreeMap::<OwnedRoomIdOrUserId, ResultGroup>::new();
+		for result in &page {
+			let order = group_results.len();
+			let group = group_results.entry(group_id(result)).or_insert_with(|| {
+				let mut group = ResultGroup::new();
+				group.order = UInt::try_from(order).ok();
+				group
+			});
+
+			group.results.push(result.pdu.event_id.clone());
+		}
+
+		for group in group_results.values_mut() {
+			group.next_batch.clone_from(&next_batch);
+		}
+
+		groups.insert(key.clone(), group_results);
+	}
+
+	let results: Vec<_> = page
+		.into_iter()
+		.map(|result| SearchResult {
+			context: EventContextResult {
+				end: None,
+				events_after: Vec::new(),
+				events_before: Vec::new(),
+				profile_info: BTreeMap::new(),
+				start: None,
+			},
+			rank: Some(result.rank),
+			result: Some(result.pdu.to_room_event()),
 		})
-		.filter_map(Result::ok)
-		.take(limit)
 		.collect();
 
-	let more_unloaded_results = searches.iter_mut().any(|s| s.peek().is_some());
-	let next_batch = more_unloaded_results.then(|| next_batch.to_string());
-
 	Ok(search_events::v3::Response::new(ResultCategories {
 		room_events: ResultRoomEvents {
-			count: Some(results.len().try_into().unwrap_or_else(|_| uint!(0))),
-			groups: BTreeMap::new(), // TODO
+			count: Some(count.try_into().unwrap_or_else(|_| uint!(0))),
+			groups,
 			next_batch,
 			results,
 			state: room_states,
-			highlights: search_criteria
-				.search_term
-				.split_terminator(|c: char| !c.is_alphanumeric())
-				.map(str::to_lowercase)
-				.collect(),
+			highlights: Query::parse(search_term, &Tokenizer::default()).highlights(),
 		},
 	}))
 }
+
+/// Returns the event the user left a room with and its position in the
+/// timeline, if their current membership is `leave` or `ban`.
+fn leave_event(services: &Services, user_id: &UserId, room_id: &RoomId) -> Result<Option<(PduCount, Arc<PduEvent>)>> {
+	let Some(leave) =
+		services
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
+	else {
+		return Ok(None);
+	};
+
+	let left = serde_json::from_str::<RoomMemberEventContent>(leave.content.get())
+		.is_ok_and(|content| matches!(content.membership, MembershipState::Leave | MembershipState::Ban));
+
+	if !left {
+		return Ok(None);
+	}
+
+	let Some(count) = services.rooms.timeline.get_pdu_count(&leave.event_id)? else {
+		return Ok(None);
+	};
+
+	Ok(Some((count, leave)))
+}
+
+/// Returns what results are grouped by for a grouping key, if supported.
+fn group_of(key: &GroupingKey) -> Option<fn(&RankedPdu) -> OwnedRoomIdOrUserId> {
+	match key {
+		GroupingKey::RoomId => Some(|result| OwnedRoomIdOrUserId::RoomId(result.pdu.room_id.clone())),
+		GroupingKey::Sender => Some(|result| OwnedRoomIdOrUserId::UserId(result.pdu.sender.clone())),
+		_ => None,
+	}
+}
@@ -1,24 +1,31 @@
-use axum::extract::State;
+use axum::{
+	extract::State,
+	response::{Html, IntoResponse, Redirect, Response},
+};
 use axum_client_ip::InsecureClientIp;
+use conduit::{err, utils::HtmlEscape, Err};
+use http::Uri;
 use ruma::{
 	api::client::{
 		error::ErrorKind,
 		session::{
 			get_login_types::{
 				self,
-				v3::{ApplicationServiceLoginType, PasswordLoginType},
+				v3::{ApplicationServiceLoginType, PasswordLoginType, SsoLoginType, TokenLoginType},
 			},
 			login::{
 				self,
 				v3::{DiscoveryInfo, HomeserverInfo},
 			},
-			logout, logout_all,
+			logout, logout_all, refresh_token, sso_login,
 		},
 		uiaa::UserIdentifier,
 	},
+	thirdparty::Medium,
 	UserId,
 };
 use serde::Deserialize;
+use service::{oidc::Completion, threepid::normalize_email};
 use tracing::{debug, info, warn};
 
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
@@ -36,12 +43,89 @@ struct Claims {
 /// the `type` field when logging in.
 #[tracing::instrument(skip_all, fields(%client), name = "login")]
 pub(crate) async fn get_login_types_route(
-	InsecureClientIp(client): InsecureClientIp, _body: Ruma<get_login_types::v3::Request>,
+	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp,
+	_body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-	Ok(get_login_types::v3::Response::new(vec![
+	let mut flows = vec![
 		get_login_types::v3::LoginType::Password(PasswordLoginType::default()),
 		get_login_types::v3::LoginType::ApplicationService(ApplicationServiceLoginType::default()),
-	]))
+	];
+
+	if services.oidc.enabled() {
+		flows.push(get_login_types::v3::LoginType::Sso(SsoLoginType::default()));
+		flows.push(get_login_types::v3::LoginType::Token(TokenLoginType::default()));
+	}
+
+	Ok(get_login_types::v3::Response::new(flows))
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect`
+///
+/// Sends the user to log in at the identity provider. Once done, the user is
+/// sent to `redirectUrl` with a `loginToken` to log in with `m.login.token`.
+#[tracing::instrument(skip_all, fields(%client), name = "login")]
+pub(crate) async fn sso_login_route(
+	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp,
+	body: Ruma<sso_login::v3::Request>,
+) -> Result<sso_login::v3::Response> {
+	if !services.oidc.enabled() {
+		return Err!(Request(Unknown("SSO login is not enabled on this server.")));
+	}
+
+	let location = services.oidc.authorization_url(&body.redirect_url).await?;
+
+	Ok(sso_login::v3::Response::new(location))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OidcCallback {
+	code: Option<String>,
+	state: String,
+	error: Option<String>,
+	error_description: Option<String>,
+}
+
+/// # `GET /_conduwuit/oidc/callback`
+///
+/// Where the identity provider sends users back to with an authorization code
+/// after logging in. Completes the login and sends the user on to the client,
+/// asking them to confirm first unless the client is trusted.
+#[tracing::instrument(skip_all, fields(%client), name = "login")]
+pub(crate) async fn oidc_callback_route(
+	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp, uri: Uri,
+) -> Result<Response> {
+	let callback: OidcCallback = serde_html_form::from_str(uri.query().unwrap_or_default())
+		.map_err(|e| err!(Request(InvalidParam("Invalid callback parameters: {e}"))))?;
+
+	if let Some(error) = callback.error {
+		let description = callback.error_description.unwrap_or_default();
+		return Err!(Request(Forbidden(debug_warn!(
+			"Identity provider refused the login: {error} {description}"
+		))));
+	}
+
+	let code = callback
+		.code
+		.ok_or_else(|| err!(Request(MissingParam("Missing authorization code."))))?;
+
+	let response = match services.oidc.complete(&code, &callback.state).await? {
+		Completion::Redirect(location) => Redirect::to(&location).into_response(),
+		Completion::Confirm {
+			user_id,
+			client,
+			location,
+		} => Html(format!(
+			"<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Continue to your \
+			 client</title></head><body><p>You are logging in as {} to the client at {}.</p><p>Only continue if you \
+			 started this login yourself.</p><p><a href=\"{}\">Continue</a></p></body></html>",
+			HtmlEscape(user_id.as_str()),
+			HtmlEscape(&client),
+			HtmlEscape(&location),
+		))
+		.into_response(),
+	};
+
+	Ok(response)
 }
 
 /// # `POST /_matrix/client/v3/login`
@@ -49,8 +133,8 @@ pub(crate) async fn get_login_types_route(
 /// Authenticates the user and returns an access token it can use in subsequent
 /// requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a
-///   json web token)
+/// - The user needs to authenticate using their password, or if enabled using a
+///   json web token or a login token from SSO
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -73,15 +157,24 @@ pub(crate) async fn login_route(
 			..
 		}) => {
 			debug!("Got password login type");
-			let user_id = if let Some(UserIdentifier::UserIdOrLocalpart(user_id)) = identifier {
+			let user_id = if let Some(UserIdentifier::Email {
+				address,
+			}) = identifier
+			{
+				services
+					.users
+					.find_from_threepid(&Medium::Email, &normalize_email(address))?
+					.ok_or(Error::BadRequest(ErrorKind::forbidden(), "Wrong username or password."))?
+			} else if let Some(UserIdentifier::UserIdOrLocalpart(user_id)) = identifier {
 				UserId::parse_with_server_name(user_id.to_lowercase(), services.globals.server_name())
+					.map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?
 			} else if let Some(user) = user {
 				UserId::parse(user)
+					.map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?
 			} else {
 				warn!("Bad login type: {:?}", &body.login_info);
 				return Err(Error::BadRequest(ErrorKind::forbidden(), "Bad login type."));
-			}
-			.map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?;
+			};
 
 			let hash = services
 				.users
@@ -102,7 +195,13 @@ pub(crate) async fn login_route(
 			token,
 		}) => {
 			debug!("Got token login type");
-			if let Some(jwt_decoding_key) = services.globals.jwt_decoding_key() {
+			if let Some(user_id) = services.oidc.take_login_token(token) {
+				if services.users.is_deactivated(&user_id)? {
+					return Err(Error::BadRequest(ErrorKind::UserDeactivated, "The user has been deactivated"));
+				}
+
+				user_id
+			} else if let Some(jwt_decoding_key) = services.globals.jwt_decoding_key() {
 				let token =
 					jsonwebtoken::decode::<Claims>(token, jwt_decoding_key, &jsonwebtoken::Validation::default())
 						.map_err(|e| {
@@ -159,6 +258,12 @@ pub(crate) async fn login_route(
 		},
 	};
 
+	if services.users.is_locked(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserLocked, "This account has been locked."));
+	}
+
+	services.account_validity.check(&user_id)?;
+
 	// Generate new device id if the user didn't specify one
 	let device_id = body
 		.device_id
@@ -188,6 +293,18 @@ pub(crate) async fn login_route(
 		)?;
 	}
 
+	// Clients supporting refresh tokens get an expiring access token
+	let (refresh_token, expires_in) = if body.refresh_token {
+		let refresh_token = utils::random_string(TOKEN_LENGTH);
+		let expires_in = services
+			.users
+			.set_refresh_token(&user_id, &device_id, &token, &refresh_token)?;
+
+		(Some(refresh_token), Some(expires_in))
+	} else {
+		(None, None)
+	};
+
 	// send client well-known if specified so the client knows to reconfigure itself
 	let client_discovery_info: Option<DiscoveryInfo> = services
 		.globals
@@ -206,9 +323,9 @@ pub(crate) async fn login_route(
 		access_token: token,
 		device_id,
 		well_known: client_discovery_info,
-		expires_in: None,
+		expires_in,
 		home_server: Some(services.globals.server_name().to_owned()),
-		refresh_token: None,
+		refresh_token,
 	})
 }
 
@@ -265,3 +382,52 @@ pub(crate) async fn logout_all_route(
 
 	Ok(logout_all::v3::Response::new())
 }
+
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Exchanges a refresh token for a new access token and refresh token.
+///
+/// - Refresh tokens can only be used once, the used one is revoked
+/// - Invalidates the previous access token of the device
+#[tracing::instrument(skip_all, fields(%client), name = "refresh")]
+pub(crate) async fn refresh_token_route(
+	State(services): State<crate::State>, InsecureClientIp(client): InsecureClientIp,
+	body: Ruma<refresh_token::v3::Request>,
+) -> Result<refresh_token::v3::Response> {
+	let Some((user_id, device_id)) = services
+		.users
+		.find_from_refresh_token(&body.refresh_token)?
+	else {
+		return Err(Error::BadRequest(
+			ErrorKind::UnknownToken {
+				soft_logout: false,
+			},
+			"Unknown refresh token.",
+		));
+	};
+
+	if services.users.is_deactivated(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserDeactivated, "The user has been deactivated"));
+	}
+
+	if services.users.is_locked(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserLocked, "This account has been locked."));
+	}
+
+	services.account_validity.check(&user_id)?;
+
+	let access_token = utils::random_string(TOKEN_LENGTH);
+	let refresh_token = utils::random_string(TOKEN_LENGTH);
+
+	let expires_in = services
+		.users
+		.rotate_tokens(&user_id, &device_id, &access_token, &refresh_token)?;
+
+	debug!("{user_id} refreshed the access token of device {device_id}");
+
+	Ok(refresh_token::v3::Response {
+		access_token,
+		refresh_token: Some(refresh_token),
+		expires_in_ms: Some(expires_in),
+	})
+}
@@ -1,3 +1,5 @@
+mod v5;
+
 use std::{
 	cmp::{self, Ordering},
 	collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
@@ -17,8 +19,8 @@ use ruma::{
 		sync::sync_events::{
 			self,
 			v3::{
-				Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom, LeftRoom, Presence,
-				RoomAccountData, RoomSummary, Rooms, State as RoomState, Timeline, ToDevice,
+				Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom, KnockState, KnockedRoom,
+				LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State as RoomState, Timeline, ToDevice,
 			},
 			v4::{SlidingOp, SlidingSyncRoomHero},
 			DeviceLists, UnreadNotificationsCount,
@@ -38,6 +40,7 @@ use ruma::{
 use service::rooms::read_receipt::pack_receipts;
 use tracing::{Instrument as _, Span};
 
+pub(crate) use self::v5::sync_events_v5_route;
 use crate::{
 	service::{pdu::EventHash, Services},
 	utils, Error, PduEvent, Result, Ruma, RumaResponse,
@@ -88,12 +91,19 @@ macro_rules! extract_variant {
 /// - If there are events in the timeline we send or the user send updated his
 ///   read mark: Notification counts
 /// - EDUs that are active now (read receipts, typing updates, presence)
-/// - TODO: Allow multiple sync streams to support Pantalaimon
+/// - Clients syncing a device more than once at the same time, like
+///   Pantalaimon, tell their streams apart with the unstable
+///   `gay.puppyirl.conduwuit.stream_id` query parameter; to-device events and
+///   lazy-loaded members are tracked per stream
 ///
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room
 ///   at the point of the invite
 ///
+/// For knocked rooms:
+/// - If the user knocked after `since`: A subset of the state of the room at
+///   the point of the knock
+///
 /// For left rooms:
 /// - If the user left after `since`: `prev_batch` token, empty state (TODO:
 ///   subset of the state at the point of the leave)
@@ -102,6 +112,7 @@ pub(crate) async fn sync_events_route(
 ) -> Result<sync_events::v3::Response, RumaResponse<UiaaResponse>> {
 	let sender_user = body.sender_user.expect("user is authenticated");
 	let sender_device = body.sender_device.expect("user is authenticated");
+	let stream_id = body.stream_id;
 	let body = body.body;
 
 	// Presence update
@@ -179,6 +190,7 @@ pub(crate) async fn sync_events_route(
 			&services,
 			&sender_user,
 			&sender_device,
+			stream_id.as_deref(),
 			&room_id,
 			since,
 			sincecount,
@@ -252,6 +264,39 @@ pub(crate) async fn sync_events_route(
 		);
 	}
 
+	let mut knocked_rooms = BTreeMap::new();
+	let all_knocked_rooms: Vec<_> = services
+		.rooms
+		.state_cache
+		.rooms_knocked(&sender_user)
+		.collect();
+	for result in all_knocked_rooms {
+		let (room_id, knock_state_events) = result?;
+
+		// Get and drop the lock to wait for remaining operations to finish
+		let insert_lock = services.rooms.timeline.mutex_insert.lock(&room_id).await;
+		drop(insert_lock);
+
+		let knock_count = services
+			.rooms
+			.state_cache
+			.get_knock_count(&room_id, &sender_user)?;
+
+		// Knocked before last sync
+		if Some(since) >= knock_count {
+			continue;
+		}
+
+		knocked_rooms.insert(
+			room_id.clone(),
+			KnockedRoom {
+				knock_state: KnockState {
+					events: knock_state_events,
+				},
+			},
+		);
+	}
+
 	for user_id in left_encrypted_users {
 		let dont_share_encrypted_room = services
 			.rooms
@@ -279,7 +324,7 @@ pub(crate) async fn sync_events_route(
 	// Remove all to-device events the device received *last time*
 	services
 		.users
-		.remove_to_device_events(&sender_user, &sender_device, since)?;
+		.remove_to_device_events(&sender_user, &sender_device, stream_id.as_deref(), since)?;
 
 	let response = sync_events::v3::Response {
 		next_batch: next_batch_string,
@@ -287,7 +332,7 @@ pub(crate) async fn sync_events_route(
 			leave: left_rooms,
 			join: joined_rooms,
 			invite: invited_rooms,
-			knock: BTreeMap::new(), // TODO
+			knock: knocked_rooms,
 		},
 		presence: Presence {
 			events: presence_updates
@@ -313,7 +358,7 @@ pub(crate) async fn sync_events_route(
 		to_device: ToDevice {
 			events: services
 				.users
-				.get_to_device_events(&sender_user, &sender_device)?,
+				.get_to_device_events(&sender_user, &sender_device, stream_id.as_deref())?,
 		},
 		// Fallback keys are not yet supported
 		device_unused_fallback_key_types: None,
@@ -541,8 +586,8 @@ async fn process_presence_updates(
 
 #[allow(clippy::too_many_arguments)]
 async fn load_joined_room(
-	services: &Services, sender_user: &UserId, sender_device: &DeviceId, room_id: &RoomId, since: u64,
-	sincecount: PduCount, next_batch: u64, next_batchcount: PduCount, lazy_load_enabled: bool,
+	services: &Services, sender_user: &UserId, sender_device: &DeviceId, stream_id: Option<&str>, room_id: &RoomId,
+	since: u64, sincecount: PduCount, next_batch: u64, next_batchcount: PduCount, lazy_load_enabled: bool,
 	lazy_load_send_redundant: bool, full_state: bool, device_list_updates: &mut HashSet<OwnedUserId>,
 	left_encrypted_users: &mut HashSet<OwnedUserId>,
 ) -> Result<JoinedRoom> {
@@ -568,7 +613,7 @@ async fn load_joined_room(
 	services
 		.rooms
 		.lazy_loading
-		.lazy_load_confirm_delivery(sender_user, sender_device, room_id, sincecount)
+		.lazy_load_confirm_delivery(sender_user, sender_device, stream_id, room_id, sincecount)
 		.await?;
 
 	// Database queries:
@@ -727,14 +772,14 @@ async fn load_joined_room(
 				services
 					.rooms
 					.lazy_loading
-					.lazy_load_reset(sender_user, sender_device, room_id)?;
+					.lazy_load_reset(sender_user, sender_device, stream_id, room_id)?;
 
 				// The state_events above should contain all timeline_users, let's mark them as
 				// lazy loaded.
 				services
 					.rooms
 					.lazy_loading
-					.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
+					.lazy_load_mark_sent(sender_user, sender_device, stream_id, room_id, lazy_loaded, next_batchcount)
 					.await;
 
 				(heroes, joined_member_count, invited_member_count, true, state_events)
@@ -880,6 +925,7 @@ async fn load_joined_room(
 					if !services.rooms.lazy_loading.lazy_load_was_sent_before(
 						sender_user,
 						sender_device,
+						stream_id,
 						room_id,
 						&event.sender,
 					)? || lazy_load_send_redundant
@@ -898,7 +944,7 @@ async fn load_joined_room(
 				services
 					.rooms
 					.lazy_loading
-					.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
+					.lazy_load_mark_sent(sender_user, sender_device, stream_id, room_id, lazy_loaded, next_batchcount)
 					.await;
 
 				(
@@ -1158,16 +1204,13 @@ pub(crate) async fn sync_events_v4_route(
 		.chain(all_invited_rooms.clone())
 		.collect();
 
+	// Every sliding sync connection receives to-device events as its own stream.
 	if body.extensions.to_device.enabled.unwrap_or(false) {
 		services
 			.users
-			.remove_to_device_events(&sender_user, &sender_device, globalsince)?;
+			.remove_to_device_events(&sender_user, &sender_device, body.conn_id.as_deref(), globalsince)?;
 	}
 
-	let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
-	let mut device_list_changes = HashSet::new();
-	let mut device_list_left = HashSet::new();
-
 	let mut receipts = sync_events::v4::Receipts {
 		rooms: BTreeMap::new(),
 	};
@@ -1199,165 +1242,11 @@ pub(crate) async fn sync_events_v4_route(
 		}
 	}
 
-	if body.extensions.e2ee.enabled.unwrap_or(false) {
-		// Look for device list updates of this account
-		device_list_changes.extend(
-			services
-				.users
-				.keys_changed(sender_user.as_ref(), globalsince, None)
-				.filter_map(Result::ok),
-		);
-
-		for room_id in &all_joined_rooms {
-			let Some(current_shortstatehash) = services.rooms.state.get_room_shortstatehash(room_id)? else {
-				error!("Room {} has no state", room_id);
-				continue;
-			};
-
-			let since_shortstatehash = services
-				.rooms
-				.user
-				.get_token_shortstatehash(room_id, globalsince)?;
-
-			let since_sender_member: Option<RoomMemberEventContent> = since_shortstatehash
-				.and_then(|shortstatehash| {
-					services
-						.rooms
-						.state_accessor
-						.state_get(shortstatehash, &StateEventType::RoomMember, sender_user.as_str())
-						.transpose()
-				})
-				.transpose()?
-				.and_then(|pdu| {
-					serde_json::from_str(pdu.content.get())
-						.map_err(|_| Error::bad_database("Invalid PDU in database."))
-						.ok()
-				});
-
-			let encrypted_room = services
-				.rooms
-				.state_accessor
-				.state_get(current_shortstatehash, &StateEventType::RoomEncryption, "")?
-				.is_some();
-
-			if let Some(since_shortstatehash) = since_shortstatehash {
-				// Skip if there are only timeline changes
-				if since_shortstatehash == current_shortstatehash {
-					continue;
-				}
-
-				let since_encryption = services.rooms.state_accessor.state_get(
-					since_shortstatehash,
-					&StateEventType::RoomEncryption,
-					"",
-				)?;
-
-				let joined_since_last_sync =
-					since_sender_member.map_or(true, |member| member.membership != MembershipState::Join);
-
-				let new_encrypted_room = encrypted_room && since_encryption.is_none();
-				if encrypted_room {
-					let current_state_ids = services
-						.rooms
-						.state_accessor
-						.state_full_ids(current_shortstatehash)
-						.await?;
-					let since_state_ids = services
-						.rooms
-						.state_accessor
-						.state_full_ids(since_shortstatehash)
-						.await?;
-
-					for (key, id) in current_state_ids {
-						if since_state_ids.get(&key) != Some(&id) {
-							let Some(pdu) = services.rooms.timeline.get_pdu(&id)? else {
-								error!("Pdu in state not found: {}", id);
-								continue;
-							};
-							if pdu.kind == TimelineEventType::RoomMember {
-								if let Some(state_key) = &pdu.state_key {
-									let user_id = UserId::parse(state_key.clone())
-										.map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
-
-									if user_id == sender_user {
-										continue;
-									}
-
-									let new_membership =
-										serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
-											.map_err(|_| Error::bad_database("Invalid PDU in database."))?
-											.membership;
-
-									match new_membership {
-										MembershipState::Join => {
-											// A new user joined an encrypted room
-											if !share_encrypted_room(&services, &sender_user, &user_id, room_id)? {
-												device_list_changes.insert(user_id);
-											}
-										},
-										MembershipState::Leave => {
-											// Write down users that have left encrypted rooms we are in
-											left_encrypted_users.insert(user_id);
-										},
-										_ => {},
-									}
-								}
-							}
-						}
-					}
-					if joined_since_last_sync || new_encrypted_room {
-						// If the user is in a new encrypted room, give them all joined users
-						device_list_changes.extend(
-							services
-								.rooms
-								.state_cache
-								.room_members(room_id)
-								.flatten()
-								.filter(|user_id| {
-									// Don't send key updates from the sender to the sender
-									&sender_user != user_id
-								})
-								.filter(|user_id| {
-									// Only send keys if the sender doesn't share an encrypted room with the target
-									// already
-									!share_encrypted_room(&services, &sender_user, user_id, room_id).unwrap_or(false)
-								}),
-						);
-					}
-				}
-			}
-			// Look for device list updates in this room
-			device_list_changes.extend(
-				services
-					.users
-					.keys_changed(room_id.as_ref(), globalsince, None)
-					.filter_map(Result::ok),
-			);
-		}
-		for user_id in left_encrypted_users {
-			let dont_share_encrypted_room = services
-				.rooms
-				.user
-				.get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
-				.filter_map(Result::ok)
-				.filter_map(|other_room_id| {
-					Some(
-						services
-							.rooms
-							.state_accessor
-							.room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
-							.ok()?
-							.is_some(),
-					)
-				})
-				.all(|encrypted| !encrypted);
-			// If the user doesn't share an encrypted room with the target anymore, we need
-			// to tell them
-			if dont_share_encrypted_room {
-				device_list_left.insert(user_id);
-			}
-		}
-	}
+	let device_lists = if body.extensions.e2ee.enabled.unwrap_or(false) {
+		collect_device_list_changes(&services, &sender_user, &all_joined_rooms, globalsince).await?
+	} else {
+		DeviceLists::default()
+	};
 
 	let mut lists = BTreeMap::new();
 	let mut todo_rooms = BTreeMap::new(); // and required state
@@ -1494,6 +1383,10 @@ pub(crate) async fn sync_events_v4_route(
 			conn_id.clone(),
 			body.room_subscriptions,
 		);
+
+		services
+			.users
+			.persist_sync_connection(sender_user.clone(), sender_device.clone(), conn_id.clone());
 	}
 
 	let mut rooms = BTreeMap::new();
@@ -1730,19 +1623,18 @@ pub(crate) async fn sync_events_v4_route(
 		extensions: sync_events::v4::Extensions {
 			to_device: if body.extensions.to_device.enabled.unwrap_or(false) {
 				Some(sync_events::v4::ToDevice {
-					events: services
-						.users
-						.get_to_device_events(&sender_user, &sender_device)?,
+					events: services.users.get_to_device_events(
+						&sender_user,
+						&sender_device,
+						body.conn_id.as_deref(),
+					)?,
 					next_batch: next_batch.to_string(),
 				})
 			} else {
 				None
 			},
 			e2ee: sync_events::v4::E2EE {
-				device_lists: DeviceLists {
-					changed: device_list_changes.into_iter().collect(),
-					left: device_list_left.into_iter().collect(),
-				},
+				device_lists,
 				device_one_time_keys_count: services
 					.users
 					.count_one_time_keys(&sender_user, &sender_device)?,
@@ -1759,6 +1651,179 @@ pub(crate) async fn sync_events_v4_route(
 	})
 }
 
+/// Collects the device list updates for the e2ee extension of sliding sync:
+/// users whose keys changed since `globalsince` in rooms shared with the
+/// sender, and users the sender no longer shares an encrypted room with.
+async fn collect_device_list_changes(
+	services: &Services, sender_user: &UserId, all_joined_rooms: &[OwnedRoomId], globalsince: u64,
+) -> Result<DeviceLists> {
+	let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
+	let mut device_list_changes = HashSet::new();
+	let mut device_list_left = HashSet::new();
+
+	// Look for device list updates of this account
+	device_list_changes.extend(
+		services
+			.users
+			.keys_changed(sender_user.as_ref(), globalsince, None)
+			.filter_map(Result::ok),
+	);
+
+	for room_id in all_joined_rooms {
+		let Some(current_shortstatehash) = services.rooms.state.get_room_shortstatehash(room_id)? else {
+			error!("Room {} has no state", room_id);
+			continue;
+		};
+
+		let since_shortstatehash = services
+			.rooms
+			.user
+			.get_token_shortstatehash(room_id, globalsince)?;
+
+		let since_sender_member: Option<RoomMemberEventContent> = since_shortstatehash
+			.and_then(|shortstatehash| {
+				services
+					.rooms
+					.state_accessor
+					.state_get(shortstatehash, &StateEventType::RoomMember, sender_user.as_str())
+					.transpose()
+			})
+			.transpose()?
+			.and_then(|pdu| {
+				serde_json::from_str(pdu.content.get())
+					.map_err(|_| Error::bad_database("Invalid PDU in database."))
+					.ok()
+			});
+
+		let encrypted_room = services
+			.rooms
+			.state_accessor
+			.state_get(current_shortstatehash, &StateEventType::RoomEncryption, "")?
+			.is_some();
+
+		if let Some(since_shortstatehash) = since_shortstatehash {
+			// Skip if there are only timeline changes
+			if since_shortstatehash == current_shortstatehash {
+				continue;
+			}
+
+			let since_encryption =
+				services
+					.rooms
+					.state_accessor
+					.state_get(since_shortstatehash, &StateEventType::RoomEncryption, "")?;
+
+			let joined_since_last_sync =
+				since_sender_member.map_or(true, |member| member.membership != MembershipState::Join);
+
+			let new_encrypted_room = encrypted_room && since_encryption.is_none();
+			if encrypted_room {
+				let current_state_ids = services
+					.rooms
+					.state_accessor
+					.state_full_ids(current_shortstatehash)
+					.await?;
+				let since_state_ids = services
+					.rooms
+					.state_accessor
+					.state_full_ids(since_shortstatehash)
+					.await?;
+
+				for (key, id) in current_state_ids {
+					if since_state_ids.get(&key) != Some(&id) {
+						let Some(pdu) = services.rooms.timeline.get_pdu(&id)? else {
+							error!("Pdu in state not found: {}", id);
+							continue;
+						};
+						if pdu.kind == TimelineEventType::RoomMember {
+							if let Some(state_key) = &pdu.state_key {
+								let user_id = UserId::parse(state_key.clone())
+									.map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
+
+								if *user_id == *sender_user {
+									continue;
+								}
+
+								let new_membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+									.map_err(|_| Error::bad_database("Invalid PDU in database."))?
+									.membership;
+
+								match new_membership {
+									MembershipState::Join => {
+										// A new user joined an encrypted room
+										if !share_encrypted_room(services, sender_user, &user_id, room_id)? {
+											device_list_changes.insert(user_id);
+										}
+									},
+									MembershipState::Leave => {
+										// Write down users that have left encrypted rooms we are in
+										left_encrypted_users.insert(user_id);
+									},
+									_ => {},
+								}
+							}
+						}
+					}
+				}
+				if joined_since_last_sync || new_encrypted_room {
+					// If the user is in a new encrypted room, give them all joined users
+					device_list_changes.extend(
+						services
+							.rooms
+							.state_cache
+							.room_members(room_id)
+							.flatten()
+							.filter(|user_id| {
+								// Don't send key updates from the sender to the sender
+								sender_user != &**user_id
+							})
+							.filter(|user_id| {
+								// Only send keys if the sender doesn't share an encrypted room with the target
+								// already
+								!share_encrypted_room(services, sender_user, user_id, room_id).unwrap_or(false)
+							}),
+					);
+				}
+			}
+		}
+		// Look for device list updates in this room
+		device_list_changes.extend(
+			services
+				.users
+				.keys_changed(room_id.as_ref(), globalsince, None)
+				.filter_map(Result::ok),
+		);
+	}
+	for user_id in left_encrypted_users {
+		let dont_share_encrypted_room = services
+			.rooms
+			.user
+			.get_shared_rooms(vec![sender_user.to_owned(), user_id.clone()])?
+			.filter_map(Result::ok)
+			.filter_map(|other_room_id| {
+				Some(
+					services
+						.rooms
+						.state_accessor
+						.room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
+						.ok()?
+						.is_some(),
+				)
+			})
+			.all(|encrypted| !encrypted);
+		// If the user doesn't share an encrypted room with the target anymore, we need
+		// to tell them
+		if dont_share_encrypted_room {
+			device_list_left.insert(user_id);
+		}
+	}
+
+	Ok(DeviceLists {
+		changed: device_list_changes.into_iter().collect(),
+		left: device_list_left.into_iter().collect(),
+	})
+}
+
 fn filter_rooms(
 	rooms: &[OwnedRoomId], State(services): State<crate::State>, filter: &[RoomTypeFilter], negate: bool,
 ) -> Vec<OwnedRoomId> {
@@ -0,0 +1,960 @@
+use std::{
+	cmp::{self, Ordering, Reverse},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	time::Duration,
+};
+
+use axum::extract::State;
+use conduit::{
+	debug, error,
+	utils::math::{ruma_from_u64, ruma_from_usize, usize_from_ruma},
+	warn, PduCount,
+};
+use ruma::{
+	api::client::{
+		error::ErrorKind,
+		sync::sync_events::{self, v5::response, UnreadNotificationsCount},
+	},
+	directory::RoomTypeFilter,
+	events::{
+		space::child::SpaceChildEventContent, AnyRawAccountDataEvent, AnySyncStateEvent, GlobalAccountDataEventType,
+		RoomAccountDataEventType, StateEventType,
+	},
+	serde::Raw,
+	uint, CanonicalJsonValue, JsOption, OwnedRoomId, RoomId, UInt, UserId,
+};
+use serde::{de::IgnoredAny, Deserialize};
+use service::rooms::read_receipt::pack_receipts;
+
+use super::{collect_device_list_changes, filter_rooms, load_timeline};
+use crate::{service::Services, Error, PduEvent, Result, Ruma};
+
+/// Rooms to send, with their required state, timeline limit and the count they
+/// were last sent at on this connection (0 when they were never sent).
+type TodoRooms = BTreeMap<OwnedRoomId, (BTreeSet<(StateEventType, String)>, u64, u64)>;
+
+/// POST `/_matrix/client/unstable/org.matrix.simplified_msc3575/sync`
+///
+/// Simplified Sliding Sync endpoint (MSC4186)
+///
+/// - Lists return the rooms within their ranges, most recently active first,
+///   along with the number of rooms matching the list
+/// - `required_state` supports `*` wildcards, `$ME` for the sender's own state
+///   key and `$LAZY` for the members who sent the timeline events
+/// - Lists support every filter of MSC4186: `is_dm`, `spaces`, `is_encrypted`,
+///   `is_invite`, `room_types`, `not_room_types`, `room_name_like`, `tags` and
+///   `not_tags`
+/// - Rooms already sent on a connection only return what changed since,
+///   including their `required_state` unless it now requests more
+/// - Invited and knocked rooms return the stripped state of the membership in
+///   `invite_state`
+/// - Supports the e2ee, to_device, account_data, receipts and typing extensions
+pub(crate) async fn sync_events_v5_route(
+	State(services): State<crate::State>, body: Ruma<sync_events::v5::Request>,
+) -> Result<sync_events::v5::Response> {
+	let sender_user = body.sender_user.expect("user is authenticated");
+	let sender_device = body.sender_device.expect("user is authenticated");
+	let list_filters = list_filters(body.json_body.as_ref());
+	let body = body.body;
+
+	// Setup watchers, so if there's no response, we can wait for them
+	let watcher = services.globals.watch(&sender_user, &sender_device);
+
+	let next_batch = services.globals.next_count()?;
+
+	let conn_id = body.conn_id.clone();
+
+	let globalsince = body
+		.pos
+		.as_ref()
+		.and_then(|string| string.parse().ok())
+		.unwrap_or(0);
+
+	if globalsince != 0
+		&& !services
+			.users
+			.remembered_simplified(sender_user.clone(), sender_device.clone(), conn_id.clone())
+	{
+		debug!("Restarting sync stream because it was gone from the database");
+		return Err(Error::Request(
+			ErrorKind::UnknownPos,
+			"Connection data lost since last time".into(),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	if globalsince == 0 {
+		services
+			.users
+			.forget_simplified_sync_connection(sender_user.clone(), sender_device.clone(), conn_id.clone());
+	}
+
+	let known_rooms =
+		services
+			.users
+			.simplified_sync_known_rooms(sender_user.clone(), sender_device.clone(), conn_id.clone());
+
+	let all_joined_rooms = services
+		.rooms
+		.state_cache
+		.rooms_joined(&sender_user)
+		.filter_map(Result::ok)
+		.collect::<Vec<_>>();
+
+	let all_invited_rooms = services
+		.rooms
+		.state_cache
+		.rooms_invited(&sender_user)
+		.filter_map(Result::ok)
+		.map(|r| r.0)
+		.collect::<Vec<_>>();
+
+	let all_knocked_rooms = services
+		.rooms
+		.state_cache
+		.rooms_knocked(&sender_user)
+		.filter_map(Result::ok)
+		.map(|r| r.0)
+		.collect::<Vec<_>>();
+
+	let bump_stamps = bump_stamps(
+		&services,
+		&sender_user,
+		&all_joined_rooms,
+		&all_invited_rooms,
+		&all_knocked_rooms,
+	);
+	let mut all_rooms = all_joined_rooms
+		.iter()
+		.chain(&all_invited_rooms)
+		.chain(&all_knocked_rooms)
+		.cloned()
+		.collect::<Vec<_>>();
+	all_rooms.sort_by_key(|room_id| Reverse(bump_stamps.get(room_id).copied().unwrap_or(0)));
+
+	let mut lists = BTreeMap::new();
+	let mut todo_rooms = TodoRooms::new();
+
+	// Rooms whose required_state asks for more than was sent with them, which
+	// are sent with their whole required_state again.
+	let mut full_state_rooms = BTreeSet::new();
+
+	for (list_id, list) in &body.lists {
+		let active_rooms = match list_filters.get(list_id) {
+			Some(filters) => filter_list(services, &sender_user, &all_rooms, &all_invited_rooms, filters)?,
+			None => all_rooms.clone(),
+		};
+
+		let full_state = services.users.update_simplified_sync_list_state(
+			sender_user.clone(),
+			sender_device.clone(),
+			conn_id.clone(),
+			list_id,
+			&list.room_details.required_state,
+		);
+
+		// Rooms in the ranges are up to date as of this response, whether they had
+		// anything new or not.
+		let mut new_known_rooms = BTreeSet::new();
+		for (start, end) in &list.ranges {
+			let start = usize_from_ruma(*start);
+			let end = usize_from_ruma(*end)
+				.saturating_add(1)
+				.min(active_rooms.len());
+			let room_ids = active_rooms.get(start..end).unwrap_or_default();
+
+			for room_id in room_ids {
+				if full_state {
+					full_state_rooms.insert(room_id.clone());
+				}
+
+				add_todo_room(
+					&mut todo_rooms,
+					room_id,
+					&list.room_details.required_state,
+					list.room_details.timeline_limit,
+					known_rooms.get(list_id).and_then(|k| k.get(room_id)),
+				);
+			}
+
+			new_known_rooms.extend(room_ids.iter().cloned());
+		}
+
+		lists.insert(
+			list_id.clone(),
+			response::List {
+				count: ruma_from_usize(active_rooms.len()),
+			},
+		);
+
+		services.users.update_simplified_sync_known_rooms(
+			sender_user.clone(),
+			sender_device.clone(),
+			conn_id.clone(),
+			list_id.clone(),
+			new_known_rooms,
+			next_batch,
+		);
+	}
+
+	let mut known_subscription_rooms = BTreeSet::new();
+	for (room_id, room) in &body.room_subscriptions {
+		if !services.rooms.metadata.exists(room_id)?
+			|| !(all_joined_rooms.contains(room_id)
+				|| all_invited_rooms.contains(room_id)
+				|| all_knocked_rooms.contains(room_id))
+		{
+			continue;
+		}
+
+		if services.users.update_simplified_sync_subscription_state(
+			sender_user.clone(),
+			sender_device.clone(),
+			conn_id.clone(),
+			room_id,
+			&room.required_state,
+		) {
+			full_state_rooms.insert(room_id.clone());
+		}
+
+		add_todo_room(
+			&mut todo_rooms,
+			room_id,
+			&room.required_state,
+			room.timeline_limit,
+			known_rooms
+				.get("subscriptions")
+				.and_then(|k| k.get(room_id)),
+		);
+		known_subscription_rooms.insert(room_id.clone());
+	}
+
+	services.users.update_simplified_sync_known_rooms(
+		sender_user.clone(),
+		sender_device.clone(),
+		conn_id.clone(),
+		"subscriptions".to_owned(),
+		known_subscription_rooms,
+		next_batch,
+	);
+
+	services
+		.users
+		.persist_simplified_sync_connection(sender_user.clone(), sender_device.clone(), conn_id.clone());
+
+	let mut rooms = BTreeMap::new();
+	for (room_id, (required_state_request, timeline_limit, roomsince)) in &todo_rooms {
+		let room = if all_invited_rooms.contains(room_id) {
+			invited_room(&services, &sender_user, room_id, *roomsince)?
+		} else if all_knocked_rooms.contains(room_id) {
+			knocked_room(&services, &sender_user, room_id, *roomsince)?
+		} else {
+			let state_since = if full_state_rooms.contains(room_id) {
+				0
+			} else {
+				*roomsince
+			};
+
+			joined_room(
+				&services,
+				&sender_user,
+				room_id,
+				required_state_request,
+				*timeline_limit,
+				*roomsince,
+				state_since,
+			)
+			.await?
+		};
+
+		if let Some(mut room) = room {
+			room.bump_stamp = bump_stamps.get(room_id).copied().map(ruma_from_u64);
+			rooms.insert(room_id.clone(), room);
+		}
+	}
+
+	let extensions = response::Extensions {
+		to_device: if body.extensions.to_device.enabled.unwrap_or(false) {
+			let since = body
+				.extensions
+				.to_device
+				.since
+				.as_ref()
+				.and_then(|string| string.parse().ok())
+				.unwrap_or(globalsince);
+
+			// Every connection receives to-device events as its own stream.
+			services
+				.users
+				.remove_to_device_events(&sender_user, &sender_device, conn_id.as_deref(), since)?;
+
+			Some(response::ToDevice {
+				events: services
+					.users
+					.get_to_device_events(&sender_user, &sender_device, conn_id.as_deref())?,
+				next_batch: next_batch.to_string(),
+			})
+		} else {
+			None
+		},
+		e2ee: if body.extensions.e2ee.enabled.unwrap_or(false) {
+			response::E2EE {
+				device_lists: collect_device_list_changes(&services, &sender_user, &all_joined_rooms, globalsince)
+					.await?,
+				device_one_time_keys_count: services
+					.users
+					.count_one_time_keys(&sender_user, &sender_device)?,
+				// Fallback keys are not yet supported
+				device_unused_fallback_key_types: None,
+			}
+		} else {
+			response::E2EE::default()
+		},
+		account_data: if body.extensions.account_data.enabled.unwrap_or(false) {
+			collect_account_data(&services, &sender_user, &todo_rooms, globalsince)?
+		} else {
+			response::AccountData::default()
+		},
+		receipts: if body.extensions.receipts.enabled.unwrap_or(false) {
+			collect_receipts(&services, &todo_rooms)
+		} else {
+			response::Receipts::default()
+		},
+		typing: if body.extensions.typing.enabled.unwrap_or(false) {
+			collect_typing(&services, &todo_rooms).await?
+		} else {
+			response::Typing::default()
+		},
+	};
+
+	let nothing_new = rooms.is_empty()
+		&& extensions
+			.to_device
+			.as_ref()
+			.map_or(true, |to_device| to_device.events.is_empty())
+		&& extensions.e2ee.device_lists.is_empty()
+		&& extensions.account_data.global.is_empty()
+		&& extensions.account_data.rooms.values().all(Vec::is_empty)
+		&& extensions.typing.rooms.is_empty();
+
+	if globalsince != 0 && nothing_new {
+		// Hang a few seconds so requests are not spammed
+		// Stop hanging if new info arrives
+		let default = Duration::from_secs(30);
+		let duration = cmp::min(body.timeout.unwrap_or(default), default);
+		_ = tokio::time::timeout(duration, watcher).await;
+	}
+
+	Ok(sync_events::v5::Response {
+		txn_id: body.txn_id.clone(),
+		pos: next_batch.to_string(),
+		lists,
+		rooms,
+		extensions,
+	})
+}
+
+/// Adds a room of a list or subscription to the rooms to send, merging the
+/// parameters when several of them include it.
+fn add_todo_room(
+	todo_rooms: &mut TodoRooms, room_id: &RoomId, required_state: &[(StateEventType, String)],
+	timeline_limit: Option<UInt>, known_since: Option<&u64>,
+) {
+	let todo_room = todo_rooms
+		.entry(room_id.to_owned())
+		.or_insert((BTreeSet::new(), 0, u64::MAX));
+
+	todo_room.0.extend(required_state.iter().cloned());
+	todo_room.1 = todo_room
+		.1
+		.max(timeline_limit.map_or(10, u64::from).min(100));
+
+	// 0 means unknown because it got out of date
+	todo_room.2 = todo_room.2.min(known_since.copied().unwrap_or(0));
+}
+
+/// Filters of a list. The request type only has `is_invite` and
+/// `not_room_types`, so they are read from the JSON body.
+#[derive(Deserialize)]
+struct ListFilters {
+	is_dm: Option<bool>,
+	#[serde(default)]
+	spaces: Vec<OwnedRoomId>,
+	is_encrypted: Option<bool>,
+	is_invite: Option<bool>,
+	#[serde(default)]
+	room_types: Vec<RoomTypeFilter>,
+	#[serde(default)]
+	not_room_types: Vec<RoomTypeFilter>,
+	room_name_like: Option<String>,
+	#[serde(default)]
+	tags: Vec<String>,
+	#[serde(default)]
+	not_tags: Vec<String>,
+}
+
+/// Returns the filters of each list of the request which has any.
+fn list_filters(json_body: Option<&CanonicalJsonValue>) -> BTreeMap<String, ListFilters> {
+	#[derive(Deserialize)]
+	struct List {
+		filters: Option<ListFilters>,
+	}
+
+	#[derive(Deserialize)]
+	struct Request {
+		#[serde(default)]
+		lists: BTreeMap<String, List>,
+	}
+
+	json_body
+		.and_then(|json_body| serde_json::to_value(json_body).ok())
+		.and_then(|json_body| serde_json::from_value::<Request>(json_body).ok())
+		.map(|request| {
+			request
+				.lists
+				.into_iter()
+				.filter_map(|(list_id, list)| Some((list_id, list.filters?)))
+				.collect()
+		})
+		.unwrap_or_default()
+}
+
+/// Returns the rooms matching the filters of a list, in the same order.
+fn filter_list(
+	services: crate::State, sender_user: &UserId, rooms: &[OwnedRoomId], invited_rooms: &[OwnedRoomId],
+	filters: &ListFilters,
+) -> Result<Vec<OwnedRoomId>> {
+	#[derive(Deserialize)]
+	struct Direct {
+		content: BTreeMap<String, Vec<OwnedRoomId>>,
+	}
+
+	#[derive(Deserialize)]
+	struct Tags {
+		content: TagsContent,
+	}
+
+	#[derive(Deserialize)]
+	struct TagsContent {
+		#[serde(default)]
+		tags: BTreeMap<String, IgnoredAny>,
+	}
+
+	let dm_rooms: HashSet<OwnedRoomId> = if filters.is_dm.is_some() {
+		services
+			.account_data
+			.get(None, sender_user, GlobalAccountDataEventType::Direct.to_string().into())?
+			.and_then(|event| serde_json::from_str::<Direct>(event.get()).ok())
+			.map(|direct| direct.content.into_values().flatten().collect())
+			.unwrap_or_default()
+	} else {
+		HashSet::new()
+	};
+
+	let room_name_like = filters.room_name_like.as_deref().map(str::to_lowercase);
+
+	let mut filtered = Vec::new();
+	for room_id in rooms {
+		if filters
+			.is_invite
+			.is_some_and(|is_invite| is_invite != invited_rooms.contains(room_id))
+		{
+			continue;
+		}
+
+		if filters
+			.is_dm
+			.is_some_and(|is_dm| is_dm != dm_rooms.contains(room_id))
+		{
+			continue;
+		}
+
+		if let Some(is_encrypted) = filters.is_encrypted {
+			let encrypted = services
+				.rooms
+				.state_accessor
+				.get_room_encryption(room_id)?
+				.is_some();
+
+			if is_encrypted != encrypted {
+				continue;
+			}
+		}
+
+		if !filters.spaces.is_empty() && !in_spaces(&services, room_id, &filters.spaces)? {
+			continue;
+		}
+
+		if let Some(room_name_like) = &room_name_like {
+			let matches = services
+				.rooms
+				.state_accessor
+				.get_name(room_id)?
+				.is_some_and(|name| name.to_lowercase().contains(room_name_like));
+
+			if !matches {
+				continue;
+			}
+		}
+
+		if !filters.tags.is_empty() || !filters.not_tags.is_empty() {
+			let tags = services
+				.account_data
+				.get(Some(room_id), sender_user, RoomAccountDataEventType::Tag)?
+				.and_then(|event| serde_json::from_str::<Tags>(event.get()).ok())
+				.map(|tags| tags.content.tags)
+				.unwrap_or_default();
+
+			if !filters.tags.is_empty() && !filters.tags.iter().any(|tag| tags.contains_key(tag)) {
+				continue;
+			}
+
+			if filters.not_tags.iter().any(|tag| tags.contains_key(tag)) {
+				continue;
+			}
+		}
+
+		filtered.push(room_id.clone());
+	}
+
+	if !filters.room_types.is_empty() {
+		filtered = filter_rooms(&filtered, State(services), &filters.room_types, false);
+	}
+
+	if !filters.not_room_types.is_empty() {
+		filtered = filter_rooms(&filtered, State(services), &filters.not_room_types, true);
+	}
+
+	Ok(filtered)
+}
+
+/// Whether a room is a child of any of the spaces, through an `m.space.child`
+/// event with a `via`.
+fn in_spaces(services: &Services, room_id: &RoomId, spaces: &[OwnedRoomId]) -> Result<bool> {
+	for space_id in spaces {
+		let Some(pdu) =
+			services
+				.rooms
+				.state_accessor
+				.room_state_get(space_id, &StateEventType::SpaceChild, room_id.as_str())?
+		else {
+			continue;
+		};
+
+		if serde_json::from_str::<SpaceChildEventContent>(pdu.content.get())
+			.is_ok_and(|content| !content.via.is_empty())
+		{
+			return Ok(true);
+		}
+	}
+
+	Ok(false)
+}
+
+/// Returns the count of the latest activity of every room, which orders the
+/// lists: the last event of joined rooms, the invite of invited rooms and the
+/// knock of knocked rooms.
+fn bump_stamps(
+	services: &Services, sender_user: &UserId, joined_rooms: &[OwnedRoomId], invited_rooms: &[OwnedRoomId],
+	knocked_rooms: &[OwnedRoomId],
+) -> HashMap<OwnedRoomId, u64> {
+	let joined = joined_rooms.iter().map(|room_id| {
+		let count = match services
+			.rooms
+			.timeline
+			.last_timeline_count(sender_user, room_id)
+		{
+			Ok(PduCount::Normal(count)) => count,
+			Ok(PduCount::Backfilled(_)) => 0,
+			Err(e) => {
+				warn!("Failed to get the last event of {room_id}: {e}");
+				0
+			},
+		};
+
+		(room_id.clone(), count)
+	});
+
+	let invited = invited_rooms.iter().map(|room_id| {
+		let count = services
+			.rooms
+			.state_cache
+			.get_invite_count(room_id, sender_user)
+			.ok()
+			.flatten()
+			.unwrap_or(0);
+
+		(room_id.clone(), count)
+	});
+
+	let knocked = knocked_rooms.iter().map(|room_id| {
+		let count = services
+			.rooms
+			.state_cache
+			.get_knock_count(room_id, sender_user)
+			.ok()
+			.flatten()
+			.unwrap_or(0);
+
+		(room_id.clone(), count)
+	});
+
+	joined.chain(invited).chain(knocked).collect()
+}
+
+fn invited_room(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, roomsince: u64,
+) -> Result<Option<response::Room>> {
+	let invite_count = services
+		.rooms
+		.state_cache
+		.get_invite_count(room_id, sender_user)?;
+
+	// Invited before it was last sent
+	if roomsince != 0 && Some(roomsince) >= invite_count {
+		return Ok(None);
+	}
+
+	Ok(Some(response::Room {
+		invite_state: services
+			.rooms
+			.state_cache
+			.invite_state(sender_user, room_id)?,
+		initial: Some(roomsince == 0),
+		..response::Room::default()
+	}))
+}
+
+/// Knocked rooms are sent with the stripped state of the knock, which clients
+/// read from `invite_state` like for invites.
+fn knocked_room(
+	services: &Services, sender_user: &UserId, room_id: &RoomId, roomsince: u64,
+) -> Result<Option<response::Room>> {
+	let knock_count = services
+		.rooms
+		.state_cache
+		.get_knock_count(room_id, sender_user)?;
+
+	// Knocked before it was last sent
+	if roomsince != 0 && Some(roomsince) >= knock_count {
+		return Ok(None);
+	}
+
+	Ok(Some(response::Room {
+		invite_state: services
+			.rooms
+			.state_cache
+			.knock_state(sender_user, room_id)?,
+		initial: Some(roomsince == 0),
+		..response::Room::default()
+	}))
+}
+
+/// Joined rooms already sent on the connection only return the state which
+/// changed since `state_since`, which is 0 to send the whole required_state.
+async fn joined_room(
+	services: &Services, sender_user: &UserId, room_id: &RoomId,
+	required_state_request: &BTreeSet<(StateEventType, String)>, timeline_limit: u64, roomsince: u64, state_since: u64,
+) -> Result<Option<response::Room>> {
+	let (timeline_pdus, limited) =
+		match load_timeline(services, sender_user, room_id, PduCount::Normal(roomsince), timeline_limit) {
+			Ok(value) => value,
+			Err(err) => {
+				warn!("Encountered missing timeline in {}, error {}", room_id, err);
+				return Ok(None);
+			},
+		};
+
+	if roomsince != 0 && state_since != 0 && timeline_pdus.is_empty() {
+		return Ok(None);
+	}
+
+	let prev_batch = timeline_pdus
+		.first()
+		.map(|(pdu_count, _)| match pdu_count {
+			PduCount::Backfilled(_) => {
+				error!("timeline in backfill state?!");
+				"0".to_owned()
+			},
+			PduCount::Normal(c) => c.to_string(),
+		})
+		.or_else(|| (roomsince != 0).then(|| roomsince.to_string()));
+
+	let required_state = required_state(
+		services,
+		sender_user,
+		room_id,
+		required_state_request,
+		&timeline_pdus,
+		state_since,
+	)
+	.await?;
+
+	let timeline = timeline_pdus
+		.iter()
+		.map(|(_, pdu)| pdu.to_sync_room_event())
+		.collect();
+
+	let heroes = heroes(services, sender_user, room_id);
+	let name = match heroes.len().cmp(&(1_usize)) {
+		Ordering::Greater => {
+			let firsts = heroes[1..]
+				.iter()
+				.map(|h| h.name.clone().unwrap_or_else(|| h.user_id.to_string()))
+				.collect::<Vec<_>>()
+				.join(", ");
+			let last = heroes[0]
+				.name
+				.clone()
+				.unwrap_or_else(|| heroes[0].user_id.to_string());
+			Some(format!("{firsts} and {last}"))
+		},
+		Ordering::Equal => Some(
+			heroes[0]
+				.name
+				.clone()
+				.unwrap_or_else(|| heroes[0].user_id.to_string()),
+		),
+		Ordering::Less => None,
+	};
+
+	let heroes_avatar = match heroes.as_slice() {
+		[hero] => hero.avatar.clone(),
+		_ => None,
+	};
+
+	let avatar = if let Some(heroes_avatar) = heroes_avatar {
+		JsOption::Some(heroes_avatar)
+	} else {
+		match services.rooms.state_accessor.get_avatar(room_id)? {
+			JsOption::Some(avatar) => JsOption::from_option(avatar.url),
+			JsOption::Null => JsOption::Null,
+			JsOption::Undefined => JsOption::Undefined,
+		}
+	};
+
+	Ok(Some(response::Room {
+		name: services.rooms.state_accessor.get_name(room_id)?.or(name),
+		avatar,
+		initial: Some(roomsince == 0),
+		is_dm: None,
+		invite_state: None,
+		unread_notifications: UnreadNotificationsCount {
+			highlight_count: Some(
+				services
+					.rooms
+					.user
+					.highlight_count(sender_user, room_id)?
+					.try_into()
+					.expect("notification count can't go that high"),
+			),
+			notification_count: Some(
+				services
+					.rooms
+					.user
+					.notification_count(sender_user, room_id)?
+					.try_into()
+					.expect("notification count can't go that high"),
+			),
+		},
+		timeline,
+		required_state,
+		prev_batch,
+		limited,
+		joined_count: Some(
+			services
+				.rooms
+				.state_cache
+				.room_joined_count(room_id)?
+				.unwrap_or(0)
+				.try_into()
+				.unwrap_or_else(|_| uint!(0)),
+		),
+		invited_count: Some(
+			services
+				.rooms
+				.state_cache
+				.room_invited_count(room_id)?
+				.unwrap_or(0)
+				.try_into()
+				.unwrap_or_else(|_| uint!(0)),
+		),
+		num_live: None, // Count events in timeline greater than global sync counter
+		bump_stamp: None,
+		heroes: Some(heroes),
+	}))
+}
+
+/// Returns the state events of a room matching the requested `required_state`.
+/// `*` matches any event type or state key, `$ME` is the sender and `$LAZY`
+/// members are the senders of the timeline events. Unless `since` is 0, only
+/// state which changed after it is returned, along with the `$LAZY` members.
+async fn required_state(
+	services: &Services, sender_user: &UserId, room_id: &RoomId,
+	required_state_request: &BTreeSet<(StateEventType, String)>, timeline_pdus: &[(PduCount, PduEvent)], since: u64,
+) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+	let is_wildcard = |event_type: &StateEventType| event_type.to_string() == "*";
+	let changed = |pdu: &PduEvent| -> Result<bool> {
+		if since == 0 {
+			return Ok(true);
+		}
+
+		Ok(services
+			.rooms
+			.timeline
+			.get_pdu_count(&pdu.event_id)?
+			.map_or(true, |count| count > PduCount::Normal(since)))
+	};
+
+	let mut state = BTreeMap::new();
+	if required_state_request
+		.iter()
+		.any(|(event_type, state_key)| is_wildcard(event_type) || state_key == "*")
+	{
+		let full_state = services
+			.rooms
+			.state_accessor
+			.room_state_full(room_id)
+			.await?;
+		for ((event_type, state_key), pdu) in full_state {
+			let requested = required_state_request
+				.iter()
+				.any(|(requested_type, requested_key)| {
+					(is_wildcard(requested_type) || *requested_type == event_type)
+						&& (requested_key == "*" || *requested_key == state_key)
+				});
+
+			if requested && changed(&*pdu)? {
+				state.insert(pdu.event_id.clone(), pdu);
+			}
+		}
+	}
+
+	for (event_type, state_key) in required_state_request {
+		let lazy = state_key == "$LAZY" && *event_type == StateEventType::RoomMember;
+		let state_keys: Vec<&str> = match state_key.as_str() {
+			"*" => continue,
+			"$ME" => vec![sender_user.as_str()],
+			"$LAZY" if lazy => timeline_pdus
+				.iter()
+				.map(|(_, pdu)| pdu.sender.as_str())
+				.collect(),
+			state_key => vec![state_key],
+		};
+
+		for state_key in state_keys {
+			if let Some(pdu) = services
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, event_type, state_key)?
+			{
+				if lazy || changed(&*pdu)? {
+					state.insert(pdu.event_id.clone(), pdu);
+				}
+			}
+		}
+	}
+
+	Ok(state
+		.into_values()
+		.map(|pdu| pdu.to_sync_state_event())
+		.collect())
+}
+
+fn heroes(services: &Services, sender_user: &UserId, room_id: &RoomId) -> Vec<response::Hero> {
+	services
+		.rooms
+		.state_cache
+		.room_members(room_id)
+		.filter_map(Result::ok)
+		.filter(|member| &**member != sender_user)
+		.map(|member| {
+			Ok::<_, Error>(
+				services
+					.rooms
+					.state_accessor
+					.get_member(room_id, &member)?
+					.map(|memberevent| response::Hero {
+						user_id: member,
+						name: memberevent.displayname,
+						avatar: memberevent.avatar_url,
+					}),
+			)
+		})
+		.filter_map(Result::ok)
+		.flatten()
+		.take(5)
+		.collect()
+}
+
+fn collect_account_data(
+	services: &Services, sender_user: &UserId, todo_rooms: &TodoRooms, globalsince: u64,
+) -> Result<response::AccountData> {
+	let global = services
+		.account_data
+		.changes_since(None, sender_user, globalsince)?
+		.into_iter()
+		.filter_map(|event| match event {
+			AnyRawAccountDataEvent::Global(event) => Some(event),
+			AnyRawAccountDataEvent::Room(_) => None,
+		})
+		.collect();
+
+	let mut rooms = BTreeMap::new();
+	for (room_id, (_, _, roomsince)) in todo_rooms {
+		let events: Vec<_> = services
+			.account_data
+			.changes_since(Some(room_id), sender_user, *roomsince)?
+			.into_iter()
+			.filter_map(|event| match event {
+				AnyRawAccountDataEvent::Room(event) => Some(event),
+				AnyRawAccountDataEvent::Global(_) => None,
+			})
+			.collect();
+
+		if !events.is_empty() {
+			rooms.insert(room_id.clone(), events);
+		}
+	}
+
+	Ok(response::AccountData {
+		global,
+		rooms,
+	})
+}
+
+fn collect_receipts(services: &Services, todo_rooms: &TodoRooms) -> response::Receipts {
+	let mut rooms = BTreeMap::new();
+	for (room_id, (_, _, roomsince)) in todo_rooms {
+		let receipts: Vec<_> = services
+			.rooms
+			.read_receipt
+			.readreceipts_since(room_id, *roomsince)
+			.collect();
+
+		if !receipts.is_empty() {
+			rooms.insert(room_id.clone(), pack_receipts(Box::new(receipts.into_iter())));
+		}
+	}
+
+	response::Receipts {
+		rooms,
+	}
+}
+
+async fn collect_typing(services: &Services, todo_rooms: &TodoRooms) -> Result<response::Typing> {
+	let mut rooms = BTreeMap::new();
+	for (room_id, (_, _, roomsince)) in todo_rooms {
+		if services.rooms.typing.last_typing_update(room_id).await? > *roomsince {
+			let typing = services.rooms.typing.typings_all(room_id).await?;
+			rooms.insert(
+				room_id.clone(),
+				Raw::new(&typing).expect("typing event serializes successfully"),
+			);
+		}
+	}
+
+	Ok(response::Typing {
+		rooms,
+	})
+}
@@ -1,18 +1,21 @@
-use std::collections::BTreeMap;
-
-use ruma::api::client::thirdparty::get_protocols;
+use axum::extract::State;
+use conduit::Err;
+use ruma::api::client::thirdparty::{
+	get_location_for_protocol, get_location_for_room_alias, get_protocol, get_protocols, get_user_for_protocol,
+	get_user_for_user_id,
+};
 
 use crate::{Result, Ruma, RumaResponse};
 
-/// # `GET /_matrix/client/r0/thirdparty/protocols`
+/// # `GET /_matrix/client/v3/thirdparty/protocols`
 ///
-/// TODO: Fetches all metadata about protocols supported by the homeserver.
+/// Fetches all metadata about protocols supported by the homeserver, as
+/// provided by its appservices.
 pub(crate) async fn get_protocols_route(
-	_body: Ruma<get_protocols::v3::Request>,
+	State(services): State<crate::State>, _body: Ruma<get_protocols::v3::Request>,
 ) -> Result<get_protocols::v3::Response> {
-	// TODO
 	Ok(get_protocols::v3::Response {
-		protocols: BTreeMap::new(),
+		protocols: services.appservice.query_protocols().await,
 	})
 }
 
@@ -21,7 +24,100 @@ pub(crate) async fn get_protocols_route(
 /// Same as `get_protocols_route`, except for some reason Element Android legacy
 /// calls this
 pub(crate) async fn get_protocols_route_unstable(
-	body: Ruma<get_protocols::v3::Request>,
+	State(services): State<crate::State>, body: Ruma<get_protocols::v3::Request>,
 ) -> Result<RumaResponse<get_protocols::v3::Response>> {
-	get_protocols_route(body).await.map(RumaResponse)
+	get_protocols_route(State(services), body)
+		.await
+		.map(RumaResponse)
+}
+
+/// # `GET /_matrix/client/v3/thirdparty/protocol/{protocol}`
+///
+/// Fetches the metadata of a single protocol from the appservices handling it.
+pub(crate) async fn get_protocol_route(
+	State(services): State<crate::State>, body: Ruma<get_protocol::v3::Request>,
+) -> Result<get_protocol::v3::Response> {
+	let Some(protocol) = services.appservice.query_protocol(&body.protocol).await else {
+		return Err!(Request(NotFound("Protocol is not supported by this server.")));
+	};
+
+	Ok(get_protocol::v3::Response {
+		protocol,
+	})
+}
+
+/// # `GET /_matrix/client/v3/thirdparty/location/{protocol}`
+///
+/// Looks up third-party locations matching the fields through the appservices
+/// handling the protocol.
+pub(crate) async fn get_location_for_protocol_route(
+	State(services): State<crate::State>, body: Ruma<get_location_for_protocol::v3::Request>,
+) -> Result<get_location_for_protocol::v3::Response> {
+	if services
+		.appservice
+		.protocol_handlers(&body.protocol)
+		.await
+		.is_empty()
+	{
+		return Err!(Request(NotFound("Protocol is not supported by this server.")));
+	}
+
+	Ok(get_location_for_protocol::v3::Response {
+		locations: services
+			.appservice
+			.query_locations(&body.protocol, &body.fields)
+			.await,
+	})
+}
+
+/// # `GET /_matrix/client/v3/thirdparty/location`
+///
+/// Looks up the third-party locations bridged to a room alias.
+pub(crate) async fn get_location_for_room_alias_route(
+	State(services): State<crate::State>, body: Ruma<get_location_for_room_alias::v3::Request>,
+) -> Result<get_location_for_room_alias::v3::Response> {
+	Ok(get_location_for_room_alias::v3::Response {
+		locations: services
+			.appservice
+			.query_locations_for_alias(&body.alias)
+			.await,
+	})
+}
+
+/// # `GET /_matrix/client/v3/thirdparty/user/{protocol}`
+///
+/// Looks up third-party users matching the fields through the appservices
+/// handling the protocol.
+pub(crate) async fn get_user_for_protocol_route(
+	State(services): State<crate::State>, body: Ruma<get_user_for_protocol::v3::Request>,
+) -> Result<get_user_for_protocol::v3::Response> {
+	if services
+		.appservice
+		.protocol_handlers(&body.protocol)
+		.await
+		.is_empty()
+	{
+		return Err!(Request(NotFound("Protocol is not supported by this server.")));
+	}
+
+	Ok(get_user_for_protocol::v3::Response {
+		users: services
+			.appservice
+			.query_users(&body.protocol, &body.fields)
+			.await,
+	})
+}
+
+/// # `GET /_matrix/client/v3/thirdparty/user`
+///
+/// Looks up the third-party users bridged to a Matrix user.
+pub(crate) async fn get_user_for_user_id_route(
+	State(services): State<crate::State>, body: Ruma<get_user_for_user_id::v3::Request>,
+) -> Result<get_user_for_user_id::v3::Response> {
+	Ok(get_user_for_user_id::v3::Response {
+		users: services
+			.appservice
+			.query_users_for_user_id(&body.userid)
+			.await,
+	})
 }
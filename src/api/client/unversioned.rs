@@ -3,7 +3,6 @@ use std::collections::BTreeMap;
 use axum::{extract::State, response::IntoResponse, Json};
 use ruma::api::client::{
 	discovery::{
-		discover_homeserver::{self, HomeserverInfo, SlidingSyncProxyInfo},
 		discover_support::{self, Contact},
 		get_supported_versions,
 	},
@@ -53,10 +52,12 @@ pub(crate) async fn get_supported_versions_route(
 			("org.matrix.msc3026.busy_presence".to_owned(), true), /* busy presence status (https://github.com/matrix-org/matrix-spec-proposals/pull/3026) */
 			("org.matrix.msc3827".to_owned(), true), /* filtering of /publicRooms by room type (https://github.com/matrix-org/matrix-spec-proposals/pull/3827) */
 			("org.matrix.msc3575".to_owned(), true), /* sliding sync (https://github.com/matrix-org/matrix-spec-proposals/pull/3575/files#r1588877046) */
+			("org.matrix.simplified_msc3575".to_owned(), true), /* simplified sliding sync (https://github.com/matrix-org/matrix-spec-proposals/pull/4186) */
 			("org.matrix.msc3916.stable".to_owned(), true), /* authenticated media (https://github.com/matrix-org/matrix-spec-proposals/pull/3916) */
 			("org.matrix.msc4180".to_owned(), true), /* stable flag for 3916 (https://github.com/matrix-org/matrix-spec-proposals/pull/4180) */
 			("uk.tcpip.msc4133".to_owned(), true), /* Extending User Profile API with Key:Value Pairs (https://github.com/matrix-org/matrix-spec-proposals/pull/4133) */
 			("us.cloke.msc4175".to_owned(), true), /* Profile field for user time zone (https://github.com/matrix-org/matrix-spec-proposals/pull/4175) */
+			("gay.puppyirl.conduwuit.sync_streams".to_owned(), true), /* several sync streams per device (docs/differences.md) */
 		]),
 	};
 
@@ -66,24 +67,28 @@ pub(crate) async fn get_supported_versions_route(
 /// # `GET /.well-known/matrix/client`
 ///
 /// Returns the .well-known URL if it is configured, otherwise returns 404.
-pub(crate) async fn well_known_client(
-	State(services): State<crate::State>, _body: Ruma<discover_homeserver::Request>,
-) -> Result<discover_homeserver::Response> {
+/// MatrixRTC foci (MSC4143) are included when configured.
+pub(crate) async fn well_known_client(State(services): State<crate::State>) -> Result<impl IntoResponse> {
 	let client_url = match services.globals.well_known_client() {
 		Some(url) => url.to_string(),
 		None => return Err(Error::BadRequest(ErrorKind::NotFound, "Not found.")),
 	};
 
-	Ok(discover_homeserver::Response {
-		homeserver: HomeserverInfo {
-			base_url: client_url.clone(),
+	let mut response = serde_json::json!({
+		"m.homeserver": {
+			"base_url": client_url,
 		},
-		identity_server: None,
-		sliding_sync_proxy: Some(SlidingSyncProxyInfo {
-			url: client_url,
-		}),
-		tile_server: None,
-	})
+		"org.matrix.msc3575.proxy": {
+			"url": client_url,
+		},
+	});
+
+	let rtc_foci = super::rtc_foci(&services);
+	if !rtc_foci.is_empty() {
+		response["org.matrix.msc4143.rtc_foci"] = rtc_foci.into();
+	}
+
+	Ok(Json(response))
 }
 
 /// # `GET /.well-known/matrix/support`
@@ -1,16 +1,27 @@
 use std::time::{Duration, SystemTime};
 
-use axum::extract::State;
+use axum::{extract::State, response::IntoResponse, Json};
 use base64::{engine::general_purpose, Engine as _};
-use conduit::{utils, Err};
+use bytes::Bytes;
+use conduit::{err, utils, Err};
 use hmac::{Hmac, Mac};
-use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch, UserId};
+use jsonwebtoken::{EncodingKey, Header};
+use ruma::{api::client::voip::get_turn_server_info, OwnedRoomId, SecondsSinceUnixEpoch, UserId};
+use serde::{Deserialize, Serialize};
+use service::Services;
 use sha1::Sha1;
 
 use crate::{Result, Ruma};
 
 const RANDOM_USER_ID_LENGTH: usize = 10;
 
+/// Base path of the built-in LiveKit JWT service, advertised as its
+/// `livekit_service_url`.
+pub(crate) const LIVEKIT_SERVICE_PATH: &str = "/_conduwuit/livekit";
+
+/// How long LiveKit tokens handed out by the built-in JWT service are valid.
+const LIVEKIT_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
 type HmacSha1 = Hmac<Sha1>;
 
 /// # `GET /_matrix/client/r0/voip/turnServer`
@@ -64,3 +75,117 @@ pub(crate) async fn turn_server_route(
 		ttl: Duration::from_secs(services.globals.turn_ttl()),
 	})
 }
+
+/// Request of the LiveKit JWT service API, as sent by Element Call.
+#[derive(Deserialize)]
+pub(crate) struct SfuRequest {
+	room: OwnedRoomId,
+	openid_token: SfuOpenIdToken,
+	device_id: String,
+}
+
+#[derive(Deserialize)]
+struct SfuOpenIdToken {
+	access_token: String,
+	matrix_server_name: String,
+}
+
+/// Claims of a LiveKit access token.
+#[derive(Serialize)]
+struct LiveKitClaims<'a> {
+	iss: &'a str,
+	sub: String,
+	nbf: u64,
+	exp: u64,
+	video: LiveKitVideoGrant<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LiveKitVideoGrant<'a> {
+	room: &'a str,
+	room_join: bool,
+	can_publish: bool,
+	can_publish_data: bool,
+	can_subscribe: bool,
+}
+
+/// # `POST /_conduwuit/livekit/sfu/get`
+///
+/// Built-in LiveKit JWT service. Exchanges an OpenID token of a local user for
+/// a token to join the LiveKit room of a call in a room the user is joined to.
+pub(crate) async fn livekit_sfu_route(State(services): State<crate::State>, body: Bytes) -> Result<impl IntoResponse> {
+	let config = &services.globals.config.matrix_rtc;
+	let (Some(livekit_url), Some(api_key), Some(api_secret)) = (
+		config.livekit_url.as_ref(),
+		config.livekit_api_key.as_deref(),
+		config.livekit_api_secret.as_deref(),
+	) else {
+		return Err!(Request(NotFound("LiveKit is not configured on this server.")));
+	};
+
+	let request: SfuRequest =
+		serde_json::from_slice(&body).map_err(|e| err!(Request(BadJson("Invalid SFU request: {e}"))))?;
+
+	if request.openid_token.matrix_server_name != services.globals.server_name().as_str() {
+		return Err!(Request(Forbidden("Only users of this server can use this focus.")));
+	}
+
+	let user_id = services
+		.users
+		.find_from_openid_token(&request.openid_token.access_token)?;
+
+	if !services
+		.rooms
+		.state_cache
+		.is_joined(&user_id, &request.room)?
+	{
+		return Err!(Request(Forbidden("You are not joined to this room.")));
+	}
+
+	let now = utils::millis_since_unix_epoch() / 1000;
+	let claims = LiveKitClaims {
+		iss: api_key,
+		sub: format!("{user_id}:{}", request.device_id),
+		nbf: now,
+		exp: now.saturating_add(LIVEKIT_TOKEN_TTL.as_secs()),
+		video: LiveKitVideoGrant {
+			room: request.room.as_str(),
+			room_join: true,
+			can_publish: true,
+			can_publish_data: true,
+			can_subscribe: true,
+		},
+	};
+
+	let jwt = jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(api_secret.as_bytes()))
+		.map_err(|e| err!("Failed to create LiveKit token: {e}"))?;
+
+	Ok(Json(serde_json::json!({
+		"url": livekit_url.as_str(),
+		"jwt": jwt,
+	})))
+}
+
+/// MatrixRTC foci to advertise to clients, the built-in LiveKit JWT service
+/// first.
+pub(crate) fn rtc_foci(services: &Services) -> Vec<serde_json::Value> {
+	let config = &services.globals.config.matrix_rtc;
+
+	let builtin = config
+		.livekit_url
+		.as_ref()
+		.and(services.globals.well_known_client().as_ref())
+		.and_then(|client_url| client_url.join(LIVEKIT_SERVICE_PATH).ok());
+
+	builtin
+		.iter()
+		.chain(config.livekit_service_urls.iter())
+		.map(|url| {
+			serde_json::json!({
+				"type": "livekit",
+				"livekit_service_url": url.as_str().trim_end_matches('/'),
+			})
+		})
+		.collect()
+}
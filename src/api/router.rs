@@ -1,6 +1,7 @@
 mod args;
 mod auth;
 mod handler;
+mod rate_limit;
 mod request;
 mod response;
 pub mod state;
@@ -34,14 +35,20 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 		.ruma_route(client::register_route)
 		.ruma_route(client::get_login_types_route)
 		.ruma_route(client::login_route)
+		.ruma_route(client::sso_login_route)
 		.ruma_route(client::whoami_route)
 		.ruma_route(client::logout_route)
 		.ruma_route(client::logout_all_route)
+		.ruma_route(client::refresh_token_route)
 		.ruma_route(client::change_password_route)
 		.ruma_route(client::deactivate_route)
 		.ruma_route(client::third_party_route)
 		.ruma_route(client::request_3pid_management_token_via_email_route)
 		.ruma_route(client::request_3pid_management_token_via_msisdn_route)
+		.ruma_route(client::request_registration_token_via_email_route)
+		.ruma_route(client::request_password_change_token_via_email_route)
+		.ruma_route(client::add_3pid_route)
+		.ruma_route(client::delete_3pid_route)
 		.ruma_route(client::check_registration_token_validity)
 		.ruma_route(client::get_capabilities_route)
 		.ruma_route(client::get_pushrules_all_route)
@@ -97,6 +104,7 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 		.ruma_route(client::join_room_by_id_route)
 		.ruma_route(client::join_room_by_id_or_alias_route)
 		.ruma_route(client::joined_members_route)
+		.ruma_route(client::knock_room_route)
 		.ruma_route(client::leave_room_route)
 		.ruma_route(client::forget_room_route)
 		.ruma_route(client::joined_rooms_route)
@@ -113,6 +121,11 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 		.ruma_route(client::get_protocols_route)
 		.route("/_matrix/client/unstable/thirdparty/protocols",
 			get(client::get_protocols_route_unstable))
+		.ruma_route(client::get_protocol_route)
+		.ruma_route(client::get_location_for_protocol_route)
+		.ruma_route(client::get_location_for_room_alias_route)
+		.ruma_route(client::get_user_for_protocol_route)
+		.ruma_route(client::get_user_for_user_id_route)
 		.ruma_route(client::send_message_event_route)
 		.ruma_route(client::send_state_event_for_key_route)
 		.ruma_route(client::get_state_events_route)
@@ -142,6 +155,7 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 		)
 		.ruma_route(client::sync_events_route)
 		.ruma_route(client::sync_events_v4_route)
+		.ruma_route(client::sync_events_v5_route)
 		.ruma_route(client::get_context_route)
 		.ruma_route(client::get_message_events_route)
 		.ruma_route(client::search_events_route)
@@ -179,8 +193,15 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 			get(client::get_room_summary_legacy)
 		)
 		.ruma_route(client::well_known_support)
-		.ruma_route(client::well_known_client)
+		.route("/.well-known/matrix/client", get(client::well_known_client))
 		.route("/_conduwuit/server_version", get(client::conduwuit_server_version))
+		.route(service::oidc::CALLBACK_PATH, get(client::oidc_callback_route))
+		.route(service::threepid::VALIDATE_PATH, get(client::validate_email_route))
+		.route(
+			&format!("{}/sfu/get", client::LIVEKIT_SERVICE_PATH),
+			post(client::livekit_sfu_route),
+		)
+		.ruma_route(client::purge_history_route)
 		.route("/_matrix/client/r0/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/_matrix/client/v3/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/client/server.json", get(client::syncv3_client_server_json));
@@ -205,6 +226,8 @@ pub fn build(router: Router<State>, server: &Server) -> Router<State> {
 			.ruma_route(server::create_join_event_template_route)
 			.ruma_route(server::create_join_event_v1_route)
 			.ruma_route(server::create_join_event_v2_route)
+			.ruma_route(server::create_knock_event_template_route)
+			.ruma_route(server::create_knock_event_v1_route)
 			.ruma_route(server::create_invite_route)
 			.ruma_route(server::get_devices_route)
 			.ruma_route(server::get_room_information_route)
@@ -6,7 +6,7 @@ use conduit::{debug, err, trace, utils::string::EMPTY, Error, Result};
 use ruma::{api::IncomingRequest, CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId};
 use service::Services;
 
-use super::{auth, auth::Auth, request, request::Request};
+use super::{auth, auth::Auth, rate_limit, request, request::Request};
 use crate::{service::appservice::RegistrationInfo, State};
 
 /// Extractor for Ruma request structs
@@ -33,19 +33,28 @@ pub(crate) struct Args<T> {
 	/// Parsed JSON content.
 	/// None when body is not a valid string
 	pub(crate) json_body: Option<CanonicalJsonValue>,
+
+	/// Sync stream of the device, from the unstable
+	/// `gay.puppyirl.conduwuit.stream_id` query parameter of `/sync`,
+	/// `/messages` and `/context`. Clients syncing a device more than once at
+	/// the same time, like Pantalaimon, pass it to receive to-device events
+	/// and lazy-loaded members independently. None for the default stream.
+	pub(crate) stream_id: Option<String>,
 }
 
 #[async_trait]
 impl<T> FromRequest<State, Body> for Args<T>
 where
-	T: IncomingRequest,
+	T: IncomingRequest + 'static,
 {
 	type Rejection = Error;
 
 	async fn from_request(request: hyper::Request<Body>, services: &State) -> Result<Self, Self::Rejection> {
 		let mut request = request::from(services, request).await?;
 		let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&request.body).ok();
-		let auth = auth::auth(services, &mut request, &json_body, &T::METADATA).await?;
+		let auth = auth::auth::<T>(services, &mut request, &json_body).await?;
+		rate_limit::check::<T>(services, &request, &auth)?;
+		let stream_id = request.query.stream_id.take();
 		Ok(Self {
 			body: make_body::<T>(services, &mut request, &mut json_body, &auth)?,
 			origin: auth.origin,
@@ -53,6 +62,7 @@ where
 			sender_device: auth.sender_device,
 			appservice_info: auth.appservice_info,
 			json_body,
+			stream_id,
 		})
 	}
 }
@@ -1,4 +1,6 @@
-use std::collections::BTreeMap;
+mod tests;
+
+use std::{any::TypeId, collections::BTreeMap};
 
 use axum::RequestPartsExt;
 use axum_extra::{
@@ -6,21 +8,31 @@ use axum_extra::{
 	typed_header::TypedHeaderRejectionReason,
 	TypedHeader,
 };
-use conduit::{debug_info, warn, Err, Error, Result};
+use conduit::{debug_info, utils, warn, Err, Error, Result};
 use http::uri::PathAndQuery;
 use ruma::{
-	api::{client::error::ErrorKind, AuthScheme, Metadata},
+	api::{
+		client::{
+			error::ErrorKind,
+			session::{logout, logout_all},
+		},
+		AuthScheme, IncomingRequest,
+	},
 	server_util::authorization::XMatrix,
 	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
 };
 use service::Services;
 
 use super::request::Request;
-use crate::service::appservice::RegistrationInfo;
+use crate::{client::TOKEN_LENGTH, service::appservice::RegistrationInfo};
+
+/// Longest device ID an appservice can act as, in bytes.
+const MAX_DEVICE_ID_LENGTH: usize = 255;
 
 enum Token {
 	Appservice(Box<RegistrationInfo>),
 	User((OwnedUserId, OwnedDeviceId)),
+	Expired,
 	Invalid,
 	None,
 }
@@ -32,9 +44,14 @@ pub(super) struct Auth {
 	pub(super) appservice_info: Option<RegistrationInfo>,
 }
 
-pub(super) async fn auth(
-	services: &Services, request: &mut Request, json_body: &Option<CanonicalJsonValue>, metadata: &Metadata,
-) -> Result<Auth> {
+pub(super) async fn auth<T>(
+	services: &Services, request: &mut Request, json_body: &Option<CanonicalJsonValue>,
+) -> Result<Auth>
+where
+	T: IncomingRequest + 'static,
+{
+	let metadata = &T::METADATA;
+	let logout = is_logout::<T>();
 	let bearer: Option<TypedHeader<Authorization<Bearer>>> = request.parts.extract().await?;
 	let token = match &bearer {
 		Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
@@ -45,7 +62,11 @@ pub(super) async fn auth(
 		if let Some(reg_info) = services.appservice.find_from_token(token).await {
 			Token::Appservice(Box::new(reg_info))
 		} else if let Some((user_id, device_id)) = services.users.find_from_token(token)? {
-			Token::User((user_id, OwnedDeviceId::from(device_id)))
+			if services.users.token_expired(token)? {
+				Token::Expired
+			} else {
+				Token::User((user_id, OwnedDeviceId::from(device_id)))
+			}
 		} else {
 			Token::Invalid
 		}
@@ -53,6 +74,16 @@ pub(super) async fn auth(
 		Token::None
 	};
 
+	if let Token::User((user_id, _)) = &token {
+		if !logout && services.users.is_locked(user_id)? {
+			return Err(Error::BadRequest(ErrorKind::UserLocked, "This account has been locked."));
+		}
+
+		if metadata.authentication != AuthScheme::None && !logout {
+			services.account_validity.check(user_id)?;
+		}
+	}
+
 	if metadata.authentication == AuthScheme::None {
 		match request.parts.uri.path() {
 			// TODO: can we check this better?
@@ -67,7 +98,7 @@ pub(super) async fn auth(
 							// we should have validated the token above
 							// already
 						},
-						Token::None | Token::Invalid => {
+						Token::None | Token::Expired | Token::Invalid => {
 							return Err(Error::BadRequest(ErrorKind::MissingToken, "Missing or invalid access token."));
 						},
 					}
@@ -78,6 +109,7 @@ pub(super) async fn auth(
 	}
 
 	match (metadata.authentication, token) {
+		(authentication, Token::Expired) => expired_token(authentication),
 		(_, Token::Invalid) => {
 			// OpenID endpoint uses a query param with the same name, drop this once query
 			// params for user auth are removed from the spec. This is required to make
@@ -98,7 +130,7 @@ pub(super) async fn auth(
 				))
 			}
 		},
-		(AuthScheme::AccessToken, Token::Appservice(info)) => Ok(auth_appservice(services, request, info)?),
+		(AuthScheme::AccessToken, Token::Appservice(info)) => Ok(auth_appservice(services, request, info, logout)?),
 		(AuthScheme::None | AuthScheme::AccessTokenOptional | AuthScheme::AppserviceToken, Token::Appservice(info)) => {
 			Ok(Auth {
 				origin: None,
@@ -150,7 +182,33 @@ pub(super) async fn auth(
 	}
 }
 
-fn auth_appservice(services: &Services, request: &Request, info: Box<RegistrationInfo>) -> Result<Auth> {
+/// Whether the request `T` logs out, which locked users and users whose
+/// account expired can still do.
+fn is_logout<T: 'static>() -> bool {
+	let endpoint = TypeId::of::<T>();
+	endpoint == TypeId::of::<logout::v3::Request>() || endpoint == TypeId::of::<logout_all::v3::Request>()
+}
+
+/// Endpoints without authentication ignore an expired access token, the others
+/// ask the client to refresh it.
+fn expired_token(authentication: AuthScheme) -> Result<Auth> {
+	match authentication {
+		AuthScheme::None => Ok(Auth {
+			origin: None,
+			sender_user: None,
+			sender_device: None,
+			appservice_info: None,
+		}),
+		_ => Err(Error::BadRequest(
+			ErrorKind::UnknownToken {
+				soft_logout: true,
+			},
+			"Access token has expired.",
+		)),
+	}
+}
+
+fn auth_appservice(services: &Services, request: &Request, info: Box<RegistrationInfo>, logout: bool) -> Result<Auth> {
 	let user_id = request
 		.query
 		.user_id
@@ -174,14 +232,58 @@ fn auth_appservice(services: &Services, request: &Request, info: Box<Registratio
 		return Err(Error::BadRequest(ErrorKind::forbidden(), "User does not exist."));
 	}
 
+	if !logout && services.users.is_locked(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserLocked, "This account has been locked."));
+	}
+
+	let sender_device = request
+		.query
+		.device_id
+		.as_deref()
+		.map(|device_id| masquerade_device(services, &user_id, device_id))
+		.transpose()?;
+
 	Ok(Auth {
 		origin: None,
 		sender_user: Some(user_id),
-		sender_device: None,
+		sender_device,
 		appservice_info: Some(*info),
 	})
 }
 
+/// Returns the device of the user an appservice acts as (MSC3202), creating it
+/// on first use.
+fn masquerade_device(services: &Services, user_id: &UserId, device_id: &str) -> Result<OwnedDeviceId> {
+	check_device_id(device_id)?;
+	let device_id: OwnedDeviceId = device_id.into();
+
+	let exists = services
+		.users
+		.all_device_ids(user_id)
+		.any(|id| id.is_ok_and(|id| id == device_id));
+
+	if !exists {
+		debug_info!("Creating device {device_id} of {user_id} for its appservice");
+		// The appservice authenticates with its own token, this one is never handed out
+		services
+			.users
+			.create_device(user_id, &device_id, &utils::random_string(TOKEN_LENGTH), None, None)?;
+	}
+
+	Ok(device_id)
+}
+
+fn check_device_id(device_id: &str) -> Result<()> {
+	if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"device_id must be between 1 and 255 bytes long.",
+		));
+	}
+
+	Ok(())
+}
+
 async fn auth_server(
 	services: &Services, request: &mut Request, json_body: &Option<CanonicalJsonValue>,
 ) -> Result<Auth> {
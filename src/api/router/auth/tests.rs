@@ -0,0 +1,62 @@
+#![cfg(test)]
+
+use conduit::Error;
+use ruma::api::{
+	client::{
+		error::ErrorKind,
+		session::{login, logout, logout_all},
+	},
+	AuthScheme,
+};
+
+use super::{check_device_id, expired_token, is_logout};
+
+#[test]
+fn expired_token_asks_for_a_refresh() {
+	for authentication in [
+		AuthScheme::AccessToken,
+		AuthScheme::AccessTokenOptional,
+		AuthScheme::AppserviceToken,
+	] {
+		assert!(
+			matches!(
+				expired_token(authentication),
+				Err(Error::BadRequest(
+					ErrorKind::UnknownToken {
+						soft_logout: true
+					},
+					_
+				))
+			),
+			"{authentication:?}"
+		);
+	}
+}
+
+#[test]
+fn expired_token_is_ignored_without_authentication() {
+	assert!(
+		expired_token(AuthScheme::None).is_ok_and(|auth| auth.sender_user.is_none() && auth.sender_device.is_none())
+	);
+}
+
+#[test]
+fn logout_is_classified_by_endpoint() {
+	assert!(is_logout::<logout::v3::Request>());
+	assert!(is_logout::<logout_all::v3::Request>());
+	assert!(!is_logout::<login::v3::Request>());
+}
+
+#[test]
+fn masqueraded_device_id_is_bounded() {
+	assert!(check_device_id("DEVICE").is_ok());
+	assert!(check_device_id(&"D".repeat(255)).is_ok());
+
+	for device_id in [String::new(), "D".repeat(256)] {
+		assert!(
+			matches!(check_device_id(&device_id), Err(Error::BadRequest(ErrorKind::InvalidParam, _))),
+			"{} bytes",
+			device_id.len()
+		);
+	}
+}
@@ -0,0 +1,127 @@
+mod tests;
+
+use std::{
+	any::TypeId,
+	net::{IpAddr, SocketAddr},
+};
+
+use axum::extract::ConnectInfo;
+use conduit::{debug_warn, Result};
+use http::HeaderMap;
+use ruma::api::{
+	client::{
+		account::register, media::create_content, message::send_message_event, redact::redact_event, session::login,
+	},
+	federation::transactions::send_transaction_message,
+};
+use service::{rate_limiter::Limit, Services};
+
+use super::{auth::Auth, request::Request};
+
+/// Counts the request against the rate limit of its endpoint, keyed by origin
+/// server, user and device, or client IP.
+pub(super) fn check<T: 'static>(services: &Services, request: &Request, auth: &Auth) -> Result<()> {
+	let Some(limit) = endpoint_limit::<T>() else {
+		return Ok(());
+	};
+
+	if auth
+		.appservice_info
+		.as_ref()
+		.is_some_and(|info| info.registration.rate_limited == Some(false))
+	{
+		return Ok(());
+	}
+
+	// Unix sockets have no peer address, only local proxies can connect to them
+	let peer = request
+		.parts
+		.extensions
+		.get::<ConnectInfo<SocketAddr>>()
+		.map(|ConnectInfo(addr)| addr.ip())
+		.filter(|ip| !ip.is_unspecified());
+
+	let client_ip = client_ip(
+		peer,
+		&request.parts.headers,
+		&services.globals.config.rate_limits.trusted_proxies,
+	);
+	let Some(key) = key(auth, client_ip) else {
+		debug_warn!(?limit, "Not rate limiting a request without a client address");
+		return Ok(());
+	};
+
+	services.rate_limiter.check(limit, &key)
+}
+
+/// The rate limit of the endpoint handling the request `T`.
+fn endpoint_limit<T: 'static>() -> Option<Limit> {
+	let endpoint = TypeId::of::<T>();
+	if endpoint == TypeId::of::<register::v3::Request>() {
+		Some(Limit::Registration)
+	} else if endpoint == TypeId::of::<login::v3::Request>() {
+		Some(Limit::Login)
+	} else if endpoint == TypeId::of::<send_message_event::v3::Request>()
+		|| endpoint == TypeId::of::<redact_event::v3::Request>()
+	{
+		Some(Limit::Message)
+	} else if endpoint == TypeId::of::<create_content::v3::Request>() {
+		Some(Limit::MediaUpload)
+	} else if endpoint == TypeId::of::<send_transaction_message::v1::Request>() {
+		Some(Limit::Federation)
+	} else {
+		None
+	}
+}
+
+/// The bucket of a request: its origin server, its user and device, or else
+/// its client address. Requests without any of them are not bucketed together.
+fn key(auth: &Auth, client_ip: Option<IpAddr>) -> Option<String> {
+	if let Some(origin) = &auth.origin {
+		Some(origin.to_string())
+	} else if let Some(sender_user) = &auth.sender_user {
+		match &auth.sender_device {
+			Some(sender_device) => Some(format!("{sender_user}/{sender_device}")),
+			None => Some(sender_user.to_string()),
+		}
+	} else {
+		client_ip.as_ref().map(ToString::to_string)
+	}
+}
+
+/// The address of the client: the peer of the connection, or when connected
+/// through trusted proxies the last address they forwarded which is not one of
+/// them. Proxies connecting over a unix socket have no peer address and are
+/// trusted.
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+	if let Some(peer) = peer.filter(|peer| !trusted_proxies.contains(peer)) {
+		return Some(peer);
+	}
+
+	let forwarded: Vec<_> = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.map(|value| value.to_str().ok())
+		.collect::<Option<Vec<_>>>()?
+		.into_iter()
+		.flat_map(|value| value.split(','))
+		.map(str::trim)
+		.collect();
+
+	if forwarded.is_empty() {
+		return headers.get("x-real-ip")?.to_str().ok()?.trim().parse().ok();
+	}
+
+	// Each proxy appends the address it received the request from, so only the
+	// addresses after the last untrusted one can be relied upon
+	let mut client = None;
+	for addr in forwarded.iter().rev() {
+		let ip: IpAddr = addr.parse().ok()?;
+		client = Some(ip);
+		if !trusted_proxies.contains(&ip) {
+			break;
+		}
+	}
+
+	client
+}
@@ -0,0 +1,94 @@
+#![cfg(test)]
+
+use std::net::IpAddr;
+
+use http::{HeaderMap, HeaderValue};
+use ruma::{
+	api::client::{account::register, message::send_message_event, session::login, sync::sync_events},
+	device_id, server_name, user_id,
+};
+use service::rate_limiter::Limit;
+
+use super::{client_ip, endpoint_limit, key, Auth};
+
+fn auth() -> Auth {
+	Auth {
+		origin: None,
+		sender_user: None,
+		sender_device: None,
+		appservice_info: None,
+	}
+}
+
+fn ip(ip: &str) -> IpAddr { ip.parse().expect("valid IP address") }
+
+fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+	pairs
+		.iter()
+		.map(|(name, value)| (*name, HeaderValue::from_static(value)))
+		.fold(HeaderMap::new(), |mut headers, (name, value)| {
+			headers.append(name, value);
+			headers
+		})
+}
+
+#[test]
+fn endpoints_are_limited_by_route() {
+	assert_eq!(endpoint_limit::<register::v3::Request>(), Some(Limit::Registration));
+	assert_eq!(endpoint_limit::<login::v3::Request>(), Some(Limit::Login));
+	assert_eq!(endpoint_limit::<send_message_event::v3::Request>(), Some(Limit::Message));
+	assert_eq!(endpoint_limit::<sync_events::v3::Request>(), None);
+}
+
+#[test]
+fn key_prefers_origin_then_device_then_ip() {
+	let client = Some(ip("192.0.2.1"));
+
+	let mut auth = auth();
+	assert_eq!(key(&auth, client).as_deref(), Some("192.0.2.1"));
+
+	auth.sender_user = Some(user_id!("@alice:example.com").to_owned());
+	assert_eq!(key(&auth, client).as_deref(), Some("@alice:example.com"));
+
+	auth.sender_device = Some(device_id!("DEVICE").to_owned());
+	assert_eq!(key(&auth, client).as_deref(), Some("@alice:example.com/DEVICE"));
+
+	auth.origin = Some(server_name!("remote.example").to_owned());
+	assert_eq!(key(&auth, client).as_deref(), Some("remote.example"));
+}
+
+#[test]
+fn key_is_never_shared() {
+	assert_eq!(key(&auth(), None), None);
+}
+
+#[test]
+fn forwarded_headers_are_ignored_from_untrusted_peers() {
+	let headers = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-real-ip", "198.51.100.8")]);
+
+	assert_eq!(client_ip(Some(ip("192.0.2.1")), &headers, &[]), Some(ip("192.0.2.1")));
+}
+
+#[test]
+fn forwarded_headers_are_used_from_trusted_proxies() {
+	let proxy = ip("10.0.0.1");
+	let headers = headers(&[("x-forwarded-for", "203.0.113.5, 198.51.100.7")]);
+
+	assert_eq!(client_ip(Some(proxy), &headers, &[proxy]), Some(ip("198.51.100.7")));
+}
+
+#[test]
+fn spoofed_forwarded_addresses_are_skipped() {
+	let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+	let headers = headers(&[("x-forwarded-for", "spoofed, 198.51.100.7"), ("x-forwarded-for", "10.0.0.2")]);
+
+	assert_eq!(client_ip(Some(proxies[0]), &headers, &proxies), Some(ip("198.51.100.7")));
+}
+
+#[test]
+fn unix_socket_proxies_are_trusted() {
+	let headers = headers(&[("x-real-ip", "198.51.100.7")]);
+
+	assert_eq!(client_ip(None, &headers, &[]), Some(ip("198.51.100.7")));
+	assert_eq!(client_ip(None, &HeaderMap::new(), &[]), None);
+}
@@ -11,6 +11,12 @@ use service::Services;
 pub(super) struct QueryParams {
 	pub(super) access_token: Option<String>,
 	pub(super) user_id: Option<String>,
+	/// Device of `user_id` an appservice acts as (MSC3202)
+	#[serde(alias = "org.matrix.msc3202.device_id")]
+	pub(super) device_id: Option<String>,
+	/// Sync stream of the device, see `Args::stream_id`
+	#[serde(rename = "gay.puppyirl.conduwuit.stream_id")]
+	pub(super) stream_id: Option<String>,
 }
 
 pub(super) struct Request {
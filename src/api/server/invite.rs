@@ -1,12 +1,13 @@
 use axum::extract::State;
 use axum_client_ip::InsecureClientIp;
-use conduit::{utils, warn, Error, PduEvent, Result};
+use conduit::{utils, warn, Err, Error, PduEvent, Result};
 use ruma::{
 	api::{client::error::ErrorKind, federation::membership::create_invite},
 	events::room::member::{MembershipState, RoomMemberEventContent},
 	serde::JsonObject,
 	CanonicalJsonValue, EventId, OwnedUserId,
 };
+use service::spam_checker::Verdict;
 
 use crate::Ruma;
 
@@ -128,6 +129,26 @@ pub(crate) async fn create_invite_route(
 	)
 	.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "sender is not a user ID."))?;
 
+	if let Some(ban) = services
+		.policy
+		.server_ban(origin)
+		.or_else(|| services.policy.user_ban(&sender))
+		.or_else(|| services.policy.room_ban(&body.room_id))
+	{
+		services
+			.policy
+			.report(
+				&format!("Rejected invite of {invited_user} by {sender} to {}", body.room_id),
+				&ban,
+			)
+			.await;
+
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Invite is banned by a policy list of this server.",
+		));
+	}
+
 	if services.rooms.metadata.is_banned(&body.room_id)? && !services.users.is_admin(&invited_user)? {
 		return Err(Error::BadRequest(
 			ErrorKind::forbidden(),
@@ -142,6 +163,22 @@ pub(crate) async fn create_invite_route(
 		));
 	}
 
+	match services
+		.spam_checker
+		.check_invite(&sender, &invited_user, &body.room_id)?
+	{
+		Verdict::Allow => {},
+		Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+		Verdict::ShadowBan => {
+			// the invite is signed but never shown to the invited user
+			return Ok(create_invite::v2::Response {
+				event: services
+					.sending
+					.convert_to_outgoing_federation_event(signed_event),
+			});
+		},
+	}
+
 	let mut invite_state = body.invite_room_state.clone();
 
 	let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -42,6 +42,11 @@ pub(crate) async fn create_join_event_template_route(
 		.event_handler
 		.acl_check(origin, &body.room_id)?;
 
+	services
+		.policy
+		.check_membership(&body.user_id, &body.room_id, &MembershipState::Join)
+		.await?;
+
 	if services
 		.globals
 		.config
@@ -0,0 +1,139 @@
+use axum::extract::State;
+use conduit::{Error, Result};
+use ruma::{
+	api::{client::error::ErrorKind, federation::knock::create_knock_event_template},
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		TimelineEventType,
+	},
+	RoomVersionId,
+};
+use serde_json::value::to_raw_value;
+use tracing::warn;
+
+use super::make_join::maybe_strip_event_id;
+use crate::{service::pdu::PduBuilder, Ruma};
+
+/// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+///
+/// Creates a knock template.
+pub(crate) async fn create_knock_event_template_route(
+	State(services): State<crate::State>, body: Ruma<create_knock_event_template::v1::Request>,
+) -> Result<create_knock_event_template::v1::Response> {
+	use RoomVersionId::*;
+
+	if !services.rooms.metadata.exists(&body.room_id)? {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Room is unknown to this server."));
+	}
+
+	let origin = body.origin.as_ref().expect("server is authenticated");
+	if body.user_id.server_name() != origin {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Not allowed to knock on behalf of another server/user",
+		));
+	}
+
+	// ACL check origin server
+	services
+		.rooms
+		.event_handler
+		.acl_check(origin, &body.room_id)?;
+
+	services
+		.policy
+		.check_membership(&body.user_id, &body.room_id, &MembershipState::Knock)
+		.await?;
+
+	if services
+		.globals
+		.config
+		.forbidden_remote_server_names
+		.contains(origin)
+	{
+		warn!(
+			"Server {origin} for remote user {} tried knocking on room ID {} which has a server name that is globally \
+			 forbidden. Rejecting.",
+			&body.user_id, &body.room_id,
+		);
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Server is banned on this homeserver.",
+		));
+	}
+
+	if let Some(server) = body.room_id.server_name() {
+		if services
+			.globals
+			.config
+			.forbidden_remote_server_names
+			.contains(&server.to_owned())
+		{
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"Server is banned on this homeserver.",
+			));
+		}
+	}
+
+	let room_version_id = services.rooms.state.get_room_version(&body.room_id)?;
+
+	// Knocking was introduced in room version 7
+	if matches!(room_version_id, V1 | V2 | V3 | V4 | V5 | V6) {
+		return Err(Error::BadRequest(
+			ErrorKind::IncompatibleRoomVersion {
+				room_version: room_version_id,
+			},
+			"Room version does not support knocking.",
+		));
+	}
+
+	if !body.ver.contains(&room_version_id) {
+		return Err(Error::BadRequest(
+			ErrorKind::IncompatibleRoomVersion {
+				room_version: room_version_id,
+			},
+			"Your homeserver does not support the features required to knock on this room.",
+		));
+	}
+
+	let state_lock = services.rooms.state.mutex.lock(&body.room_id).await;
+
+	if services
+		.rooms
+		.state_cache
+		.is_joined(&body.user_id, &body.room_id)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"User is already joined to this room.",
+		));
+	}
+
+	let content =
+		to_raw_value(&RoomMemberEventContent::new(MembershipState::Knock)).expect("member event is valid value");
+
+	let (_pdu, mut pdu_json) = services.rooms.timeline.create_hash_and_sign_event(
+		PduBuilder {
+			event_type: TimelineEventType::RoomMember,
+			content,
+			unsigned: None,
+			state_key: Some(body.user_id.to_string()),
+			redacts: None,
+			timestamp: None,
+		},
+		&body.user_id,
+		&body.room_id,
+		&state_lock,
+	)?;
+
+	drop(state_lock);
+
+	// room v3 and above removed the "event_id" field from remote PDU format
+	maybe_strip_event_id(&mut pdu_json, &room_version_id)?;
+
+	Ok(create_knock_event_template::v1::Response {
+		room_version: room_version_id,
+		event: to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
+	})
+}
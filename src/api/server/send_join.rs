@@ -123,6 +123,11 @@ async fn create_join_event(
 		));
 	};
 
+	services
+		.policy
+		.check_membership(&sender, room_id, &MembershipState::Join)
+		.await?;
+
 	if content
 		.join_authorized_via_users_server
 		.is_some_and(|user| services.globals.user_is_local(&user))
@@ -94,6 +94,37 @@ pub fn check(config: &Config) -> Result<()> {
 		));
 	}
 
+	if config.smtp.require_email_for_registration && config.smtp.connection_url.is_none() {
+		return Err!(Config(
+			"smtp.require_email_for_registration",
+			"Email validation is required for registration, but no SMTP relay is configured."
+		));
+	}
+
+	for (key, limit) in [
+		("rate_limits.registration", &config.rate_limits.registration),
+		("rate_limits.login", &config.rate_limits.login),
+		("rate_limits.message", &config.rate_limits.message),
+		("rate_limits.media_upload", &config.rate_limits.media_upload),
+		("rate_limits.federation", &config.rate_limits.federation),
+	] {
+		if !limit.per_second.is_finite() || limit.per_second < 0.0001 || limit.burst_count == 0 {
+			return Err!(Config(
+				key,
+				"Rate limits need a per_second of at least 0.0001 and a positive burst_count."
+			));
+		}
+	}
+
+	if config.matrix_rtc.livekit_url.is_some()
+		&& (config.matrix_rtc.livekit_api_key.is_none() || config.matrix_rtc.livekit_api_secret.is_none())
+	{
+		return Err!(Config(
+			"matrix_rtc.livekit_url",
+			"A LiveKit API key and secret are required to hand out LiveKit tokens."
+		));
+	}
+
 	if config.max_request_size < 5_120_000 {
 		return Err!(Config(
 			"max_request_size",
@@ -111,6 +142,7 @@ pub fn check(config: &Config) -> Result<()> {
 	if config.allow_registration
 		&& !config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
 		&& config.registration_token.is_none()
+		&& !config.registration_requires_token
 	{
 		return Err!(Config(
 			"registration_token",
@@ -126,6 +158,7 @@ For security and safety reasons, conduwuit will shut down. If you are extra sure
 	if config.allow_registration
 		&& config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
 		&& config.registration_token.is_none()
+		&& !config.registration_requires_token
 	{
 		warn!(
 			"Open registration is enabled via setting \
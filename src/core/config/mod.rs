@@ -139,8 +139,11 @@ pub struct Config {
 	#[serde(default)]
 	pub yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse: bool,
 	pub registration_token: Option<String>,
+	#[serde(default)]
+	pub registration_requires_token: bool,
 	#[serde(default = "true_fn")]
 	pub allow_encryption: bool,
+	pub search_default_language: Option<String>,
 	#[serde(default = "true_fn")]
 	pub allow_federation: bool,
 	#[serde(default)]
@@ -178,6 +181,18 @@ pub struct Config {
 	#[serde(default)]
 	pub proxy: ProxyConfig,
 	pub jwt_secret: Option<String>,
+	#[serde(default)]
+	pub oidc: OidcConfig,
+	#[serde(default)]
+	pub smtp: SmtpConfig,
+	#[serde(default)]
+	pub account_validity: AccountValidityConfig,
+	#[serde(default)]
+	pub rate_limits: RateLimitsConfig,
+	#[serde(default)]
+	pub matrix_rtc: MatrixRtcConfig,
+	#[serde(default)]
+	pub retention: RetentionConfig,
 	#[serde(default = "default_trusted_servers")]
 	pub trusted_servers: Vec<OwnedServerName>,
 	#[serde(default = "true_fn")]
@@ -188,6 +203,10 @@ pub struct Config {
 	pub log_colors: bool,
 	#[serde(default = "default_openid_token_ttl")]
 	pub openid_token_ttl: u64,
+	#[serde(default = "default_access_token_ttl")]
+	pub access_token_ttl: u64,
+	#[serde(default = "default_sliding_sync_connection_ttl")]
+	pub sliding_sync_connection_ttl: u64,
 	#[serde(default)]
 	pub turn_username: String,
 	#[serde(default)]
@@ -309,6 +328,9 @@ pub struct Config {
 	pub forbidden_remote_server_names: Vec<OwnedServerName>,
 	#[serde(default = "Vec::new")]
 	pub forbidden_remote_room_directory_server_names: Vec<OwnedServerName>,
+	/// Rooms whose `m.policy.rule.*` bans are enforced
+	#[serde(default = "Vec::new")]
+	pub policy_list_rooms: Vec<OwnedRoomId>,
 
 	#[serde(default = "default_ip_range_denylist")]
 	pub ip_range_denylist: Vec<String>,
@@ -333,6 +355,8 @@ pub struct Config {
 	#[serde(default = "RegexSet::empty")]
 	#[serde(with = "serde_regex")]
 	pub forbidden_usernames: RegexSet,
+	/// Rules file of the keyword spam checker
+	pub spam_checker_rules: Option<PathBuf>,
 
 	#[serde(default = "true_fn")]
 	pub startup_netburst: bool,
@@ -408,6 +432,167 @@ pub struct WellKnownConfig {
 	pub support_mxid: Option<OwnedUserId>,
 }
 
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct OidcConfig {
+	/// Issuer of the identity provider; OIDC login is enabled when set
+	pub issuer: Option<Url>,
+	pub client_id: Option<String>,
+	pub client_secret: Option<String>,
+	/// Where the identity provider sends users back to, defaults to the
+	/// callback under the well-known client URL
+	pub redirect_uri: Option<Url>,
+	#[serde(default = "default_oidc_scopes")]
+	pub scopes: Vec<String>,
+	#[serde(default = "default_oidc_localpart_claim")]
+	pub localpart_claim: String,
+	#[serde(default = "default_oidc_displayname_claim")]
+	pub displayname_claim: String,
+	#[serde(default = "true_fn")]
+	pub allow_registration: bool,
+	#[serde(default)]
+	pub allow_existing_users: bool,
+	/// Clients sent back with a login token without asking the user to
+	/// confirm first
+	#[serde(default)]
+	pub client_redirect_urls: Vec<Url>,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct SmtpConfig {
+	/// smtp:// or smtps:// URL of the relay; sending email is enabled when set
+	pub connection_url: Option<String>,
+	/// Mailbox emails are sent from
+	pub sender: Option<String>,
+	#[serde(default)]
+	pub require_email_for_registration: bool,
+	#[serde(default = "default_email_validation_token_ttl")]
+	pub validation_token_ttl: u64,
+	/// Minimum seconds between validation emails sent to the same address
+	#[serde(default = "default_email_request_token_interval")]
+	pub request_token_interval: u64,
+	/// Seconds to wait after a missed highlight before emailing a digest, so
+	/// that later messages are batched into the same email
+	#[serde(default = "default_email_notification_delay")]
+	pub notification_delay: u64,
+	#[serde(default = "default_email_notification_subject")]
+	pub notification_subject: String,
+	#[serde(default = "default_email_notification_body")]
+	pub notification_body: String,
+	/// Template for each message listed in `{messages}` of the body
+	#[serde(default = "default_email_notification_message")]
+	pub notification_message: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccountValidityConfig {
+	/// Seconds new accounts stay valid for; accounts only expire when an admin
+	/// sets an expiry if unset
+	pub period: Option<u64>,
+	/// Seconds before expiry at which users are reminded to renew
+	#[serde(default = "default_account_validity_renew_at")]
+	pub renew_at: u64,
+	/// Deactivate expired accounts instead of locking them until renewed
+	#[serde(default)]
+	pub deactivate_expired: bool,
+}
+
+impl Default for AccountValidityConfig {
+	fn default() -> Self {
+		Self {
+			period: None,
+			renew_at: default_account_validity_renew_at(),
+			deactivate_expired: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitsConfig {
+	#[serde(default = "true_fn")]
+	pub enabled: bool,
+	/// Keyed by client IP
+	#[serde(default = "default_rate_limit_registration")]
+	pub registration: RateLimitConfig,
+	/// Keyed by client IP
+	#[serde(default = "default_rate_limit_login")]
+	pub login: RateLimitConfig,
+	/// Keyed by user and device
+	#[serde(default = "default_rate_limit_message")]
+	pub message: RateLimitConfig,
+	/// Keyed by user and device
+	#[serde(default = "default_rate_limit_media_upload")]
+	pub media_upload: RateLimitConfig,
+	/// Keyed by origin server
+	#[serde(default = "default_rate_limit_federation")]
+	pub federation: RateLimitConfig,
+	/// Reverse proxies whose `X-Forwarded-For` header is trusted for client IPs
+	#[serde(default)]
+	pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// A token bucket which refills at `per_second` up to `burst_count` requests.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+	pub per_second: f64,
+	pub burst_count: u32,
+}
+
+impl Default for RateLimitsConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			registration: default_rate_limit_registration(),
+			login: default_rate_limit_login(),
+			message: default_rate_limit_message(),
+			media_upload: default_rate_limit_media_upload(),
+			federation: default_rate_limit_federation(),
+			trusted_proxies: Vec::new(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct MatrixRtcConfig {
+	/// External LiveKit JWT services advertised as MatrixRTC foci
+	#[serde(default)]
+	pub livekit_service_urls: Vec<Url>,
+	/// WebSocket URL of the LiveKit SFU; the built-in JWT service is enabled
+	/// and advertised when set
+	pub livekit_url: Option<Url>,
+	pub livekit_api_key: Option<String>,
+	pub livekit_api_secret: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+	/// Purge expired events; `m.room.retention` is ignored if disabled
+	#[serde(default)]
+	pub enabled: bool,
+	/// Seconds events are kept in rooms without a retention policy, forever if
+	/// unset
+	pub default_max_lifetime: Option<u64>,
+	/// Bounds in seconds the `max_lifetime` of rooms and the default are
+	/// clamped to; the minimum keeps messages from expiring right away
+	#[serde(default = "default_retention_allowed_lifetime_min")]
+	pub allowed_lifetime_min: u64,
+	pub allowed_lifetime_max: Option<u64>,
+	/// Seconds between two runs of the purge job
+	#[serde(default = "default_retention_purge_interval")]
+	pub purge_interval: u64,
+}
+
+impl Default for RetentionConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			default_max_lifetime: None,
+			allowed_lifetime_min: default_retention_allowed_lifetime_min(),
+			allowed_lifetime_max: None,
+			purge_interval: default_retention_purge_interval(),
+		}
+	}
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(transparent)]
 struct ListeningPort {
@@ -576,6 +761,7 @@ impl fmt::Display for Config {
 				"not set (open registration!)"
 			},
 		);
+		line("Registration requires token", &self.registration_requires_token.to_string());
 		line(
 			"Allow guest registration (inherently false if allow registration is false)",
 			&self.allow_guest_registration.to_string(),
@@ -590,6 +776,10 @@ impl fmt::Display for Config {
 		);
 		line("New user display name suffix", &self.new_user_displayname_suffix);
 		line("Allow encryption", &self.allow_encryption.to_string());
+		line(
+			"Default search language",
+			self.search_default_language.as_deref().unwrap_or("none"),
+		);
 		line("Allow federation", &self.allow_federation.to_string());
 		line("Federation loopback", &self.federation_loopback.to_string());
 		line(
@@ -666,6 +856,21 @@ impl fmt::Display for Config {
 				None => "not set",
 			},
 		);
+		line(
+			"OIDC issuer",
+			self.oidc
+				.issuer
+				.as_ref()
+				.map_or("not set", |url| url.as_str()),
+		);
+		line(
+			"SMTP relay",
+			if self.smtp.connection_url.is_some() {
+				"set"
+			} else {
+				"not set"
+			},
+		);
 		line(
 			"Trusted key servers",
 			&self
@@ -679,6 +884,17 @@ impl fmt::Display for Config {
 			&self.query_trusted_key_servers_first.to_string(),
 		);
 		line("OpenID Token TTL", &self.openid_token_ttl.to_string());
+		line("Refreshable access token TTL", &self.access_token_ttl.to_string());
+		line(
+			"Account validity period",
+			&self
+				.account_validity
+				.period
+				.map_or_else(|| "disabled".to_owned(), |period| period.to_string()),
+		);
+		line("Rate limiting", &self.rate_limits.enabled.to_string());
+		line("Message retention", &self.retention.enabled.to_string());
+		line("Sliding sync connection TTL", &self.sliding_sync_connection_ttl.to_string());
 		line(
 			"TURN username",
 			if self.turn_username.is_empty() {
@@ -707,6 +923,13 @@ impl fmt::Display for Config {
 				.map_or("", |path| path.to_str().unwrap_or_default())
 		});
 		line("Turn TTL", &self.turn_ttl.to_string());
+		line(
+			"LiveKit SFU URL",
+			self.matrix_rtc
+				.livekit_url
+				.as_ref()
+				.map_or("", |url| url.as_str()),
+		);
 		line("Turn URIs", {
 			let mut lst = Vec::with_capacity(self.turn_uris.len());
 			for item in self.turn_uris.iter().cloned().enumerate() {
@@ -774,6 +997,13 @@ impl fmt::Display for Config {
 			}
 			&lst.join(", ")
 		});
+		line("Policy list rooms", {
+			let mut lst = Vec::with_capacity(self.policy_list_rooms.len());
+			for room_id in &self.policy_list_rooms {
+				lst.push(room_id.as_str());
+			}
+			&lst.join(", ")
+		});
 		line("Forbidden Remote Server Names (\"Global\" ACLs)", {
 			let mut lst = Vec::with_capacity(self.forbidden_remote_server_names.len());
 			for domain in &self.forbidden_remote_server_names {
@@ -799,6 +1029,11 @@ impl fmt::Display for Config {
 		line("Forbidden usernames", {
 			&self.forbidden_usernames.patterns().iter().join(", ")
 		});
+		line("Spam checker rules", {
+			self.spam_checker_rules
+				.as_ref()
+				.map_or("", |path| path.to_str().unwrap_or_default())
+		});
 		line("Forbidden room aliases", {
 			&self.forbidden_alias_names.patterns().iter().join(", ")
 		});
@@ -956,6 +1191,68 @@ fn default_request_idle_per_host() -> u16 { 1 }
 
 fn default_well_known_conn_timeout() -> u64 { 6 }
 
+fn default_oidc_scopes() -> Vec<String> { vec!["openid".to_owned(), "profile".to_owned()] }
+
+fn default_oidc_localpart_claim() -> String { "preferred_username".to_owned() }
+
+fn default_oidc_displayname_claim() -> String { "name".to_owned() }
+
+fn default_email_validation_token_ttl() -> u64 { 60 * 60 }
+
+fn default_email_request_token_interval() -> u64 { 60 }
+
+fn default_rate_limit_registration() -> RateLimitConfig {
+	// Each stage of user-interactive authentication is a separate request
+	RateLimitConfig {
+		per_second: 0.17,
+		burst_count: 10,
+	}
+}
+
+fn default_rate_limit_login() -> RateLimitConfig {
+	RateLimitConfig {
+		per_second: 0.17,
+		burst_count: 3,
+	}
+}
+
+fn default_rate_limit_message() -> RateLimitConfig {
+	RateLimitConfig {
+		per_second: 0.5,
+		burst_count: 20,
+	}
+}
+
+fn default_rate_limit_media_upload() -> RateLimitConfig {
+	RateLimitConfig {
+		per_second: 0.2,
+		burst_count: 10,
+	}
+}
+
+fn default_rate_limit_federation() -> RateLimitConfig {
+	RateLimitConfig {
+		per_second: 10.0,
+		burst_count: 50,
+	}
+}
+
+fn default_account_validity_renew_at() -> u64 { 60 * 60 * 24 * 7 }
+
+fn default_email_notification_delay() -> u64 { 60 * 10 }
+
+fn default_retention_allowed_lifetime_min() -> u64 { 60 * 60 * 24 }
+
+fn default_retention_purge_interval() -> u64 { 60 * 60 }
+
+fn default_email_notification_subject() -> String { "[{server_name}] You have {count} unread messages".to_owned() }
+
+fn default_email_notification_body() -> String {
+	"Hello {displayname},\n\nYou have {count} unread messages on {server_name}:\n\n{messages}\n".to_owned()
+}
+
+fn default_email_notification_message() -> String { "{sender} in {room_name}: {body}".to_owned() }
+
 fn default_well_known_timeout() -> u64 { 10 }
 
 fn default_federation_timeout() -> u64 { 300 }
@@ -1009,6 +1306,10 @@ fn default_notification_push_path() -> String { "/_matrix/push/v1/notify".to_own
 
 fn default_openid_token_ttl() -> u64 { 60 * 60 }
 
+fn default_access_token_ttl() -> u64 { 60 * 5 }
+
+fn default_sliding_sync_connection_ttl() -> u64 { 60 * 60 * 24 * 7 }
+
 fn default_turn_ttl() -> u64 { 60 * 60 * 24 }
 
 fn default_presence_idle_timeout_s() -> u64 { 5 * 60 }
@@ -118,6 +118,15 @@ pub enum Error {
 impl Error {
 	pub fn bad_database(message: &'static str) -> Self { crate::err!(Database(error!("{message}"))) }
 
+	/// Request error with an errcode ruma has no `ErrorKind` for, like those of
+	/// unstable features.
+	pub fn custom(errcode: &str, message: &'static str, status_code: http::StatusCode) -> Self {
+		let kind = serde_json::from_value(serde_json::json!({ "errcode": errcode }))
+			.expect("custom errcodes are valid error kinds");
+
+		Self::Request(kind, message.into(), status_code)
+	}
+
 	/// Sanitizes public-facing errors that can leak sensitive information.
 	pub fn sanitized_string(&self) -> String {
 		match self {
@@ -86,6 +86,7 @@ pub(super) fn bad_request_code(kind: &ErrorKind) -> StatusCode {
 			..
 		}
 		| MissingToken
+		| UserLocked
 		| Unauthorized => StatusCode::UNAUTHORIZED,
 
 		// 400
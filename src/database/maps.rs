@@ -31,18 +31,23 @@ pub const MAPS: &[&str] = &[
 	"eventid_pduid",
 	"eventid_shorteventid",
 	"global",
+	"historypurgedroomids",
 	"id_appserviceregistrations",
 	"keychangeid_userid",
 	"keyid_key",
 	"lazyloadedids",
+	"mediaid_eventid",
 	"mediaid_file",
 	"mediaid_user",
+	"oidcsubject_userid",
 	"onetimekeyid_onetimekeys",
 	"pduid_pdu",
 	"presenceid_presence",
 	"publicroomids",
 	"readreceiptid_readreceipt",
 	"referencedevents",
+	"refreshtoken_userdeviceid",
+	"registrationtoken_info",
 	"roomid_invitedcount",
 	"roomid_inviteviaservers",
 	"roomid_joinedcount",
@@ -54,51 +59,68 @@ pub const MAPS: &[&str] = &[
 	"roomuserdataid_accountdata",
 	"roomuserid_invitecount",
 	"roomuserid_joined",
+	"roomuserid_knockedcount",
 	"roomuserid_lastprivatereadupdate",
 	"roomuserid_leftcount",
 	"roomuserid_privateread",
 	"roomuseroncejoinedids",
 	"roomusertype_roomuserdataid",
+	"searchpduids",
 	"senderkey_pusher",
 	"server_signingkeys",
 	"servercurrentevent_data",
 	"servername_educount",
 	"servernameevent_data",
 	"serverroomids",
+	"shadowbanneduserids",
 	"shorteventid_authchain",
 	"shorteventid_eventid",
 	"shorteventid_shortstatehash",
+	"shortroomid_searchcount",
+	"shortroomid_searchlanguage",
 	"shortstatehash_statediff",
 	"shortstatekey_statekey",
 	"softfailedeventids",
 	"statehash_shortstatehash",
 	"statekey_shortstatekey",
 	"threadid_userids",
+	"threepid_userid",
 	"todeviceid_events",
 	"tofrom_relation",
+	"token_expiresat",
 	"token_userdeviceid",
 	"tokenids",
 	"url_previews",
+	"userdeviceconnid_slidingsync",
 	"userdeviceid_metadata",
+	"userdeviceid_refreshtoken",
 	"userdeviceid_token",
+	"userdevicestream_todevicecount",
 	"userdevicesessionid_uiaainfo",
 	"userdevicetxnid_response",
 	"userfilterid_filter",
+	"userid_accountvalidity",
 	"userid_avatarurl",
 	"userid_blurhash",
 	"userid_devicelistversion",
 	"userid_displayname",
 	"userid_lastonetimekeyupdate",
+	"userid_lockedat",
 	"userid_masterkeyid",
+	"userid_noticeroomid",
 	"userid_password",
 	"userid_presenceid",
 	"userid_selfsigningkeyid",
+	"userid_suspendedat",
 	"userid_usersigningkeyid",
 	"useridprofilekey_value",
 	"openidtoken_expiresatuserid",
+	"userpushkeyeventid_queuedat",
 	"userroomid_highlightcount",
 	"userroomid_invitestate",
 	"userroomid_joined",
+	"userroomid_knockedstate",
 	"userroomid_leftstate",
 	"userroomid_notificationcount",
+	"userthreepid_metadata",
 ];
@@ -24,7 +24,7 @@ pub(crate) async fn run(services: Arc<Services>) -> Result<()> {
 	debug!("Start");
 
 	// Install the admin room callback here for now
-	admin::init(&services.admin).await;
+	admin::init(&services).await;
 
 	// Setup shutdown/signal handling
 	let handle = ServerHandle::new();
@@ -49,7 +49,7 @@ pub(crate) async fn run(services: Arc<Services>) -> Result<()> {
 	_ = sigs.await;
 
 	// Remove the admin room callback
-	admin::fini(&services.admin).await;
+	admin::fini(&services).await;
 
 	debug_info!("Finish");
 	res
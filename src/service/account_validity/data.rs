@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use conduit::{err, utils, Result};
+use database::Map;
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+use super::Validity;
+
+pub(super) struct Data {
+	userid_accountvalidity: Arc<Map>,
+	userid_noticeroomid: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(args: &crate::Args<'_>) -> Self {
+		let db = &args.db;
+		Self {
+			userid_accountvalidity: db["userid_accountvalidity"].clone(),
+			userid_noticeroomid: db["userid_noticeroomid"].clone(),
+		}
+	}
+
+	pub(super) fn get_validity(&self, user_id: &UserId) -> Result<Option<Validity>> {
+		self.userid_accountvalidity
+			.get(user_id.as_bytes())?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|e| err!(Database("Invalid validity in userid_accountvalidity. {e}")))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_validity(&self, user_id: &UserId, validity: &Validity) -> Result<()> {
+		self.userid_accountvalidity.insert(
+			user_id.as_bytes(),
+			&serde_json::to_vec(validity).expect("Validity::to_vec always works"),
+		)
+	}
+
+	pub(super) fn remove_validity(&self, user_id: &UserId) -> Result<()> {
+		self.userid_accountvalidity.remove(user_id.as_bytes())
+	}
+
+	pub(super) fn iter_validity<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, Validity)>> + 'a> {
+		Box::new(self.userid_accountvalidity.iter().map(|(key, value)| {
+			let user_id = utils::string_from_bytes(&key)
+				.ok()
+				.and_then(|user_id| UserId::parse(user_id).ok())
+				.ok_or_else(|| err!(Database("Invalid user ID in userid_accountvalidity.")))?;
+			let validity = serde_json::from_slice(&value)
+				.map_err(|e| err!(Database("Invalid validity in userid_accountvalidity. {e}")))?;
+
+			Ok((user_id, validity))
+		}))
+	}
+
+	pub(super) fn notice_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>> {
+		self.userid_noticeroomid
+			.get(user_id.as_bytes())?
+			.map(|bytes| {
+				utils::string_from_bytes(&bytes)
+					.ok()
+					.and_then(|room_id| RoomId::parse(room_id).ok())
+					.ok_or_else(|| err!(Database("Invalid room ID in userid_noticeroomid.")))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		self.userid_noticeroomid
+			.insert(user_id.as_bytes(), room_id.as_bytes())
+	}
+}
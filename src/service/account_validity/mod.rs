@@ -0,0 +1,301 @@
+mod data;
+mod notice;
+
+use std::{
+	future::Future,
+	pin::Pin,
+	sync::{Arc, RwLock, Weak},
+	time::{Duration, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use conduit::{
+	debug, err, info,
+	utils::{self, time},
+	warn, Err, Error, Result,
+};
+use data::Data;
+use http::StatusCode;
+use ruma::{thirdparty::Medium, OwnedUserId, UserId};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::interval};
+
+use crate::{globals, mailer, rooms, users, Dep};
+
+pub struct Service {
+	services: Services,
+	db: Data,
+	interrupt: Notify,
+	pub deactivate: RwLock<Option<Deactivator>>,
+}
+
+struct Services {
+	globals: Dep<globals::Service>,
+	mailer: Dep<mailer::Service>,
+	state: Dep<rooms::state::Service>,
+	state_cache: Dep<rooms::state_cache::Service>,
+	timeline: Dep<rooms::timeline::Service>,
+	users: Dep<users::Service>,
+	services: RwLock<Option<Weak<crate::Services>>>,
+}
+
+/// Prototype of the callback deactivating expired accounts with every step of
+/// a deactivation requested by their user. This is supplied by the reloadable
+/// admin module.
+pub type Deactivator = fn(Arc<crate::Services>, OwnedUserId) -> DeactivatorFuture;
+
+/// Return type of the deactivator
+pub type DeactivatorFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Errcode of requests by users whose account expired.
+const EXPIRED_ACCOUNT: &str = "ORG_MATRIX_EXPIRED_ACCOUNT";
+
+/// When an account expires and whether its user was reminded to renew it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Validity {
+	/// Milliseconds since the unix epoch
+	expires_at: u64,
+	reminded: bool,
+}
+
+/// How often accounts are checked for upcoming or passed expiry.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[async_trait]
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			services: Services {
+				globals: args.depend::<globals::Service>("globals"),
+				mailer: args.depend::<mailer::Service>("mailer"),
+				state: args.depend::<rooms::state::Service>("rooms::state"),
+				state_cache: args.depend::<rooms::state_cache::Service>("rooms::state_cache"),
+				timeline: args.depend::<rooms::timeline::Service>("rooms::timeline"),
+				users: args.depend::<users::Service>("users"),
+				services: None.into(),
+			},
+			db: Data::new(&args),
+			interrupt: Notify::new(),
+			deactivate: RwLock::new(None),
+		}))
+	}
+
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		let mut i = interval(CHECK_INTERVAL);
+		loop {
+			tokio::select! {
+				() = self.interrupt.notified() => return Ok(()),
+				_ = i.tick() => (),
+			}
+
+			if let Err(e) = self.check_accounts().await {
+				warn!(%e, "Failed to check account validity");
+			}
+		}
+	}
+
+	fn interrupt(&self) { self.interrupt.notify_waiters(); }
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Gives a newly registered account an expiry date if
+	/// `account_validity.period` is set.
+	pub fn start(&self, user_id: &UserId) -> Result<()> {
+		let Some(period) = self.services.globals.config.account_validity.period else {
+			return Ok(());
+		};
+
+		self.db.set_validity(
+			user_id,
+			&Validity {
+				expires_at: utils::millis_since_unix_epoch().saturating_add(period.saturating_mul(1000)),
+				reminded: false,
+			},
+		)
+	}
+
+	/// Returns when the account expires, in milliseconds since the unix epoch.
+	pub fn expires_at(&self, user_id: &UserId) -> Result<Option<u64>> {
+		Ok(self
+			.db
+			.get_validity(user_id)?
+			.map(|validity| validity.expires_at))
+	}
+
+	/// Whether the account expired and was not renewed.
+	pub fn is_expired(&self, user_id: &UserId) -> Result<bool> {
+		Ok(self
+			.expires_at(user_id)?
+			.is_some_and(|expires_at| expires_at <= utils::millis_since_unix_epoch()))
+	}
+
+	/// Errors with `ORG_MATRIX_EXPIRED_ACCOUNT` if the account expired and was
+	/// not renewed.
+	pub fn check(&self, user_id: &UserId) -> Result<()> {
+		if !self.is_expired(user_id)? {
+			return Ok(());
+		}
+
+		Err(Error::custom(
+			EXPIRED_ACCOUNT,
+			"This account has expired.",
+			StatusCode::FORBIDDEN,
+		))
+	}
+
+	/// Pushes the expiry of an account back by `duration`, or by
+	/// `account_validity.period` if not given. Expired accounts are extended
+	/// from now. Returns the new expiry.
+	pub fn extend(&self, user_id: &UserId, duration: Option<Duration>) -> Result<u64> {
+		let duration = match duration {
+			Some(duration) => u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+			None => match self.services.globals.config.account_validity.period {
+				Some(period) => period.saturating_mul(1000),
+				None => {
+					return Err!(Request(InvalidParam(
+						"A duration is required when account_validity.period is not configured."
+					)))
+				},
+			},
+		};
+
+		let now = utils::millis_since_unix_epoch();
+		let expires_at = self
+			.expires_at(user_id)?
+			.unwrap_or(now)
+			.max(now)
+			.saturating_add(duration);
+
+		self.db.set_validity(
+			user_id,
+			&Validity {
+				expires_at,
+				reminded: false,
+			},
+		)?;
+
+		Ok(expires_at)
+	}
+
+	/// Returns all accounts with an expiry date, soonest first.
+	pub fn list(&self) -> Result<Vec<(OwnedUserId, u64)>> {
+		let mut accounts = self
+			.db
+			.iter_validity()
+			.map(|result| result.map(|(user_id, validity)| (user_id, validity.expires_at)))
+			.collect::<Result<Vec<_>>>()?;
+
+		accounts.sort_by_key(|(_, expires_at)| *expires_at);
+
+		Ok(accounts)
+	}
+
+	/// Reminds users whose account expires within `account_validity.renew_at`
+	/// and deactivates expired accounts if configured to.
+	#[tracing::instrument(skip_all)]
+	async fn check_accounts(&self) -> Result<()> {
+		let config = &self.services.globals.config.account_validity;
+		let now = utils::millis_since_unix_epoch();
+		let renew_at = config.renew_at.saturating_mul(1000);
+
+		let accounts = self.db.iter_validity().collect::<Result<Vec<_>>>()?;
+		for (user_id, mut validity) in accounts {
+			if self.services.users.is_deactivated(&user_id)? {
+				self.db.remove_validity(&user_id)?;
+				continue;
+			}
+
+			if validity.expires_at <= now {
+				if config.deactivate_expired {
+					info!("Account {user_id} expired, deactivating it");
+					if let Err(e) = self.deactivate_expired(&user_id).await {
+						warn!(%e, "Failed to deactivate expired account {user_id}");
+						continue;
+					}
+
+					self.db.remove_validity(&user_id)?;
+				}
+
+				continue;
+			}
+
+			if !validity.reminded && validity.expires_at.saturating_sub(renew_at) <= now {
+				if let Err(e) = self.remind(&user_id, validity.expires_at).await {
+					warn!(%e, "Failed to remind {user_id} of their account expiry");
+					continue;
+				}
+
+				validity.reminded = true;
+				self.db.set_validity(&user_id, &validity)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn deactivate_expired(&self, user_id: &UserId) -> Result<()> {
+		let deactivate = self
+			.deactivate
+			.read()
+			.expect("locked")
+			.ok_or_else(|| err!("Admin module is not loaded"))?;
+
+		deactivate(self.services()?, user_id.to_owned()).await
+	}
+
+	async fn remind(&self, user_id: &UserId, expires_at: u64) -> Result<()> {
+		let date = time::format(UNIX_EPOCH + Duration::from_millis(expires_at), "%Y-%m-%d %H:%M UTC");
+		let consequence = if self
+			.services
+			.globals
+			.config
+			.account_validity
+			.deactivate_expired
+		{
+			"deactivated"
+		} else {
+			"locked"
+		};
+		let body = format!(
+			"Your account {user_id} expires on {date} and will then be {consequence}. Please contact the \
+			 administrators of {} to renew it.",
+			self.services.globals.server_name()
+		);
+
+		debug!("Reminding {user_id} that their account expires on {date}");
+		self.send_server_notice(user_id, &body).await?;
+
+		if self.services.mailer.enabled() {
+			for threepid in self.services.users.threepids(user_id) {
+				let threepid = threepid?;
+				if threepid.medium == Medium::Email {
+					self.services
+						.mailer
+						.send(&threepid.address, "Your account is about to expire", body.clone())
+						.await
+						.ok();
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn services(&self) -> Result<Arc<crate::Services>> {
+		self.services
+			.services
+			.read()
+			.expect("locked")
+			.as_ref()
+			.and_then(Weak::upgrade)
+			.ok_or_else(|| err!("Services self-reference not initialized."))
+	}
+
+	pub(super) fn set_services(&self, services: &Option<Arc<crate::Services>>) {
+		let receiver = &mut *self.services.services.write().expect("locked for writing");
+		let weak = services.as_ref().map(Arc::downgrade);
+		*receiver = weak;
+	}
+}
@@ -0,0 +1,98 @@
+use conduit::{pdu::PduBuilder, Result};
+use ruma::{
+	events::{
+		room::{
+			member::{MembershipState, RoomMemberEventContent},
+			message::RoomMessageEventContent,
+			name::RoomNameEventContent,
+		},
+		TimelineEventType,
+	},
+	OwnedRoomId, RoomId, UserId,
+};
+use serde_json::value::to_raw_value;
+
+use super::Service;
+use crate::admin;
+
+impl Service {
+	/// Sends a notice from the server user to a local user, in a direct room
+	/// which is created the first time or when the user left the previous one.
+	pub(super) async fn send_server_notice(&self, user_id: &UserId, body: &str) -> Result<()> {
+		let room_id = match self.db.notice_room(user_id)? {
+			Some(room_id)
+				if self.services.state_cache.is_joined(user_id, &room_id)?
+					|| self.services.state_cache.is_invited(user_id, &room_id)? =>
+			{
+				room_id
+			},
+			_ => self.create_notice_room(user_id).await?,
+		};
+
+		let state_lock = self.services.state.mutex.lock(&room_id).await;
+		self.services
+			.timeline
+			.build_and_append_pdu(
+				PduBuilder {
+					event_type: TimelineEventType::RoomMessage,
+					content: to_raw_value(&RoomMessageEventContent::notice_plain(body))
+						.expect("event is valid, we just created it"),
+					unsigned: None,
+					state_key: None,
+					redacts: None,
+					timestamp: None,
+				},
+				&self.services.globals.server_user,
+				&room_id,
+				&state_lock,
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn create_notice_room(&self, user_id: &UserId) -> Result<OwnedRoomId> {
+		let room_id = RoomId::new(self.services.globals.server_name());
+		let state_lock = admin::create_server_room(&*self.services()?, &room_id, false).await?;
+
+		let mut invite_content = RoomMemberEventContent::new(MembershipState::Invite);
+		invite_content.is_direct = Some(true);
+
+		let events = [
+			(
+				TimelineEventType::RoomName,
+				to_raw_value(&RoomNameEventContent::new("Server Notices".to_owned())),
+				String::new(),
+			),
+			(
+				TimelineEventType::RoomMember,
+				to_raw_value(&invite_content),
+				user_id.to_string(),
+			),
+		];
+
+		let server_user = &self.services.globals.server_user;
+		for (event_type, content, state_key) in events {
+			self.services
+				.timeline
+				.build_and_append_pdu(
+					PduBuilder {
+						event_type,
+						content: content.expect("event is valid, we just created it"),
+						unsigned: None,
+						state_key: Some(state_key),
+						redacts: None,
+						timestamp: None,
+					},
+					server_user,
+					&room_id,
+					&state_lock,
+				)
+				.await?;
+		}
+
+		self.db.set_notice_room(user_id, &room_id)?;
+
+		Ok(room_id)
+	}
+}
@@ -21,7 +21,7 @@ use ruma::{
 };
 use serde_json::value::to_raw_value;
 
-use crate::Services;
+use crate::{rooms::state::RoomMutexGuard, Services};
 
 /// Create the admin room.
 ///
@@ -30,36 +30,21 @@ use crate::Services;
 pub async fn create_admin_room(services: &Services) -> Result<()> {
 	let room_id = RoomId::new(services.globals.server_name());
 
-	let _short_id = services.rooms.short.get_or_create_shortroomid(&room_id)?;
-
-	let state_lock = services.rooms.state.mutex.lock(&room_id).await;
-
 	// Create a user for the server
 	let server_user = &services.globals.server_user;
 	services.users.create(server_user, None)?;
 
-	let room_version = services.globals.default_room_version();
-
-	let mut content = {
-		use RoomVersionId::*;
-		match room_version {
-			V1 | V2 | V3 | V4 | V5 | V6 | V7 | V8 | V9 | V10 => RoomCreateEventContent::new_v1(server_user.clone()),
-			_ => RoomCreateEventContent::new_v11(),
-		}
-	};
-
-	content.federate = true;
-	content.predecessor = None;
-	content.room_version = room_version;
+	let state_lock = create_server_room(services, &room_id, true).await?;
 
-	// 1. The room create event
+	// 4.3 Guest Access
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomCreate,
-				content: to_raw_value(&content).expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomGuestAccess,
+				content: to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
+					.expect("event is valid, we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
@@ -71,26 +56,18 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 		)
 		.await?;
 
-	// 2. Make conduit bot join
+	// 5. Events implied by name and topic
+	let room_name = format!("{} Admin Room", services.globals.server_name());
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomMember,
-				content: to_raw_value(&RoomMemberEventContent {
-					membership: MembershipState::Join,
-					displayname: None,
-					avatar_url: None,
-					is_direct: None,
-					third_party_invite: None,
-					blurhash: None,
-					reason: None,
-					join_authorized_via_users_server: None,
-				})
-				.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomName,
+				content: to_raw_value(&RoomNameEventContent::new(room_name))
+					.expect("event is valid, we just created it"),
 				unsigned: None,
-				state_key: Some(server_user.to_string()),
+				state_key: Some(String::new()),
 				redacts: None,
 				timestamp: None,
 			},
@@ -100,18 +77,14 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 		)
 		.await?;
 
-	// 3. Power levels
-	let users = BTreeMap::from_iter([(server_user.clone(), 100.into())]);
-
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomPowerLevels,
-				content: to_raw_value(&RoomPowerLevelsEventContent {
-					users,
-					..Default::default()
+				event_type: TimelineEventType::RoomTopic,
+				content: to_raw_value(&RoomTopicEventContent {
+					topic: format!("Manage {}", services.globals.server_name()),
 				})
 				.expect("event is valid, we just created it"),
 				unsigned: None,
@@ -125,15 +98,20 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 		)
 		.await?;
 
-	// 4.1 Join Rules
+	// 6. Room alias
+	let alias = &services.globals.admin_alias;
+
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomJoinRules,
-				content: to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite))
-					.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomCanonicalAlias,
+				content: to_raw_value(&RoomCanonicalAliasEventContent {
+					alias: Some(alias.clone()),
+					alt_aliases: Vec::new(),
+				})
+				.expect("event is valid, we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
@@ -145,15 +123,22 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 		)
 		.await?;
 
-	// 4.2 History Visibility
+	services
+		.rooms
+		.alias
+		.set_alias(alias, &room_id, server_user)?;
+
+	// 7. (ad-hoc) Disable room previews for everyone by default
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomHistoryVisibility,
-				content: to_raw_value(&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared))
-					.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomPreviewUrls,
+				content: to_raw_value(&RoomPreviewUrlsEventContent {
+					disabled: true,
+				})
+				.expect("event is valid we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
@@ -165,55 +150,93 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 		)
 		.await?;
 
-	// 4.3 Guest Access
+	Ok(())
+}
+
+/// Creates a room of the server user which only invited users can join and
+/// whose history is shared with members. Returns the lock on the room state for
+/// sending the remaining initial events.
+pub async fn create_server_room(services: &Services, room_id: &RoomId, federate: bool) -> Result<RoomMutexGuard> {
+	let _short_id = services.rooms.short.get_or_create_shortroomid(room_id)?;
+
+	let state_lock = services.rooms.state.mutex.lock(room_id).await;
+
+	let server_user = &services.globals.server_user;
+
+	let room_version = services.globals.default_room_version();
+
+	let mut content = {
+		use RoomVersionId::*;
+		match room_version {
+			V1 | V2 | V3 | V4 | V5 | V6 | V7 | V8 | V9 | V10 => RoomCreateEventContent::new_v1(server_user.clone()),
+			_ => RoomCreateEventContent::new_v11(),
+		}
+	};
+
+	content.federate = federate;
+	content.predecessor = None;
+	content.room_version = room_version;
+
+	// 1. The room create event
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomGuestAccess,
-				content: to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
-					.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomCreate,
+				content: to_raw_value(&content).expect("event is valid, we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
 				timestamp: None,
 			},
 			server_user,
-			&room_id,
+			room_id,
 			&state_lock,
 		)
 		.await?;
 
-	// 5. Events implied by name and topic
-	let room_name = format!("{} Admin Room", services.globals.server_name());
+	// 2. Make conduit bot join
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomName,
-				content: to_raw_value(&RoomNameEventContent::new(room_name))
-					.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomMember,
+				content: to_raw_value(&RoomMemberEventContent {
+					membership: MembershipState::Join,
+					displayname: None,
+					avatar_url: None,
+					is_direct: None,
+					third_party_invite: None,
+					blurhash: None,
+					reason: None,
+					join_authorized_via_users_server: None,
+				})
+				.expect("event is valid, we just created it"),
 				unsigned: None,
-				state_key: Some(String::new()),
+				state_key: Some(server_user.to_string()),
 				redacts: None,
 				timestamp: None,
 			},
 			server_user,
-			&room_id,
+			room_id,
 			&state_lock,
 		)
 		.await?;
 
+	// 3. Power levels
+	let users = BTreeMap::from_iter([(server_user.clone(), 100.into())]);
+
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomTopic,
-				content: to_raw_value(&RoomTopicEventContent {
-					topic: format!("Manage {}", services.globals.server_name()),
+				event_type: TimelineEventType::RoomPowerLevels,
+				content: to_raw_value(&RoomPowerLevelsEventContent {
+					users,
+					..Default::default()
 				})
 				.expect("event is valid, we just created it"),
 				unsigned: None,
@@ -222,62 +245,50 @@ pub async fn create_admin_room(services: &Services) -> Result<()> {
 				timestamp: None,
 			},
 			server_user,
-			&room_id,
+			room_id,
 			&state_lock,
 		)
 		.await?;
 
-	// 6. Room alias
-	let alias = &services.globals.admin_alias;
-
+	// 4.1 Join Rules
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomCanonicalAlias,
-				content: to_raw_value(&RoomCanonicalAliasEventContent {
-					alias: Some(alias.clone()),
-					alt_aliases: Vec::new(),
-				})
-				.expect("event is valid, we just created it"),
+				event_type: TimelineEventType::RoomJoinRules,
+				content: to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite))
+					.expect("event is valid, we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
 				timestamp: None,
 			},
 			server_user,
-			&room_id,
+			room_id,
 			&state_lock,
 		)
 		.await?;
 
-	services
-		.rooms
-		.alias
-		.set_alias(alias, &room_id, server_user)?;
-
-	// 7. (ad-hoc) Disable room previews for everyone by default
+	// 4.2 History Visibility
 	services
 		.rooms
 		.timeline
 		.build_and_append_pdu(
 			PduBuilder {
-				event_type: TimelineEventType::RoomPreviewUrls,
-				content: to_raw_value(&RoomPreviewUrlsEventContent {
-					disabled: true,
-				})
-				.expect("event is valid we just created it"),
+				event_type: TimelineEventType::RoomHistoryVisibility,
+				content: to_raw_value(&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared))
+					.expect("event is valid, we just created it"),
 				unsigned: None,
 				state_key: Some(String::new()),
 				redacts: None,
 				timestamp: None,
 			},
 			server_user,
-			&room_id,
+			room_id,
 			&state_lock,
 		)
 		.await?;
 
-	Ok(())
+	Ok(state_lock)
 }
@@ -11,7 +11,7 @@ use std::{
 
 use async_trait::async_trait;
 use conduit::{debug, err, error, error::default_log, pdu::PduBuilder, Error, PduEvent, Result, Server};
-pub use create::create_admin_room;
+pub use create::{create_admin_room, create_server_room};
 use loole::{Receiver, Sender};
 use ruma::{
 	events::{
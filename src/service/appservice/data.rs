@@ -3,6 +3,9 @@ use std::sync::Arc;
 use conduit::{utils, Error, Result};
 use database::{Database, Map};
 use ruma::api::appservice::Registration;
+use serde::Serialize;
+
+use super::RegistrationExtensions;
 
 pub struct Data {
 	id_appserviceregistrations: Arc<Map>,
@@ -16,10 +19,26 @@ impl Data {
 	}
 
 	/// Registers an appservice and returns the ID to the caller
-	pub(super) fn register_appservice(&self, yaml: &Registration) -> Result<String> {
+	pub(super) fn register_appservice(
+		&self, yaml: &Registration, extensions: &RegistrationExtensions,
+	) -> Result<String> {
+		/// The extensions are stored next to the keys ruma knows about, as
+		/// they were in the registration file.
+		#[derive(Serialize)]
+		struct Stored<'a> {
+			#[serde(flatten)]
+			registration: &'a Registration,
+			#[serde(flatten)]
+			extensions: &'a RegistrationExtensions,
+		}
+
 		let id = yaml.id.as_str();
+		let stored = Stored {
+			registration: yaml,
+			extensions,
+		};
 		self.id_appserviceregistrations
-			.insert(id.as_bytes(), serde_yaml::to_string(&yaml).unwrap().as_bytes())?;
+			.insert(id.as_bytes(), serde_yaml::to_string(&stored).unwrap().as_bytes())?;
 
 		Ok(id.to_owned())
 	}
@@ -45,6 +64,15 @@ impl Data {
 			.transpose()
 	}
 
+	pub fn get_extensions(&self, id: &str) -> Result<RegistrationExtensions> {
+		self.id_appserviceregistrations
+			.get(id.as_bytes())?
+			.map_or(Ok(RegistrationExtensions::default()), |bytes| {
+				serde_yaml::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Invalid registration bytes in id_appserviceregistrations."))
+			})
+	}
+
 	pub(super) fn iter_ids<'a>(&'a self) -> Result<Box<dyn Iterator<Item = Result<String>> + 'a>> {
 		Ok(Box::new(self.id_appserviceregistrations.iter().map(|(id, _)| {
 			utils::string_from_bytes(&id)
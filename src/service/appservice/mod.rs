@@ -1,6 +1,10 @@
 mod data;
+mod thirdparty;
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, RwLock as StdRwLock},
+};
 
 use conduit::{err, Result};
 use data::Data;
@@ -10,6 +14,7 @@ use ruma::{
 	api::appservice::{Namespace, Registration},
 	RoomAliasId, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::{sending, Dep};
@@ -94,10 +99,20 @@ impl TryFrom<Vec<Namespace>> for NamespaceRegex {
 	}
 }
 
+/// Keys of the registration for unstable features ruma does not know about.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RegistrationExtensions {
+	/// Whether the appservice receives device list changes and one-time key
+	/// counts (MSC3202)
+	#[serde(default, rename = "org.matrix.msc3202", skip_serializing_if = "std::ops::Not::not")]
+	pub msc3202: bool,
+}
+
 /// Appservice registration combined with its compiled regular expressions.
 #[derive(Clone, Debug)]
 pub struct RegistrationInfo {
 	pub registration: Registration,
+	pub extensions: RegistrationExtensions,
 	pub users: NamespaceRegex,
 	pub aliases: NamespaceRegex,
 	pub rooms: NamespaceRegex,
@@ -112,6 +127,7 @@ impl TryFrom<Registration> for RegistrationInfo {
 			aliases: value.namespaces.aliases.clone().try_into()?,
 			rooms: value.namespaces.rooms.clone().try_into()?,
 			registration: value,
+			extensions: RegistrationExtensions::default(),
 		})
 	}
 }
@@ -120,6 +136,34 @@ pub struct Service {
 	pub db: Data,
 	services: Services,
 	registration_info: RwLock<BTreeMap<String, RegistrationInfo>>,
+	edu_receivers: StdRwLock<EduReceivers>,
+}
+
+/// IDs of the appservices which opted into EDUs, derived from
+/// `registration_info` for synchronous code.
+#[derive(Default)]
+struct EduReceivers {
+	/// Ephemeral events and to-device messages (MSC2409)
+	ephemeral: Vec<String>,
+	/// Device list changes and one-time key counts (MSC3202)
+	msc3202: Vec<String>,
+}
+
+impl From<&BTreeMap<String, RegistrationInfo>> for EduReceivers {
+	fn from(registration_info: &BTreeMap<String, RegistrationInfo>) -> Self {
+		let receivers = |receives: fn(&RegistrationInfo) -> bool| -> Vec<String> {
+			registration_info
+				.iter()
+				.filter(|(_, info)| receives(info))
+				.map(|(id, _)| id.clone())
+				.collect()
+		};
+
+		Self {
+			ephemeral: receivers(|info| info.registration.receive_ephemeral),
+			msc3202: receivers(|info| info.extensions.msc3202),
+		}
+	}
 }
 
 struct Services {
@@ -131,14 +175,12 @@ impl crate::Service for Service {
 		let mut registration_info = BTreeMap::new();
 		let db = Data::new(args.db);
 		// Inserting registrations into cache
-		for appservice in iter_ids(&db)? {
-			registration_info.insert(
-				appservice.0,
-				appservice
-					.1
-					.try_into()
-					.expect("Should be validated on registration"),
-			);
+		for (id, registration) in iter_ids(&db)? {
+			let mut info: RegistrationInfo = registration
+				.try_into()
+				.expect("Should be validated on registration");
+			info.extensions = db.get_extensions(&id)?;
+			registration_info.insert(id, info);
 		}
 
 		Ok(Arc::new(Self {
@@ -146,6 +188,7 @@ impl crate::Service for Service {
 			services: Services {
 				sending: args.depend::<sending::Service>("sending"),
 			},
+			edu_receivers: StdRwLock::new((&registration_info).into()),
 			registration_info: RwLock::new(registration_info),
 		}))
 	}
@@ -158,14 +201,28 @@ impl Service {
 	pub fn all(&self) -> Result<Vec<(String, Registration)>> { iter_ids(&self.db) }
 
 	/// Registers an appservice and returns the ID to the caller
-	pub async fn register_appservice(&self, yaml: Registration) -> Result<String> {
+	pub async fn register_appservice(&self, yaml: Registration, extensions: RegistrationExtensions) -> Result<String> {
 		//TODO: Check for collisions between exclusive appservice namespaces
-		self.registration_info
-			.write()
-			.await
-			.insert(yaml.id.clone(), yaml.clone().try_into()?);
+		let mut info: RegistrationInfo = yaml.clone().try_into()?;
+		info.extensions = extensions.clone();
+		let mut registration_info = self.registration_info.write().await;
+		registration_info.insert(yaml.id.clone(), info);
+		*self.edu_receivers.write().expect("locked for writing") = (&*registration_info).into();
+		drop(registration_info);
+
+		self.db.register_appservice(&yaml, &extensions)
+	}
 
-		self.db.register_appservice(&yaml)
+	/// Returns the IDs of the appservices which receive ephemeral events and
+	/// to-device messages (MSC2409), or device list changes and one-time key
+	/// counts (MSC3202). Does not wait, so it can be used by synchronous code.
+	pub fn edu_receivers(&self, msc3202: bool) -> Vec<String> {
+		let receivers = self.edu_receivers.read().expect("locked");
+		if msc3202 {
+			receivers.msc3202.clone()
+		} else {
+			receivers.ephemeral.clone()
+		}
 	}
 
 	/// Remove an appservice registration
@@ -175,11 +232,12 @@ impl Service {
 	/// * `service_name` - the name you send to register the service previously
 	pub async fn unregister_appservice(&self, service_name: &str) -> Result<()> {
 		// removes the appservice registration info
-		self.registration_info
-			.write()
-			.await
+		let mut registration_info = self.registration_info.write().await;
+		registration_info
 			.remove(service_name)
 			.ok_or(err!("Appservice not found"))?;
+		*self.edu_receivers.write().expect("locked for writing") = (&*registration_info).into();
+		drop(registration_info);
 
 		// remove the appservice from the database
 		self.db.unregister_appservice(service_name)?;
@@ -0,0 +1,239 @@
+use std::{
+	collections::{btree_map::Entry, BTreeMap},
+	fmt::Debug,
+};
+
+use conduit::debug_warn;
+use futures_util::future::join_all;
+use ruma::{
+	api::{
+		appservice::{
+			thirdparty::{
+				get_location_for_protocol, get_location_for_room_alias, get_protocol, get_user_for_protocol,
+				get_user_for_user_id,
+			},
+			Registration,
+		},
+		OutgoingRequest,
+	},
+	thirdparty::{Location, Protocol, User},
+	RoomAliasId, UserId,
+};
+
+use super::Service;
+
+impl Service {
+	/// Returns the registrations of all appservices which handle the
+	/// third-party protocol.
+	pub async fn protocol_handlers(&self, protocol: &str) -> Vec<Registration> {
+		self.read()
+			.await
+			.values()
+			.filter(|info| {
+				info.registration
+					.protocols
+					.as_ref()
+					.is_some_and(|protocols| protocols.iter().any(|p| p == protocol))
+			})
+			.map(|info| info.registration.clone())
+			.collect()
+	}
+
+	/// Fetches the metadata of every third-party protocol of every appservice.
+	/// Instances of a protocol handled by several appservices are merged.
+	pub async fn query_protocols(&self) -> BTreeMap<String, Protocol> {
+		let handlers: Vec<_> = self
+			.read()
+			.await
+			.values()
+			.flat_map(|info| {
+				let protocols = info.registration.protocols.clone().unwrap_or_default();
+				protocols
+					.into_iter()
+					.map(move |protocol| (protocol, info.registration.clone()))
+			})
+			.collect();
+
+		let requests = handlers.iter().map(|(protocol, registration)| {
+			let request = get_protocol::v1::Request {
+				protocol: protocol.clone(),
+			};
+
+			(registration.clone(), request)
+		});
+
+		let responses = self
+			.send_all(requests, "return the metadata of its protocols")
+			.await;
+
+		let mut protocols = BTreeMap::new();
+		for ((protocol, _), response) in handlers.into_iter().zip(responses) {
+			let Some(response) = response else {
+				continue;
+			};
+
+			match protocols.entry(protocol) {
+				Entry::Vacant(entry) => {
+					entry.insert(response.protocol);
+				},
+				Entry::Occupied(mut entry) => entry
+					.get_mut()
+					.instances
+					.extend(response.protocol.instances),
+			}
+		}
+
+		protocols
+	}
+
+	/// Fetches the metadata of a third-party protocol from the appservices
+	/// handling it. Returns `None` if none of them do.
+	pub async fn query_protocol(&self, protocol: &str) -> Option<Protocol> {
+		let requests = self
+			.protocol_handlers(protocol)
+			.await
+			.into_iter()
+			.map(|registration| {
+				let request = get_protocol::v1::Request {
+					protocol: protocol.to_owned(),
+				};
+
+				(registration, request)
+			});
+
+		self.send_all(requests, &format!("return the metadata of protocol {protocol}"))
+			.await
+			.into_iter()
+			.flatten()
+			.map(|response| response.protocol)
+			.reduce(|mut merged, metadata| {
+				merged.instances.extend(metadata.instances);
+				merged
+			})
+	}
+
+	/// Looks up third-party locations of the protocol matching the fields.
+	pub async fn query_locations(&self, protocol: &str, fields: &BTreeMap<String, String>) -> Vec<Location> {
+		let requests = self
+			.protocol_handlers(protocol)
+			.await
+			.into_iter()
+			.map(|registration| {
+				let request = get_location_for_protocol::v1::Request {
+					protocol: protocol.to_owned(),
+					fields: fields.clone(),
+				};
+
+				(registration, request)
+			});
+
+		self.send_all(requests, &format!("look up {protocol} locations"))
+			.await
+			.into_iter()
+			.flatten()
+			.flat_map(|response| response.locations)
+			.collect()
+	}
+
+	/// Looks up third-party locations bridged to the room alias, asking the
+	/// appservices whose namespace includes the alias.
+	pub async fn query_locations_for_alias(&self, alias: &RoomAliasId) -> Vec<Location> {
+		let registrations: Vec<_> = self
+			.read()
+			.await
+			.values()
+			.filter(|info| info.aliases.is_match(alias.as_str()))
+			.map(|info| info.registration.clone())
+			.collect();
+
+		let requests = registrations.into_iter().map(|registration| {
+			let request = get_location_for_room_alias::v1::Request {
+				alias: alias.to_owned(),
+			};
+
+			(registration, request)
+		});
+
+		self.send_all(requests, &format!("look up locations for {alias}"))
+			.await
+			.into_iter()
+			.flatten()
+			.flat_map(|response| response.locations)
+			.collect()
+	}
+
+	/// Looks up third-party users of the protocol matching the fields.
+	pub async fn query_users(&self, protocol: &str, fields: &BTreeMap<String, String>) -> Vec<User> {
+		let requests = self
+			.protocol_handlers(protocol)
+			.await
+			.into_iter()
+			.map(|registration| {
+				let request = get_user_for_protocol::v1::Request {
+					protocol: protocol.to_owned(),
+					fields: fields.clone(),
+				};
+
+				(registration, request)
+			});
+
+		self.send_all(requests, &format!("look up {protocol} users"))
+			.await
+			.into_iter()
+			.flatten()
+			.flat_map(|response| response.users)
+			.collect()
+	}
+
+	/// Looks up third-party users bridged to the Matrix user, asking the
+	/// appservices whose namespace includes the user.
+	pub async fn query_users_for_user_id(&self, user_id: &UserId) -> Vec<User> {
+		let registrations: Vec<_> = self
+			.read()
+			.await
+			.values()
+			.filter(|info| info.is_user_match(user_id))
+			.map(|info| info.registration.clone())
+			.collect();
+
+		let requests = registrations.into_iter().map(|registration| {
+			let request = get_user_for_user_id::v1::Request {
+				userid: user_id.to_owned(),
+			};
+
+			(registration, request)
+		});
+
+		self.send_all(requests, &format!("look up users for {user_id}"))
+			.await
+			.into_iter()
+			.flatten()
+			.flat_map(|response| response.users)
+			.collect()
+	}
+
+	/// Sends every request to its appservice at once. Returns the responses in
+	/// the order of the requests, `None` for appservices which have no URL or
+	/// failed to `action`.
+	async fn send_all<T, I>(&self, requests: I, action: &str) -> Vec<Option<T::IncomingResponse>>
+	where
+		T: OutgoingRequest + Debug + Send,
+		I: IntoIterator<Item = (Registration, T)>,
+	{
+		let sending = &self.services.sending;
+		let requests = requests
+			.into_iter()
+			.map(|(registration, request)| async move {
+				let id = registration.id.clone();
+				sending
+					.send_appservice_request(registration, request)
+					.await
+					.unwrap_or_else(|e| {
+						debug_warn!("Appservice {id} failed to {action}: {e}");
+						None
+					})
+			});
+
+		join_all(requests).await
+	}
+}
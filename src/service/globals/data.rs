@@ -19,6 +19,7 @@ pub struct Data {
 	todeviceid_events: Arc<Map>,
 	userroomid_joined: Arc<Map>,
 	userroomid_invitestate: Arc<Map>,
+	userroomid_knockedstate: Arc<Map>,
 	userroomid_leftstate: Arc<Map>,
 	userroomid_notificationcount: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
@@ -50,6 +51,7 @@ impl Data {
 			todeviceid_events: db["todeviceid_events"].clone(),
 			userroomid_joined: db["userroomid_joined"].clone(),
 			userroomid_invitestate: db["userroomid_invitestate"].clone(),
+			userroomid_knockedstate: db["userroomid_knockedstate"].clone(),
 			userroomid_leftstate: db["userroomid_leftstate"].clone(),
 			userroomid_notificationcount: db["userroomid_notificationcount"].clone(),
 			userroomid_highlightcount: db["userroomid_highlightcount"].clone(),
@@ -125,6 +127,7 @@ impl Data {
 
 		futures.push(self.userroomid_joined.watch_prefix(&userid_prefix));
 		futures.push(self.userroomid_invitestate.watch_prefix(&userid_prefix));
+		futures.push(self.userroomid_knockedstate.watch_prefix(&userid_prefix));
 		futures.push(self.userroomid_leftstate.watch_prefix(&userid_prefix));
 		futures.push(
 			self.userroomid_notificationcount
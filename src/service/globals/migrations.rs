@@ -1,17 +1,18 @@
 use std::{
-	collections::{HashMap, HashSet},
+	borrow::Cow,
+	collections::{BTreeMap, HashMap, HashSet},
 	fs::{self},
 	io::Write,
 	mem::size_of,
 	sync::Arc,
 };
 
-use conduit::{debug, debug_info, debug_warn, error, info, utils, warn, Error, Result};
+use conduit::{debug, debug_info, debug_warn, err, error, info, utils, warn, Error, Result};
 use itertools::Itertools;
 use ruma::{
 	events::{push_rules::PushRulesEvent, room::member::MembershipState, GlobalAccountDataEventType},
 	push::Ruleset,
-	EventId, OwnedRoomId, RoomId, UserId,
+	CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedRoomId, RoomId, UserId,
 };
 
 use crate::{media, Services};
@@ -65,6 +66,10 @@ async fn fresh(services: &Services) -> Result<()> {
 	db["global"].insert(b"feat_sha256_media", &[])?;
 	db["global"].insert(b"fix_bad_double_separator_in_state_cache", &[])?;
 	db["global"].insert(b"retroactively_fix_bad_data_from_roomuserid_joined", &[])?;
+	db["global"].insert(b"feat_sync_streams", &[])?;
+	db["global"].insert(b"feat_search_document_index", &[])?;
+	db["global"].insert(b"feat_sync_connection_deltas", &[])?;
+	db["global"].insert(b"feat_media_references", &[])?;
 
 	// Create the admin room and server user on first run
 	crate::admin::create_admin_room(services).await?;
@@ -156,6 +161,22 @@ async fn migrate(services: &Services) -> Result<()> {
 		retroactively_fix_bad_data_from_roomuserid_joined(services).await?;
 	}
 
+	if db["global"].get(b"feat_sync_streams")?.is_none() {
+		migrate_lazy_loading_to_sync_streams(services).await?;
+	}
+
+	if db["global"].get(b"feat_search_document_index")?.is_none() {
+		reindex_search(services).await?;
+	}
+
+	if db["global"].get(b"feat_sync_connection_deltas")?.is_none() {
+		migrate_sync_connections(services).await?;
+	}
+
+	if db["global"].get(b"feat_media_references")?.is_none() {
+		index_media_references(services).await?;
+	}
+
 	let version_match = services.globals.db.database_version().unwrap() == DATABASE_VERSION
 		|| services.globals.db.database_version().unwrap() == CONDUIT_DATABASE_VERSION;
 
@@ -862,3 +883,353 @@ async fn retroactively_fix_bad_data_from_roomuserid_joined(services: &Services)
 	info!("Finished fixing");
 	Ok(())
 }
+
+async fn migrate_lazy_loading_to_sync_streams(services: &Services) -> Result<()> {
+	warn!("Moving lazy-loaded members and pending to-device events to the default sync stream of each device");
+
+	let db = &services.db;
+	let lazyloadedids = &db["lazyloadedids"];
+	let _cork = db.cork_and_sync();
+
+	// Collected first as the rewritten keys sort after the original ones. Keys
+	// which already have a stream are skipped, so an interrupted migration can
+	// run again.
+	let mut invalid = Vec::new();
+	let mut moved = Vec::new();
+	for (key, value) in lazyloadedids.iter() {
+		match lazy_loading_key_with_stream(&key) {
+			None => invalid.push(key),
+			Some(Cow::Owned(new_key)) => moved.push((key, new_key, value)),
+			Some(Cow::Borrowed(_)) => {},
+		}
+	}
+
+	for key in &invalid {
+		debug_warn!("Removing invalid key: {key:?}");
+	}
+
+	lazyloadedids.remove_batch(invalid.iter().map(Vec::as_slice))?;
+
+	// Written before the original keys are removed, so nothing is lost when
+	// interrupted in between.
+	for chunk in moved.chunks(1000) {
+		lazyloadedids.insert_batch(
+			chunk
+				.iter()
+				.map(|(_, new_key, value)| (new_key.as_slice(), value.as_slice()))
+				.map(database::KeyVal::from),
+		)?;
+		lazyloadedids.remove_batch(chunk.iter().map(|(key, ..)| key.as_slice()))?;
+		debug_info!(moved = chunk.len());
+	}
+
+	// Pending to-device events were never acknowledged by the default stream;
+	// registering it keeps other streams from deleting them.
+	let todevicecount = &db["userdevicestream_todevicecount"];
+	let now = utils::millis_since_unix_epoch();
+	let mut value = 0_u64.to_be_bytes().to_vec();
+	value.extend_from_slice(&now.to_be_bytes());
+	for (key, _) in db["todeviceid_events"].iter() {
+		// user_id 0xFF device_id 0xFF count; the default stream key is the prefix
+		// before the count.
+		let Some(streamkey) = key
+			.len()
+			.checked_sub(size_of::<u64>())
+			.and_then(|len| key.get(..len))
+		else {
+			continue;
+		};
+
+		if todevicecount.get(streamkey)?.is_none() {
+			todevicecount.insert(streamkey, &value)?;
+		}
+	}
+
+	db.db.cleanup()?;
+	db["global"].insert(b"feat_sync_streams", &[])?;
+
+	info!("Finished moving {} lazy-loaded members to sync streams", moved.len());
+	Ok(())
+}
+
+/// Moves a key of `lazyloadedids` to the default sync stream: user_id 0xFF
+/// device_id 0xFF room_id 0xFF user_id becomes user_id 0xFF device_id 0xFF
+/// stream 0xFF room_id 0xFF user_id, the default stream being empty. Keys
+/// which already have a stream are returned as is, invalid keys as None.
+fn lazy_loading_key_with_stream(key: &[u8]) -> Option<Cow<'_, [u8]>> {
+	let separators: Vec<usize> = key
+		.iter()
+		.enumerate()
+		.filter(|&(_, &byte)| byte == 0xFF)
+		.map(|(index, _)| index)
+		.collect();
+
+	match separators.len() {
+		3 => {
+			let mut new_key = key.to_vec();
+			new_key.insert(separators[1], 0xFF);
+			Some(Cow::Owned(new_key))
+		},
+		4 => Some(Cow::Borrowed(key)),
+		_ => None,
+	}
+}
+
+/// Events indexed before the search index kept track of them are neither
+/// counted nor have their timestamp and language recorded, and their tokens
+/// come from an older tokenizer; reindexing every room in the background
+/// rebuilds them.
+async fn reindex_search(services: &Services) -> Result<()> {
+	warn!("Queuing every room to have its search index rebuilt");
+
+	let rooms: Vec<OwnedRoomId> = services
+		.rooms
+		.metadata
+		.iter_ids()
+		.filter_map(Result::ok)
+		.collect();
+
+	let job = services.rooms.search.reindex(rooms).await?;
+
+	services.db["global"].insert(b"feat_search_document_index", &[])?;
+
+	info!("Queued {} rooms for reindexing", job.rooms_total);
+	Ok(())
+}
+
+/// Sync connections were first stored as a single JSON object under
+/// user_id 0xFF device_id 0xFF conn_id, then under the same key followed by
+/// 0xFF and the sync version. Both are split into the time the connection was
+/// last used, its sticky parameters and one key per known room, which are
+/// written separately as they change.
+async fn migrate_sync_connections(services: &Services) -> Result<()> {
+	warn!("Splitting stored sliding sync connections");
+
+	let db = &services.db;
+	let slidingsync = &db["userdeviceconnid_slidingsync"];
+	let _cork = db.cork_and_sync();
+
+	let mut invalid = Vec::new();
+	let mut moved = 0_usize;
+	for (key, value) in slidingsync.iter() {
+		let Some(entries) = split_sync_connection(&key, &value) else {
+			continue;
+		};
+
+		let Ok(entries) = entries else {
+			invalid.push(key);
+			continue;
+		};
+
+		slidingsync.insert_batch(entries.iter().map(database::KeyVal::from))?;
+		if entries.iter().all(|(new_key, _)| *new_key != key) {
+			slidingsync.remove(&key)?;
+		}
+
+		moved = moved.saturating_add(1);
+	}
+
+	for key in &invalid {
+		debug_warn!("Removing invalid key: {key:?}");
+	}
+
+	slidingsync.remove_batch(invalid.iter().map(Vec::as_slice))?;
+
+	db.db.cleanup()?;
+	db["global"].insert(b"feat_sync_connection_deltas", &[])?;
+
+	info!("Finished splitting {moved} sliding sync connections");
+	Ok(())
+}
+
+/// Expired media is only deleted when no event recorded to refer to it still
+/// does, so the events stored before are recorded too.
+async fn index_media_references(services: &Services) -> Result<()> {
+	warn!("Indexing the media referenced by stored events");
+
+	let db = &services.db;
+	let _cork = db.cork_and_sync();
+
+	let mut indexed = 0_usize;
+	for (_, value) in db["pduid_pdu"].iter() {
+		let Ok(pdu_json) = serde_json::from_slice::<CanonicalJsonObject>(&value) else {
+			continue;
+		};
+
+		let Some(Ok(event_id)) = pdu_json
+			.get("event_id")
+			.and_then(CanonicalJsonValue::as_str)
+			.map(EventId::parse)
+		else {
+			continue;
+		};
+
+		services
+			.rooms
+			.retention
+			.add_media_references(&event_id, &pdu_json)?;
+		indexed = indexed.saturating_add(1);
+	}
+
+	for (key, value) in db["eventid_outlierpdu"].iter() {
+		let Ok(event_id) = utils::string_from_bytes(&key) else {
+			continue;
+		};
+
+		let (Ok(event_id), Ok(pdu_json)) =
+			(EventId::parse(event_id), serde_json::from_slice::<CanonicalJsonObject>(&value))
+		else {
+			continue;
+		};
+
+		services
+			.rooms
+			.retention
+			.add_media_references(&event_id, &pdu_json)?;
+		indexed = indexed.saturating_add(1);
+	}
+
+	db["global"].insert(b"feat_media_references", &[])?;
+
+	info!("Finished indexing the media of {indexed} events");
+	Ok(())
+}
+
+/// Returns the entries a sync connection stored in an older layout is split
+/// into, an error if it cannot be read, or None if it is in the current
+/// layout already.
+fn split_sync_connection(key: &[u8], value: &[u8]) -> Option<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+	#[derive(serde::Deserialize)]
+	struct Connection {
+		#[serde(default)]
+		known_rooms: BTreeMap<String, BTreeMap<OwnedRoomId, u64>>,
+		#[serde(default)]
+		last_used: u64,
+		#[serde(flatten)]
+		params: serde_json::Map<String, serde_json::Value>,
+	}
+
+	let mut head = key.to_vec();
+	match key.iter().filter(|&&byte| byte == 0xFF).count() {
+		// Connections without a version were all sliding sync (MSC3575).
+		2 => {
+			head.push(0xFF);
+			head.extend_from_slice(b"msc3575");
+		},
+		3 if value.len() != size_of::<u64>() => {},
+		_ => return None,
+	}
+
+	let connection = match serde_json::from_slice::<Connection>(value) {
+		Ok(connection) => connection,
+		Err(e) => return Some(Err(err!(Database("Invalid sync connection: {e}")))),
+	};
+
+	let mut params_key = head.clone();
+	params_key.push(0xFF);
+
+	let mut entries = vec![
+		(head.clone(), connection.last_used.to_be_bytes().to_vec()),
+		(
+			params_key,
+			serde_json::to_vec(&connection.params).expect("sync connection serializes"),
+		),
+	];
+
+	for (list_id, rooms) in connection.known_rooms {
+		for (room_id, count) in rooms {
+			let mut room_key = head.clone();
+			room_key.push(0xFF);
+			room_key.extend_from_slice(list_id.as_bytes());
+			room_key.push(0xFF);
+			room_key.extend_from_slice(room_id.as_bytes());
+			entries.push((room_key, count.to_be_bytes().to_vec()));
+		}
+	}
+
+	Some(Ok(entries))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+
+	use super::{lazy_loading_key_with_stream, split_sync_connection};
+
+	fn join(parts: &[&[u8]]) -> Vec<u8> { parts.join(&0xFF) }
+
+	#[test]
+	fn lazy_loading_key_moved_to_default_stream() {
+		let old = join(&[b"@alice:example.com", b"DEVICE", b"!room:example.com", b"@bob:example.com"]);
+		let new = join(&[b"@alice:example.com", b"DEVICE", b"", b"!room:example.com", b"@bob:example.com"]);
+
+		assert_eq!(lazy_loading_key_with_stream(&old), Some(Cow::Owned(new.clone())));
+		assert_eq!(
+			lazy_loading_key_with_stream(&new),
+			Some(Cow::Borrowed(new.as_slice())),
+			"already moved"
+		);
+	}
+
+	#[test]
+	fn lazy_loading_key_with_named_stream_kept() {
+		let key = join(&[
+			b"@alice:example.com",
+			b"DEVICE",
+			b"pantalaimon",
+			b"!room:example.com",
+			b"@bob:example.com",
+		]);
+
+		assert!(
+			matches!(lazy_loading_key_with_stream(&key), Some(Cow::Borrowed(_))),
+			"named stream"
+		);
+	}
+
+	#[test]
+	fn lazy_loading_key_invalid() {
+		assert_eq!(lazy_loading_key_with_stream(b"@alice:example.com"), None);
+		assert_eq!(lazy_loading_key_with_stream(&join(&[b"@alice:example.com", b"DEVICE"])), None);
+	}
+
+	#[test]
+	fn sync_connection_split() {
+		let old = join(&[b"@alice:example.com", b"DEVICE", b"conn"]);
+		let value = br#"{"lists":{},"known_rooms":{"list":{"!room:example.com":5}},"last_used":42}"#;
+
+		let head = join(&[b"@alice:example.com", b"DEVICE", b"conn", b"msc3575"]);
+		let params = join(&[&head, b""]);
+		let room = join(&[&head, b"list", b"!room:example.com"]);
+
+		let entries = split_sync_connection(&old, value)
+			.expect("older layout")
+			.expect("valid connection");
+
+		assert_eq!(
+			entries,
+			vec![
+				(head.clone(), 42_u64.to_be_bytes().to_vec()),
+				(params, br#"{"lists":{}}"#.to_vec()),
+				(room, 5_u64.to_be_bytes().to_vec()),
+			]
+		);
+
+		assert!(split_sync_connection(&head, &42_u64.to_be_bytes()).is_none(), "current layout");
+	}
+
+	#[test]
+	fn sync_connection_split_versioned() {
+		let key = join(&[b"@alice:example.com", b"DEVICE", b"conn", b"msc4186"]);
+		let entries = split_sync_connection(&key, br#"{"known_rooms":{},"last_used":42}"#)
+			.expect("older layout")
+			.expect("valid connection");
+
+		assert_eq!(entries[0], (key, 42_u64.to_be_bytes().to_vec()), "rewritten in place");
+		assert!(
+			split_sync_connection(&join(&[b"@alice:example.com", b"DEVICE", b"conn"]), b"{")
+				.is_some_and(|e| e.is_err()),
+			"invalid"
+		);
+	}
+}
@@ -0,0 +1,105 @@
+mod tests;
+
+use std::sync::Arc;
+
+use conduit::{debug, err, Err, Result};
+use lettre::{
+	message::{header::ContentType, Mailbox},
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+pub struct Service {
+	transport: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+}
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		let config = &args.server.config.smtp;
+		let transport = config
+			.connection_url
+			.as_deref()
+			.map(|url| {
+				let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+					.map_err(|e| err!(Config("smtp.connection_url", "Invalid SMTP URL: {e}")))?
+					.build();
+
+				let sender = config
+					.sender
+					.as_deref()
+					.ok_or_else(|| err!(Config("smtp.sender", "A sender is required to send email.")))?
+					.parse()
+					.map_err(|e| err!(Config("smtp.sender", "Invalid mailbox: {e}")))?;
+
+				Ok::<_, conduit::Error>((transport, sender))
+			})
+			.transpose()?;
+
+		Ok(Arc::new(Self {
+			transport,
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Whether an SMTP relay is configured.
+	#[inline]
+	#[must_use]
+	pub fn enabled(&self) -> bool { self.transport.is_some() }
+
+	/// Sends a plain text email.
+	pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+		let Some((transport, sender)) = &self.transport else {
+			return Err!(Config("smtp.connection_url", "Sending email is not configured."));
+		};
+
+		let recipient: Mailbox = to
+			.parse()
+			.map_err(|e| err!(Request(InvalidParam("Invalid email address: {e}"))))?;
+
+		let message = Message::builder()
+			.from(sender.clone())
+			.to(recipient)
+			.subject(subject)
+			.header(ContentType::TEXT_PLAIN)
+			.body(body)
+			.map_err(|e| err!("Failed to build email: {e}"))?;
+
+		transport
+			.send(message)
+			.await
+			.map_err(|e| err!(error!("Failed to send email to {to}: {e}")))?;
+
+		debug!("Sent email {subject:?} to {to}");
+
+		Ok(())
+	}
+}
+
+/// Replaces each `{name}` in the template with its value in a single pass, so
+/// that values are never expanded themselves. Unknown names are kept as is.
+#[must_use]
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+	let mut rendered = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some((before, after)) = rest.split_once('{') {
+		rendered.push_str(before);
+
+		let var = after.split_once('}').and_then(|(name, tail)| {
+			let (_, value) = vars.iter().find(|(var, _)| *var == name)?;
+			Some((value, tail))
+		});
+
+		if let Some((value, tail)) = var {
+			rendered.push_str(value);
+			rest = tail;
+		} else {
+			rendered.push('{');
+			rest = after;
+		}
+	}
+
+	rendered.push_str(rest);
+	rendered
+}
@@ -0,0 +1,27 @@
+#![cfg(test)]
+
+use super::render;
+
+#[test]
+fn render_substitutes_variables() {
+	assert_eq!(
+		render(
+			"Hello {displayname}, {count} unread",
+			&[("displayname", "Alice"), ("count", "3")]
+		),
+		"Hello Alice, 3 unread"
+	);
+}
+
+#[test]
+fn render_does_not_expand_values() {
+	assert_eq!(
+		render("{sender}: {body}", &[("sender", "{body}"), ("body", "{sender} hi")]),
+		"{body}: {sender} hi"
+	);
+}
+
+#[test]
+fn render_keeps_unknown_names_and_braces() {
+	assert_eq!(render("{unknown} { {count}} {", &[("count", "1")]), "{unknown} { 1} {");
+}
@@ -22,7 +22,7 @@ use tokio::{
 
 use self::data::{Data, Metadata};
 pub use self::thumbnail::Dim;
-use crate::{client, globals, sending, Dep};
+use crate::{client, globals, policy, sending, Dep};
 
 #[derive(Debug)]
 pub struct FileMeta {
@@ -41,6 +41,7 @@ struct Services {
 	server: Arc<Server>,
 	client: Dep<client::Service>,
 	globals: Dep<globals::Service>,
+	policy: Dep<policy::Service>,
 	sending: Dep<sending::Service>,
 }
 
@@ -63,6 +64,7 @@ impl crate::Service for Service {
 				server: args.server.clone(),
 				client: args.depend::<client::Service>("client"),
 				globals: args.depend::<globals::Service>("globals"),
+				policy: args.depend::<policy::Service>("policy"),
 				sending: args.depend::<sending::Service>("sending"),
 			},
 		}))
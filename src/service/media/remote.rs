@@ -391,6 +391,11 @@ fn check_fetch_authorized(&self, mxc: &Mxc<'_>) -> Result<()> {
 		return Err!(Request(NotFound("Media not found.")));
 	}
 
+	if let Some(ban) = self.services.policy.server_ban(mxc.server_name) {
+		debug_warn!(%mxc, "Received request for media on server banned by policy list: {ban}");
+		return Err!(Request(NotFound("Media not found.")));
+	}
+
 	Ok(())
 }
 
@@ -6,19 +6,27 @@ mod service;
 pub mod services;
 
 pub mod account_data;
+pub mod account_validity;
 pub mod admin;
 pub mod appservice;
 pub mod client;
 pub mod emergency;
 pub mod globals;
 pub mod key_backups;
+pub mod mailer;
 pub mod media;
+pub mod oidc;
+pub mod policy;
 pub mod presence;
 pub mod pusher;
+pub mod rate_limiter;
+pub mod registration_tokens;
 pub mod resolver;
 pub mod rooms;
 pub mod sending;
 pub mod server_keys;
+pub mod spam_checker;
+pub mod threepid;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod updates;
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use conduit::{utils, Error, Result};
+use database::Map;
+use ruma::{OwnedUserId, UserId};
+
+pub(super) struct Data {
+	oidcsubject_userid: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(args: &crate::Args<'_>) -> Self {
+		let db = &args.db;
+		Self {
+			oidcsubject_userid: db["oidcsubject_userid"].clone(),
+		}
+	}
+
+	/// Returns the local user an upstream subject is linked to.
+	pub(super) fn subject_user(&self, issuer: &str, subject: &str) -> Result<Option<OwnedUserId>> {
+		self.oidcsubject_userid
+			.get(&subject_key(issuer, subject))?
+			.map(|bytes| {
+				UserId::parse(
+					utils::string_from_bytes(&bytes)
+						.map_err(|_| Error::bad_database("User ID in oidcsubject_userid is invalid unicode."))?,
+				)
+				.map_err(|_| Error::bad_database("User ID in oidcsubject_userid is invalid."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn link_subject(&self, issuer: &str, subject: &str, user_id: &UserId) -> Result<()> {
+		self.oidcsubject_userid
+			.insert(&subject_key(issuer, subject), user_id.as_bytes())
+	}
+}
+
+fn subject_key(issuer: &str, subject: &str) -> Vec<u8> {
+	let mut key = issuer.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(subject.as_bytes());
+	key
+}
@@ -0,0 +1,560 @@
+mod data;
+mod tests;
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, RwLock},
+	time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use conduit::{err, info, utils, warn, Err, Result, Server};
+use data::Data;
+use jsonwebtoken::{
+	jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm},
+	Algorithm, DecodingKey, Validation,
+};
+use ruma::{
+	events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
+	push, OwnedUserId, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{account_data, account_validity, admin, client, globals, users, Dep};
+
+/// How long users have to log in at the identity provider.
+const SESSION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How long clients have to exchange a login token after being sent back.
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+const STATE_LENGTH: usize = 32;
+const VERIFIER_LENGTH: usize = 64;
+const LOGIN_TOKEN_LENGTH: usize = 32;
+const PASSWORD_LENGTH: usize = 32;
+
+/// Path of the callback the identity provider sends users back to.
+pub const CALLBACK_PATH: &str = "/_conduwuit/oidc/callback";
+
+pub struct Service {
+	server: Arc<Server>,
+	services: Services,
+	db: Data,
+	provider: RwLock<Option<Arc<Provider>>>,
+	sessions: Mutex<HashMap<String, Session>>,
+	login_tokens: Mutex<HashMap<String, LoginToken>>,
+}
+
+struct Services {
+	account_data: Dep<account_data::Service>,
+	account_validity: Dep<account_validity::Service>,
+	admin: Dep<admin::Service>,
+	client: Dep<client::Service>,
+	globals: Dep<globals::Service>,
+	users: Dep<users::Service>,
+}
+
+/// Endpoints of the identity provider, from its discovery document.
+#[derive(Deserialize)]
+struct Provider {
+	issuer: String,
+	authorization_endpoint: Url,
+	token_endpoint: Url,
+	jwks_uri: Url,
+}
+
+/// A login in progress at the identity provider.
+struct Session {
+	redirect_url: Url,
+	verifier: String,
+	nonce: String,
+	created: Instant,
+}
+
+struct LoginToken {
+	user_id: OwnedUserId,
+	created: Instant,
+}
+
+/// Where to send a user back to once logged in at the identity provider.
+pub enum Completion {
+	/// The client is trusted by `oidc.client_redirect_urls`: send the user
+	/// straight to its `redirectUrl` with the login token.
+	Redirect(String),
+
+	/// Any other client could be trying to log in as the user, who has to
+	/// confirm before being sent to `location` with the login token.
+	Confirm {
+		user_id: OwnedUserId,
+		client: String,
+		location: String,
+	},
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+	sub: String,
+	nonce: Option<String>,
+	#[serde(flatten)]
+	other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			server: args.server.clone(),
+			services: Services {
+				account_data: args.depend::<account_data::Service>("account_data"),
+				account_validity: args.depend::<account_validity::Service>("account_validity"),
+				admin: args.depend::<admin::Service>("admin"),
+				client: args.depend::<client::Service>("client"),
+				globals: args.depend::<globals::Service>("globals"),
+				users: args.depend::<users::Service>("users"),
+			},
+			db: Data::new(&args),
+			provider: RwLock::new(None),
+			sessions: Mutex::new(HashMap::new()),
+			login_tokens: Mutex::new(HashMap::new()),
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Whether logins are delegated to an identity provider.
+	#[inline]
+	pub fn enabled(&self) -> bool { self.server.config.oidc.issuer.is_some() }
+
+	/// Starts a login with the authorization code flow, returning the URL of
+	/// the identity provider to send the user to. Once logged in there, the
+	/// user comes back through the callback and is then sent to
+	/// `redirect_url` with a login token.
+	pub async fn authorization_url(&self, redirect_url: &str) -> Result<String> {
+		let redirect_url =
+			Url::parse(redirect_url).map_err(|e| err!(Request(InvalidParam("redirectUrl is not a valid URL: {e}"))))?;
+
+		// The user may be shown a link to it, which must not run anything here.
+		if matches!(redirect_url.scheme(), "javascript" | "data" | "vbscript" | "file") {
+			return Err!(Request(InvalidParam("redirectUrl must be a URL of the client.")));
+		}
+
+		let provider = self.provider().await?;
+		let state = utils::random_string(STATE_LENGTH);
+		let verifier = utils::random_string(VERIFIER_LENGTH);
+		let nonce = utils::random_string(STATE_LENGTH);
+		let challenge = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+		let mut url = provider.authorization_endpoint.clone();
+		url.query_pairs_mut()
+			.append_pair("response_type", "code")
+			.append_pair("client_id", self.client_id()?)
+			.append_pair("redirect_uri", self.redirect_uri()?.as_str())
+			.append_pair("scope", &self.server.config.oidc.scopes.join(" "))
+			.append_pair("state", &state)
+			.append_pair("nonce", &nonce)
+			.append_pair("code_challenge", &challenge)
+			.append_pair("code_challenge_method", "S256");
+
+		let mut sessions = self.sessions.lock().expect("locked");
+		sessions.retain(|_, session| session.created.elapsed() < SESSION_LIFETIME);
+		sessions.insert(
+			state,
+			Session {
+				redirect_url,
+				verifier,
+				nonce,
+				created: Instant::now(),
+			},
+		);
+
+		Ok(url.into())
+	}
+
+	/// Completes a login when the identity provider sends the user back with
+	/// an authorization code. The user of the ID token is registered on their
+	/// first login. Returns where to send the user to: the `redirectUrl` of
+	/// the client with a login token for `m.login.token`, to be confirmed
+	/// first unless the client is trusted.
+	pub async fn complete(&self, code: &str, state: &str) -> Result<Completion> {
+		let session = self
+			.sessions
+			.lock()
+			.expect("locked")
+			.remove(state)
+			.filter(|session| session.created.elapsed() < SESSION_LIFETIME)
+			.ok_or_else(|| err!(Request(Forbidden("Unknown or expired login session."))))?;
+
+		let provider = self.provider().await?;
+		let claims = self.exchange_code(&provider, code, &session).await?;
+		let user_id = self.claims_user(&provider, &claims).await?;
+
+		let mut redirect_url = session.redirect_url;
+		let trusted = trusted_redirect_url(&self.server.config.oidc.client_redirect_urls, &redirect_url);
+		let client = redirect_url
+			.host_str()
+			.unwrap_or_else(|| redirect_url.scheme())
+			.to_owned();
+
+		redirect_url
+			.query_pairs_mut()
+			.append_pair("loginToken", &self.create_login_token(&user_id));
+
+		info!("{user_id} authenticated with the identity provider");
+
+		if trusted {
+			return Ok(Completion::Redirect(redirect_url.into()));
+		}
+
+		Ok(Completion::Confirm {
+			user_id,
+			client,
+			location: redirect_url.into(),
+		})
+	}
+
+	/// Creates a single use token to log in with `m.login.token`.
+	pub fn create_login_token(&self, user_id: &UserId) -> String {
+		let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+
+		let mut login_tokens = self.login_tokens.lock().expect("locked");
+		login_tokens.retain(|_, login_token| login_token.created.elapsed() < LOGIN_TOKEN_LIFETIME);
+		login_tokens.insert(
+			token.clone(),
+			LoginToken {
+				user_id: user_id.to_owned(),
+				created: Instant::now(),
+			},
+		);
+
+		token
+	}
+
+	/// Returns the user a login token was created for, consuming the token.
+	pub fn take_login_token(&self, token: &str) -> Option<OwnedUserId> {
+		self.login_tokens
+			.lock()
+			.expect("locked")
+			.remove(token)
+			.filter(|login_token| login_token.created.elapsed() < LOGIN_TOKEN_LIFETIME)
+			.map(|login_token| login_token.user_id)
+	}
+
+	/// Trades the authorization code for an ID token, returning its validated
+	/// claims.
+	async fn exchange_code(&self, provider: &Provider, code: &str, session: &Session) -> Result<IdClaims> {
+		let client_id = self.client_id()?;
+		let mut form = url::form_urlencoded::Serializer::new(String::new());
+		form.append_pair("grant_type", "authorization_code")
+			.append_pair("code", code)
+			.append_pair("redirect_uri", self.redirect_uri()?.as_str())
+			.append_pair("client_id", client_id)
+			.append_pair("code_verifier", &session.verifier);
+
+		if let Some(client_secret) = &self.server.config.oidc.client_secret {
+			form.append_pair("client_secret", client_secret);
+		}
+
+		let response = self
+			.services
+			.client
+			.default
+			.post(provider.token_endpoint.clone())
+			.header(http::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.body(form.finish())
+			.send()
+			.await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			return Err!(BadServerResponse(warn!(
+				"Identity provider refused the authorization code: {status}"
+			)));
+		}
+
+		let response: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+			.map_err(|e| err!(BadServerResponse("Invalid token response from the identity provider: {e}")))?;
+
+		// Keys are fetched on every login so rotations are picked up.
+		let jwks: JwkSet = self.fetch_json(provider.jwks_uri.as_str()).await?;
+
+		validate_id_token(&response.id_token, &jwks, &provider.issuer, client_id, &session.nonce)
+	}
+
+	/// Returns the local user of an ID token. Subjects are linked to a user on
+	/// their first login, registering it unless it exists already.
+	async fn claims_user(&self, provider: &Provider, claims: &IdClaims) -> Result<OwnedUserId> {
+		let config = &self.server.config.oidc;
+		if let Some(user_id) = self.db.subject_user(&provider.issuer, &claims.sub)? {
+			return Ok(user_id);
+		}
+
+		// Existing users are only linked by the subject, which the identity provider
+		// never reassigns; other claims such as usernames may be chosen by its users.
+		if config.allow_existing_users {
+			if let Some(user_id) = self.existing_subject_user(&claims.sub)? {
+				self.db
+					.link_subject(&provider.issuer, &claims.sub, &user_id)?;
+
+				return Ok(user_id);
+			}
+		}
+
+		let claim = &config.localpart_claim;
+		let localpart = claims
+			.other
+			.get(claim)
+			.and_then(serde_json::Value::as_str)
+			.ok_or_else(|| err!(Request(Forbidden("ID token has no {claim:?} claim."))))?
+			.to_lowercase();
+
+		let user_id = UserId::parse_with_server_name(localpart, self.services.globals.server_name())
+			.map_err(|e| err!(Request(InvalidUsername("The {claim:?} claim is not a valid username: {e}"))))?;
+
+		if self.services.users.exists(&user_id)? {
+			return Err!(Request(Forbidden(
+				"{user_id} already exists and is not linked to the identity provider."
+			)));
+		}
+
+		if !config.allow_registration {
+			return Err!(Request(Forbidden("Registration through the identity provider is disabled.")));
+		}
+
+		self.register(&user_id, claims).await?;
+
+		self.db
+			.link_subject(&provider.issuer, &claims.sub, &user_id)?;
+
+		Ok(user_id)
+	}
+
+	/// Returns the existing local user whose localpart is a subject.
+	fn existing_subject_user(&self, subject: &str) -> Result<Option<OwnedUserId>> {
+		let Ok(user_id) = UserId::parse_with_server_name(subject.to_lowercase(), self.services.globals.server_name())
+		else {
+			return Ok(None);
+		};
+
+		if !self.services.users.exists(&user_id)? {
+			return Ok(None);
+		}
+
+		if self.services.users.is_deactivated(&user_id)? {
+			return Err!(Request(UserDeactivated("The user has been deactivated")));
+		}
+
+		Ok(Some(user_id))
+	}
+
+	async fn register(&self, user_id: &UserId, claims: &IdClaims) -> Result<()> {
+		// These users log in through the identity provider, but an empty password
+		// hash would mark them as deactivated.
+		self.services
+			.users
+			.create(user_id, Some(&utils::random_string(PASSWORD_LENGTH)))?;
+
+		self.services.account_validity.start(user_id)?;
+
+		let displayname = claims
+			.other
+			.get(&self.server.config.oidc.displayname_claim)
+			.and_then(serde_json::Value::as_str)
+			.map_or_else(|| user_id.localpart().to_owned(), ToOwned::to_owned);
+
+		self.services
+			.users
+			.set_displayname(user_id, Some(displayname))
+			.await?;
+
+		// Initial account data
+		self.services.account_data.update(
+			None,
+			user_id,
+			GlobalAccountDataEventType::PushRules.to_string().into(),
+			&serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+				content: ruma::events::push_rules::PushRulesEventContent {
+					global: push::Ruleset::server_default(user_id),
+				},
+			})
+			.expect("to json always works"),
+		)?;
+
+		info!("New user \"{user_id}\" registered through the identity provider.");
+
+		if self.services.globals.config.admin_room_notices {
+			self.services
+				.admin
+				.send_message(RoomMessageEventContent::notice_plain(format!(
+					"New user \"{user_id}\" registered on this server through the identity provider"
+				)))
+				.await;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the endpoints of the identity provider, discovering them on
+	/// first use.
+	async fn provider(&self) -> Result<Arc<Provider>> {
+		if let Some(provider) = self.provider.read().expect("locked").clone() {
+			return Ok(provider);
+		}
+
+		let issuer = self
+			.server
+			.config
+			.oidc
+			.issuer
+			.as_ref()
+			.ok_or_else(|| err!(Request(Unknown("SSO login is not enabled on this server."))))?;
+
+		let issuer = issuer.as_str().trim_end_matches('/');
+		let provider: Provider = self
+			.fetch_json(&format!("{issuer}/.well-known/openid-configuration"))
+			.await?;
+
+		if provider.issuer.trim_end_matches('/') != issuer {
+			return Err!(BadServerResponse(warn!(
+				"Identity provider claims to be {:?} instead of the configured issuer {issuer:?}",
+				provider.issuer
+			)));
+		}
+
+		let provider = Arc::new(provider);
+		*self.provider.write().expect("locked") = Some(provider.clone());
+
+		Ok(provider)
+	}
+
+	async fn fetch_json<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+		let response = self.services.client.default.get(url).send().await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			return Err!(BadServerResponse(warn!("Identity provider returned {status} for {url}")));
+		}
+
+		serde_json::from_slice(&response.bytes().await?)
+			.map_err(|e| err!(BadServerResponse("Invalid response from the identity provider at {url}: {e}")))
+	}
+
+	fn client_id(&self) -> Result<&str> {
+		self.server.config.oidc.client_id.as_deref().ok_or_else(|| {
+			err!(Config(
+				"oidc.client_id",
+				"The client ID of this server at the identity provider is not set."
+			))
+		})
+	}
+
+	fn redirect_uri(&self) -> Result<Url> {
+		let config = &self.server.config;
+		if let Some(redirect_uri) = &config.oidc.redirect_uri {
+			return Ok(redirect_uri.clone());
+		}
+
+		config
+			.well_known
+			.client
+			.as_ref()
+			.ok_or_else(|| {
+				err!(Config(
+					"oidc.redirect_uri",
+					"Set either oidc.redirect_uri or well_known.client."
+				))
+			})?
+			.join(CALLBACK_PATH)
+			.map_err(|e| err!(Config("oidc.redirect_uri", "Invalid callback URL: {e}")))
+	}
+}
+
+/// Validates an ID token issued by `issuer` for this server as `client_id` and
+/// the login of `nonce`, returning its claims.
+fn validate_id_token(id_token: &str, jwks: &JwkSet, issuer: &str, client_id: &str, nonce: &str) -> Result<IdClaims> {
+	let header = jsonwebtoken::decode_header(id_token)
+		.map_err(|e| err!(BadServerResponse("Invalid ID token from the identity provider: {e}")))?;
+
+	let jwk = match &header.kid {
+		Some(kid) => jwks.find(kid),
+		None => jwks.keys.first(),
+	}
+	.ok_or_else(|| err!(BadServerResponse("Identity provider has no key for its ID token.")))?;
+
+	let key = DecodingKey::from_jwk(jwk)
+		.map_err(|e| err!(BadServerResponse("Unusable key from the identity provider: {e}")))?;
+
+	// The key decides the algorithm, so a token cannot pick a weaker one.
+	let algorithm = key_algorithm(jwk)
+		.ok_or_else(|| err!(BadServerResponse("Identity provider has no usable algorithm for its key.")))?;
+
+	if header.alg != algorithm {
+		return Err!(Request(Forbidden(warn!(
+			"ID token is signed with {:?} instead of {algorithm:?}",
+			header.alg
+		))));
+	}
+
+	let mut validation = Validation::new(algorithm);
+	validation.set_issuer(&[issuer]);
+	validation.set_audience(&[client_id]);
+
+	let claims = jsonwebtoken::decode::<IdClaims>(id_token, &key, &validation)
+		.map_err(|e| err!(Request(Forbidden(warn!("Invalid ID token from the identity provider: {e}")))))?
+		.claims;
+
+	if claims.nonce.as_deref() != Some(nonce) {
+		return Err!(Request(Forbidden("ID token was not issued for this login.")));
+	}
+
+	Ok(claims)
+}
+
+/// Whether a client `redirectUrl` is under one of
+/// `oidc.client_redirect_urls`.
+fn trusted_redirect_url(trusted_urls: &[Url], redirect_url: &Url) -> bool {
+	trusted_urls.iter().any(|trusted| {
+		redirect_url.scheme() == trusted.scheme()
+			&& redirect_url.host_str() == trusted.host_str()
+			&& redirect_url.port_or_known_default() == trusted.port_or_known_default()
+			&& redirect_url.path().starts_with(trusted.path())
+	})
+}
+
+/// Returns the algorithm ID tokens signed with a key must use: the `alg` of the
+/// key, or the default of its type when the identity provider leaves it out.
+/// Symmetric keys are never used as they would be public.
+fn key_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+	let Some(key_algorithm) = &jwk.common.key_algorithm else {
+		return match &jwk.algorithm {
+			AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+			AlgorithmParameters::EllipticCurve(params) => match params.curve {
+				EllipticCurve::P256 => Some(Algorithm::ES256),
+				EllipticCurve::P384 => Some(Algorithm::ES384),
+				_ => None,
+			},
+			AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+			AlgorithmParameters::OctetKey(_) => None,
+		};
+	};
+
+	match key_algorithm {
+		KeyAlgorithm::RS256 => Some(Algorithm::RS256),
+		KeyAlgorithm::RS384 => Some(Algorithm::RS384),
+		KeyAlgorithm::RS512 => Some(Algorithm::RS512),
+		KeyAlgorithm::PS256 => Some(Algorithm::PS256),
+		KeyAlgorithm::PS384 => Some(Algorithm::PS384),
+		KeyAlgorithm::PS512 => Some(Algorithm::PS512),
+		KeyAlgorithm::ES256 => Some(Algorithm::ES256),
+		KeyAlgorithm::ES384 => Some(Algorithm::ES384),
+		KeyAlgorithm::EdDSA => Some(Algorithm::EdDSA),
+		_ => None,
+	}
+}
@@ -0,0 +1,284 @@
+mod tests;
+
+use std::{
+	collections::HashMap,
+	fmt::{Display, Formatter},
+	sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use conduit::{debug, info, warn, Err, PduEvent, Result, Server};
+use regex::Regex;
+use ruma::{
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		StateEventType, TimelineEventType,
+	},
+	OwnedRoomId, RoomId, ServerName, UserId,
+};
+use serde::Deserialize;
+
+use crate::{admin, globals, rooms, Dep};
+
+pub struct Service {
+	services: Services,
+	server: Arc<Server>,
+	rules: RwLock<HashMap<OwnedRoomId, HashMap<(StateEventType, String), Rule>>>,
+}
+
+struct Services {
+	admin: Dep<admin::Service>,
+	globals: Dep<globals::Service>,
+	state_accessor: Dep<rooms::state_accessor::Service>,
+}
+
+/// What a policy rule applies to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Entity {
+	User,
+	Room,
+	Server,
+}
+
+/// A ban of a policy list matching a user, room or server.
+#[derive(Clone, Debug)]
+pub struct PolicyBan {
+	pub policy_room: OwnedRoomId,
+	pub kind: Entity,
+	/// The glob of the rule
+	pub entity: String,
+	pub reason: String,
+}
+
+struct Rule {
+	ban: PolicyBan,
+	pattern: Regex,
+}
+
+#[derive(Deserialize)]
+struct PolicyRuleContent {
+	entity: String,
+	recommendation: String,
+	reason: Option<String>,
+}
+
+/// Event type prefixes of policy rules: the stable one and those still used by
+/// older lists.
+const RULE_PREFIXES: &[&str] = &["m.policy.rule.", "m.room.rule.", "org.matrix.mjolnir.rule."];
+
+/// Recommendations which ban the entity.
+const BAN_RECOMMENDATIONS: &[&str] = &["m.ban", "org.matrix.mjolnir.ban"];
+
+#[async_trait]
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			services: Services {
+				admin: args.depend::<admin::Service>("admin"),
+				globals: args.depend::<globals::Service>("globals"),
+				state_accessor: args.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
+			},
+			server: args.server.clone(),
+			rules: RwLock::new(HashMap::new()),
+		}))
+	}
+
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		for room_id in &self.server.config.policy_list_rooms {
+			if let Err(e) = self.reload_room(room_id).await {
+				warn!(%room_id, "Failed to load policy list: {e}");
+			}
+		}
+
+		Ok(())
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Whether the room is a configured policy list.
+	#[inline]
+	pub fn is_policy_room(&self, room_id: &RoomId) -> bool {
+		self.server
+			.config
+			.policy_list_rooms
+			.iter()
+			.any(|policy_room| policy_room == room_id)
+	}
+
+	/// Updates the rules when an event is appended to a configured policy
+	/// list: the rule it changes, or all of them once a local user joins and
+	/// the state of the list is known or a rule may have been redacted.
+	pub async fn handle_event(&self, pdu: &PduEvent) {
+		if pdu.kind == TimelineEventType::RoomRedaction {
+			if let Err(e) = self.reload_room(&pdu.room_id).await {
+				warn!(room_id = %pdu.room_id, "Failed to reload policy list: {e}");
+			}
+		} else if pdu.kind == TimelineEventType::RoomMember {
+			let joined_local = pdu
+				.state_key
+				.as_deref()
+				.and_then(|state_key| UserId::parse(state_key).ok())
+				.is_some_and(|user_id| self.services.globals.user_is_local(&user_id))
+				&& serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+					.is_ok_and(|content| content.membership == MembershipState::Join);
+
+			if joined_local {
+				if let Err(e) = self.reload_room(&pdu.room_id).await {
+					warn!(room_id = %pdu.room_id, "Failed to load policy list: {e}");
+				}
+			}
+		} else if pdu.state_key.is_some() {
+			self.update_rule(pdu);
+		}
+	}
+
+	/// Reads the ban rules of a policy list from its current state.
+	pub async fn reload_room(&self, room_id: &RoomId) -> Result<()> {
+		let state = self
+			.services
+			.state_accessor
+			.room_state_full(room_id)
+			.await?;
+
+		let rules: HashMap<_, _> = state
+			.into_iter()
+			.filter_map(|(key, pdu)| Some((key, parse_rule(&pdu)?)))
+			.collect();
+
+		info!(%room_id, "Loaded {} ban rules from policy list", rules.len());
+		self.rules
+			.write()
+			.expect("locked")
+			.insert(room_id.to_owned(), rules);
+
+		Ok(())
+	}
+
+	/// Replaces the rule changed by a new state event of a policy list.
+	fn update_rule(&self, pdu: &PduEvent) {
+		let Some(state_key) = &pdu.state_key else {
+			return;
+		};
+
+		let key = (pdu.kind.to_string().into(), state_key.clone());
+		let mut rules = self.rules.write().expect("locked");
+		let rules = rules.entry(pdu.room_id.clone()).or_default();
+		match parse_rule(pdu) {
+			Some(rule) => {
+				debug!(room_id = %pdu.room_id, "Policy list bans {}", rule.ban.entity);
+				rules.insert(key, rule);
+			},
+			None => {
+				rules.remove(&key);
+			},
+		}
+	}
+
+	/// Refuses a join, invite or knock of a user banned by a policy list or of
+	/// a user of a banned server, telling the admin room about it.
+	pub async fn check_membership(
+		&self, user_id: &UserId, room_id: &RoomId, membership: &MembershipState,
+	) -> Result<()> {
+		let Some(ban) = self.user_ban(user_id) else {
+			return Ok(());
+		};
+
+		self.report(&format!("Refused {membership} of {user_id} in {room_id}"), &ban)
+			.await;
+
+		Err!(Request(Forbidden("User is banned by a policy list of this server.")))
+	}
+
+	/// Returns the ban of the user or of their server.
+	pub fn user_ban(&self, user_id: &UserId) -> Option<PolicyBan> {
+		self.find_ban(Entity::User, user_id.as_str())
+			.or_else(|| self.server_ban(user_id.server_name()))
+	}
+
+	/// Returns the ban of the server.
+	pub fn server_ban(&self, server_name: &ServerName) -> Option<PolicyBan> {
+		self.find_ban(Entity::Server, server_name.as_str())
+	}
+
+	/// Returns the ban of the room.
+	pub fn room_ban(&self, room_id: &RoomId) -> Option<PolicyBan> { self.find_ban(Entity::Room, room_id.as_str()) }
+
+	/// Tells the admin room about an action taken because of a policy list, if
+	/// `admin_room_notices` is enabled.
+	pub async fn report(&self, action: &str, ban: &PolicyBan) {
+		warn!("{action}: {ban}");
+		if self.server.config.admin_room_notices {
+			self.services
+				.admin
+				.send_text(&format!("{action}: {ban}"))
+				.await;
+		}
+	}
+
+	fn find_ban(&self, kind: Entity, entity: &str) -> Option<PolicyBan> {
+		self.rules
+			.read()
+			.expect("locked")
+			.values()
+			.flat_map(HashMap::values)
+			.find(|rule| rule.ban.kind == kind && rule.pattern.is_match(entity))
+			.map(|rule| rule.ban.clone())
+	}
+}
+
+impl Display for PolicyBan {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} is banned by {}", self.entity, self.policy_room)?;
+		if !self.reason.is_empty() {
+			write!(f, " ({})", self.reason)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Parses a state event of a policy list into a ban rule. Rules with other
+/// recommendations are ignored, and rules are removed by redacting them or
+/// replacing their content with `{}`.
+fn parse_rule(pdu: &PduEvent) -> Option<Rule> {
+	let event_type = pdu.kind.to_string();
+	let kind = RULE_PREFIXES
+		.iter()
+		.find_map(|prefix| event_type.strip_prefix(prefix))
+		.and_then(|kind| match kind {
+			"user" => Some(Entity::User),
+			"room" => Some(Entity::Room),
+			"server" => Some(Entity::Server),
+			_ => None,
+		})?;
+
+	let content: PolicyRuleContent = serde_json::from_str(pdu.content.get()).ok()?;
+	if !BAN_RECOMMENDATIONS.contains(&content.recommendation.as_str()) {
+		return None;
+	}
+
+	let Some(pattern) = glob_regex(&content.entity) else {
+		debug!(room_id = %pdu.room_id, "Ignoring policy rule with invalid entity {:?}", content.entity);
+		return None;
+	};
+
+	Some(Rule {
+		ban: PolicyBan {
+			policy_room: pdu.room_id.clone(),
+			kind,
+			entity: content.entity,
+			reason: content.reason.unwrap_or_default(),
+		},
+		pattern,
+	})
+}
+
+/// Compiles a policy rule glob, where `*` matches any number of characters and
+/// `?` a single one.
+fn glob_regex(glob: &str) -> Option<Regex> {
+	let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+
+	Regex::new(&format!("^{pattern}$")).ok()
+}
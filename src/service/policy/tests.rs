@@ -0,0 +1,30 @@
+#![cfg(test)]
+
+use super::glob_regex;
+
+fn matches(glob: &str, entity: &str) -> bool { glob_regex(glob).expect("valid glob").is_match(entity) }
+
+#[test]
+fn glob_matches_exactly() {
+	assert!(matches("@spam:example.com", "@spam:example.com"));
+	assert!(!matches("@spam:example.com", "@spammer:example.com"));
+	assert!(!matches("@spam:example.com", "@alice:@spam:example.com.evil"), "anchored");
+}
+
+#[test]
+fn glob_wildcards() {
+	assert!(matches("*.example.com", "matrix.example.com"));
+	assert!(matches("*example.com", "example.com"), "* matches nothing");
+	assert!(!matches("*.example.com", "example.org"));
+	assert!(matches("@*:example.com", "@anyone:example.com"));
+	assert!(matches("@bot?:example.com", "@bot1:example.com"));
+	assert!(!matches("@bot?:example.com", "@bot:example.com"), "? matches one character");
+	assert!(!matches("@bot?:example.com", "@bot12:example.com"));
+}
+
+#[test]
+fn glob_escapes_regex() {
+	assert!(!matches("evil.com", "evilxcom"), ". is literal");
+	assert!(matches("[a-z]+.org", "[a-z]+.org"));
+	assert!(!matches("[a-z]+.org", "abc.org"));
+}
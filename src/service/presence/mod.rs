@@ -10,7 +10,7 @@ use ruma::{events::presence::PresenceEvent, presence::PresenceState, OwnedUserId
 use tokio::{sync::Mutex, time::sleep};
 
 use self::{data::Data, presence::Presence};
-use crate::{globals, users, Dep};
+use crate::{globals, sending, sending::AppserviceEdu, users, Dep};
 
 pub struct Service {
 	timer_sender: loole::Sender<TimerType>,
@@ -25,6 +25,7 @@ pub struct Service {
 struct Services {
 	server: Arc<Server>,
 	globals: Dep<globals::Service>,
+	sending: Dep<sending::Service>,
 	users: Dep<users::Service>,
 }
 
@@ -47,6 +48,7 @@ impl crate::Service for Service {
 			services: Services {
 				server: args.server.clone(),
 				globals: args.depend::<globals::Service>("globals"),
+				sending: args.depend::<sending::Service>("sending"),
 				users: args.depend::<users::Service>("users"),
 			},
 		}))
@@ -133,6 +135,15 @@ impl Service {
 		self.db
 			.set_presence(user_id, presence_state, currently_active, last_active_ago, status_msg)?;
 
+		if let Some(presence) = self.get_presence(user_id)? {
+			self.services
+				.sending
+				.send_edu_appservices(&AppserviceEdu::Presence(
+					user_id.to_owned(),
+					serde_json::to_value(presence).expect("PresenceEvent can be serialized"),
+				))?;
+		}
+
 		if self.timeout_remote_users || self.services.globals.user_is_local(user_id) {
 			let timeout = match presence_state {
 				PresenceState::Online => self.services.server.config.presence_idle_timeout_s,
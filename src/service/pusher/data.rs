@@ -4,17 +4,19 @@ use conduit::{utils, Error, Result};
 use database::{Database, Map};
 use ruma::{
 	api::client::push::{set_pusher, Pusher},
-	UserId,
+	EventId, OwnedEventId, OwnedUserId, UserId,
 };
 
 pub(super) struct Data {
 	senderkey_pusher: Arc<Map>,
+	userpushkeyeventid_queuedat: Arc<Map>,
 }
 
 impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
 			senderkey_pusher: db["senderkey_pusher"].clone(),
+			userpushkeyeventid_queuedat: db["userpushkeyeventid_queuedat"].clone(),
 		}
 	}
 
@@ -32,7 +34,14 @@ impl Data {
 				let mut key = sender.as_bytes().to_vec();
 				key.push(0xFF);
 				key.extend_from_slice(ids.pushkey.as_bytes());
-				self.senderkey_pusher.remove(&key).map_err(Into::into)
+				self.senderkey_pusher.remove(&key)?;
+
+				key.push(0xFF);
+				for (queued, _) in self.userpushkeyeventid_queuedat.scan_prefix(key) {
+					self.userpushkeyeventid_queuedat.remove(&queued)?;
+				}
+
+				Ok(())
 			},
 		}
 	}
@@ -74,4 +83,52 @@ impl Data {
 			Ok(push_key_string)
 		}))
 	}
+
+	/// Remembers an event to include in the next email digest for a pusher.
+	pub(super) fn queue_email(&self, user_id: &UserId, pushkey: &str, event_id: &EventId) -> Result<()> {
+		self.userpushkeyeventid_queuedat.insert(
+			&email_key(user_id, pushkey, event_id),
+			&utils::millis_since_unix_epoch().to_be_bytes(),
+		)
+	}
+
+	pub(super) fn dequeue_email(&self, user_id: &UserId, pushkey: &str, event_id: &EventId) -> Result<()> {
+		self.userpushkeyeventid_queuedat
+			.remove(&email_key(user_id, pushkey, event_id))
+	}
+
+	/// Returns all events waiting for an email digest with the time they were
+	/// queued at, grouped by user and pushkey.
+	pub(super) fn queued_emails<'a>(
+		&'a self,
+	) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, OwnedEventId, u64)>> + 'a> {
+		Box::new(self.userpushkeyeventid_queuedat.iter().map(|(key, value)| {
+			let mut parts = key.splitn(3, |&b| b == 0xFF);
+			let mut next_part = || {
+				parts
+					.next()
+					.and_then(|part| utils::string_from_bytes(part).ok())
+					.ok_or_else(|| Error::bad_database("Invalid key in userpushkeyeventid_queuedat."))
+			};
+
+			let user_id = UserId::parse(next_part()?)
+				.map_err(|_| Error::bad_database("Invalid user ID in userpushkeyeventid_queuedat."))?;
+			let pushkey = next_part()?;
+			let event_id = EventId::parse(next_part()?)
+				.map_err(|_| Error::bad_database("Invalid event ID in userpushkeyeventid_queuedat."))?;
+			let queued_at = utils::u64_from_bytes(&value)
+				.map_err(|_| Error::bad_database("Invalid queued time in userpushkeyeventid_queuedat."))?;
+
+			Ok((user_id, pushkey, event_id, queued_at))
+		}))
+	}
+}
+
+fn email_key(user_id: &UserId, pushkey: &str, event_id: &EventId) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(pushkey.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(event_id.as_bytes());
+	key
 }
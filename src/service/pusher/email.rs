@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use conduit::{utils, PduCount, PduEvent, Result};
+use ruma::{events::TimelineEventType, OwnedEventId, OwnedUserId, UserId};
+
+use super::Service;
+use crate::mailer::render;
+
+impl Service {
+	/// Emails a digest to each email pusher whose oldest queued highlight has
+	/// waited for `smtp.notification_delay`. Messages the user read in the
+	/// meantime are left out.
+	#[tracing::instrument(skip_all)]
+	pub(super) async fn send_email_digests(&self) -> Result<()> {
+		let delay = self
+			.services
+			.globals
+			.config
+			.smtp
+			.notification_delay
+			.saturating_mul(1000);
+		let now = utils::millis_since_unix_epoch();
+
+		let mut queued: BTreeMap<(OwnedUserId, String), Vec<(OwnedEventId, u64)>> = BTreeMap::new();
+		for entry in self.db.queued_emails() {
+			let (user_id, pushkey, event_id, queued_at) = entry?;
+			queued
+				.entry((user_id, pushkey))
+				.or_default()
+				.push((event_id, queued_at));
+		}
+
+		for ((user_id, pushkey), events) in queued {
+			let oldest = events.iter().map(|(_, queued_at)| *queued_at).min();
+			if oldest.is_some_and(|oldest| oldest.saturating_add(delay) > now) {
+				continue;
+			}
+
+			let mut messages = Vec::with_capacity(events.len());
+			for (event_id, _) in &events {
+				if let Some(pdu) = self.unread_pdu(&user_id, event_id)? {
+					messages.push(pdu);
+				}
+
+				self.db.dequeue_email(&user_id, &pushkey, event_id)?;
+			}
+
+			if messages.is_empty() {
+				continue;
+			}
+
+			// Failures are logged by the mailer. The digest is not retried, so a broken
+			// relay does not pile up stale messages.
+			let (subject, body) = self.render_digest(&user_id, &messages)?;
+			self.services
+				.mailer
+				.send(&pushkey, &subject, body)
+				.await
+				.ok();
+		}
+
+		Ok(())
+	}
+
+	/// Returns the event if it still exists and the user has not read it.
+	fn unread_pdu(&self, user_id: &UserId, event_id: &OwnedEventId) -> Result<Option<Arc<PduEvent>>> {
+		let Some(pdu) = self.services.timeline.get_pdu(event_id)? else {
+			return Ok(None);
+		};
+
+		if pdu.is_redacted() {
+			return Ok(None);
+		}
+
+		let Some(PduCount::Normal(count)) = self.services.timeline.get_pdu_count(event_id)? else {
+			return Ok(None);
+		};
+
+		let last_read = self
+			.services
+			.user
+			.last_notification_read(user_id, &pdu.room_id)?
+			.max(
+				self.services
+					.read_receipt
+					.private_read_get(&pdu.room_id, user_id)?
+					.unwrap_or(0),
+			);
+
+		Ok((count > last_read).then_some(pdu))
+	}
+
+	fn render_digest(&self, user_id: &UserId, messages: &[Arc<PduEvent>]) -> Result<(String, String)> {
+		let config = &self.services.globals.config;
+		let server_name = config.server_name.as_str();
+		let count = messages.len().to_string();
+		let displayname = self
+			.services
+			.users
+			.displayname(user_id)?
+			.unwrap_or_else(|| user_id.localpart().to_owned());
+
+		let mut rendered = Vec::with_capacity(messages.len());
+		for pdu in messages {
+			let sender = self
+				.services
+				.users
+				.displayname(&pdu.sender)?
+				.unwrap_or_else(|| pdu.sender.to_string());
+			let room_name = self
+				.services
+				.state_accessor
+				.get_name(&pdu.room_id)?
+				.unwrap_or_else(|| pdu.room_id.to_string());
+
+			rendered.push(render(
+				&config.smtp.notification_message,
+				&[("room_name", &room_name), ("sender", &sender), ("body", &message_body(pdu))],
+			));
+		}
+		let messages = rendered.join("\n");
+
+		let vars = [
+			("server_name", server_name),
+			("user_id", user_id.as_str()),
+			("displayname", &displayname),
+			("count", &count),
+			("messages", &messages),
+		];
+
+		Ok((
+			render(&config.smtp.notification_subject, &vars),
+			render(&config.smtp.notification_body, &vars),
+		))
+	}
+}
+
+fn message_body(pdu: &PduEvent) -> String {
+	if pdu.kind == TimelineEventType::RoomEncrypted {
+		return "(encrypted message)".to_owned();
+	}
+
+	serde_json::from_str::<serde_json::Value>(pdu.content.get())
+		.ok()
+		.and_then(|content| content.get("body")?.as_str().map(ToOwned::to_owned))
+		.unwrap_or_else(|| format!("({})", pdu.kind))
+}
@@ -1,9 +1,11 @@
 mod data;
+mod email;
 
-use std::{fmt::Debug, mem, sync::Arc};
+use std::{fmt::Debug, mem, sync::Arc, time::Duration};
 
+use async_trait::async_trait;
 use bytes::BytesMut;
-use conduit::{debug_error, err, trace, utils::string_from_bytes, warn, Err, PduEvent, Result};
+use conduit::{debug, debug_error, err, trace, utils::string_from_bytes, warn, Err, PduEvent, Result};
 use ipaddress::IPAddress;
 use ruma::{
 	api::{
@@ -21,37 +23,73 @@ use ruma::{
 	serde::Raw,
 	uint, RoomId, UInt, UserId,
 };
+use tokio::{sync::Notify, time::interval};
 
 use self::data::Data;
-use crate::{client, globals, rooms, users, Dep};
+use crate::{client, globals, mailer, rooms, users, Dep};
 
 pub struct Service {
 	services: Services,
 	db: Data,
+	interrupt: Notify,
 }
 
 struct Services {
 	globals: Dep<globals::Service>,
 	client: Dep<client::Service>,
+	mailer: Dep<mailer::Service>,
+	read_receipt: Dep<rooms::read_receipt::Service>,
 	state_accessor: Dep<rooms::state_accessor::Service>,
 	state_cache: Dep<rooms::state_cache::Service>,
+	timeline: Dep<rooms::timeline::Service>,
+	user: Dep<rooms::user::Service>,
 	users: Dep<users::Service>,
 }
 
+/// How often queued email notifications are checked for digests to send.
+const EMAIL_DIGEST_INTERVAL: Duration = Duration::from_secs(60);
+
+#[async_trait]
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 		Ok(Arc::new(Self {
 			services: Services {
 				globals: args.depend::<globals::Service>("globals"),
 				client: args.depend::<client::Service>("client"),
+				mailer: args.depend::<mailer::Service>("mailer"),
+				read_receipt: args.depend::<rooms::read_receipt::Service>("rooms::read_receipt"),
 				state_accessor: args.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
 				state_cache: args.depend::<rooms::state_cache::Service>("rooms::state_cache"),
+				timeline: args.depend::<rooms::timeline::Service>("rooms::timeline"),
+				user: args.depend::<rooms::user::Service>("rooms::user"),
 				users: args.depend::<users::Service>("users"),
 			},
 			db: Data::new(args.db),
+			interrupt: Notify::new(),
 		}))
 	}
 
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		if !self.services.mailer.enabled() {
+			debug!("SMTP is not configured, disabling email notifications");
+			return Ok(());
+		}
+
+		let mut i = interval(EMAIL_DIGEST_INTERVAL);
+		loop {
+			tokio::select! {
+				() = self.interrupt.notified() => return Ok(()),
+				_ = i.tick() => (),
+			}
+
+			if let Err(e) = self.send_email_digests().await {
+				warn!(%e, "Failed to send email notifications");
+			}
+		}
+	}
+
+	fn interrupt(&self) { self.interrupt.notify_waiters(); }
+
 	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
 }
 
@@ -189,7 +227,7 @@ impl Service {
 		}
 
 		if notify == Some(true) {
-			self.send_notice(unread, pusher, tweaks, pdu).await?;
+			self.send_notice(user, unread, pusher, tweaks, pdu).await?;
 		}
 		// Else the event triggered no actions
 
@@ -228,9 +266,10 @@ impl Service {
 		Ok(ruleset.get_actions(pdu, &ctx))
 	}
 
-	#[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
-	async fn send_notice(&self, unread: UInt, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent) -> Result<()> {
-		// TODO: email
+	#[tracing::instrument(skip(self, user, unread, pusher, tweaks, event))]
+	async fn send_notice(
+		&self, user: &UserId, unread: UInt, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent,
+	) -> Result<()> {
 		match &pusher.kind {
 			PusherKind::Http(http) => {
 				// TODO:
@@ -288,8 +327,15 @@ impl Service {
 
 				Ok(())
 			},
-			// TODO: Handle email
-			//PusherKind::Email(_) => Ok(()),
+			PusherKind::Email(_) => {
+				// Only highlights are emailed; they are batched into a digest by the worker
+				if tweaks.iter().any(|t| matches!(t, Tweak::Highlight(true))) {
+					self.db
+						.queue_email(user, &pusher.ids.pushkey, &event.event_id)?;
+				}
+
+				Ok(())
+			},
 			_ => Ok(()),
 		}
 	}
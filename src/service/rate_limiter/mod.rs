@@ -0,0 +1,87 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use conduit::{config::RateLimitConfig, Error, Result, Server};
+use ruma::api::client::error::{ErrorKind, RetryAfter};
+
+pub struct Service {
+	server: Arc<Server>,
+	buckets: Mutex<HashMap<(Limit, String), Instant>>,
+}
+
+/// The endpoints which are rate limited, each with its own configurable limit.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Limit {
+	Registration,
+	Login,
+	Message,
+	MediaUpload,
+	Federation,
+}
+
+/// Buckets which refilled completely are dropped once there are this many.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			server: args.server.clone(),
+			buckets: Mutex::new(HashMap::new()),
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Takes a token from the bucket of `key` for the limit, or errors with
+	/// `M_LIMIT_EXCEEDED` and how long until a token is available.
+	pub fn check(&self, limit: Limit, key: &str) -> Result<()> {
+		let config = &self.server.config.rate_limits;
+		if !config.enabled {
+			return Ok(());
+		}
+
+		let RateLimitConfig {
+			per_second,
+			burst_count,
+		} = match limit {
+			Limit::Registration => &config.registration,
+			Limit::Login => &config.login,
+			Limit::Message => &config.message,
+			Limit::MediaUpload => &config.media_upload,
+			Limit::Federation => &config.federation,
+		};
+
+		// Instead of counting tokens, each bucket stores when it is full again. Every
+		// request pushes that back by the time one token takes to refill, and is
+		// limited if the bucket would need longer than its capacity to refill.
+		let refill = Duration::from_secs_f64(per_second.recip());
+		let capacity = refill.saturating_mul(*burst_count);
+		let now = Instant::now();
+
+		let mut buckets = self.buckets.lock().expect("locked");
+		if buckets.len() >= PRUNE_THRESHOLD {
+			buckets.retain(|_, full_at| *full_at > now);
+		}
+
+		let full_at = buckets.entry((limit, key.to_owned())).or_insert(now);
+		let next_full_at = (*full_at).max(now) + refill;
+		let wait = next_full_at.saturating_duration_since(now);
+		if wait > capacity {
+			return Err(Error::BadRequest(
+				ErrorKind::LimitExceeded {
+					retry_after: Some(RetryAfter::Delay(wait.saturating_sub(capacity))),
+				},
+				"Too many requests, please try again later.",
+			));
+		}
+
+		*full_at = next_full_at;
+
+		Ok(())
+	}
+}
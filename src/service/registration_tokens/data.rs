@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use conduit::{err, utils, Result};
+use database::Map;
+
+use super::TokenInfo;
+
+pub(super) struct Data {
+	registrationtoken_info: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(args: &crate::Args<'_>) -> Self {
+		let db = &args.db;
+		Self {
+			registrationtoken_info: db["registrationtoken_info"].clone(),
+		}
+	}
+
+	pub(super) fn get_token(&self, token: &str) -> Result<Option<TokenInfo>> {
+		self.registrationtoken_info
+			.get(token.as_bytes())?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|e| err!(Database("Invalid token info in registrationtoken_info. {e}")))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_token(&self, token: &str, info: &TokenInfo) -> Result<()> {
+		self.registrationtoken_info.insert(
+			token.as_bytes(),
+			&serde_json::to_vec(info).expect("TokenInfo::to_vec always works"),
+		)
+	}
+
+	pub(super) fn remove_token(&self, token: &str) -> Result<()> {
+		self.registrationtoken_info.remove(token.as_bytes())
+	}
+
+	pub(super) fn iter_tokens<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(String, TokenInfo)>> + 'a> {
+		Box::new(self.registrationtoken_info.iter().map(|(key, value)| {
+			let token = utils::string_from_bytes(&key)
+				.map_err(|e| err!(Database("Token in registrationtoken_info is invalid unicode. {e}")))?;
+			let info = serde_json::from_slice(&value)
+				.map_err(|e| err!(Database("Invalid token info in registrationtoken_info. {e}")))?;
+
+			Ok((token, info))
+		}))
+	}
+}
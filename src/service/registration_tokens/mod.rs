@@ -0,0 +1,205 @@
+mod data;
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use conduit::{utils, Err, Result, Server};
+use data::Data;
+use serde::{Deserialize, Serialize};
+
+/// Registrations which do not finish within this long no longer hold a use of
+/// their token.
+const PENDING_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+const TOKEN_LENGTH: usize = 16;
+
+/// Tokens longer than this are rejected by the spec.
+const TOKEN_MAX_LENGTH: usize = 64;
+
+pub struct Service {
+	server: Arc<Server>,
+	db: Data,
+	pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// A registration token managed in the database.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenInfo {
+	/// How many users can register with the token, unlimited if None.
+	pub uses_allowed: Option<u64>,
+	/// How many users have registered with the token.
+	pub completed: u64,
+	/// How many registrations have validated the token but not finished yet.
+	#[serde(skip)]
+	pub pending: u64,
+	/// When the token stops being valid, in milliseconds since the unix epoch.
+	pub expiry_time: Option<u64>,
+}
+
+/// A registration in progress which validated a token.
+struct Pending {
+	token: String,
+	created: Instant,
+}
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			server: args.server.clone(),
+			db: Data::new(&args),
+			pending: Mutex::new(HashMap::new()),
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Whether new users need a registration token to register.
+	#[must_use]
+	pub fn required(&self) -> bool {
+		let config = &self.server.config;
+		config.registration_token.is_some() || config.registration_requires_token
+	}
+
+	/// Stores a new registration token, generating a random one if none is
+	/// given. Returns the token.
+	pub fn create(
+		&self, token: Option<String>, uses_allowed: Option<u64>, expires_in: Option<Duration>,
+	) -> Result<String> {
+		let token = token.unwrap_or_else(|| utils::random_string(TOKEN_LENGTH));
+
+		if token.is_empty()
+			|| token.len() > TOKEN_MAX_LENGTH
+			|| !token
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '~' | '-'))
+		{
+			return Err!(Request(InvalidParam(
+				"Registration tokens must be 1 to {TOKEN_MAX_LENGTH} characters from [A-Za-z0-9._~-]."
+			)));
+		}
+
+		if self.server.config.registration_token.as_ref() == Some(&token) || self.db.get_token(&token)?.is_some() {
+			return Err!(Request(InvalidParam("Registration token {token:?} already exists.")));
+		}
+
+		let expiry_time = expires_in.map(|expires_in| {
+			let expires_in = u64::try_from(expires_in.as_millis()).unwrap_or(u64::MAX);
+			utils::millis_since_unix_epoch().saturating_add(expires_in)
+		});
+
+		self.db.set_token(
+			&token,
+			&TokenInfo {
+				uses_allowed,
+				expiry_time,
+				..TokenInfo::default()
+			},
+		)?;
+
+		Ok(token)
+	}
+
+	/// Deletes a registration token from the database. Registrations which
+	/// already validated it can still finish.
+	pub fn revoke(&self, token: &str) -> Result<()> {
+		if self.db.get_token(token)?.is_none() {
+			return Err!(Request(NotFound("Registration token {token:?} does not exist.")));
+		}
+
+		self.db.remove_token(token)
+	}
+
+	/// Returns all registration tokens in the database, including ones which
+	/// expired or were used up.
+	pub fn list(&self) -> Result<Vec<(String, TokenInfo)>> {
+		let pending = self.pending.lock().expect("locked");
+
+		self.db
+			.iter_tokens()
+			.map(|result| {
+				result.map(|(token, mut info)| {
+					info.pending = pending_uses(&pending, &token);
+					(token, info)
+				})
+			})
+			.collect()
+	}
+
+	/// Checks whether a token can currently be used to register.
+	pub fn is_valid(&self, token: &str) -> Result<bool> {
+		let pending = self.pending.lock().expect("locked");
+		self.check(token, &pending)
+	}
+
+	/// Validates a token for a registration session and holds one of its uses
+	/// until the registration finishes. Returns false if the token is invalid.
+	pub fn reserve(&self, session: &str, token: &str) -> Result<bool> {
+		let mut pending = self.pending.lock().expect("locked");
+		pending.retain(|_, reservation| reservation.created.elapsed() < PENDING_LIFETIME);
+		pending.remove(session);
+
+		if !self.check(token, &pending)? {
+			return Ok(false);
+		}
+
+		pending.insert(
+			session.to_owned(),
+			Pending {
+				token: token.to_owned(),
+				created: Instant::now(),
+			},
+		);
+
+		Ok(true)
+	}
+
+	/// Counts a finished registration against the token its session reserved.
+	pub fn complete(&self, session: &str) -> Result<()> {
+		let mut pending = self.pending.lock().expect("locked");
+		let Some(reservation) = pending.remove(session) else {
+			return Ok(());
+		};
+
+		if let Some(mut info) = self.db.get_token(&reservation.token)? {
+			info.completed = info.completed.saturating_add(1);
+			self.db.set_token(&reservation.token, &info)?;
+		}
+
+		Ok(())
+	}
+
+	fn check(&self, token: &str, pending: &HashMap<String, Pending>) -> Result<bool> {
+		if self.server.config.registration_token.as_deref() == Some(token) {
+			return Ok(true);
+		}
+
+		let Some(info) = self.db.get_token(token)? else {
+			return Ok(false);
+		};
+
+		if info
+			.expiry_time
+			.is_some_and(|expiry_time| expiry_time < utils::millis_since_unix_epoch())
+		{
+			return Ok(false);
+		}
+
+		Ok(info.uses_allowed.map_or(true, |uses_allowed| {
+			info.completed.saturating_add(pending_uses(pending, token)) < uses_allowed
+		}))
+	}
+}
+
+fn pending_uses(pending: &HashMap<String, Pending>, token: &str) -> u64 {
+	let count = pending
+		.values()
+		.filter(|reservation| reservation.token == token && reservation.created.elapsed() < PENDING_LIFETIME)
+		.count();
+
+	u64::try_from(count).unwrap_or(u64::MAX)
+}
@@ -34,7 +34,7 @@ use ruma::{
 use tokio::sync::RwLock;
 
 use super::state_compressor::CompressedStateEvent;
-use crate::{globals, rooms, sending, server_keys, Dep};
+use crate::{globals, rooms, sending, server_keys, spam_checker, spam_checker::Verdict, Dep};
 
 pub struct Service {
 	services: Services,
@@ -50,6 +50,7 @@ struct Services {
 	outlier: Dep<rooms::outlier::Service>,
 	pdu_metadata: Dep<rooms::pdu_metadata::Service>,
 	server_keys: Dep<server_keys::Service>,
+	spam_checker: Dep<spam_checker::Service>,
 	short: Dep<rooms::short::Service>,
 	state: Dep<rooms::state::Service>,
 	state_accessor: Dep<rooms::state_accessor::Service>,
@@ -78,6 +79,7 @@ impl crate::Service for Service {
 				metadata: args.depend::<rooms::metadata::Service>("rooms::metadata"),
 				outlier: args.depend::<rooms::outlier::Service>("rooms::outlier"),
 				server_keys: args.depend::<server_keys::Service>("server_keys"),
+				spam_checker: args.depend::<spam_checker::Service>("spam_checker"),
 				pdu_metadata: args.depend::<rooms::pdu_metadata::Service>("rooms::pdu_metadata"),
 				short: args.depend::<rooms::short::Service>("rooms::short"),
 				state: args.depend::<rooms::state::Service>("rooms::state"),
@@ -618,6 +620,21 @@ impl Service {
 					}
 		};
 
+		// Events refused by the spam checker are soft failed too, including prev
+		// events, so they never reach the timeline of our users
+		let soft_fail = soft_fail
+			|| match self
+				.services
+				.spam_checker
+				.check_federated_pdu(origin, &incoming_pdu)
+			{
+				Verdict::Allow => false,
+				verdict => {
+					debug_info!(?verdict, "Event {} refused by the spam checker", incoming_pdu.event_id);
+					true
+				},
+			};
+
 		// 13. Use state resolution to find new room state
 
 		// We start looking at current room state now, so lets lock the room
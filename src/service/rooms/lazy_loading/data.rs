@@ -16,28 +16,18 @@ impl Data {
 	}
 
 	pub(super) fn lazy_load_was_sent_before(
-		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, ll_user: &UserId,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId, ll_user: &UserId,
 	) -> Result<bool> {
-		let mut key = user_id.as_bytes().to_vec();
-		key.push(0xFF);
-		key.extend_from_slice(device_id.as_bytes());
-		key.push(0xFF);
-		key.extend_from_slice(room_id.as_bytes());
-		key.push(0xFF);
+		let mut key = prefix(user_id, device_id, stream, room_id);
 		key.extend_from_slice(ll_user.as_bytes());
 		Ok(self.lazyloadedids.get(&key)?.is_some())
 	}
 
 	pub(super) fn lazy_load_confirm_delivery(
-		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId,
 		confirmed_user_ids: &mut dyn Iterator<Item = &UserId>,
 	) -> Result<()> {
-		let mut prefix = user_id.as_bytes().to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(device_id.as_bytes());
-		prefix.push(0xFF);
-		prefix.extend_from_slice(room_id.as_bytes());
-		prefix.push(0xFF);
+		let prefix = prefix(user_id, device_id, stream, room_id);
 
 		for ll_id in confirmed_user_ids {
 			let mut key = prefix.clone();
@@ -48,13 +38,10 @@ impl Data {
 		Ok(())
 	}
 
-	pub(super) fn lazy_load_reset(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
-		let mut prefix = user_id.as_bytes().to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(device_id.as_bytes());
-		prefix.push(0xFF);
-		prefix.extend_from_slice(room_id.as_bytes());
-		prefix.push(0xFF);
+	pub(super) fn lazy_load_reset(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId,
+	) -> Result<()> {
+		let prefix = prefix(user_id, device_id, stream, room_id);
 
 		for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
 			self.lazyloadedids.remove(&key)?;
@@ -63,3 +50,17 @@ impl Data {
 		Ok(())
 	}
 }
+
+/// Key prefix of the members lazy-loaded in a room by one sync stream of a
+/// device. The default stream has an empty name.
+fn prefix(user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId) -> Vec<u8> {
+	let mut prefix = user_id.as_bytes().to_vec();
+	prefix.push(0xFF);
+	prefix.extend_from_slice(device_id.as_bytes());
+	prefix.push(0xFF);
+	prefix.extend_from_slice(stream.unwrap_or_default().as_bytes());
+	prefix.push(0xFF);
+	prefix.extend_from_slice(room_id.as_bytes());
+	prefix.push(0xFF);
+	prefix
+}
@@ -11,13 +11,17 @@ use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use self::data::Data;
 
+/// Tracks which room members were already sent to a sync stream of a device.
+///
+/// Several sync loops may run on one device (e.g. a client and Pantalaimon),
+/// each identified by a stream name; `stream` is None for the default stream.
 pub struct Service {
 	pub lazy_load_waiting: Mutex<LazyLoadWaiting>,
 	db: Data,
 }
 
 type LazyLoadWaiting = HashMap<LazyLoadWaitingKey, LazyLoadWaitingVal>;
-type LazyLoadWaitingKey = (OwnedUserId, OwnedDeviceId, OwnedRoomId, PduCount);
+type LazyLoadWaitingKey = (OwnedUserId, OwnedDeviceId, Option<String>, OwnedRoomId, PduCount);
 type LazyLoadWaitingVal = HashSet<OwnedUserId>;
 
 impl crate::Service for Service {
@@ -43,35 +47,47 @@ impl crate::Service for Service {
 impl Service {
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub fn lazy_load_was_sent_before(
-		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, ll_user: &UserId,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId, ll_user: &UserId,
 	) -> Result<bool> {
 		self.db
-			.lazy_load_was_sent_before(user_id, device_id, room_id, ll_user)
+			.lazy_load_was_sent_before(user_id, device_id, stream, room_id, ll_user)
 	}
 
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub async fn lazy_load_mark_sent(
-		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, lazy_load: HashSet<OwnedUserId>,
-		count: PduCount,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId,
+		lazy_load: HashSet<OwnedUserId>, count: PduCount,
 	) {
-		self.lazy_load_waiting
-			.lock()
-			.expect("locked")
-			.insert((user_id.to_owned(), device_id.to_owned(), room_id.to_owned(), count), lazy_load);
+		self.lazy_load_waiting.lock().expect("locked").insert(
+			(
+				user_id.to_owned(),
+				device_id.to_owned(),
+				stream.map(ToOwned::to_owned),
+				room_id.to_owned(),
+				count,
+			),
+			lazy_load,
+		);
 	}
 
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub async fn lazy_load_confirm_delivery(
-		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, since: PduCount,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId, since: PduCount,
 	) -> Result<()> {
 		if let Some(user_ids) = self.lazy_load_waiting.lock().expect("locked").remove(&(
 			user_id.to_owned(),
 			device_id.to_owned(),
+			stream.map(ToOwned::to_owned),
 			room_id.to_owned(),
 			since,
 		)) {
-			self.db
-				.lazy_load_confirm_delivery(user_id, device_id, room_id, &mut user_ids.iter().map(|u| &**u))?;
+			self.db.lazy_load_confirm_delivery(
+				user_id,
+				device_id,
+				stream,
+				room_id,
+				&mut user_ids.iter().map(|u| &**u),
+			)?;
 		} else {
 			// Ignore
 		}
@@ -80,7 +96,9 @@ impl Service {
 	}
 
 	#[tracing::instrument(skip(self), level = "debug")]
-	pub fn lazy_load_reset(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
-		self.db.lazy_load_reset(user_id, device_id, room_id)
+	pub fn lazy_load_reset(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, room_id: &RoomId,
+	) -> Result<()> {
+		self.db.lazy_load_reset(user_id, device_id, stream, room_id)
 	}
 }
@@ -6,15 +6,23 @@ use conduit::Result;
 use data::Data;
 use ruma::{CanonicalJsonObject, EventId};
 
-use crate::PduEvent;
+use crate::{rooms, Dep, PduEvent};
 
 pub struct Service {
+	services: Services,
 	db: Data,
 }
 
+struct Services {
+	retention: Dep<rooms::retention::Service>,
+}
+
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 		Ok(Arc::new(Self {
+			services: Services {
+				retention: args.depend::<rooms::retention::Service>("rooms::retention"),
+			},
 			db: Data::new(args.db),
 		}))
 	}
@@ -37,6 +45,7 @@ impl Service {
 	/// Append the PDU as an outlier.
 	#[tracing::instrument(skip(self, pdu), level = "debug")]
 	pub fn add_pdu_outlier(&self, event_id: &EventId, pdu: &CanonicalJsonObject) -> Result<()> {
-		self.db.add_pdu_outlier(event_id, pdu)
+		self.db.add_pdu_outlier(event_id, pdu)?;
+		self.services.retention.add_media_references(event_id, pdu)
 	}
 }
@@ -40,6 +40,27 @@ impl Data {
 		Ok(())
 	}
 
+	/// Removes the relations to the event at `count` and from it to each of
+	/// `related`.
+	pub(super) fn remove_relations(&self, count: u64, related: &[u64]) -> Result<()> {
+		let keys: Vec<Vec<u8>> = self
+			.tofrom_relation
+			.scan_prefix(count.to_be_bytes().to_vec())
+			.map(|(key, _)| key)
+			.collect();
+
+		self.tofrom_relation
+			.remove_batch(keys.iter().map(Vec::as_slice))?;
+
+		for target in related {
+			let mut key = target.to_be_bytes().to_vec();
+			key.extend_from_slice(&count.to_be_bytes());
+			self.tofrom_relation.remove(&key)?;
+		}
+
+		Ok(())
+	}
+
 	pub(super) fn relations_until<'a>(
 		&'a self, user_id: &'a UserId, shortroomid: u64, target: u64, until: PduCount,
 	) -> Result<PdusIterator<'a>> {
@@ -99,6 +120,10 @@ impl Data {
 		self.softfailedeventids.insert(event_id.as_bytes(), &[])
 	}
 
+	pub(super) fn unmark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
+		self.softfailedeventids.remove(event_id.as_bytes())
+	}
+
 	pub(super) fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool> {
 		self.softfailedeventids
 			.get(event_id.as_bytes())
@@ -62,6 +62,26 @@ impl Service {
 		}
 	}
 
+	/// Forgets the relations of a deleted event: those of other events to it
+	/// and its own to the `related` events. Events being referenced by it is
+	/// kept, so it does not become a forward extremity if it arrives again.
+	#[tracing::instrument(skip(self), level = "debug")]
+	pub fn purge_pdu(&self, event_id: &EventId, count: PduCount, related: &[PduCount]) -> Result<()> {
+		if let PduCount::Normal(count) = count {
+			let related: Vec<u64> = related
+				.iter()
+				.filter_map(|related| match related {
+					PduCount::Normal(related) => Some(*related),
+					PduCount::Backfilled(_) => None,
+				})
+				.collect();
+
+			self.db.remove_relations(count, &related)?;
+		}
+
+		self.db.unmark_event_soft_failed(event_id)
+	}
+
 	#[allow(clippy::too_many_arguments)]
 	pub fn paginate_relations_with_filter(
 		&self, sender_user: &UserId, room_id: &RoomId, target: &EventId, filter_event_type: &Option<TimelineEventType>,
@@ -0,0 +1,344 @@
+use std::{collections::BTreeSet, mem::size_of, sync::Arc};
+
+use conduit::{utils, Error, Result};
+use database::Map;
+use ruma::{EventId, RoomId};
+
+use super::PurgeJob;
+
+const PURGE_JOB: &[u8] = b"room_purge";
+
+pub(super) struct Data {
+	alias_roomid: Arc<Map>,
+	alias_userid: Arc<Map>,
+	aliasid_alias: Arc<Map>,
+	eventid_outlierpdu: Arc<Map>,
+	eventid_pduid: Arc<Map>,
+	eventid_shorteventid: Arc<Map>,
+	global: Arc<Map>,
+	historypurgedroomids: Arc<Map>,
+	pduid_pdu: Arc<Map>,
+	publicroomids: Arc<Map>,
+	readreceiptid_readreceipt: Arc<Map>,
+	referencedevents: Arc<Map>,
+	roomid_invitedcount: Arc<Map>,
+	roomid_inviteviaservers: Arc<Map>,
+	roomid_joinedcount: Arc<Map>,
+	roomid_pduleaves: Arc<Map>,
+	roomid_shortroomid: Arc<Map>,
+	roomid_shortstatehash: Arc<Map>,
+	roomserverids: Arc<Map>,
+	roomsynctoken_shortstatehash: Arc<Map>,
+	roomuserdataid_accountdata: Arc<Map>,
+	roomuserid_invitecount: Arc<Map>,
+	roomuserid_joined: Arc<Map>,
+	roomuserid_knockedcount: Arc<Map>,
+	roomuserid_lastprivatereadupdate: Arc<Map>,
+	roomuserid_leftcount: Arc<Map>,
+	roomuserid_privateread: Arc<Map>,
+	roomuseroncejoinedids: Arc<Map>,
+	roomusertype_roomuserdataid: Arc<Map>,
+	serverroomids: Arc<Map>,
+	shorteventid_authchain: Arc<Map>,
+	shorteventid_eventid: Arc<Map>,
+	shorteventid_shortstatehash: Arc<Map>,
+	shortroomid_searchlanguage: Arc<Map>,
+	shortstatehash_statediff: Arc<Map>,
+	softfailedeventids: Arc<Map>,
+	statehash_shortstatehash: Arc<Map>,
+	threadid_userids: Arc<Map>,
+	tofrom_relation: Arc<Map>,
+	userroomid_highlightcount: Arc<Map>,
+	userroomid_invitestate: Arc<Map>,
+	userroomid_joined: Arc<Map>,
+	userroomid_knockedstate: Arc<Map>,
+	userroomid_leftstate: Arc<Map>,
+	userroomid_notificationcount: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(args: &crate::Args<'_>) -> Self {
+		let db = &args.db;
+		Self {
+			alias_roomid: db["alias_roomid"].clone(),
+			alias_userid: db["alias_userid"].clone(),
+			aliasid_alias: db["aliasid_alias"].clone(),
+			eventid_outlierpdu: db["eventid_outlierpdu"].clone(),
+			eventid_pduid: db["eventid_pduid"].clone(),
+			eventid_shorteventid: db["eventid_shorteventid"].clone(),
+			global: db["global"].clone(),
+			historypurgedroomids: db["historypurgedroomids"].clone(),
+			pduid_pdu: db["pduid_pdu"].clone(),
+			publicroomids: db["publicroomids"].clone(),
+			readreceiptid_readreceipt: db["readreceiptid_readreceipt"].clone(),
+			referencedevents: db["referencedevents"].clone(),
+			roomid_invitedcount: db["roomid_invitedcount"].clone(),
+			roomid_inviteviaservers: db["roomid_inviteviaservers"].clone(),
+			roomid_joinedcount: db["roomid_joinedcount"].clone(),
+			roomid_pduleaves: db["roomid_pduleaves"].clone(),
+			roomid_shortroomid: db["roomid_shortroomid"].clone(),
+			roomid_shortstatehash: db["roomid_shortstatehash"].clone(),
+			roomserverids: db["roomserverids"].clone(),
+			roomsynctoken_shortstatehash: db["roomsynctoken_shortstatehash"].clone(),
+			roomuserdataid_accountdata: db["roomuserdataid_accountdata"].clone(),
+			roomuserid_invitecount: db["roomuserid_invitecount"].clone(),
+			roomuserid_joined: db["roomuserid_joined"].clone(),
+			roomuserid_knockedcount: db["roomuserid_knockedcount"].clone(),
+			roomuserid_lastprivatereadupdate: db["roomuserid_lastprivatereadupdate"].clone(),
+			roomuserid_leftcount: db["roomuserid_leftcount"].clone(),
+			roomuserid_privateread: db["roomuserid_privateread"].clone(),
+			roomuseroncejoinedids: db["roomuseroncejoinedids"].clone(),
+			roomusertype_roomuserdataid: db["roomusertype_roomuserdataid"].clone(),
+			serverroomids: db["serverroomids"].clone(),
+			shorteventid_authchain: db["shorteventid_authchain"].clone(),
+			shorteventid_eventid: db["shorteventid_eventid"].clone(),
+			shorteventid_shortstatehash: db["shorteventid_shortstatehash"].clone(),
+			shortroomid_searchlanguage: db["shortroomid_searchlanguage"].clone(),
+			shortstatehash_statediff: db["shortstatehash_statediff"].clone(),
+			softfailedeventids: db["softfailedeventids"].clone(),
+			statehash_shortstatehash: db["statehash_shortstatehash"].clone(),
+			threadid_userids: db["threadid_userids"].clone(),
+			tofrom_relation: db["tofrom_relation"].clone(),
+			userroomid_highlightcount: db["userroomid_highlightcount"].clone(),
+			userroomid_invitestate: db["userroomid_invitestate"].clone(),
+			userroomid_joined: db["userroomid_joined"].clone(),
+			userroomid_knockedstate: db["userroomid_knockedstate"].clone(),
+			userroomid_leftstate: db["userroomid_leftstate"].clone(),
+			userroomid_notificationcount: db["userroomid_notificationcount"].clone(),
+		}
+	}
+
+	pub(super) fn purge_job(&self) -> Result<Option<PurgeJob>> {
+		self.global
+			.get(PURGE_JOB)?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes).map_err(|_| Error::bad_database("Invalid room purge job in db."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_purge_job(&self, job: Option<&PurgeJob>) -> Result<()> {
+		match job {
+			Some(job) => self
+				.global
+				.insert(PURGE_JOB, &serde_json::to_vec(job).expect("PurgeJob can be serialized")),
+			None => self.global.remove(PURGE_JOB),
+		}
+	}
+
+	/// Returns up to `limit` timeline PDUs of the room as pdu id and json.
+	pub(super) fn timeline_pdus(&self, shortroomid: u64, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.pduid_pdu
+			.scan_prefix(shortroomid.to_be_bytes().to_vec())
+			.take(limit)
+			.collect()
+	}
+
+	/// Returns the json of an outlier.
+	pub(super) fn outlier_pdu(&self, event_id: &EventId) -> Result<Option<database::Handle<'_>>> {
+		self.eventid_outlierpdu.get(event_id.as_bytes())
+	}
+
+	/// Removes an event from the timeline, outliers, short ids, auth chain
+	/// cache and relations. The state the event was sent in is added to
+	/// `statehashes`.
+	pub(super) fn purge_event(
+		&self, event_id: &EventId, pdu_id: Option<&[u8]>, statehashes: &mut BTreeSet<u64>,
+	) -> Result<()> {
+		if let Some(pdu_id) = pdu_id {
+			// Only events in the timeline proper are related to by their count; see
+			// `timeline::pdu_count`.
+			let stride = size_of::<u64>();
+			if pdu_id.len() == stride * 2 {
+				let count = pdu_id[stride..].to_vec();
+				for (key, _) in self.tofrom_relation.scan_prefix(count) {
+					self.tofrom_relation.remove(&key)?;
+				}
+			}
+
+			self.pduid_pdu.remove(pdu_id)?;
+		}
+
+		if let Some(shorteventid) = self.eventid_shorteventid.get(event_id.as_bytes())? {
+			if let Some(shortstatehash) = self.shorteventid_shortstatehash.get(&shorteventid)? {
+				statehashes.insert(
+					utils::u64_from_bytes(&shortstatehash)
+						.map_err(|_| Error::bad_database("Invalid shortstatehash in db."))?,
+				);
+			}
+
+			self.shorteventid_shortstatehash.remove(&shorteventid)?;
+			self.shorteventid_authchain.remove(&shorteventid)?;
+			self.shorteventid_eventid.remove(&shorteventid)?;
+			self.eventid_shorteventid.remove(event_id.as_bytes())?;
+		}
+
+		self.eventid_pduid.remove(event_id.as_bytes())?;
+		self.eventid_outlierpdu.remove(event_id.as_bytes())?;
+		self.softfailedeventids.remove(event_id.as_bytes())
+	}
+
+	/// Returns the current state of the room and the states sync tokens point
+	/// at.
+	pub(super) fn room_statehashes(&self, room_id: &RoomId, shortroomid: u64) -> Result<BTreeSet<u64>> {
+		let mut statehashes = BTreeSet::new();
+		if let Some(shortstatehash) = self.roomid_shortstatehash.get(room_id.as_bytes())? {
+			statehashes.insert(
+				utils::u64_from_bytes(&shortstatehash)
+					.map_err(|_| Error::bad_database("Invalid shortstatehash in db."))?,
+			);
+		}
+
+		for (_, shortstatehash) in self
+			.roomsynctoken_shortstatehash
+			.scan_prefix(shortroomid.to_be_bytes().to_vec())
+		{
+			statehashes.insert(
+				utils::u64_from_bytes(&shortstatehash)
+					.map_err(|_| Error::bad_database("Invalid shortstatehash in db."))?,
+			);
+		}
+
+		Ok(statehashes)
+	}
+
+	/// Returns the state a compressed state is a diff against, if any.
+	pub(super) fn statediff_parent(&self, shortstatehash: u64) -> Result<Option<u64>> {
+		let Some(value) = self
+			.shortstatehash_statediff
+			.get(&shortstatehash.to_be_bytes())?
+		else {
+			return Ok(None);
+		};
+
+		let parent = utils::u64_from_bytes(&value[0..size_of::<u64>()])
+			.map_err(|_| Error::bad_database("Invalid statehash in db."))?;
+
+		Ok((parent != 0).then_some(parent))
+	}
+
+	/// Removes compressed states and the hashes pointing at them.
+	pub(super) fn purge_statehashes(&self, statehashes: &BTreeSet<u64>) -> Result<()> {
+		for shortstatehash in statehashes {
+			self.shortstatehash_statediff
+				.remove(&shortstatehash.to_be_bytes())?;
+		}
+
+		let hashes: Vec<Vec<u8>> = self
+			.statehash_shortstatehash
+			.iter()
+			.filter(|(_, shortstatehash)| {
+				utils::u64_from_bytes(shortstatehash).is_ok_and(|shortstatehash| statehashes.contains(&shortstatehash))
+			})
+			.map(|(hash, _)| hash)
+			.collect();
+
+		self.statehash_shortstatehash
+			.remove_batch(hashes.iter().map(Vec::as_slice))
+	}
+
+	/// Removes everything left which is keyed by the room: membership, read
+	/// receipts, thread participants, aliases, account data, forward
+	/// extremities and finally its short id.
+	pub(super) fn purge_room(&self, room_id: &RoomId, shortroomid: u64) -> Result<()> {
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		// Users who ever had a membership in the room, to clean up the maps keyed by
+		// user first.
+		let mut users = BTreeSet::new();
+		for map in [
+			&self.roomuserid_joined,
+			&self.roomuserid_invitecount,
+			&self.roomuserid_knockedcount,
+			&self.roomuserid_leftcount,
+			&self.roomuseroncejoinedids,
+		] {
+			for (key, _) in map.scan_prefix(prefix.clone()) {
+				users.insert(key[prefix.len()..].to_vec());
+				map.remove(&key)?;
+			}
+		}
+
+		for user_id in users {
+			let mut userroom_id = user_id;
+			userroom_id.push(0xFF);
+			userroom_id.extend_from_slice(room_id.as_bytes());
+			for map in [
+				&self.userroomid_joined,
+				&self.userroomid_invitestate,
+				&self.userroomid_knockedstate,
+				&self.userroomid_leftstate,
+				&self.userroomid_notificationcount,
+				&self.userroomid_highlightcount,
+			] {
+				map.remove(&userroom_id)?;
+			}
+		}
+
+		for (key, _) in self.roomserverids.scan_prefix(prefix.clone()) {
+			let mut serverroom_id = key[prefix.len()..].to_vec();
+			serverroom_id.push(0xFF);
+			serverroom_id.extend_from_slice(room_id.as_bytes());
+			self.serverroomids.remove(&serverroom_id)?;
+			self.roomserverids.remove(&key)?;
+		}
+
+		for (key, alias) in self.aliasid_alias.scan_prefix(prefix.clone()) {
+			self.alias_roomid.remove(&alias)?;
+			self.alias_userid.remove(&alias)?;
+			self.aliasid_alias.remove(&key)?;
+		}
+
+		for map in [
+			&self.readreceiptid_readreceipt,
+			&self.roomuserid_privateread,
+			&self.roomuserid_lastprivatereadupdate,
+			&self.roomid_pduleaves,
+			&self.roomuserdataid_accountdata,
+			&self.roomusertype_roomuserdataid,
+			// roomuserid_lastnotificationread is stored in here, see rooms::user
+			&self.userroomid_highlightcount,
+		] {
+			for (key, _) in map.scan_prefix(prefix.clone()) {
+				map.remove(&key)?;
+			}
+		}
+
+		// Keys are the room id immediately followed by the event id.
+		let mut referenced = room_id.as_bytes().to_vec();
+		referenced.push(b'$');
+		for (key, _) in self.referencedevents.scan_prefix(referenced) {
+			self.referencedevents.remove(&key)?;
+		}
+
+		for (key, _) in self
+			.threadid_userids
+			.scan_prefix(shortroomid.to_be_bytes().to_vec())
+		{
+			self.threadid_userids.remove(&key)?;
+		}
+
+		for map in [
+			&self.historypurgedroomids,
+			&self.roomid_invitedcount,
+			&self.roomid_inviteviaservers,
+			&self.roomid_joinedcount,
+			&self.roomid_shortstatehash,
+			&self.publicroomids,
+		] {
+			map.remove(room_id.as_bytes())?;
+		}
+
+		for (key, _) in self
+			.roomsynctoken_shortstatehash
+			.scan_prefix(shortroomid.to_be_bytes().to_vec())
+		{
+			self.roomsynctoken_shortstatehash.remove(&key)?;
+		}
+
+		self.shortroomid_searchlanguage
+			.remove(&shortroomid.to_be_bytes())?;
+		self.roomid_shortroomid.remove(room_id.as_bytes())
+	}
+}
@@ -0,0 +1,395 @@
+mod data;
+
+use std::{
+	collections::{BTreeSet, VecDeque},
+	sync::Arc,
+};
+
+use async_trait::async_trait;
+use conduit::{err, error, info, utils, warn, Err, PduEvent, Result, Server};
+use data::Data;
+use ruma::{OwnedEventId, OwnedRoomId, RoomId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::{admin, rooms, Dep, Service as _};
+
+/// Number of events deleted between two saves of the job progress.
+const PURGE_BATCH: usize = 500;
+
+pub struct Service {
+	db: Data,
+	services: Services,
+	server: Arc<Server>,
+	purge_lock: Mutex<()>,
+	purge_queued: Notify,
+	interrupt: Notify,
+}
+
+struct Services {
+	admin: Dep<admin::Service>,
+	auth_chain: Dep<rooms::auth_chain::Service>,
+	event_handler: Dep<rooms::event_handler::Service>,
+	metadata: Dep<rooms::metadata::Service>,
+	search: Dep<rooms::search::Service>,
+	short: Dep<rooms::short::Service>,
+	state: Dep<rooms::state::Service>,
+	state_accessor: Dep<rooms::state_accessor::Service>,
+	state_cache: Dep<rooms::state_cache::Service>,
+	state_compressor: Dep<rooms::state_compressor::Service>,
+	timeline: Dep<rooms::timeline::Service>,
+}
+
+/// Progress of deleting rooms from the database, persisted so the job resumes
+/// where it stopped after a restart.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PurgeJob {
+	/// Rooms left to purge; the first one is in progress.
+	pub rooms: VecDeque<OwnedRoomId>,
+
+	/// What is being deleted of the room in progress.
+	pub stage: PurgeStage,
+
+	/// Events referenced by the room in progress which may still be stored as
+	/// outliers.
+	#[serde(default)]
+	pub outliers: BTreeSet<OwnedEventId>,
+
+	/// Compressed states of the room in progress found so far.
+	pub statehashes: BTreeSet<u64>,
+
+	pub rooms_total: usize,
+	pub rooms_done: usize,
+
+	/// Rooms which could not be purged, with the error. They are partially
+	/// deleted and can be queued again.
+	#[serde(default)]
+	pub rooms_failed: Vec<(OwnedRoomId, String)>,
+
+	pub events_purged: usize,
+
+	/// When the job was first queued, in milliseconds since the unix epoch.
+	pub started: u64,
+}
+
+/// The stages a room is purged in, in order.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PurgeStage {
+	/// Timeline events, in batches.
+	#[default]
+	Timeline,
+
+	/// Outliers such as auth events, in batches following the auth and prev
+	/// events of the room's events and its current state.
+	Outliers,
+
+	/// Compressed states.
+	State,
+
+	/// Membership, receipts, aliases and whatever else is keyed by the room.
+	Room,
+}
+
+#[derive(Deserialize)]
+struct ExtractOutlier {
+	room_id: OwnedRoomId,
+	#[serde(default)]
+	auth_events: Vec<OwnedEventId>,
+	#[serde(default)]
+	prev_events: Vec<OwnedEventId>,
+}
+
+#[async_trait]
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			db: Data::new(&args),
+			server: args.server.clone(),
+			purge_lock: Mutex::new(()),
+			purge_queued: Notify::new(),
+			interrupt: Notify::new(),
+			services: Services {
+				admin: args.depend::<admin::Service>("admin"),
+				auth_chain: args.depend::<rooms::auth_chain::Service>("rooms::auth_chain"),
+				event_handler: args.depend::<rooms::event_handler::Service>("rooms::event_handler"),
+				metadata: args.depend::<rooms::metadata::Service>("rooms::metadata"),
+				search: args.depend::<rooms::search::Service>("rooms::search"),
+				short: args.depend::<rooms::short::Service>("rooms::short"),
+				state: args.depend::<rooms::state::Service>("rooms::state"),
+				state_accessor: args.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
+				state_cache: args.depend::<rooms::state_cache::Service>("rooms::state_cache"),
+				state_compressor: args.depend::<rooms::state_compressor::Service>("rooms::state_compressor"),
+				timeline: args.depend::<rooms::timeline::Service>("rooms::timeline"),
+			},
+		}))
+	}
+
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		loop {
+			while self.server.running() {
+				match self.purge_step().await {
+					Ok(true) => tokio::task::yield_now().await,
+					Ok(false) => break,
+					Err(e) => {
+						error!("Room purge failed: {e}");
+						break;
+					},
+				}
+			}
+
+			if !self.server.running() {
+				return Ok(());
+			}
+
+			tokio::select! {
+				() = self.interrupt.notified() => return Ok(()),
+				() = self.purge_queued.notified() => {},
+			}
+		}
+	}
+
+	fn interrupt(&self) { self.interrupt.notify_waiters(); }
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Queues a room to be deleted from the database in the background.
+	///
+	/// Local users must have left the room. Federation is disabled for the room
+	/// right away so no new events arrive while it is purged; the room stays
+	/// disabled afterwards.
+	pub async fn purge(&self, room_id: OwnedRoomId) -> Result<PurgeJob> {
+		if self.services.admin.is_admin_room(&room_id) {
+			return Err!(Request(Forbidden("The admin room cannot be purged.")));
+		}
+
+		if self
+			.services
+			.state_cache
+			.local_users_in_room(&room_id)
+			.next()
+			.is_some()
+		{
+			return Err!(Request(Forbidden(
+				"Local users are still joined to the room, evict them first with `rooms moderation ban-room`."
+			)));
+		}
+
+		self.services.metadata.disable_room(&room_id, true)?;
+
+		let _lock = self.purge_lock.lock().await;
+		let mut job = self.db.purge_job()?.unwrap_or_else(|| PurgeJob {
+			started: utils::millis_since_unix_epoch(),
+			..PurgeJob::default()
+		});
+
+		job.rooms_failed.retain(|(failed, _)| *failed != room_id);
+		if !job.rooms.contains(&room_id) {
+			job.rooms.push_back(room_id);
+			job.rooms_total = job.rooms_total.saturating_add(1);
+		}
+
+		self.db.set_purge_job(Some(&job))?;
+		self.purge_queued.notify_one();
+
+		Ok(job)
+	}
+
+	/// Returns the progress of the purge job if one is running.
+	pub fn purge_job(&self) -> Result<Option<PurgeJob>> { self.db.purge_job() }
+
+	/// Runs the next batch of the purge job, returning whether any work is
+	/// left.
+	async fn purge_step(&self) -> Result<bool> {
+		let Some(room_id) = self
+			.db
+			.purge_job()?
+			.and_then(|job| job.rooms.front().cloned())
+		else {
+			let _lock = self.purge_lock.lock().await;
+			if self.db.purge_job()?.is_some() {
+				self.db.set_purge_job(None)?;
+			}
+
+			return Ok(false);
+		};
+
+		// Keep incoming federation and local sends out of the room while deleting.
+		let _federation_lock = self
+			.services
+			.event_handler
+			.mutex_federation
+			.lock(&room_id)
+			.await;
+		let _state_lock = self.services.state.mutex.lock(&room_id).await;
+		let _lock = self.purge_lock.lock().await;
+
+		let Some(mut job) = self.db.purge_job()? else {
+			return Ok(false);
+		};
+
+		if job.rooms.front() == Some(&room_id) {
+			match self.purge_batch(&mut job, &room_id).await {
+				Ok(false) => {},
+				Ok(true) => {
+					self.clear_caches();
+					Self::next_room(&mut job);
+					job.rooms_done = job.rooms_done.saturating_add(1);
+
+					info!(%room_id, "Purged room from the database");
+					self.services
+						.admin
+						.send_text(&format!(
+							"Purged {room_id} from the database, {}/{} rooms done.",
+							job.rooms_done, job.rooms_total
+						))
+						.await;
+				},
+				Err(e) => {
+					// Part of the room may be gone already, caches must not keep serving it
+					self.clear_caches();
+					Self::next_room(&mut job);
+					job.rooms_failed.push((room_id.clone(), e.to_string()));
+
+					warn!(%room_id, "Failed to purge room: {e}");
+					self.services
+						.admin
+						.send_text(&format!(
+							"Failed to purge {room_id}, it is partially deleted: {e}. Purge it again to retry once \
+							 the cause is fixed."
+						))
+						.await;
+				},
+			}
+		}
+
+		if job.rooms.is_empty() {
+			info!(
+				"Room purge of {} rooms finished, {} failed, {} events deleted",
+				job.rooms_done,
+				job.rooms_failed.len(),
+				job.events_purged
+			);
+
+			self.db.set_purge_job(None)?;
+			return Ok(false);
+		}
+
+		self.db.set_purge_job(Some(&job))?;
+
+		Ok(true)
+	}
+
+	/// Moves the job on from the room in progress.
+	fn next_room(job: &mut PurgeJob) {
+		job.rooms.pop_front();
+		job.stage = PurgeStage::default();
+		job.outliers.clear();
+		job.statehashes.clear();
+	}
+
+	/// Deletes the next batch of the room, returning whether the room is gone.
+	async fn purge_batch(&self, job: &mut PurgeJob, room_id: &RoomId) -> Result<bool> {
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(true);
+		};
+
+		match job.stage {
+			PurgeStage::Timeline => {
+				let pdus = self.db.timeline_pdus(shortroomid, PURGE_BATCH);
+				if pdus.is_empty() {
+					// The state of the room may include events only stored as outliers
+					if let Some(shortstatehash) = self.services.state.get_room_shortstatehash(room_id)? {
+						let state = self
+							.services
+							.state_accessor
+							.state_full_ids(shortstatehash)
+							.await?;
+						job.outliers
+							.extend(state.into_values().map(|event_id| (*event_id).to_owned()));
+					}
+
+					job.stage = PurgeStage::Outliers;
+					self.services.search.deindex_room(shortroomid)?;
+				}
+
+				for (pdu_id, json) in pdus {
+					let pdu: PduEvent =
+						serde_json::from_slice(&json).map_err(|e| err!(Database("Invalid PDU in db: {e}")))?;
+					job.outliers.extend(
+						pdu.auth_events
+							.iter()
+							.chain(&pdu.prev_events)
+							.map(|event_id| (**event_id).to_owned()),
+					);
+					self.db
+						.purge_event(&pdu.event_id, Some(&pdu_id), &mut job.statehashes)?;
+					job.events_purged = job.events_purged.saturating_add(1);
+				}
+			},
+
+			PurgeStage::Outliers => {
+				if job.outliers.is_empty() {
+					job.stage = PurgeStage::State;
+				}
+
+				for _ in 0..PURGE_BATCH {
+					let Some(event_id) = job.outliers.pop_first() else {
+						break;
+					};
+
+					// Events of other rooms and events already deleted are left alone; the
+					// latter also ends the walk through the event graph.
+					let Some(json) = self.db.outlier_pdu(&event_id)? else {
+						continue;
+					};
+					let Ok(outlier) = serde_json::from_slice::<ExtractOutlier>(&json) else {
+						continue;
+					};
+					if outlier.room_id != room_id {
+						continue;
+					}
+
+					self.db.purge_event(&event_id, None, &mut job.statehashes)?;
+					job.events_purged = job.events_purged.saturating_add(1);
+					job.outliers
+						.extend(outlier.auth_events.into_iter().chain(outlier.prev_events));
+				}
+			},
+
+			PurgeStage::State => {
+				let mut pending: Vec<u64> = job.statehashes.iter().copied().collect();
+				pending.extend(self.db.room_statehashes(room_id, shortroomid)?);
+				while let Some(shortstatehash) = pending.pop() {
+					job.statehashes.insert(shortstatehash);
+					if let Some(parent) = self.db.statediff_parent(shortstatehash)? {
+						if !job.statehashes.contains(&parent) {
+							pending.push(parent);
+						}
+					}
+				}
+
+				self.db.purge_statehashes(&job.statehashes)?;
+				job.stage = PurgeStage::Room;
+			},
+
+			PurgeStage::Room => {
+				self.db.purge_room(room_id, shortroomid)?;
+				return Ok(true);
+			},
+		}
+
+		Ok(false)
+	}
+
+	/// Drops cached state, auth chains and timeline counts which may still
+	/// refer to the purged room.
+	fn clear_caches(&self) {
+		self.services.auth_chain.clear_cache();
+		self.services.state_accessor.clear_cache();
+		self.services.state_cache.clear_appservice_in_room_cache();
+		self.services.state_compressor.clear_cache();
+		self.services.timeline.clear_cache();
+	}
+}
@@ -6,14 +6,14 @@ use conduit::{debug, Result};
 use data::Data;
 use ruma::{
 	events::{
-		receipt::{ReceiptEvent, ReceiptEventContent},
+		receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
 		AnySyncEphemeralRoomEvent, SyncEphemeralRoomEvent,
 	},
 	serde::Raw,
 	OwnedUserId, RoomId, UserId,
 };
 
-use crate::{sending, Dep};
+use crate::{sending, sending::AppserviceEdu, Dep};
 
 pub struct Service {
 	services: Services,
@@ -46,6 +46,21 @@ impl Service {
 		self.db.readreceipt_update(user_id, room_id, event)?;
 		self.services.sending.flush_room(room_id)?;
 
+		// private receipts are not for appservices either
+		let mut event = event.clone();
+		for receipts in event.content.0.values_mut() {
+			receipts.remove(&ReceiptType::ReadPrivate);
+		}
+		event.content.0.retain(|_, receipts| !receipts.is_empty());
+		if !event.content.0.is_empty() {
+			self.services
+				.sending
+				.send_edu_appservices(&AppserviceEdu::Room(
+					room_id.to_owned(),
+					serde_json::to_value(event).expect("ReceiptEvent can be serialized"),
+				))?;
+		}
+
 		Ok(())
 	}
 
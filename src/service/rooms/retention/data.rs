@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use conduit::{utils, Result};
+use database::{Database, Map};
+use ruma::{EventId, MxcUri, OwnedEventId};
+
+pub(super) struct Data {
+	mediaid_eventid: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			mediaid_eventid: db["mediaid_eventid"].clone(),
+		}
+	}
+
+	/// Records that an event refers to a media.
+	pub(super) fn add_reference(&self, mxc: &MxcUri, event_id: &EventId) -> Result<()> {
+		self.mediaid_eventid
+			.insert(&reference_key(mxc, event_id), &[])
+	}
+
+	pub(super) fn remove_reference(&self, mxc: &MxcUri, event_id: &EventId) -> Result<()> {
+		self.mediaid_eventid.remove(&reference_key(mxc, event_id))
+	}
+
+	/// Returns the events recorded to refer to a media. They may have been
+	/// purged or redacted since.
+	pub(super) fn referencing_events<'a>(&'a self, mxc: &MxcUri) -> impl Iterator<Item = OwnedEventId> + 'a {
+		let mut prefix = mxc.as_str().as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		self.mediaid_eventid
+			.scan_prefix(prefix)
+			.filter_map(|(key, _)| {
+				let event_id = key.rsplit(|&b| b == 0xFF).next()?;
+				utils::string_from_bytes(event_id)
+					.ok()
+					.and_then(|event_id| event_id.try_into().ok())
+			})
+	}
+}
+
+fn reference_key(mxc: &MxcUri, event_id: &EventId) -> Vec<u8> {
+	let mut key = mxc.as_str().as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(event_id.as_bytes());
+	key
+}
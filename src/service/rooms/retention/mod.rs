@@ -0,0 +1,288 @@
+mod data;
+mod tests;
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use conduit::{config::RetentionConfig, debug, debug_warn, info, utils, warn, Result, Server};
+use data::Data;
+use ruma::{events::StateEventType, CanonicalJsonObject, CanonicalJsonValue, EventId, Mxc, OwnedMxcUri, RoomId};
+use serde::Deserialize;
+use tokio::{sync::Notify, time::interval};
+
+use crate::{globals, media, rooms, rooms::timeline::HistoryCutoff, users, Dep};
+
+pub struct Service {
+	services: Services,
+	db: Data,
+	server: Arc<Server>,
+	interrupt: Notify,
+}
+
+struct Services {
+	globals: Dep<globals::Service>,
+	media: Dep<media::Service>,
+	metadata: Dep<rooms::metadata::Service>,
+	state_accessor: Dep<rooms::state_accessor::Service>,
+	timeline: Dep<rooms::timeline::Service>,
+	users: Dep<users::Service>,
+}
+
+/// Content of `m.room.retention`; lifetimes are in milliseconds.
+#[derive(Deserialize)]
+struct RoomRetentionEventContent {
+	max_lifetime: Option<u64>,
+}
+
+#[async_trait]
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			services: Services {
+				globals: args.depend::<globals::Service>("globals"),
+				media: args.depend::<media::Service>("media"),
+				metadata: args.depend::<rooms::metadata::Service>("rooms::metadata"),
+				state_accessor: args.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
+				timeline: args.depend::<rooms::timeline::Service>("rooms::timeline"),
+				users: args.depend::<users::Service>("users"),
+			},
+			db: Data::new(args.db),
+			server: args.server.clone(),
+			interrupt: Notify::new(),
+		}))
+	}
+
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		let config = &self.server.config.retention;
+		if !config.enabled {
+			return Ok(());
+		}
+
+		let mut i = interval(Duration::from_secs(config.purge_interval));
+		loop {
+			tokio::select! {
+				() = self.interrupt.notified() => return Ok(()),
+				_ = i.tick() => (),
+			}
+
+			if let Err(e) = self.purge_expired().await {
+				warn!(%e, "Failed to purge expired events");
+			}
+		}
+	}
+
+	fn interrupt(&self) { self.interrupt.notify_waiters(); }
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Returns how long events of the room are kept in milliseconds, or `None`
+	/// if forever.
+	pub fn max_lifetime(&self, room_id: &RoomId) -> Result<Option<u64>> {
+		let config = &self.server.config.retention;
+		if !config.enabled {
+			return Ok(None);
+		}
+
+		let room_lifetime = self
+			.services
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::from("m.room.retention"), "")?
+			.and_then(|event| serde_json::from_str::<RoomRetentionEventContent>(event.content.get()).ok())
+			.and_then(|content| content.max_lifetime);
+
+		Ok(lifetime(config, room_lifetime))
+	}
+
+	/// Records the local media an event refers to, so it is not deleted with
+	/// the expired events referring to it as well.
+	pub fn add_media_references(&self, event_id: &EventId, pdu_json: &CanonicalJsonObject) -> Result<()> {
+		let Some(content) = pdu_json.get("content") else {
+			return Ok(());
+		};
+
+		for mxc in self.local_media(content) {
+			self.db.add_reference(&mxc, event_id)?;
+		}
+
+		Ok(())
+	}
+
+	/// Purges the expired events of every room.
+	#[tracing::instrument(skip_all)]
+	async fn purge_expired(&self) -> Result<()> {
+		let mut purged: usize = 0;
+		let mut media = BTreeSet::new();
+		for room_id in self.services.metadata.iter_ids() {
+			if !self.server.running() {
+				break;
+			}
+
+			let room_id = room_id?;
+			let Some(max_lifetime) = self.max_lifetime(&room_id)? else {
+				continue;
+			};
+
+			let before = utils::millis_since_unix_epoch().saturating_sub(max_lifetime);
+			match self.purge_room(&room_id, before, &mut media).await {
+				Ok(count) => purged = purged.saturating_add(count),
+				Err(e) => warn!(%room_id, "Failed to purge expired events: {e}"),
+			}
+		}
+
+		if purged > 0 {
+			info!("Purged {purged} expired events");
+		}
+
+		if !media.is_empty() {
+			self.delete_unreferenced_media(media).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Deletes the non-state events of a room sent before `before`, in
+	/// milliseconds since the unix epoch, adding the local media they
+	/// referenced to `media`.
+	async fn purge_room(&self, room_id: &RoomId, before: u64, media: &mut BTreeSet<OwnedMxcUri>) -> Result<usize> {
+		let mut purged = Vec::new();
+		let count = self
+			.services
+			.timeline
+			.purge_history(room_id, HistoryCutoff::Timestamp(before), |pdu| {
+				let Ok(content) = serde_json::from_str(pdu.content.get()) else {
+					return;
+				};
+
+				for mxc in self.local_media(&content) {
+					purged.push((mxc, pdu.event_id.clone()));
+				}
+			})
+			.await?;
+
+		for (mxc, event_id) in purged {
+			self.db.remove_reference(&mxc, &event_id)?;
+			media.insert(mxc);
+		}
+
+		Ok(count)
+	}
+
+	/// Deletes the media of expired events which nothing left refers to: the
+	/// events recorded to refer to it which still do and the avatars of users.
+	async fn delete_unreferenced_media(&self, mut mxcs: BTreeSet<OwnedMxcUri>) -> Result<()> {
+		let mut referenced = Vec::new();
+		for mxc in &mxcs {
+			for event_id in self.db.referencing_events(mxc) {
+				if self.refers_to(&event_id, mxc)? {
+					referenced.push(mxc.clone());
+					break;
+				}
+
+				// Purged or redacted since
+				self.db.remove_reference(mxc, &event_id)?;
+			}
+		}
+
+		for mxc in &referenced {
+			mxcs.remove(mxc);
+		}
+
+		if mxcs.is_empty() {
+			return Ok(());
+		}
+
+		for user_id in self.services.users.iter() {
+			if let Some(avatar_url) = self.services.users.avatar_url(&user_id?)? {
+				mxcs.remove(&avatar_url);
+			}
+		}
+
+		for mxc in mxcs {
+			let Ok((server_name, media_id)) = mxc.parts() else {
+				continue;
+			};
+
+			let mxc = Mxc {
+				server_name,
+				media_id,
+			};
+
+			debug!("Deleting expired media {mxc}");
+			if let Err(e) = self.services.media.delete(&mxc).await {
+				debug_warn!("Failed to delete expired media {mxc}: {e}");
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Whether an event of the timeline or an outlier still refers to a media.
+	fn refers_to(&self, event_id: &EventId, mxc: &OwnedMxcUri) -> Result<bool> {
+		Ok(self
+			.services
+			.timeline
+			.get_pdu_json(event_id)?
+			.and_then(|pdu_json| pdu_json.get("content").map(referenced_media))
+			.is_some_and(|media| media.contains(mxc)))
+	}
+
+	/// Returns the media of this server event content refers to.
+	fn local_media(&self, content: &CanonicalJsonValue) -> Vec<OwnedMxcUri> {
+		let globals = &self.services.globals;
+		referenced_media(content)
+			.into_iter()
+			.filter(|mxc| {
+				mxc.server_name()
+					.is_ok_and(|server| globals.server_is_ours(server))
+			})
+			.collect()
+	}
+}
+
+/// Returns how long events are kept in milliseconds, or `None` if forever:
+/// the `max_lifetime` of the room, or else the configured default, clamped to
+/// the allowed bounds.
+fn lifetime(config: &RetentionConfig, room_lifetime: Option<u64>) -> Option<u64> {
+	let lifetime = room_lifetime.or_else(|| {
+		config
+			.default_max_lifetime
+			.map(|secs| secs.saturating_mul(1000))
+	})?;
+
+	let min = config.allowed_lifetime_min.saturating_mul(1000);
+	let max = config
+		.allowed_lifetime_max
+		.map_or(u64::MAX, |secs| secs.saturating_mul(1000));
+
+	Some(lifetime.clamp(min, max.max(min)))
+}
+
+/// Returns the media event content links to, such as the file of an `m.image`
+/// and its thumbnail.
+fn referenced_media(content: &CanonicalJsonValue) -> Vec<OwnedMxcUri> {
+	fn collect(value: &CanonicalJsonValue, mxcs: &mut Vec<OwnedMxcUri>) {
+		match value {
+			CanonicalJsonValue::Object(object) => {
+				for (key, value) in object {
+					match value {
+						CanonicalJsonValue::String(url) if key == "url" || key == "thumbnail_url" => {
+							if url.starts_with("mxc://") {
+								mxcs.push(url.as_str().into());
+							}
+						},
+						value => collect(value, mxcs),
+					}
+				}
+			},
+			CanonicalJsonValue::Array(values) => values.iter().for_each(|value| collect(value, mxcs)),
+			_ => {},
+		}
+	}
+
+	let mut mxcs = Vec::new();
+	collect(content, &mut mxcs);
+
+	mxcs
+}
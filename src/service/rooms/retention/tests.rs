@@ -0,0 +1,45 @@
+#![cfg(test)]
+
+use conduit::config::RetentionConfig;
+
+use super::lifetime;
+
+const DAY_MS: u64 = 86_400_000;
+
+fn config(default_max_lifetime: Option<u64>, allowed_lifetime_max: Option<u64>) -> RetentionConfig {
+	RetentionConfig {
+		enabled: true,
+		default_max_lifetime,
+		allowed_lifetime_max,
+		..RetentionConfig::default()
+	}
+}
+
+#[test]
+fn room_lifetime_is_clamped() {
+	let config = config(None, Some(2_592_000));
+
+	assert_eq!(lifetime(&config, Some(604_800_000)), Some(604_800_000));
+	assert_eq!(lifetime(&config, Some(31_536_000_000)), Some(2_592_000_000));
+}
+
+#[test]
+fn room_lifetime_has_a_floor_by_default() {
+	let config = config(None, None);
+
+	assert_eq!(lifetime(&config, Some(0)), Some(DAY_MS), "expired right away");
+	assert_eq!(lifetime(&config, Some(1000)), Some(DAY_MS));
+	assert_eq!(lifetime(&config, Some(u64::MAX)), Some(u64::MAX));
+}
+
+#[test]
+fn default_lifetime_applies_without_room_policy() {
+	assert_eq!(lifetime(&config(None, None), None), None, "kept forever");
+	assert_eq!(lifetime(&config(Some(604_800), None), None), Some(604_800_000));
+}
+
+#[test]
+fn default_lifetime_is_clamped() {
+	assert_eq!(lifetime(&config(Some(7_776_000), Some(2_592_000)), None), Some(2_592_000_000));
+	assert_eq!(lifetime(&config(Some(0), None), None), Some(DAY_MS), "expired right away");
+}
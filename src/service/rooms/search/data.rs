@@ -1,20 +1,27 @@
-use std::sync::Arc;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	mem::size_of,
+	sync::{Arc, Mutex},
+};
 
-use conduit::{utils, Result};
+use conduit::{utils, Error, Result};
 use database::Map;
-use ruma::RoomId;
 
-use crate::{rooms, Dep};
+use super::{IndexStats, ReindexJob};
 
-type SearchPdusResult<'a> = Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>>;
+const REINDEX_JOB: &[u8] = b"search_reindex";
+
+/// Posting list of a single search term: matching pdu ids and the number of
+/// times the term occurs in each of them.
+pub(super) type Postings = BTreeMap<Vec<u8>, u32>;
 
 pub(super) struct Data {
 	tokenids: Arc<Map>,
-	services: Services,
-}
-
-struct Services {
-	short: Dep<rooms::short::Service>,
+	searchpduids: Arc<Map>,
+	shortroomid_searchcount: Arc<Map>,
+	shortroomid_searchlanguage: Arc<Map>,
+	global: Arc<Map>,
+	count_lock: Mutex<()>,
 }
 
 impl Data {
@@ -22,87 +29,280 @@ impl Data {
 		let db = &args.db;
 		Self {
 			tokenids: db["tokenids"].clone(),
-			services: Services {
-				short: args.depend::<rooms::short::Service>("rooms::short"),
-			},
+			searchpduids: db["searchpduids"].clone(),
+			shortroomid_searchcount: db["shortroomid_searchcount"].clone(),
+			shortroomid_searchlanguage: db["shortroomid_searchlanguage"].clone(),
+			global: db["global"].clone(),
+			count_lock: Mutex::new(()),
 		}
 	}
 
-	pub(super) fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		let batch = tokenize(message_body)
-			.map(|word| {
+	pub(super) fn index_pdu<I>(
+		&self, shortroomid: u64, pdu_id: &[u8], origin_server_ts: u64, language: Option<&str>, tokens: I,
+	) -> Result<()>
+	where
+		I: Iterator<Item = String>,
+	{
+		let batch = term_frequencies(tokens)
+			.into_iter()
+			.map(|(word, frequency)| {
 				let mut key = shortroomid.to_be_bytes().to_vec();
 				key.extend_from_slice(word.as_bytes());
 				key.push(0xFF);
 				key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
-				(key, Vec::<u8>::new())
+				(key, frequency.to_be_bytes().to_vec())
 			})
 			.collect::<Vec<_>>();
 
 		self.tokenids
-			.insert_batch(batch.iter().map(database::KeyVal::from))
+			.insert_batch(batch.iter().map(database::KeyVal::from))?;
+
+		let _lock = self.count_lock.lock().expect("locked");
+		if self.searchpduids.get(pdu_id)?.is_none() {
+			let count = self.document_count(shortroomid)?.saturating_add(1);
+			self.set_document_count(shortroomid, count)?;
+		}
+
+		let mut value = origin_server_ts.to_be_bytes().to_vec();
+		value.extend_from_slice(language.unwrap_or_default().as_bytes());
+
+		self.searchpduids.insert(pdu_id, &value)
 	}
 
-	pub(super) fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		let batch = tokenize(message_body).map(|word| {
-			let mut key = shortroomid.to_be_bytes().to_vec();
-			key.extend_from_slice(word.as_bytes());
-			key.push(0xFF);
-			key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
-			key
-		});
+	pub(super) fn deindex_pdu<I>(&self, shortroomid: u64, pdu_id: &[u8], tokens: I) -> Result<()>
+	where
+		I: Iterator<Item = String>,
+	{
+		let batch = term_frequencies(tokens)
+			.into_keys()
+			.map(|word| {
+				let mut key = shortroomid.to_be_bytes().to_vec();
+				key.extend_from_slice(word.as_bytes());
+				key.push(0xFF);
+				key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
+				key
+			})
+			.collect::<Vec<_>>();
+
+		self.tokenids
+			.remove_batch(batch.iter().map(Vec::as_slice))?;
 
-		for token in batch {
-			self.tokenids.remove(&token)?;
+		let _lock = self.count_lock.lock().expect("locked");
+		if self.searchpduids.get(pdu_id)?.is_some() {
+			let count = self.document_count(shortroomid)?.saturating_sub(1);
+			self.set_document_count(shortroomid, count)?;
+			self.searchpduids.remove(pdu_id)?;
 		}
 
 		Ok(())
 	}
 
-	pub(super) fn search_pdus<'a>(&'a self, room_id: &RoomId, search_string: &str) -> SearchPdusResult<'a> {
-		let prefix = self
-			.services
-			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists")
-			.to_be_bytes()
-			.to_vec();
-
-		let words: Vec<_> = tokenize(search_string).collect();
-
-		let iterators = words.clone().into_iter().map(move |word| {
-			let mut prefix2 = prefix.clone();
-			prefix2.extend_from_slice(word.as_bytes());
-			prefix2.push(0xFF);
-			let prefix3 = prefix2.clone();
-
-			let mut last_possible_id = prefix2.clone();
-			last_possible_id.extend_from_slice(&u64::MAX.to_be_bytes());
-
-			self.tokenids
-				.iter_from(&last_possible_id, true) // Newest pdus first
-				.take_while(move |(k, _)| k.starts_with(&prefix2))
-				.map(move |(key, _)| key[prefix3.len()..].to_vec())
-		});
-
-		let Some(common_elements) = utils::common_elements(iterators, |a, b| {
-			// We compare b with a because we reversed the iterator earlier
-			b.cmp(a)
-		}) else {
-			return Ok(None);
+	/// Removes every entry of a room from the index.
+	pub(super) fn deindex_room(&self, shortroomid: u64) -> Result<()> {
+		let prefix = shortroomid.to_be_bytes().to_vec();
+		let keys: Vec<Vec<u8>> = self
+			.tokenids
+			.scan_prefix(prefix.clone())
+			.map(|(key, _)| key)
+			.collect();
+
+		self.tokenids.remove_batch(keys.iter().map(Vec::as_slice))?;
+
+		let _lock = self.count_lock.lock().expect("locked");
+		let pdu_ids: Vec<Vec<u8>> = self
+			.searchpduids
+			.scan_prefix(prefix)
+			.map(|(pdu_id, _)| pdu_id)
+			.collect();
+
+		self.searchpduids
+			.remove_batch(pdu_ids.iter().map(Vec::as_slice))?;
+		self.shortroomid_searchcount
+			.remove(&shortroomid.to_be_bytes())
+	}
+
+	/// Number of events of a room in the index.
+	pub(super) fn document_count(&self, shortroomid: u64) -> Result<u64> {
+		self.shortroomid_searchcount
+			.get(&shortroomid.to_be_bytes())?
+			.map_or(Ok(0), |bytes| {
+				utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid search document count in db."))
+			})
+	}
+
+	fn set_document_count(&self, shortroomid: u64, count: u64) -> Result<()> {
+		self.shortroomid_searchcount
+			.insert(&shortroomid.to_be_bytes(), &count.to_be_bytes())
+	}
+
+	/// Returns the timestamps of indexed events, `None` for events indexed
+	/// before they were recorded.
+	pub(super) fn origin_server_ts(&self, pdu_ids: &[&[u8]]) -> Result<Vec<Option<u64>>> {
+		Ok(self
+			.searchpduids
+			.multi_get(pdu_ids)?
+			.into_iter()
+			.map(|val| val.and_then(|bytes| bytes.get(..size_of::<u64>()).map(utils::u64_from_u8)))
+			.collect())
+	}
+
+	/// Returns the language an event was indexed in if it is indexed, `None`
+	/// inside for no language.
+	pub(super) fn indexed_language(&self, pdu_id: &[u8]) -> Result<Option<Option<String>>> {
+		self.searchpduids
+			.get(pdu_id)?
+			.map(|bytes| {
+				let language = bytes.get(size_of::<u64>()..).unwrap_or_default();
+				let language = utils::string_from_bytes(language)
+					.map_err(|_| Error::bad_database("Invalid search language in db."))?;
+
+				Ok((!language.is_empty()).then_some(language))
+			})
+			.transpose()
+	}
+
+	/// Measures the index of a room, or of every room keyed by shortroomid.
+	pub(super) fn index_stats(&self, shortroomid: Option<u64>) -> BTreeMap<u64, IndexStats> {
+		let entries = match shortroomid {
+			Some(shortroomid) => self
+				.tokenids
+				.scan_prefix(shortroomid.to_be_bytes().to_vec()),
+			None => self.tokenids.iter(),
 		};
 
-		Ok(Some((Box::new(common_elements), words)))
+		// Keys are sorted by room and then token, so the distinct tokens and events
+		// of a room only need to be tracked until the next room starts.
+		let mut stats = BTreeMap::<u64, IndexStats>::new();
+		let mut room = None;
+		let mut token = Vec::new();
+		let mut events = BTreeSet::new();
+		for (key, val) in entries {
+			let Some(shortroomid) = key.get(..size_of::<u64>()).map(utils::u64_from_u8) else {
+				continue;
+			};
+
+			let Some(separator) = key.iter().skip(size_of::<u64>()).position(|&b| b == 0xFF) else {
+				continue;
+			};
+
+			let (key_token, pdu_id) = key[size_of::<u64>()..].split_at(separator);
+			if room != Some(shortroomid) {
+				room = Some(shortroomid);
+				token.clear();
+				events.clear();
+			}
+
+			let room_stats = stats.entry(shortroomid).or_default();
+			room_stats.entries = room_stats.entries.saturating_add(1);
+			room_stats.bytes = room_stats
+				.bytes
+				.saturating_add(key.len())
+				.saturating_add(val.len());
+			room_stats.occurrences = room_stats
+				.occurrences
+				.saturating_add(frequency_from_bytes(&val).into());
+
+			if key_token != token {
+				token = key_token.to_vec();
+				room_stats.tokens = room_stats.tokens.saturating_add(1);
+			}
+
+			if events.insert(pdu_id[1..].to_vec()) {
+				room_stats.events = room_stats.events.saturating_add(1);
+			}
+		}
+
+		stats
+	}
+
+	pub(super) fn reindex_job(&self) -> Result<Option<ReindexJob>> {
+		self.global
+			.get(REINDEX_JOB)?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes).map_err(|_| Error::bad_database("Invalid search reindex job in db."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_reindex_job(&self, job: Option<&ReindexJob>) -> Result<()> {
+		match job {
+			Some(job) => self
+				.global
+				.insert(REINDEX_JOB, &serde_json::to_vec(job).expect("ReindexJob can be serialized")),
+			None => self.global.remove(REINDEX_JOB),
+		}
+	}
+
+	pub(super) fn room_language(&self, shortroomid: u64) -> Result<Option<String>> {
+		self.shortroomid_searchlanguage
+			.get(&shortroomid.to_be_bytes())?
+			.map(|bytes| {
+				utils::string_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid search language in db."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_room_language(&self, shortroomid: u64, language: Option<&str>) -> Result<()> {
+		match language {
+			Some(language) => self
+				.shortroomid_searchlanguage
+				.insert(&shortroomid.to_be_bytes(), language.as_bytes()),
+			None => self
+				.shortroomid_searchlanguage
+				.remove(&shortroomid.to_be_bytes()),
+		}
+	}
+
+	/// Returns the posting list for an exact token in a room.
+	pub(super) fn word_postings(&self, shortroomid: u64, word: &str) -> Postings {
+		let mut prefix = shortroomid.to_be_bytes().to_vec();
+		prefix.extend_from_slice(word.as_bytes());
+		prefix.push(0xFF);
+
+		let offset = prefix.len();
+		self.tokenids
+			.scan_prefix(prefix)
+			.map(|(key, val)| (key[offset..].to_vec(), frequency_from_bytes(&val)))
+			.collect()
+	}
+
+	/// Returns the merged posting list of every token in a room starting with
+	/// `word`. Frequencies of different tokens in the same pdu are summed.
+	pub(super) fn prefix_postings(&self, shortroomid: u64, word: &str) -> Postings {
+		let mut prefix = shortroomid.to_be_bytes().to_vec();
+		prefix.extend_from_slice(word.as_bytes());
+
+		let mut postings = Postings::new();
+		for (key, val) in self.tokenids.scan_prefix(prefix) {
+			// Tokens are valid UTF-8 and thus never contain 0xFF; the first one
+			// after the room prefix separates the token from the pdu id.
+			let Some(separator) = key.iter().skip(size_of::<u64>()).position(|&b| b == 0xFF) else {
+				continue;
+			};
+
+			let pdu_id = key[size_of::<u64>().saturating_add(separator).saturating_add(1)..].to_vec();
+			let frequency = postings.entry(pdu_id).or_default();
+			*frequency = frequency.saturating_add(frequency_from_bytes(&val));
+		}
+
+		postings
 	}
 }
 
-/// Splits a string into tokens used as keys in the search inverted index
-///
-/// This may be used to tokenize both message bodies (for indexing) or search
-/// queries (for querying).
-fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
-	body.split_terminator(|c: char| !c.is_alphanumeric())
-		.filter(|s| !s.is_empty())
-		.filter(|word| word.len() <= 50)
-		.map(str::to_lowercase)
+/// Entries written before term frequencies were recorded have an empty value
+/// and count as a single occurrence.
+fn frequency_from_bytes(val: &[u8]) -> u32 { val.try_into().map(u32::from_be_bytes).unwrap_or(1) }
+
+/// Counts the occurrences of every token of a message body.
+fn term_frequencies<I>(tokens: I) -> BTreeMap<String, u32>
+where
+	I: Iterator<Item = String>,
+{
+	let mut frequencies = BTreeMap::<String, u32>::new();
+	for word in tokens {
+		let frequency = frequencies.entry(word).or_default();
+		*frequency = frequency.saturating_add(1);
+	}
+
+	frequencies
 }
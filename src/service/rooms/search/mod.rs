@@ -1,40 +1,627 @@
 mod data;
+mod query;
+mod reindex;
+mod tests;
+mod tokenizer;
 
-use std::sync::Arc;
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, BTreeSet},
+	fmt::{Display, Formatter},
+	str::FromStr,
+	sync::Arc,
+};
 
-use conduit::Result;
-use data::Data;
-use ruma::RoomId;
+use async_trait::async_trait;
+use conduit::{err, error, Err, PduEvent, Result, Server};
+use data::{Data, Postings};
+use ruma::{
+	api::client::{error::ErrorKind, search::search_events::v3::OrderBy},
+	EventId, OwnedEventId, OwnedRoomId, RoomId,
+};
+use serde::Deserialize;
+use tokio::sync::{Mutex, Notify};
+
+pub use self::{
+	query::{Query, Term},
+	reindex::ReindexJob,
+	tokenizer::{language, TokenFilter, Tokenizer},
+};
+use crate::{globals, rooms, Dep, Error};
+
+/// Age in milliseconds after which the recency boost of a result is halved.
+const RECENCY_HALF_LIFE: f64 = 30.0 * 24.0 * 60.0 * 60.0 * 1000.0;
 
 pub struct Service {
 	db: Data,
+	services: Services,
+	server: Arc<Server>,
+	reindex_lock: Mutex<()>,
+	reindex_queued: Notify,
+	interrupt: Notify,
+}
+
+struct Services {
+	globals: Dep<globals::Service>,
+	metadata: Dep<rooms::metadata::Service>,
+	short: Dep<rooms::short::Service>,
+	timeline: Dep<rooms::timeline::Service>,
+}
+
+/// An event matching a search query along with its relevance.
+pub struct RankedPdu {
+	pub pdu_id: Vec<u8>,
+	pub pdu: PduEvent,
+	pub rank: f64,
+}
+
+/// What to search for and which page of results to return.
+#[derive(Debug)]
+pub struct SearchCriteria<'a> {
+	pub room_ids: &'a [OwnedRoomId],
+	pub search_term: &'a str,
+	pub order_by: Option<&'a OrderBy>,
+
+	/// Position of the end of the previous page.
+	pub from: Option<&'a PaginationToken>,
+
+	/// Time the recency of events is computed as of.
+	pub now: u64,
+
+	pub limit: usize,
+}
+
+/// A page of search results.
+pub struct SearchResults {
+	pub results: Vec<RankedPdu>,
+
+	/// Number of events matching the index, before they are verified.
+	pub count: usize,
+
+	/// Whether more results follow this page.
+	pub more: bool,
+}
+
+/// Events of a room matching a search term in the index.
+struct RoomCandidates {
+	/// Number of indexed events of the room.
+	documents: u64,
+	tokenizer: Tokenizer,
+	query: Query,
+	postings: BTreeMap<Term, Postings>,
+	candidates: BTreeSet<Vec<u8>>,
+}
+
+struct Candidate<'a> {
+	pdu_id: &'a Vec<u8>,
+	room: &'a RoomCandidates,
+
+	/// Text score according to the term frequencies of the index.
+	score: f64,
+
+	/// Upper bound of the sort key of the result.
+	bound: u64,
+}
+
+/// Position in a list of search results, handed out as `next_batch`.
+///
+/// Results are ordered by their sort key, descending, then by event id. The
+/// time ranks were computed at is kept so the recency boost, and with it the
+/// order of the results, does not shift between pages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaginationToken {
+	pub now: u64,
+	/// Sort key of the last result of the previous page.
+	pub key: u64,
+	pub event_id: OwnedEventId,
+}
+
+/// Size of the search index of a room.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndexStats {
+	/// Number of (token, event) pairs.
+	pub entries: usize,
+
+	/// Number of distinct tokens.
+	pub tokens: usize,
+
+	/// Number of tokens in all indexed events together.
+	pub occurrences: u64,
+
+	/// Number of indexed events.
+	pub events: usize,
+
+	/// Size of the keys and values, in bytes.
+	pub bytes: usize,
+}
+
+#[derive(Deserialize)]
+struct ExtractBody {
+	body: Option<String>,
 }
 
+#[async_trait]
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		let config = &args.server.config;
+		if let Some(code) = &config.search_default_language {
+			if language(code).is_none() {
+				return Err(err!(Config("search_default_language", "Unsupported language {code:?}.")));
+			}
+		}
+
 		Ok(Arc::new(Self {
 			db: Data::new(&args),
+			server: args.server.clone(),
+			reindex_lock: Mutex::new(()),
+			reindex_queued: Notify::new(),
+			interrupt: Notify::new(),
+			services: Services {
+				globals: args.depend::<globals::Service>("globals"),
+				metadata: args.depend::<rooms::metadata::Service>("rooms::metadata"),
+				short: args.depend::<rooms::short::Service>("rooms::short"),
+				timeline: args.depend::<rooms::timeline::Service>("rooms::timeline"),
+			},
 		}))
 	}
 
+	async fn worker(self: Arc<Self>) -> Result<()> {
+		loop {
+			while self.server.running() {
+				match self.reindex_step().await {
+					Ok(true) => tokio::task::yield_now().await,
+					Ok(false) => break,
+					Err(e) => {
+						error!("Search reindex failed: {e}");
+						break;
+					},
+				}
+			}
+
+			if !self.server.running() {
+				return Ok(());
+			}
+
+			tokio::select! {
+				() = self.interrupt.notified() => return Ok(()),
+				() = self.reindex_queued.notified() => {},
+			}
+		}
+	}
+
+	fn interrupt(&self) { self.interrupt.notify_waiters(); }
+
 	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
 }
 
 impl Service {
 	#[tracing::instrument(skip(self), level = "debug")]
-	pub fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		self.db.index_pdu(shortroomid, pdu_id, message_body)
+	pub fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], origin_server_ts: u64, message_body: &str) -> Result<()> {
+		let code = self.indexing_language(shortroomid)?;
+		let tokenizer = Tokenizer::new(code.as_deref().and_then(language));
+		self.db.index_pdu(
+			shortroomid,
+			pdu_id,
+			origin_server_ts,
+			code.as_deref(),
+			tokenizer.tokenize(message_body),
+		)
 	}
 
+	/// Removes an event from the index, tokenizing its body in the language it
+	/// was indexed in.
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+		let tokenizer = match self.db.indexed_language(pdu_id)? {
+			Some(code) => Tokenizer::new(code.as_deref().and_then(language)),
+			None => self.tokenizer(shortroomid)?,
+		};
+
+		self.db
+			.deindex_pdu(shortroomid, pdu_id, tokenizer.tokenize(message_body))
 	}
 
-	#[tracing::instrument(skip(self), level = "debug")]
-	pub fn search_pdus<'a>(
-		&'a self, room_id: &RoomId, search_string: &str,
-	) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
-		self.db.search_pdus(room_id, search_string)
+	/// Removes every indexed message of a room.
+	pub fn deindex_room(&self, shortroomid: u64) -> Result<()> { self.db.deindex_room(shortroomid) }
+
+	/// Measures the search index of a room.
+	pub fn index_stats(&self, room_id: &RoomId) -> Result<IndexStats> {
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(IndexStats::default());
+		};
+
+		Ok(self
+			.db
+			.index_stats(Some(shortroomid))
+			.remove(&shortroomid)
+			.unwrap_or_default())
+	}
+
+	/// Measures the search index of every room with indexed messages.
+	pub fn all_index_stats(&self) -> Result<Vec<(OwnedRoomId, IndexStats)>> {
+		let mut stats = self.db.index_stats(None);
+		let mut rooms = Vec::with_capacity(stats.len());
+		for room_id in self.services.metadata.iter_ids() {
+			let room_id = room_id?;
+			if let Some(room_stats) = self
+				.services
+				.short
+				.get_shortroomid(&room_id)?
+				.and_then(|shortroomid| stats.remove(&shortroomid))
+			{
+				rooms.push((room_id, room_stats));
+			}
+		}
+
+		Ok(rooms)
+	}
+
+	/// The tokenizer bodies of a room are indexed with, stemming words in the
+	/// language of the room or else the configured default.
+	pub fn tokenizer(&self, shortroomid: u64) -> Result<Tokenizer> {
+		let code = self.indexing_language(shortroomid)?;
+
+		Ok(Tokenizer::new(code.as_deref().and_then(language)))
+	}
+
+	/// The ISO 639-1 code of the language bodies of a room are indexed in.
+	fn indexing_language(&self, shortroomid: u64) -> Result<Option<String>> {
+		Ok(self
+			.db
+			.room_language(shortroomid)?
+			.or_else(|| self.server.config.search_default_language.clone()))
+	}
+
+	/// Returns the ISO 639-1 code of the language set for a room.
+	pub fn room_language(&self, room_id: &RoomId) -> Result<Option<String>> {
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(None);
+		};
+
+		self.db.room_language(shortroomid)
+	}
+
+	/// Sets the language words of a room are stemmed in, or unsets it to use
+	/// the default, and queues the room to be reindexed in it.
+	pub async fn set_room_language(&self, room_id: &RoomId, code: Option<&str>) -> Result<()> {
+		if let Some(code) = code {
+			if language(code).is_none() {
+				return Err!(Request(InvalidParam("Unsupported language {code:?}.")));
+			}
+		}
+
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Err!(Request(NotFound("Room not found.")));
+		};
+
+		self.db.set_room_language(shortroomid, code)?;
+		self.reindex([room_id.to_owned()]).await?;
+
+		Ok(())
+	}
+
+	/// Finds the events of some rooms matching a search term and returns a
+	/// page of them in the requested order.
+	///
+	/// Candidates are looked up in the token index, the search term being
+	/// parsed with the tokenizer of each room. The rank combines term
+	/// frequency, weighted by how rare each term is among the indexed events
+	/// of the searched rooms, with a boost for recent events as of `now`.
+	///
+	/// Candidates are then visited in the order of an upper bound of their
+	/// sort key, computed from the index alone. Only these are loaded and
+	/// verified against the tokens of their body, which is what makes phrases
+	/// exact, until no remaining candidate can make it onto the page.
+	#[tracing::instrument(skip(self, visible), level = "debug")]
+	pub fn search_pdus<F>(&self, criteria: &SearchCriteria<'_>, visible: F) -> Result<SearchResults>
+	where
+		F: Fn(&PduEvent) -> bool,
+	{
+		let mut rooms = Vec::with_capacity(criteria.room_ids.len());
+		for room_id in criteria.room_ids {
+			if let Some(room) = self.room_candidates(room_id, criteria.search_term)? {
+				rooms.push(room);
+			}
+		}
+
+		let mut total: u64 = 0;
+		let mut document_frequencies = BTreeMap::<&Term, usize>::new();
+		for room in &rooms {
+			total = total.saturating_add(room.documents);
+			for (term, postings) in &room.postings {
+				let document_frequency = document_frequencies.entry(term).or_default();
+				*document_frequency = document_frequency.saturating_add(postings.len());
+			}
+		}
+
+		let weights: BTreeMap<&Term, f64> = document_frequencies
+			.into_iter()
+			.map(|(term, document_frequency)| (term, idf(total, document_frequency)))
+			.collect();
+
+		let mut candidates = Vec::new();
+		for room in &rooms {
+			let pdu_ids: Vec<&[u8]> = room.candidates.iter().map(Vec::as_slice).collect();
+			let timestamps = self.db.origin_server_ts(&pdu_ids)?;
+			for (pdu_id, origin_server_ts) in room.candidates.iter().zip(timestamps) {
+				let origin_server_ts = match origin_server_ts {
+					Some(origin_server_ts) => origin_server_ts,
+					None => match self.services.timeline.get_pdu_from_id(pdu_id)? {
+						Some(pdu) => pdu.origin_server_ts.into(),
+						None => continue,
+					},
+				};
+
+				let score = room.score_bound(&weights, pdu_id);
+				let bound = match criteria.order_by {
+					Some(OrderBy::Rank) => (score * recency_boost(criteria.now, origin_server_ts)).to_bits(),
+					_ => origin_server_ts,
+				};
+
+				candidates.push(Candidate {
+					pdu_id,
+					room,
+					score,
+					bound,
+				});
+			}
+		}
+
+		candidates.sort_unstable_by(|a, b| b.bound.cmp(&a.bound));
+
+		// One more result than requested tells whether there is a next page.
+		let mut results = Vec::<RankedPdu>::with_capacity(criteria.limit.saturating_add(1));
+		for candidate in &candidates {
+			if results
+				.get(criteria.limit)
+				.is_some_and(|last| candidate.bound < last.sort_key(criteria.order_by))
+			{
+				break;
+			}
+
+			// Timestamps are exact, ranks could still be lower than their bound.
+			if criteria
+				.from
+				.is_some_and(|from| candidate.bound > from.key && !matches!(criteria.order_by, Some(OrderBy::Rank)))
+			{
+				continue;
+			}
+
+			let Some(result) = self.verify(candidate, &weights, criteria.now)? else {
+				continue;
+			};
+
+			if criteria
+				.from
+				.is_some_and(|from| !from.precedes(&result, criteria.order_by))
+				|| !visible(&result.pdu)
+			{
+				continue;
+			}
+
+			let position = results
+				.binary_search_by(|other| other.cmp_by(&result, criteria.order_by))
+				.unwrap_or_else(|position| position);
+
+			results.insert(position, result);
+			results.truncate(criteria.limit.saturating_add(1));
+		}
+
+		let more = results.len() > criteria.limit;
+		results.truncate(criteria.limit);
+
+		Ok(SearchResults {
+			results,
+			count: candidates.len(),
+			more,
+		})
+	}
+
+	/// Looks up the events of a room containing the terms of a search term.
+	fn room_candidates(&self, room_id: &RoomId, search_term: &str) -> Result<Option<RoomCandidates>> {
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(None);
+		};
+
+		let tokenizer = self.tokenizer(shortroomid)?;
+		let query = Query::parse(search_term, &tokenizer);
+		let postings: BTreeMap<Term, Postings> = query
+			.terms()
+			.map(|term| (term.clone(), self.postings(shortroomid, term)))
+			.collect();
+
+		let mut candidates = BTreeSet::<Vec<u8>>::new();
+		for clause in &query.clauses {
+			let Some((first, rest)) = clause.split_first() else {
+				continue;
+			};
+
+			candidates.extend(
+				postings[first]
+					.keys()
+					.filter(|pdu_id| rest.iter().all(|term| postings[term].contains_key(*pdu_id)))
+					.cloned(),
+			);
+		}
+
+		// Excluded phrases are only verified against the body, everything else can
+		// be dropped from the index directly.
+		for term in query
+			.exclude
+			.iter()
+			.filter(|term| !matches!(term, Term::Phrase(_)))
+		{
+			for pdu_id in self.postings(shortroomid, term).keys() {
+				candidates.remove(pdu_id);
+			}
+		}
+
+		Ok(Some(RoomCandidates {
+			documents: self.db.document_count(shortroomid)?,
+			tokenizer,
+			query,
+			postings,
+			candidates,
+		}))
+	}
+
+	/// Loads a candidate and ranks it if its body matches the query.
+	fn verify(&self, candidate: &Candidate<'_>, weights: &BTreeMap<&Term, f64>, now: u64) -> Result<Option<RankedPdu>> {
+		let Some(pdu) = self.services.timeline.get_pdu_from_id(candidate.pdu_id)? else {
+			return Ok(None);
+		};
+
+		let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+			.ok()
+			.and_then(|content| content.body)
+		else {
+			return Ok(None);
+		};
+
+		let room = candidate.room;
+		let tokens: Vec<String> = room.tokenizer.tokenize(&body).collect();
+		if !room.query.matches(&tokens) {
+			return Ok(None);
+		}
+
+		// A body tokenized differently than when it was indexed must not score
+		// above the bound its candidate was visited by.
+		let score = room
+			.postings
+			.keys()
+			.map(|term| weighted_frequency(term.frequency(&tokens), weights[term]))
+			.sum::<f64>()
+			.min(candidate.score);
+
+		Ok(Some(RankedPdu {
+			pdu_id: candidate.pdu_id.clone(),
+			rank: score * recency_boost(now, pdu.origin_server_ts.into()),
+			pdu,
+		}))
+	}
+
+	fn postings(&self, shortroomid: u64, term: &Term) -> Postings {
+		match term {
+			Term::Word(word) => self.db.word_postings(shortroomid, word),
+			Term::Prefix(prefix) => self.db.prefix_postings(shortroomid, prefix),
+			Term::Phrase(words) => {
+				let mut words = words.iter();
+				let Some(first) = words.next() else {
+					return Postings::new();
+				};
+
+				let mut postings = self.db.word_postings(shortroomid, first);
+				for word in words {
+					let other = self.db.word_postings(shortroomid, word);
+					postings.retain(|pdu_id, _| other.contains_key(pdu_id));
+				}
+
+				postings
+			},
+		}
 	}
 }
+
+impl RankedPdu {
+	/// Key results are sorted by, descending. Ranks are never negative, so the
+	/// order of their bit patterns matches the order of the values.
+	#[must_use]
+	pub fn sort_key(&self, order_by: Option<&OrderBy>) -> u64 {
+		match order_by {
+			Some(OrderBy::Rank) => self.rank.to_bits(),
+			_ => self.pdu.origin_server_ts.into(),
+		}
+	}
+
+	/// Orders results as they are returned to the client.
+	#[must_use]
+	pub fn cmp_by(&self, other: &Self, order_by: Option<&OrderBy>) -> Ordering {
+		other
+			.sort_key(order_by)
+			.cmp(&self.sort_key(order_by))
+			.then_with(|| self.pdu.event_id.cmp(&other.pdu.event_id))
+	}
+}
+
+impl RoomCandidates {
+	/// Text score of a candidate from the term frequencies of the index, which
+	/// its verified score does not exceed.
+	fn score_bound(&self, weights: &BTreeMap<&Term, f64>, pdu_id: &[u8]) -> f64 {
+		self.postings
+			.iter()
+			.filter_map(|(term, postings)| {
+				let frequency = (*postings.get(pdu_id)?).try_into().unwrap_or(usize::MAX);
+				Some(weighted_frequency(frequency, weights[term]))
+			})
+			.sum()
+	}
+}
+
+impl PaginationToken {
+	#[must_use]
+	pub fn new(now: u64, result: &RankedPdu, order_by: Option<&OrderBy>) -> Self {
+		Self {
+			now,
+			key: result.sort_key(order_by),
+			event_id: result.pdu.event_id.clone(),
+		}
+	}
+
+	/// Whether a result comes after the position of this token.
+	#[must_use]
+	pub fn precedes(&self, result: &RankedPdu, order_by: Option<&OrderBy>) -> bool {
+		let key = result.sort_key(order_by);
+		key < self.key || (key == self.key && *result.pdu.event_id > *self.event_id)
+	}
+}
+
+impl FromStr for PaginationToken {
+	type Err = Error;
+
+	fn from_str(value: &str) -> Result<Self> {
+		// Event ids may contain the separator, so they come last.
+		let mut values = value.splitn(3, '_');
+
+		let mut pag_tok = || {
+			Some(Self {
+				now: u64::from_str(values.next()?).ok()?,
+				key: u64::from_str(values.next()?).ok()?,
+				event_id: EventId::parse(values.next()?).ok()?,
+			})
+		};
+
+		pag_tok().ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token."))
+	}
+}
+
+impl Display for PaginationToken {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}_{}_{}", self.now, self.key, self.event_id)
+	}
+}
+
+/// Log-scaled frequency of a term in an event, weighted by the rarity of the
+/// term.
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+fn weighted_frequency(frequency: usize, weight: f64) -> f64 {
+	if frequency == 0 {
+		return 0.0;
+	}
+
+	(1.0 + (frequency as f64).ln()) * weight
+}
+
+/// Inverse document frequency of a term found in `document_frequency` of
+/// `total` indexed events.
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+fn idf(total: u64, document_frequency: usize) -> f64 {
+	(total.max(1) as f64 / document_frequency.max(1) as f64).ln_1p()
+}
+
+/// Multiplier between 0.5 and 1.0, halving its distance to 0.5 every
+/// `RECENCY_HALF_LIFE`.
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+fn recency_boost(now: u64, origin_server_ts: u64) -> f64 {
+	let age = now.saturating_sub(origin_server_ts) as f64;
+	0.5_f64.mul_add(0.5_f64.powf(age / RECENCY_HALF_LIFE), 0.5)
+}
@@ -0,0 +1,164 @@
+use super::{tokenizer::is_cjk, Tokenizer};
+
+/// A parsed search query.
+///
+/// Supported syntax:
+/// - `foo bar` matches events containing both `foo` and `bar`
+/// - `"foo bar"` matches events containing the exact phrase
+/// - `deploy*` matches any token starting with `deploy`
+/// - `-foo` or `-"foo bar"` excludes matching events
+/// - `foo OR bar` matches events matching either side
+///
+/// Terms are tokenized like the bodies of the room being searched; a word
+/// written in CJK script becomes a phrase of its bigrams, and a single CJK
+/// character matches the words starting with it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Query {
+	/// Alternatives; an event matches when all terms of any clause match.
+	pub clauses: Vec<Vec<Term>>,
+
+	/// An event matching any of these is never a result.
+	pub exclude: Vec<Term>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Term {
+	/// A single token which must be present as-is.
+	Word(String),
+
+	/// Any token starting with this string.
+	Prefix(String),
+
+	/// A sequence of tokens which must be present in this order.
+	Phrase(Vec<String>),
+}
+
+impl Query {
+	#[must_use]
+	pub fn parse(input: &str, tokenizer: &Tokenizer) -> Self {
+		let mut query = Self::default();
+		let mut clause = Vec::new();
+		let mut chars = input.chars().peekable();
+		loop {
+			while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+			if chars.peek().is_none() {
+				break;
+			}
+
+			let negated = chars.next_if_eq(&'-').is_some();
+			let quoted = chars.next_if_eq(&'"').is_some();
+			let text: String = if quoted {
+				chars.by_ref().take_while(|&c| c != '"').collect()
+			} else {
+				chars.by_ref().take_while(|c| !c.is_whitespace()).collect()
+			};
+
+			if !negated && !quoted && text == "OR" {
+				if !clause.is_empty() {
+					query.clauses.push(std::mem::take(&mut clause));
+				}
+				continue;
+			}
+
+			let Some(term) = Term::parse(&text, quoted, tokenizer) else {
+				continue;
+			};
+
+			if negated {
+				query.exclude.push(term);
+			} else {
+				clause.push(term);
+			}
+		}
+
+		if !clause.is_empty() {
+			query.clauses.push(clause);
+		}
+
+		query
+	}
+
+	/// Whether the query has nothing to look for.
+	#[must_use]
+	pub fn is_empty(&self) -> bool { self.clauses.is_empty() }
+
+	/// Every positive term of the query, without duplicates.
+	pub fn terms(&self) -> impl Iterator<Item = &Term> + '_ {
+		let mut seen = Vec::new();
+		self.clauses.iter().flatten().filter(move |term| {
+			if seen.contains(term) {
+				return false;
+			}
+
+			seen.push(*term);
+			true
+		})
+	}
+
+	/// The words to highlight in matching events.
+	#[must_use]
+	pub fn highlights(&self) -> Vec<String> {
+		let mut highlights: Vec<String> = self.terms().flat_map(Term::words).cloned().collect();
+		highlights.sort_unstable();
+		highlights.dedup();
+		highlights
+	}
+
+	/// Evaluates the query against the tokens of an event body.
+	#[must_use]
+	pub fn matches(&self, tokens: &[String]) -> bool {
+		self.clauses
+			.iter()
+			.any(|clause| clause.iter().all(|term| term.matches(tokens)))
+			&& !self.exclude.iter().any(|term| term.matches(tokens))
+	}
+}
+
+impl Term {
+	fn parse(text: &str, quoted: bool, tokenizer: &Tokenizer) -> Option<Self> {
+		let prefix = !quoted && text.ends_with('*');
+		let mut words: Vec<String> = tokenizer.tokenize(text.trim_end_matches('*')).collect();
+		match words.len() {
+			0 => None,
+			1 if prefix || is_cjk_char(&words[0]) => Some(Self::Prefix(words.remove(0))),
+			1 => Some(Self::Word(words.remove(0))),
+			_ => Some(Self::Phrase(words)),
+		}
+	}
+
+	/// The tokens this term is made of.
+	#[must_use]
+	pub fn words(&self) -> &[String] {
+		match self {
+			Self::Word(word) | Self::Prefix(word) => std::slice::from_ref(word),
+			Self::Phrase(words) => words,
+		}
+	}
+
+	/// Number of occurrences of this term in the tokens of an event body.
+	#[must_use]
+	pub fn frequency(&self, tokens: &[String]) -> usize {
+		match self {
+			Self::Word(word) => tokens.iter().filter(|token| *token == word).count(),
+			Self::Prefix(prefix) => tokens
+				.iter()
+				.filter(|token| token.starts_with(prefix.as_str()))
+				.count(),
+			Self::Phrase(words) => tokens
+				.windows(words.len())
+				.filter(|window| window == words)
+				.count(),
+		}
+	}
+
+	#[must_use]
+	pub fn matches(&self, tokens: &[String]) -> bool { self.frequency(tokens) > 0 }
+}
+
+/// CJK text is indexed as bigrams, a lone character is only found at their
+/// start.
+fn is_cjk_char(word: &str) -> bool {
+	let mut chars = word.chars();
+	chars.next().is_some_and(is_cjk) && chars.next().is_none()
+}
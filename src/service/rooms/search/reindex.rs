@@ -0,0 +1,167 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use conduit::{info, utils, warn, PduCount, Result};
+use ruma::{events::TimelineEventType, OwnedEventId, OwnedRoomId, RoomId};
+use serde::{Deserialize, Serialize};
+
+use super::{language, ExtractBody, Tokenizer};
+
+/// Number of events indexed between two saves of the job progress.
+const REINDEX_BATCH: usize = 500;
+
+/// Progress of rebuilding the search index, persisted so the job resumes
+/// where it stopped after a restart.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReindexJob {
+	/// Rooms left to reindex; the first one is in progress.
+	pub rooms: VecDeque<OwnedRoomId>,
+
+	/// Last event indexed in the room in progress.
+	pub position: Option<OwnedEventId>,
+
+	pub rooms_total: usize,
+	pub rooms_done: usize,
+	pub events_indexed: usize,
+
+	/// When the job was first queued, in milliseconds since the unix epoch.
+	pub started: u64,
+}
+
+impl super::Service {
+	/// Queues rooms to have their index rebuilt in the background. Rooms which
+	/// are already queued are not added again.
+	pub async fn reindex<I>(&self, rooms: I) -> Result<ReindexJob>
+	where
+		I: IntoIterator<Item = OwnedRoomId> + Send,
+	{
+		let _lock = self.reindex_lock.lock().await;
+		let mut job = self.db.reindex_job()?.unwrap_or_else(|| ReindexJob {
+			started: utils::millis_since_unix_epoch(),
+			..ReindexJob::default()
+		});
+
+		for room_id in rooms {
+			if !job.rooms.contains(&room_id) {
+				job.rooms.push_back(room_id);
+				job.rooms_total = job.rooms_total.saturating_add(1);
+			}
+		}
+
+		self.db.set_reindex_job(Some(&job))?;
+		self.reindex_queued.notify_one();
+
+		Ok(job)
+	}
+
+	/// Returns the progress of the reindex job if one is running.
+	pub fn reindex_job(&self) -> Result<Option<ReindexJob>> { self.db.reindex_job() }
+
+	/// Indexes the next batch of events of the reindex job, returning whether
+	/// any work is left. The batch runs on a blocking thread.
+	pub(super) async fn reindex_step(self: &Arc<Self>) -> Result<bool> {
+		let _lock = self.reindex_lock.lock().await;
+		let Some(mut job) = self.db.reindex_job()? else {
+			return Ok(false);
+		};
+
+		if let Some(room_id) = job.rooms.front().cloned() {
+			let this = Arc::clone(self);
+			let (batch_job, done) = self
+				.server
+				.runtime()
+				.spawn_blocking(move || {
+					let done = this.reindex_batch(&mut job, &room_id).unwrap_or_else(|e| {
+						warn!(%room_id, "Failed to reindex room, skipping: {e}");
+						true
+					});
+
+					(job, done)
+				})
+				.await?;
+
+			job = batch_job;
+
+			if done {
+				job.rooms.pop_front();
+				job.position = None;
+				job.rooms_done = job.rooms_done.saturating_add(1);
+			}
+		}
+
+		if job.rooms.is_empty() {
+			info!(
+				"Search reindex of {} rooms finished, {} messages indexed",
+				job.rooms_done, job.events_indexed
+			);
+
+			self.db.set_reindex_job(None)?;
+			return Ok(false);
+		}
+
+		self.db.set_reindex_job(Some(&job))?;
+
+		Ok(true)
+	}
+
+	/// Indexes up to `REINDEX_BATCH` events of a room, returning whether the
+	/// room is done. The existing index of the room is removed when starting.
+	fn reindex_batch(&self, job: &mut ReindexJob, room_id: &RoomId) -> Result<bool> {
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(true);
+		};
+
+		let from = match &job.position {
+			Some(event_id) => self.services.timeline.get_pdu_count(event_id)?,
+			None => None,
+		};
+
+		// Start over if the event we stopped at is gone.
+		let from = if let Some(from) = from {
+			from
+		} else {
+			self.db.deindex_room(shortroomid)?;
+			PduCount::min()
+		};
+
+		let code = self.indexing_language(shortroomid)?;
+		let tokenizer = Tokenizer::new(code.as_deref().and_then(language));
+		let mut pdus = self
+			.services
+			.timeline
+			.pdus_after(&self.services.globals.server_user, room_id, from)?;
+
+		for _ in 0..REINDEX_BATCH {
+			let Some(result) = pdus.next() else {
+				return Ok(true);
+			};
+
+			let (_, pdu) = result?;
+			job.position = Some((*pdu.event_id).to_owned());
+			if pdu.kind != TimelineEventType::RoomMessage {
+				continue;
+			}
+
+			let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+				.ok()
+				.and_then(|content| content.body)
+			else {
+				continue;
+			};
+
+			let Some(pdu_id) = self.services.timeline.get_pdu_id(&pdu.event_id)? else {
+				continue;
+			};
+
+			self.db.index_pdu(
+				shortroomid,
+				&pdu_id,
+				pdu.origin_server_ts.into(),
+				code.as_deref(),
+				tokenizer.tokenize(&body),
+			)?;
+			job.events_indexed = job.events_indexed.saturating_add(1);
+		}
+
+		Ok(false)
+	}
+}
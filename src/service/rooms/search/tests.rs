@@ -0,0 +1,141 @@
+#![cfg(test)]
+
+use rust_stemmers::Algorithm;
+
+use super::{idf, weighted_frequency, PaginationToken, Query, Term, Tokenizer};
+
+fn tokens(body: &str) -> Vec<String> { Tokenizer::default().tokenize(body).collect() }
+
+fn parse(input: &str) -> Query { Query::parse(input, &Tokenizer::default()) }
+
+#[test]
+fn parse_words() {
+	let query = parse("Deploy  the Server");
+	assert_eq!(
+		query.clauses,
+		vec![vec![
+			Term::Word("deploy".to_owned()),
+			Term::Word("the".to_owned()),
+			Term::Word("server".to_owned()),
+		]]
+	);
+	assert!(query.exclude.is_empty(), "nothing excluded");
+}
+
+#[test]
+fn parse_prefix_phrase_exclude() {
+	let query = parse(r#"deploy* "release notes" -staging -"dry run""#);
+	assert_eq!(
+		query.clauses,
+		vec![vec![
+			Term::Prefix("deploy".to_owned()),
+			Term::Phrase(vec!["release".to_owned(), "notes".to_owned()]),
+		]]
+	);
+	assert_eq!(
+		query.exclude,
+		vec![
+			Term::Word("staging".to_owned()),
+			Term::Phrase(vec!["dry".to_owned(), "run".to_owned()]),
+		]
+	);
+}
+
+#[test]
+fn parse_or() {
+	let query = parse("cat OR dog bird OR");
+	assert_eq!(
+		query.clauses,
+		vec![
+			vec![Term::Word("cat".to_owned())],
+			vec![Term::Word("dog".to_owned()), Term::Word("bird".to_owned())],
+		]
+	);
+}
+
+#[test]
+fn parse_empty() {
+	assert!(parse("").is_empty(), "empty query");
+	assert!(parse("  *  - \"\" ").is_empty(), "only syntax");
+	assert!(parse("-foo").is_empty(), "only exclusions");
+}
+
+#[test]
+fn matches() {
+	let body = tokens("We deployed the release notes to staging today.");
+
+	assert!(parse("deploy*").matches(&body), "prefix");
+	assert!(!parse("deploy").matches(&body), "exact word");
+	assert!(parse("\"release notes\"").matches(&body), "phrase");
+	assert!(!parse("\"notes release\"").matches(&body), "phrase order");
+	assert!(!parse("release -staging").matches(&body), "excluded");
+	assert!(parse("rollback OR today").matches(&body), "alternative");
+	assert!(!parse("rollback OR yesterday").matches(&body), "no alternative");
+}
+
+#[test]
+fn frequency() {
+	let body = tokens("ping ping pong, ping pong");
+
+	assert_eq!(Term::Word("ping".to_owned()).frequency(&body), 3);
+	assert_eq!(Term::Prefix("p".to_owned()).frequency(&body), 5);
+	assert_eq!(Term::Phrase(vec!["ping".to_owned(), "pong".to_owned()]).frequency(&body), 2);
+}
+
+#[test]
+fn tokenize_folding() {
+	assert_eq!(tokens("Crème Brûlée, NAÏVE café"), ["creme", "brulee", "naive", "cafe"]);
+	assert_eq!(tokens("ﬁnance ＡＢＣ"), ["finance", "abc"]);
+	assert_eq!(tokens("がっこう"), ["がっ", "っこ", "こう"], "voiced kana are kept");
+}
+
+#[test]
+fn tokenize_cjk() {
+	assert_eq!(tokens("東京都に行く"), ["東京", "京都", "都に", "に行", "行く"]);
+	assert_eq!(tokens("matrix服务器 v2"), ["matrix", "服务", "务器", "v2"]);
+	assert_eq!(tokens("猫"), ["猫"]);
+
+	let body = tokens("明日東京都に行きます");
+	assert!(parse("東京").matches(&body), "word");
+	assert!(parse("京都").matches(&body), "inner word");
+	assert!(!parse("東都").matches(&body), "not adjacent");
+	assert!(parse("東").matches(&body), "single character");
+}
+
+#[test]
+fn tokenize_stemming() {
+	let tokenizer = Tokenizer::new(Some(Algorithm::English));
+	let body: Vec<String> = tokenizer
+		.tokenize("They were running the deployments")
+		.collect();
+
+	assert!(Query::parse("run", &tokenizer).matches(&body), "inflected");
+	assert!(Query::parse("deployment", &tokenizer).matches(&body), "plural");
+	assert!(!parse("run").matches(&tokens("They were running")), "no language");
+}
+
+#[test]
+fn pagination_token() {
+	let token: PaginationToken = "1700000000000_4611686018427387904_$a_b-c:example.com"
+		.parse()
+		.expect("valid token");
+
+	assert_eq!(token.now, 1_700_000_000_000);
+	assert_eq!(token.key, 4_611_686_018_427_387_904);
+	assert_eq!(token.event_id, "$a_b-c:example.com");
+	assert_eq!(token.to_string().parse::<PaginationToken>().ok(), Some(token));
+
+	assert!("1700000000000_1".parse::<PaginationToken>().is_err(), "missing event id");
+	assert!("now_1_$a:example.com".parse::<PaginationToken>().is_err(), "invalid time");
+}
+
+#[test]
+fn term_weights() {
+	assert!(idf(1000, 1) > idf(1000, 100), "rare terms weigh more");
+	assert!(idf(1000, 1000) > 0.0, "terms in every event still count");
+	assert!(idf(0, 0).is_finite(), "empty index");
+
+	assert!(weighted_frequency(0, 1.0).abs() < f64::EPSILON, "absent term");
+	assert!((weighted_frequency(1, 2.0) - 2.0).abs() < f64::EPSILON, "single occurrence");
+	assert!(weighted_frequency(4, 1.0) < 4.0, "log-scaled frequency");
+}
@@ -0,0 +1,177 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Longest token kept in the index, in bytes.
+const MAX_TOKEN_LEN: usize = 50;
+
+/// Turns message bodies and search terms into the tokens of the search index.
+///
+/// Text is normalized to NFC and lowercased, then split into words; runs of
+/// CJK characters, which are written without spaces, become overlapping
+/// bigrams. Every word then goes through the filters in order: stemming for
+/// the language of the room when it has one, diacritic folding and a length
+/// limit.
+///
+/// Bodies must be indexed and queried with the same pipeline, so rooms need to
+/// be reindexed whenever it changes.
+pub struct Tokenizer {
+	filters: Vec<Box<dyn TokenFilter>>,
+}
+
+/// A step of the token pipeline, applied to every word after segmentation.
+pub trait TokenFilter: Send + Sync {
+	/// Transforms a token, or drops it by returning None.
+	fn filter(&self, token: String) -> Option<String>;
+}
+
+struct Stem(Stemmer);
+
+struct FoldDiacritics;
+
+struct MaxLength(usize);
+
+impl Tokenizer {
+	#[must_use]
+	pub fn new(language: Option<Algorithm>) -> Self {
+		let mut tokenizer = Self {
+			filters: Vec::new(),
+		};
+
+		if let Some(language) = language {
+			tokenizer = tokenizer.with_filter(Stem(Stemmer::create(language)));
+		}
+
+		tokenizer
+			.with_filter(FoldDiacritics)
+			.with_filter(MaxLength(MAX_TOKEN_LEN))
+	}
+
+	/// Appends a filter to the pipeline.
+	#[must_use]
+	pub fn with_filter<F: TokenFilter + 'static>(mut self, filter: F) -> Self {
+		self.filters.push(Box::new(filter));
+		self
+	}
+
+	pub fn tokenize<'a>(&'a self, text: &str) -> impl Iterator<Item = String> + 'a {
+		let text: String = text.nfc().flat_map(char::to_lowercase).collect();
+
+		segment(&text).into_iter().filter_map(|token| {
+			self.filters
+				.iter()
+				.try_fold(token, |token, filter| filter.filter(token))
+		})
+	}
+}
+
+impl Default for Tokenizer {
+	fn default() -> Self { Self::new(None) }
+}
+
+impl TokenFilter for Stem {
+	fn filter(&self, token: String) -> Option<String> { Some(self.0.stem(&token).into_owned()) }
+}
+
+impl TokenFilter for FoldDiacritics {
+	fn filter(&self, token: String) -> Option<String> {
+		Some(
+			token
+				.nfkd()
+				.filter(|&c| !is_combining_mark(c) || is_kana_voicing_mark(c))
+				.nfc()
+				.collect(),
+		)
+	}
+}
+
+impl TokenFilter for MaxLength {
+	fn filter(&self, token: String) -> Option<String> { (!token.is_empty() && token.len() <= self.0).then_some(token) }
+}
+
+/// Returns the stemming algorithm for an ISO 639-1 language code.
+#[must_use]
+pub fn language(code: &str) -> Option<Algorithm> {
+	Some(match code {
+		"ar" => Algorithm::Arabic,
+		"da" => Algorithm::Danish,
+		"de" => Algorithm::German,
+		"el" => Algorithm::Greek,
+		"en" => Algorithm::English,
+		"es" => Algorithm::Spanish,
+		"fi" => Algorithm::Finnish,
+		"fr" => Algorithm::French,
+		"hu" => Algorithm::Hungarian,
+		"it" => Algorithm::Italian,
+		"nb" | "nn" | "no" => Algorithm::Norwegian,
+		"nl" => Algorithm::Dutch,
+		"pt" => Algorithm::Portuguese,
+		"ro" => Algorithm::Romanian,
+		"ru" => Algorithm::Russian,
+		"sv" => Algorithm::Swedish,
+		"ta" => Algorithm::Tamil,
+		"tr" => Algorithm::Turkish,
+		_ => return None,
+	})
+}
+
+/// Splits lowercased text into words, and runs of CJK characters into
+/// overlapping bigrams. A lone CJK character is kept as is.
+fn segment(text: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut word = String::new();
+	let mut cjk = Vec::new();
+	for c in text.chars() {
+		if is_cjk(c) {
+			flush_word(&mut tokens, &mut word);
+			cjk.push(c);
+		} else if c.is_alphanumeric() || is_combining_mark(c) {
+			flush_cjk(&mut tokens, &mut cjk);
+			word.push(c);
+		} else {
+			flush_word(&mut tokens, &mut word);
+			flush_cjk(&mut tokens, &mut cjk);
+		}
+	}
+
+	flush_word(&mut tokens, &mut word);
+	flush_cjk(&mut tokens, &mut cjk);
+
+	tokens
+}
+
+fn flush_word(tokens: &mut Vec<String>, word: &mut String) {
+	if !word.is_empty() {
+		tokens.push(std::mem::take(word));
+	}
+}
+
+fn flush_cjk(tokens: &mut Vec<String>, cjk: &mut Vec<char>) {
+	if cjk.len() == 1 {
+		tokens.push(cjk.iter().collect());
+	} else {
+		tokens.extend(cjk.windows(2).map(|bigram| bigram.iter().collect()));
+	}
+
+	cjk.clear();
+}
+
+/// Whether a character belongs to a script written without spaces between
+/// words: Han ideographs, kana and Hangul.
+pub(super) fn is_cjk(c: char) -> bool {
+	matches!(c,
+		'\u{1100}'..='\u{11FF}' // Hangul Jamo
+		| '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+		| '\u{3130}'..='\u{318F}' // Hangul Compatibility Jamo
+		| '\u{31F0}'..='\u{31FF}' // Katakana Phonetic Extensions
+		| '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+		| '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+		| '\u{AC00}'..='\u{D7AF}' // Hangul Syllables
+		| '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+		| '\u{FF66}'..='\u{FF9F}' // Halfwidth Katakana
+		| '\u{20000}'..='\u{2FA1F}' // Supplementary Ideographic Plane
+	)
+}
+
+/// The voiced sound marks of kana change the character rather than accenting
+/// it, so they are never folded.
+fn is_kana_voicing_mark(c: char) -> bool { matches!(c, '\u{3099}' | '\u{309A}') }
@@ -264,6 +264,53 @@ impl Service {
 		Ok(visibility)
 	}
 
+	/// Whether a user who left a room was allowed to see an event while they
+	/// were a member, based on the room's history_visibility at that event's
+	/// state. Only meaningful for events sent before the user left.
+	#[tracing::instrument(skip(self, user_id, room_id, event_id))]
+	pub fn user_could_see_event(&self, user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+		let Some(shortstatehash) = self.pdu_shortstatehash(event_id)? else {
+			return Ok(true);
+		};
+
+		let history_visibility = self
+			.state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
+			.map_or(Ok(HistoryVisibility::Shared), |s| {
+				serde_json::from_str(s.content.get())
+					.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
+					.map_err(|e| {
+						error!(
+							"Invalid history visibility event in database for room {room_id}, assuming is \"shared\": \
+							 {e}"
+						);
+						Error::bad_database("Invalid history visibility event in database.")
+					})
+			})
+			.unwrap_or(HistoryVisibility::Shared);
+
+		let visibility = match history_visibility {
+			HistoryVisibility::WorldReadable => true,
+			HistoryVisibility::Shared => {
+				// Allow if the user was joined at any point, else deny
+				self.services.state_cache.once_joined(user_id, room_id)?
+			},
+			HistoryVisibility::Invited => {
+				// Allow if the user was AT LEAST invited, else deny
+				self.user_was_invited(shortstatehash, user_id)
+			},
+			HistoryVisibility::Joined => {
+				// Allow if the user was joined, else deny
+				self.user_was_joined(shortstatehash, user_id)
+			},
+			_ => {
+				error!("Unknown history visibility {history_visibility}");
+				false
+			},
+		};
+
+		Ok(visibility)
+	}
+
 	/// Whether a user is allowed to see an event, based on
 	/// the room's history_visibility at that event's state.
 	#[tracing::instrument(skip(self, user_id, room_id))]
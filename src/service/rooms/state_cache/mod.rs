@@ -217,6 +217,9 @@ impl Service {
 				self.db
 					.mark_as_invited(user_id, room_id, last_state, invite_via)?;
 			},
+			MembershipState::Knock => {
+				self.db.mark_as_knocked(user_id, room_id, last_state)?;
+			},
 			MembershipState::Leave | MembershipState::Ban => {
 				self.db.mark_as_left(user_id, room_id)?;
 			},
@@ -346,6 +349,11 @@ impl Service {
 		self.db.get_invite_count(room_id, user_id)
 	}
 
+	#[tracing::instrument(skip(self), level = "debug")]
+	pub fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+		self.db.get_knock_count(room_id, user_id)
+	}
+
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
 		self.db.get_left_count(room_id, user_id)
@@ -370,6 +378,19 @@ impl Service {
 		self.db.invite_state(user_id, room_id)
 	}
 
+	/// Returns an iterator over all rooms a user has knocked on.
+	#[tracing::instrument(skip(self), level = "debug")]
+	pub fn rooms_knocked(
+		&self, user_id: &UserId,
+	) -> impl Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + '_ {
+		self.db.rooms_knocked(user_id)
+	}
+
+	#[tracing::instrument(skip(self), level = "debug")]
+	pub fn knock_state(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+		self.db.knock_state(user_id, room_id)
+	}
+
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub fn left_state(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
 		self.db.left_state(user_id, room_id)
@@ -396,6 +417,11 @@ impl Service {
 		self.db.is_invited(user_id, room_id)
 	}
 
+	#[tracing::instrument(skip(self), level = "debug")]
+	pub fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+		self.db.is_knocked(user_id, room_id)
+	}
+
 	#[tracing::instrument(skip(self), level = "debug")]
 	pub fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> { self.db.is_left(user_id, room_id) }
 
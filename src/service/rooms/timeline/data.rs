@@ -13,6 +13,7 @@ use crate::{rooms, Dep};
 pub(super) struct Data {
 	eventid_outlierpdu: Arc<Map>,
 	eventid_pduid: Arc<Map>,
+	historypurgedroomids: Arc<Map>,
 	pduid_pdu: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
 	userroomid_notificationcount: Arc<Map>,
@@ -35,6 +36,7 @@ impl Data {
 		Self {
 			eventid_outlierpdu: db["eventid_outlierpdu"].clone(),
 			eventid_pduid: db["eventid_pduid"].clone(),
+			historypurgedroomids: db["historypurgedroomids"].clone(),
 			pduid_pdu: db["pduid_pdu"].clone(),
 			userroomid_highlightcount: db["userroomid_highlightcount"].clone(),
 			userroomid_notificationcount: db["userroomid_notificationcount"].clone(),
@@ -217,6 +219,29 @@ impl Data {
 		Ok(())
 	}
 
+	pub(super) fn purge_pdu(&self, pdu_id: &[u8], event_id: &EventId) -> Result<()> {
+		self.pduid_pdu.remove(pdu_id)?;
+		self.eventid_pduid.remove(event_id.as_bytes())
+	}
+
+	/// Moves the point the history of the room is purged up to forward, in
+	/// milliseconds since the unix epoch.
+	pub(super) fn set_history_purged_until(&self, room_id: &RoomId, purged_until: u64) -> Result<()> {
+		let purged_until = self
+			.history_purged_until(room_id)?
+			.map_or(purged_until, |previous| previous.max(purged_until));
+
+		self.historypurgedroomids
+			.insert(room_id.as_bytes(), &purged_until.to_be_bytes())
+	}
+
+	pub(super) fn history_purged_until(&self, room_id: &RoomId) -> Result<Option<u64>> {
+		self.historypurgedroomids
+			.get(room_id.as_bytes())?
+			.map(|bytes| utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid purge point in db.")))
+			.transpose()
+	}
+
 	/// Returns an iterator over all events and their tokens in a room that
 	/// happened before the event with id `until` in reverse-chronological
 	/// order.
@@ -1,4 +1,5 @@
 mod data;
+mod purge;
 
 use std::{
 	collections::{BTreeMap, HashSet},
@@ -11,7 +12,7 @@ use conduit::{
 	pdu::{EventHash, PduBuilder, PduCount, PduEvent},
 	utils,
 	utils::{MutexMap, MutexMapGuard},
-	validated, warn, Error, Result, Server,
+	validated, warn, Err, Error, Result, Server,
 };
 use itertools::Itertools;
 use ruma::{
@@ -39,9 +40,11 @@ use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::RwLock;
 
 use self::data::Data;
+pub use self::purge::HistoryCutoff;
 use crate::{
-	account_data, admin, appservice, appservice::NamespaceRegex, globals, pusher, rooms,
-	rooms::state_compressor::CompressedStateEvent, sending, server_keys, Dep,
+	account_data, admin, appservice, appservice::NamespaceRegex, globals, policy, pusher, rooms,
+	rooms::state_compressor::CompressedStateEvent, sending, server_keys, spam_checker, spam_checker::Verdict, users,
+	Dep,
 };
 
 // Update Relationships
@@ -85,6 +88,7 @@ struct Services {
 	state_accessor: Dep<rooms::state_accessor::Service>,
 	pdu_metadata: Dep<rooms::pdu_metadata::Service>,
 	read_receipt: Dep<rooms::read_receipt::Service>,
+	retention: Dep<rooms::retention::Service>,
 	sending: Dep<sending::Service>,
 	server_keys: Dep<server_keys::Service>,
 	user: Dep<rooms::user::Service>,
@@ -93,6 +97,9 @@ struct Services {
 	search: Dep<rooms::search::Service>,
 	spaces: Dep<rooms::spaces::Service>,
 	event_handler: Dep<rooms::event_handler::Service>,
+	policy: Dep<policy::Service>,
+	spam_checker: Dep<spam_checker::Service>,
+	users: Dep<users::Service>,
 }
 
 type RoomMutexMap = MutexMap<OwnedRoomId, ()>;
@@ -114,6 +121,7 @@ impl crate::Service for Service {
 				state_accessor: args.depend::<rooms::state_accessor::Service>("rooms::state_accessor"),
 				pdu_metadata: args.depend::<rooms::pdu_metadata::Service>("rooms::pdu_metadata"),
 				read_receipt: args.depend::<rooms::read_receipt::Service>("rooms::read_receipt"),
+				retention: args.depend::<rooms::retention::Service>("rooms::retention"),
 				sending: args.depend::<sending::Service>("sending"),
 				server_keys: args.depend::<server_keys::Service>("server_keys"),
 				user: args.depend::<rooms::user::Service>("rooms::user"),
@@ -122,6 +130,9 @@ impl crate::Service for Service {
 				search: args.depend::<rooms::search::Service>("rooms::search"),
 				spaces: args.depend::<rooms::spaces::Service>("rooms::spaces"),
 				event_handler: args.depend::<rooms::event_handler::Service>("rooms::event_handler"),
+				policy: args.depend::<policy::Service>("policy"),
+				spam_checker: args.depend::<spam_checker::Service>("spam_checker"),
+				users: args.depend::<users::Service>("users"),
 			},
 			db: Data::new(&args),
 			mutex_insert: RoomMutexMap::new(),
@@ -341,6 +352,9 @@ impl Service {
 
 		// Insert pdu
 		self.db.append_pdu(&pdu_id, pdu, &pdu_json, count2)?;
+		self.services
+			.retention
+			.add_media_references(&pdu.event_id, &pdu_json)?;
 
 		drop(insert_lock);
 
@@ -491,7 +505,7 @@ impl Service {
 					})?;
 
 					let invite_state = match content.membership {
-						MembershipState::Invite => {
+						MembershipState::Invite | MembershipState::Knock => {
 							let state = self.services.state.calculate_invite_state(pdu)?;
 							Some(state)
 						},
@@ -518,7 +532,7 @@ impl Service {
 				if let Some(body) = content.body {
 					self.services
 						.search
-						.index_pdu(shortroomid, &pdu_id, &body)?;
+						.index_pdu(shortroomid, &pdu_id, pdu.origin_server_ts.into(), &body)?;
 
 					if self.services.admin.is_admin_command(pdu, &body).await {
 						self.services
@@ -530,6 +544,10 @@ impl Service {
 			_ => {},
 		}
 
+		if self.services.policy.is_policy_room(&pdu.room_id) {
+			self.services.policy.handle_event(pdu).await;
+		}
+
 		if let Ok(content) = serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get()) {
 			if let Some(related_pducount) = self.get_pdu_count(&content.relates_to.event_id)? {
 				self.services
@@ -800,6 +818,55 @@ impl Service {
 		Ok((pdu, pdu_json))
 	}
 
+	/// Locked (MSC3939) users can only leave rooms. Suspended (MSC3823) users
+	/// can also redact their own events.
+	fn check_sender_restrictions(&self, pdu_builder: &PduBuilder, sender: &UserId) -> Result<()> {
+		let locked = self.services.users.is_locked(sender)?;
+		if !locked && !self.services.users.is_suspended(sender)? {
+			return Ok(());
+		}
+
+		if is_own_leave(
+			&pdu_builder.event_type,
+			pdu_builder.state_key.as_deref(),
+			sender,
+			&pdu_builder.content,
+		) {
+			return Ok(());
+		}
+
+		if locked {
+			return Err(Error::BadRequest(ErrorKind::UserLocked, "This account has been locked."));
+		}
+
+		if pdu_builder.event_type == TimelineEventType::RoomRedaction {
+			if let Some(redacts) = &pdu_builder.redacts {
+				if self
+					.get_pdu(redacts)?
+					.is_some_and(|pdu| pdu.sender == sender)
+				{
+					return Ok(());
+				}
+			}
+		}
+
+		self.services.users.check_suspended(sender)
+	}
+
+	/// Asks the spam checker about an event of a local user. Leaving a room
+	/// and events of the server user are never refused.
+	fn check_spam(&self, pdu: &PduEvent) -> Result<Verdict> {
+		if pdu.sender == self.services.globals.server_user
+			|| is_own_leave(&pdu.kind, pdu.state_key.as_deref(), &pdu.sender, &pdu.content)
+		{
+			return Ok(Verdict::Allow);
+		}
+
+		self.services
+			.spam_checker
+			.check_event(&pdu.sender, &pdu.room_id, &pdu.kind.to_string(), &pdu.content)
+	}
+
 	/// Creates a new persisted data unit and adds it to a room. This function
 	/// takes a roomid_mutex_state, meaning that only this function is able to
 	/// mutate the room state.
@@ -811,6 +878,10 @@ impl Service {
 		room_id: &RoomId,
 		state_lock: &RoomMutexGuard, // Take mutex guard to make sure users get the room state mutex
 	) -> Result<Arc<EventId>> {
+		if self.services.globals.user_is_local(sender) {
+			self.check_sender_restrictions(&pdu_builder, sender)?;
+		}
+
 		let (pdu, pdu_json) = self.create_hash_and_sign_event(pdu_builder, sender, room_id, state_lock)?;
 		if let Some(admin_room) = self.services.admin.get_admin_room()? {
 			if admin_room == room_id {
@@ -919,6 +990,19 @@ impl Service {
 			}
 		};
 
+		// Checked here so every local event is, once it passed all other checks
+		if self.services.globals.user_is_local(sender) {
+			match self.check_spam(&pdu)? {
+				Verdict::Allow => {},
+				Verdict::Deny(reason) => return Err!(Request(Forbidden("{reason}"))),
+				Verdict::ShadowBan => {
+					// answer with the id of an event nobody else will ever see
+					debug!("Dropping event {} of {sender} shadow-banned by the spam checker", pdu.event_id);
+					return Ok(pdu.event_id);
+				},
+			}
+		}
+
 		// We append to state before appending the pdu, so we don't have a moment in
 		// time with the pdu without it's state. This is okay because append_pdu can't
 		// fail.
@@ -1180,6 +1264,19 @@ impl Service {
 			return Ok(());
 		}
 
+		// Skip the PDU if it was purged from the history, state is never purged
+		if let Some(purged_until) = self.db.history_purged_until(&room_id)? {
+			let sent = match value.get("origin_server_ts") {
+				Some(CanonicalJsonValue::Integer(ts)) => u64::try_from(i64::from(*ts)).ok(),
+				_ => None,
+			};
+
+			if !value.contains_key("state_key") && sent.is_some_and(|sent| sent <= purged_until) {
+				debug!("Not backfilling {event_id} purged from the history of {room_id}");
+				return Ok(());
+			}
+		}
+
 		self.services
 			.server_keys
 			.fetch_required_signing_keys([&value], pub_key_map)
@@ -1209,6 +1306,9 @@ impl Service {
 
 		// Insert pdu
 		self.db.prepend_backfill_pdu(&pdu_id, &event_id, &value)?;
+		self.services
+			.retention
+			.add_media_references(&event_id, &value)?;
 
 		drop(insert_lock);
 
@@ -1219,7 +1319,7 @@ impl Service {
 			if let Some(body) = content.body {
 				self.services
 					.search
-					.index_pdu(shortroomid, &pdu_id, &body)?;
+					.index_pdu(shortroomid, &pdu_id, pdu.origin_server_ts.into(), &body)?;
 			}
 		}
 		drop(mutex_lock);
@@ -1229,6 +1329,14 @@ impl Service {
 	}
 }
 
+/// Whether the event is its sender leaving the room.
+fn is_own_leave(kind: &TimelineEventType, state_key: Option<&str>, sender: &UserId, content: &RawJsonValue) -> bool {
+	*kind == TimelineEventType::RoomMember
+		&& state_key == Some(sender.as_str())
+		&& serde_json::from_str::<RoomMemberEventContent>(content.get())
+			.is_ok_and(|content| content.membership == MembershipState::Leave)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -0,0 +1,122 @@
+use conduit::{PduCount, PduEvent, Result};
+use ruma::{events::room::encrypted::Relation, RoomId};
+
+use super::{data::pdu_count, ExtractBody, ExtractRelatesTo, ExtractRelatesToEventId};
+
+/// Number of events deleted while holding the state lock of a room.
+const PURGE_BATCH: usize = 500;
+
+/// Up to where `purge_history` deletes the history of a room.
+#[derive(Clone, Copy, Debug)]
+pub enum HistoryCutoff {
+	/// Events before the event at this position.
+	Event(PduCount),
+
+	/// Events sent before this time, in milliseconds since the unix epoch.
+	/// Events are visited in timeline order up to the first one sent later.
+	Timestamp(u64),
+}
+
+impl super::Service {
+	/// Deletes the non-state events of a room before the cutoff from the
+	/// timeline, the search index and the relations, calling `on_purge` with
+	/// each. State events and the forward extremities are kept, and events
+	/// sent before the last deleted one are no longer backfilled so they are
+	/// not fetched again.
+	/// Returns the number of deleted events.
+	pub async fn purge_history<F>(&self, room_id: &RoomId, cutoff: HistoryCutoff, mut on_purge: F) -> Result<usize>
+	where
+		F: FnMut(&PduEvent) + Send,
+	{
+		let Some(shortroomid) = self.services.short.get_shortroomid(room_id)? else {
+			return Ok(0);
+		};
+
+		let mut purged: usize = 0;
+		let mut from = PduCount::min();
+		loop {
+			let state_lock = self.services.state.mutex.lock(room_id).await;
+			let extremities = self.services.state.get_forward_extremities(room_id)?;
+
+			let mut pdus = Vec::with_capacity(PURGE_BATCH);
+			let mut done = true;
+			for result in self.pdus_after(&self.services.globals.server_user, room_id, from)? {
+				let (count, pdu) = result?;
+				from = count;
+				let expired = match cutoff {
+					HistoryCutoff::Event(until) => count < until,
+					HistoryCutoff::Timestamp(before) => u64::from(pdu.origin_server_ts) < before,
+				};
+
+				if !expired {
+					break;
+				}
+
+				if pdu.state_key.is_some() || extremities.contains(&pdu.event_id) {
+					continue;
+				}
+
+				pdus.push(pdu);
+				if pdus.len() >= PURGE_BATCH {
+					done = false;
+					break;
+				}
+			}
+
+			if let Some(purged_until) = pdus.iter().map(|pdu| u64::from(pdu.origin_server_ts)).max() {
+				self.db.set_history_purged_until(room_id, purged_until)?;
+			}
+
+			for pdu in &pdus {
+				let Some(pdu_id) = self.get_pdu_id(&pdu.event_id)? else {
+					continue;
+				};
+
+				self.purge_pdu(&pdu_id, pdu, shortroomid)?;
+				on_purge(pdu);
+				purged = purged.saturating_add(1);
+			}
+
+			drop(state_lock);
+			if done || !self.services.server.running() {
+				return Ok(purged);
+			}
+
+			tokio::task::yield_now().await;
+		}
+	}
+
+	/// Deletes an event from the timeline, the search index and the relations.
+	/// Its state and short ids are kept, so events after it still resolve
+	/// their state.
+	#[tracing::instrument(skip(self, pdu))]
+	fn purge_pdu(&self, pdu_id: &[u8], pdu: &PduEvent, shortroomid: u64) -> Result<()> {
+		if let Ok(content) = serde_json::from_str::<ExtractBody>(pdu.content.get()) {
+			if let Some(body) = content.body {
+				self.services
+					.search
+					.deindex_pdu(shortroomid, pdu_id, &body)?;
+			}
+		}
+
+		let mut related = Vec::new();
+		if let Ok(content) = serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get()) {
+			related.extend(self.get_pdu_count(&content.relates_to.event_id)?);
+		}
+
+		if let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
+			if let Relation::Reply {
+				in_reply_to,
+			} = content.relates_to
+			{
+				related.extend(self.get_pdu_count(&in_reply_to.event_id)?);
+			}
+		}
+
+		self.services
+			.pdu_metadata
+			.purge_pdu(&pdu.event_id, pdu_count(pdu_id)?, &related)?;
+
+		self.db.purge_pdu(pdu_id, &pdu.event_id)
+	}
+}
@@ -3,12 +3,12 @@ use std::{collections::BTreeMap, sync::Arc};
 use conduit::{debug_info, trace, utils, Result, Server};
 use ruma::{
 	api::federation::transactions::edu::{Edu, TypingContent},
-	events::SyncEphemeralRoomEvent,
+	events::{typing::TypingEvent, SyncEphemeralRoomEvent},
 	OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 use tokio::sync::{broadcast, RwLock};
 
-use crate::{globals, sending, Dep};
+use crate::{globals, sending, sending::AppserviceEdu, Dep};
 
 pub struct Service {
 	server: Arc<Server>,
@@ -67,6 +67,9 @@ impl Service {
 			self.federation_send(room_id, user_id, true)?;
 		}
 
+		// update appservices
+		self.appservice_send(room_id).await?;
+
 		Ok(())
 	}
 
@@ -93,6 +96,9 @@ impl Service {
 			self.federation_send(room_id, user_id, false)?;
 		}
 
+		// update appservices
+		self.appservice_send(room_id).await?;
+
 		Ok(())
 	}
 
@@ -127,7 +133,8 @@ impl Service {
 			drop(typing);
 		};
 
-		if !removable.is_empty() {
+		let changed = !removable.is_empty();
+		if changed {
 			let typing = &mut self.typing.write().await;
 			let room = typing.entry(room_id.to_owned()).or_default();
 			for user in &removable {
@@ -151,6 +158,11 @@ impl Service {
 			}
 		}
 
+		// update appservices, after the typing lock was released
+		if changed {
+			self.appservice_send(room_id).await?;
+		}
+
 		Ok(())
 	}
 
@@ -201,4 +213,19 @@ impl Service {
 
 		Ok(())
 	}
+
+	/// Queues the users now typing in the room for appservices (MSC2409).
+	async fn appservice_send(&self, room_id: &RoomId) -> Result<()> {
+		let event = TypingEvent {
+			content: self.typings_all(room_id).await?.content,
+			room_id: room_id.to_owned(),
+		};
+
+		self.services
+			.sending
+			.send_edu_appservices(&AppserviceEdu::Room(
+				room_id.to_owned(),
+				serde_json::to_value(event).expect("TypingEvent can be serialized"),
+			))
+	}
 }
@@ -3,7 +3,39 @@ use std::{fmt::Debug, mem};
 use bytes::BytesMut;
 use conduit::{debug_error, err, trace, utils, warn, Err, Result};
 use reqwest::Client;
-use ruma::api::{appservice::Registration, IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken};
+use ruma::{
+	api::{appservice::Registration, IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken},
+	OwnedDeviceId, OwnedRoomId, OwnedUserId,
+};
+use serde::{Deserialize, Serialize};
+
+/// EDU queued for appservices. Which appservices are interested in it is only
+/// decided when the transaction is sent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppserviceEdu {
+	/// Typing notification or receipt event of a room (MSC2409)
+	Room(OwnedRoomId, serde_json::Value),
+
+	/// Presence event of a user (MSC2409)
+	Presence(OwnedUserId, serde_json::Value),
+
+	/// To-device event for a device of a local user (MSC2409)
+	ToDevice(OwnedUserId, serde_json::Value),
+
+	/// The devices or cross-signing keys of a user changed (MSC3202)
+	DeviceListUpdate(OwnedUserId),
+
+	/// The one-time key count of a device of a local user changed (MSC3202)
+	OneTimeKeysCount(OwnedUserId, OwnedDeviceId),
+}
+
+impl AppserviceEdu {
+	/// Whether the EDU is for appservices receiving device list changes and
+	/// one-time key counts, rather than ephemeral events.
+	#[must_use]
+	pub fn is_msc3202(&self) -> bool { matches!(self, Self::DeviceListUpdate(_) | Self::OneTimeKeysCount(..)) }
+}
 
 /// Sends a request to an appservice
 ///
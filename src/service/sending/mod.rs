@@ -15,7 +15,7 @@ use ruma::{
 use tokio::sync::Mutex;
 
 use self::data::Data;
-pub use self::dest::Destination;
+pub use self::{appservice::AppserviceEdu, dest::Destination};
 use crate::{account_data, client, globals, presence, pusher, resolver, rooms, users, Dep};
 
 pub struct Service {
@@ -211,6 +211,38 @@ impl Service {
 		Ok(())
 	}
 
+	/// Queues an EDU for the appservices which opted into receiving it.
+	#[tracing::instrument(skip(self, edu), level = "debug")]
+	pub fn send_edu_appservices(&self, edu: &AppserviceEdu) -> Result<()> {
+		let appservices = self.services.appservice.edu_receivers(edu.is_msc3202());
+		if appservices.is_empty() {
+			return Ok(());
+		}
+
+		let serialized = serde_json::to_vec(edu).expect("AppserviceEdu can be serialized");
+		let requests = appservices
+			.into_iter()
+			.map(|id| (Destination::Appservice(id), SendingEvent::Edu(serialized.clone())))
+			.collect::<Vec<_>>();
+		let _cork = self.db.db.cork();
+		let keys = self.db.queue_requests(
+			&requests
+				.iter()
+				.map(|(o, e)| (o, e.clone()))
+				.collect::<Vec<_>>(),
+		)?;
+
+		for ((dest, event), queue_id) in requests.into_iter().zip(keys) {
+			self.dispatch(Msg {
+				dest,
+				event,
+				queue_id,
+			})?;
+		}
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, room_id), level = "debug")]
 	pub fn flush_room(&self, room_id: &RoomId) -> Result<()> {
 		let servers = self
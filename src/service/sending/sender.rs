@@ -1,6 +1,6 @@
 use std::{
 	cmp,
-	collections::{BTreeMap, HashMap, HashSet},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
 	fmt::Debug,
 	time::{Duration, Instant},
 };
@@ -14,10 +14,13 @@ use conduit::{
 use federation::transactions::send_transaction_message;
 use futures_util::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
 use ruma::{
-	api::federation::{
-		self,
-		transactions::edu::{
-			DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent, ReceiptData, ReceiptMap,
+	api::{
+		appservice::event::push_events,
+		federation::{
+			self,
+			transactions::edu::{
+				DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent, ReceiptData, ReceiptMap,
+			},
 		},
 	},
 	device_id,
@@ -28,7 +31,8 @@ use ruma::{
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::time::sleep_until;
 
-use super::{appservice, Destination, Msg, SendingEvent, Service};
+use super::{appservice, AppserviceEdu, Destination, Msg, SendingEvent, Service};
+use crate::appservice::RegistrationInfo;
 
 #[derive(Debug)]
 enum TransactionStatus {
@@ -427,7 +431,18 @@ impl Service {
 	async fn send_events_dest_appservice(
 		&self, dest: &Destination, id: &str, events: Vec<SendingEvent>,
 	) -> SendingResult {
+		let Some(appservice) = self.services.appservice.read().await.get(id).cloned() else {
+			return Err((
+				dest.clone(),
+				Error::bad_database("[Appservice] Could not load registration from db."),
+			));
+		};
+
 		let mut pdu_jsons = Vec::new();
+		let mut ephemeral = Vec::new();
+		let mut to_device = Vec::new();
+		let mut device_list_changes = BTreeSet::new();
+		let mut one_time_keys_devices = BTreeSet::new();
 
 		for event in &events {
 			match event {
@@ -446,28 +461,77 @@ impl Service {
 							.to_room_event(),
 					);
 				},
-				SendingEvent::Edu(_) | SendingEvent::Flush => {
-					// Appservices don't need EDUs (?) and flush only;
-					// no new content
+				SendingEvent::Edu(edu) => {
+					let edu: AppserviceEdu = match serde_json::from_slice(edu) {
+						Ok(edu) => edu,
+						Err(e) => {
+							debug_warn!("Dropping invalid appservice EDU: {e}");
+							continue;
+						},
+					};
+
+					if !self
+						.appservice_interested(&appservice, &edu)
+						.map_err(|e| (dest.clone(), e))?
+					{
+						continue;
+					}
+
+					match edu {
+						AppserviceEdu::Room(_, event) | AppserviceEdu::Presence(_, event) => {
+							match serde_json::from_value(event) {
+								Ok(event) => ephemeral.push(event),
+								Err(e) => debug_warn!("Dropping ephemeral event appservices can't receive: {e}"),
+							}
+						},
+						AppserviceEdu::ToDevice(_, event) => match serde_json::from_value(event) {
+							Ok(event) => to_device.push(event),
+							Err(e) => debug_warn!("Dropping invalid to-device event: {e}"),
+						},
+						AppserviceEdu::DeviceListUpdate(user_id) => {
+							device_list_changes.insert(user_id);
+						},
+						AppserviceEdu::OneTimeKeysCount(user_id, device_id) => {
+							one_time_keys_devices.insert((user_id, device_id));
+						},
+					}
+				},
+				SendingEvent::Flush => {
+					// flush only; no new content
 				},
 			}
 		}
 
+		let mut device_one_time_keys_count: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+		for (user_id, device_id) in one_time_keys_devices {
+			let count = self
+				.services
+				.users
+				.count_one_time_keys(&user_id, &device_id)
+				.map_err(|e| (dest.clone(), e))?;
+
+			device_one_time_keys_count
+				.entry(user_id)
+				.or_default()
+				.insert(device_id, count);
+		}
+
+		// EDUs the appservice isn't interested in don't warrant a transaction
+		if pdu_jsons.is_empty()
+			&& ephemeral.is_empty()
+			&& to_device.is_empty()
+			&& device_list_changes.is_empty()
+			&& device_one_time_keys_count.is_empty()
+		{
+			return Ok(dest.clone());
+		}
+
 		//debug_assert!(!pdu_jsons.is_empty(), "sending empty transaction");
 		let client = &self.services.client.appservice;
 		match appservice::send_request(
 			client,
-			self.services
-				.appservice
-				.get_registration(id)
-				.await
-				.ok_or_else(|| {
-					(
-						dest.clone(),
-						Error::bad_database("[Appservice] Could not load registration from db."),
-					)
-				})?,
-			ruma::api::appservice::event::push_events::v1::Request {
+			appservice.registration,
+			push_events::v1::Request {
 				events: pdu_jsons,
 				txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
 					&events
@@ -479,8 +543,14 @@ impl Service {
 						.collect::<Vec<_>>(),
 				)))
 					.into(),
-				ephemeral: Vec::new(),
-				to_device: Vec::new(),
+				ephemeral,
+				to_device,
+				device_lists: push_events::v1::DeviceLists {
+					changed: device_list_changes.into_iter().collect(),
+					left: Vec::new(),
+				},
+				device_one_time_keys_count,
+				device_unused_fallback_key_types: BTreeMap::new(),
 			},
 		)
 		.await
@@ -490,6 +560,47 @@ impl Service {
 		}
 	}
 
+	/// Whether the appservice still opts into receiving the EDU and is
+	/// interested in its room or user.
+	fn appservice_interested(&self, appservice: &RegistrationInfo, edu: &AppserviceEdu) -> Result<bool> {
+		let opted_in = if edu.is_msc3202() {
+			appservice.extensions.msc3202
+		} else {
+			appservice.registration.receive_ephemeral
+		};
+
+		if !opted_in {
+			return Ok(false);
+		}
+
+		match edu {
+			AppserviceEdu::Room(room_id, _) => self
+				.services
+				.state_cache
+				.appservice_in_room(room_id, appservice),
+			AppserviceEdu::ToDevice(user_id, _) | AppserviceEdu::OneTimeKeysCount(user_id, _) => {
+				Ok(appservice.is_user_match(user_id))
+			},
+			AppserviceEdu::Presence(user_id, _) | AppserviceEdu::DeviceListUpdate(user_id) => {
+				if appservice.is_user_match(user_id) {
+					return Ok(true);
+				}
+
+				for room_id in self.services.state_cache.rooms_joined(user_id) {
+					if self
+						.services
+						.state_cache
+						.appservice_in_room(&room_id?, appservice)?
+					{
+						return Ok(true);
+					}
+				}
+
+				Ok(false)
+			},
+		}
+	}
+
 	#[tracing::instrument(skip(self, dest, events), name = "push")]
 	async fn send_events_dest_push(
 		&self, dest: &Destination, userid: &OwnedUserId, pushkey: &str, events: Vec<SendingEvent>,
@@ -10,28 +10,36 @@ use database::Database;
 use tokio::sync::Mutex;
 
 use crate::{
-	account_data, admin, appservice, client, emergency, globals, key_backups,
+	account_data, account_validity, admin, appservice, client, emergency, globals, key_backups, mailer,
 	manager::Manager,
-	media, presence, pusher, resolver, rooms, sending, server_keys, service,
+	media, oidc, presence, pusher, rate_limiter, registration_tokens, resolver, rooms, sending, server_keys, service,
 	service::{Args, Map, Service},
-	transaction_ids, uiaa, updates, users,
+	spam_checker, threepid, transaction_ids, uiaa, updates, users,
 };
 
 pub struct Services {
 	pub account_data: Arc<account_data::Service>,
+	pub account_validity: Arc<account_validity::Service>,
 	pub admin: Arc<admin::Service>,
 	pub appservice: Arc<appservice::Service>,
 	pub client: Arc<client::Service>,
 	pub emergency: Arc<emergency::Service>,
 	pub globals: Arc<globals::Service>,
 	pub key_backups: Arc<key_backups::Service>,
+	pub mailer: Arc<mailer::Service>,
 	pub media: Arc<media::Service>,
+	pub oidc: Arc<oidc::Service>,
+	pub policy: Arc<policy::Service>,
 	pub presence: Arc<presence::Service>,
 	pub pusher: Arc<pusher::Service>,
+	pub rate_limiter: Arc<rate_limiter::Service>,
+	pub registration_tokens: Arc<registration_tokens::Service>,
 	pub resolver: Arc<resolver::Service>,
 	pub rooms: rooms::Service,
 	pub sending: Arc<sending::Service>,
 	pub server_keys: Arc<server_keys::Service>,
+	pub spam_checker: Arc<spam_checker::Service>,
+	pub threepid: Arc<threepid::Service>,
 	pub transaction_ids: Arc<transaction_ids::Service>,
 	pub uiaa: Arc<uiaa::Service>,
 	pub updates: Arc<updates::Service>,
@@ -62,6 +70,7 @@ impl Services {
 
 		Ok(Arc::new(Self {
 			account_data: build!(account_data::Service),
+			account_validity: build!(account_validity::Service),
 			admin: build!(admin::Service),
 			appservice: build!(appservice::Service),
 			resolver: build!(resolver::Service),
@@ -69,9 +78,14 @@ impl Services {
 			emergency: build!(emergency::Service),
 			globals: build!(globals::Service),
 			key_backups: build!(key_backups::Service),
+			mailer: build!(mailer::Service),
 			media: build!(media::Service),
+			oidc: build!(oidc::Service),
+			policy: build!(policy::Service),
 			presence: build!(presence::Service),
 			pusher: build!(pusher::Service),
+			rate_limiter: build!(rate_limiter::Service),
+			registration_tokens: build!(registration_tokens::Service),
 			rooms: rooms::Service {
 				alias: build!(rooms::alias::Service),
 				auth_chain: build!(rooms::auth_chain::Service),
@@ -81,7 +95,9 @@ impl Services {
 				metadata: build!(rooms::metadata::Service),
 				outlier: build!(rooms::outlier::Service),
 				pdu_metadata: build!(rooms::pdu_metadata::Service),
+				purge: build!(rooms::purge::Service),
 				read_receipt: build!(rooms::read_receipt::Service),
+				retention: build!(rooms::retention::Service),
 				search: build!(rooms::search::Service),
 				short: build!(rooms::short::Service),
 				spaces: build!(rooms::spaces::Service),
@@ -96,6 +112,8 @@ impl Services {
 			},
 			sending: build!(sending::Service),
 			server_keys: build!(server_keys::Service),
+			spam_checker: build!(spam_checker::Service),
+			threepid: build!(threepid::Service),
 			transaction_ids: build!(transaction_ids::Service),
 			uiaa: build!(uiaa::Service),
 			updates: build!(updates::Service),
@@ -112,6 +130,7 @@ impl Services {
 		debug_info!("Starting services...");
 
 		self.admin.set_services(&Some(Arc::clone(self)));
+		self.account_validity.set_services(&Some(Arc::clone(self)));
 		globals::migrations::migrations(self).await?;
 		self.manager
 			.lock()
@@ -134,6 +153,7 @@ impl Services {
 		}
 
 		self.admin.set_services(&None);
+		self.account_validity.set_services(&None);
 
 		debug_info!("Services shutdown complete.");
 	}
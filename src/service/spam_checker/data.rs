@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use conduit::Result;
+use database::{Database, Map};
+use ruma::UserId;
+
+pub(super) struct Data {
+	shadowbanneduserids: Arc<Map>,
+}
+
+impl Data {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			shadowbanneduserids: db["shadowbanneduserids"].clone(),
+		}
+	}
+
+	pub(super) fn is_shadow_banned(&self, user_id: &UserId) -> Result<bool> {
+		Ok(self.shadowbanneduserids.get(user_id.as_bytes())?.is_some())
+	}
+
+	pub(super) fn set_shadow_banned(&self, user_id: &UserId, shadow_banned: bool) -> Result<()> {
+		if shadow_banned {
+			self.shadowbanneduserids.insert(user_id.as_bytes(), &[])?;
+		} else {
+			self.shadowbanneduserids.remove(user_id.as_bytes())?;
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,189 @@
+mod tests;
+
+use std::{fs, path::PathBuf, sync::RwLock};
+
+use conduit::{err, info, Result};
+use regex::Regex;
+use ruma::{RoomId, UserId};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+
+use super::{SpamChecker, Verdict};
+
+/// Matches regular expressions of the rules file configured by
+/// `spam_checker_rules` against usernames, messages, room names and media.
+pub(super) struct KeywordChecker {
+	path: Option<PathBuf>,
+	rules: RwLock<Vec<Rule>>,
+}
+
+struct Rule {
+	pattern: Regex,
+	targets: Vec<Target>,
+	action: Action,
+	reason: String,
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+	#[serde(default)]
+	rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+	pattern: String,
+	#[serde(default)]
+	targets: Vec<Target>,
+	#[serde(default)]
+	action: Action,
+	reason: Option<String>,
+}
+
+/// What a rule is matched against; every target if none is given.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Target {
+	/// Localpart on registration
+	Username,
+	/// Users registering rooms, inviting, joining, uploading or sending
+	UserId,
+	/// Names and topics of new rooms and of `m.room.name`/`m.room.topic`
+	RoomName,
+	/// `body` and `formatted_body` of events
+	Message,
+	/// Content type and filename of uploads
+	Media,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+	#[default]
+	Deny,
+	ShadowBan,
+}
+
+#[derive(Deserialize)]
+struct ExtractText {
+	body: Option<String>,
+	formatted_body: Option<String>,
+	name: Option<String>,
+	topic: Option<String>,
+}
+
+impl KeywordChecker {
+	pub(super) fn new(path: Option<PathBuf>) -> Result<Self> {
+		let checker = Self {
+			path,
+			rules: RwLock::new(Vec::new()),
+		};
+
+		checker.reload()?;
+		Ok(checker)
+	}
+
+	fn verdict(&self, target: Target, text: &str) -> Verdict {
+		let rules = self.rules.read().expect("locked");
+		let Some(rule) = rules
+			.iter()
+			.find(|rule| (rule.targets.is_empty() || rule.targets.contains(&target)) && rule.pattern.is_match(text))
+		else {
+			return Verdict::Allow;
+		};
+
+		match rule.action {
+			Action::Deny => Verdict::Deny(rule.reason.clone()),
+			Action::ShadowBan => Verdict::ShadowBan,
+		}
+	}
+
+	fn first_verdict<'a, I>(&self, texts: I) -> Verdict
+	where
+		I: IntoIterator<Item = (Target, Option<&'a str>)>,
+	{
+		texts
+			.into_iter()
+			.filter_map(|(target, text)| Some((target, text?)))
+			.map(|(target, text)| self.verdict(target, text))
+			.find(|verdict| *verdict != Verdict::Allow)
+			.unwrap_or(Verdict::Allow)
+	}
+}
+
+impl SpamChecker for KeywordChecker {
+	fn name(&self) -> &str { "keyword" }
+
+	fn reload(&self) -> Result<()> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+
+		let file =
+			fs::read_to_string(path).map_err(|e| err!(Config("spam_checker_rules", "Failed to read {path:?}: {e}")))?;
+
+		let rules = parse_rules(&file)?;
+		info!("Loaded {} spam checker rules from {path:?}", rules.len());
+		*self.rules.write().expect("locked") = rules;
+
+		Ok(())
+	}
+
+	fn check_registration(&self, username: &str) -> Verdict { self.verdict(Target::Username, username) }
+
+	fn check_room_creation(&self, user_id: &UserId, name: Option<&str>, topic: Option<&str>) -> Verdict {
+		self.first_verdict([
+			(Target::UserId, Some(user_id.as_str())),
+			(Target::RoomName, name),
+			(Target::RoomName, topic),
+		])
+	}
+
+	fn check_invite(&self, inviter: &UserId, _invitee: &UserId, _room_id: &RoomId) -> Verdict {
+		self.verdict(Target::UserId, inviter.as_str())
+	}
+
+	fn check_join(&self, user_id: &UserId, _room_id: &RoomId) -> Verdict {
+		self.verdict(Target::UserId, user_id.as_str())
+	}
+
+	fn check_event(&self, sender: &UserId, _room_id: &RoomId, event_type: &str, content: &RawJsonValue) -> Verdict {
+		let Ok(text) = serde_json::from_str::<ExtractText>(content.get()) else {
+			return self.verdict(Target::UserId, sender.as_str());
+		};
+
+		let room_name = matches!(event_type, "m.room.name" | "m.room.topic");
+		self.first_verdict([
+			(Target::UserId, Some(sender.as_str())),
+			(Target::Message, text.body.as_deref()),
+			(Target::Message, text.formatted_body.as_deref()),
+			(Target::RoomName, text.name.as_deref().filter(|_| room_name)),
+			(Target::RoomName, text.topic.as_deref().filter(|_| room_name)),
+		])
+	}
+
+	fn check_media_upload(&self, user_id: &UserId, content_type: Option<&str>, filename: Option<&str>) -> Verdict {
+		self.first_verdict([
+			(Target::UserId, Some(user_id.as_str())),
+			(Target::Media, content_type),
+			(Target::Media, filename),
+		])
+	}
+}
+
+fn parse_rules(file: &str) -> Result<Vec<Rule>> {
+	toml::from_str::<RulesFile>(file)?
+		.rules
+		.into_iter()
+		.map(|rule| -> Result<Rule> {
+			Ok(Rule {
+				pattern: Regex::new(&rule.pattern)?,
+				targets: rule.targets,
+				action: rule.action,
+				reason: rule
+					.reason
+					.unwrap_or_else(|| "Rejected by the spam checker.".to_owned()),
+			})
+		})
+		.collect()
+}
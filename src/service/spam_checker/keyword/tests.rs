@@ -0,0 +1,120 @@
+#![cfg(test)]
+
+use std::sync::RwLock;
+
+use ruma::{room_id, user_id};
+use serde_json::value::to_raw_value;
+
+use super::{parse_rules, KeywordChecker, SpamChecker, Verdict};
+
+const RULES: &str = r#"
+[[rules]]
+pattern = "(?i)cheap pills"
+targets = ["message", "room_name"]
+reason = "No pills."
+
+[[rules]]
+pattern = "^@spammer"
+targets = ["user_id"]
+action = "shadow_ban"
+
+[[rules]]
+pattern = "casino"
+"#;
+
+fn checker() -> KeywordChecker {
+	KeywordChecker {
+		path: None,
+		rules: RwLock::new(parse_rules(RULES).expect("valid rules")),
+	}
+}
+
+fn message(body: &str) -> Verdict {
+	let content = to_raw_value(&serde_json::json!({ "msgtype": "m.text", "body": body })).expect("valid json");
+
+	checker().check_event(
+		user_id!("@alice:example.com"),
+		room_id!("!room:example.com"),
+		"m.room.message",
+		&content,
+	)
+}
+
+#[test]
+fn matching_message_is_denied_with_its_reason() {
+	assert_eq!(message("Buy CHEAP pills now"), Verdict::Deny("No pills.".to_owned()));
+	assert_eq!(message("hello"), Verdict::Allow);
+}
+
+#[test]
+fn rule_without_targets_matches_everything() {
+	let checker = checker();
+
+	assert_eq!(
+		message("casino night"),
+		Verdict::Deny("Rejected by the spam checker.".to_owned())
+	);
+	assert_eq!(
+		checker.check_registration("casino"),
+		Verdict::Deny("Rejected by the spam checker.".to_owned())
+	);
+}
+
+#[test]
+fn rules_only_match_their_targets() {
+	let checker = checker();
+
+	assert_eq!(checker.check_registration("cheap pills"), Verdict::Allow, "not a username rule");
+	assert_eq!(
+		checker.check_room_creation(user_id!("@alice:example.com"), Some("Cheap pills"), None),
+		Verdict::Deny("No pills.".to_owned())
+	);
+	assert_eq!(
+		checker.check_media_upload(user_id!("@alice:example.com"), None, Some("cheap pills.png")),
+		Verdict::Allow
+	);
+}
+
+#[test]
+fn shadow_ban_rule() {
+	let checker = checker();
+
+	assert_eq!(
+		checker.check_join(user_id!("@spammer:example.com"), room_id!("!room:example.com")),
+		Verdict::ShadowBan
+	);
+	assert_eq!(
+		checker.check_join(user_id!("@alice:example.com"), room_id!("!room:example.com")),
+		Verdict::Allow
+	);
+}
+
+#[test]
+fn room_name_is_only_checked_in_name_and_topic_events() {
+	let checker = checker();
+	let content = to_raw_value(&serde_json::json!({ "name": "cheap pills" })).expect("valid json");
+
+	assert_eq!(
+		checker.check_event(
+			user_id!("@alice:example.com"),
+			room_id!("!room:example.com"),
+			"m.room.name",
+			&content
+		),
+		Verdict::Deny("No pills.".to_owned())
+	);
+	assert_eq!(
+		checker.check_event(
+			user_id!("@alice:example.com"),
+			room_id!("!room:example.com"),
+			"m.room.canonical_alias",
+			&content
+		),
+		Verdict::Allow
+	);
+}
+
+#[test]
+fn invalid_pattern_is_refused() {
+	assert!(parse_rules("[[rules]]\npattern = \"(unclosed\"").is_err());
+}
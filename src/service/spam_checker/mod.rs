@@ -0,0 +1,203 @@
+mod data;
+mod keyword;
+
+use std::sync::{Arc, RwLock};
+
+use conduit::{debug_info, error, info, Err, PduEvent, Result};
+use ruma::{RoomId, ServerName, UserId};
+use serde_json::value::RawValue as RawJsonValue;
+
+use self::{data::Data, keyword::KeywordChecker};
+use crate::{globals, Dep};
+
+pub struct Service {
+	services: Services,
+	db: Data,
+	checkers: RwLock<Vec<Arc<dyn SpamChecker>>>,
+}
+
+struct Services {
+	globals: Dep<globals::Service>,
+}
+
+/// What to do with a request or an event checked for spam.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+	/// Handle it as usual.
+	Allow,
+
+	/// Refuse it with the reason given to the client. Events received over
+	/// federation are soft failed instead.
+	Deny(String),
+
+	/// Pretend to handle it without doing anything visible to other users.
+	/// Local users registered with this verdict stay shadow-banned.
+	ShadowBan,
+}
+
+/// A spam checker consulted before requests and events are handled. Checks
+/// return `Verdict::Allow` unless implemented; the first checker which does
+/// not allow decides.
+pub trait SpamChecker: Send + Sync {
+	/// Name of the checker in logs.
+	fn name(&self) -> &str;
+
+	/// Reloads the configuration of the checker, on the admin command
+	/// `server reload-spam-checker`.
+	fn reload(&self) -> Result<()> { Ok(()) }
+
+	/// Registration of an account with the localpart `username`.
+	fn check_registration(&self, _username: &str) -> Verdict { Verdict::Allow }
+
+	/// Creation of a room by a local user, with its initial name and topic.
+	fn check_room_creation(&self, _user_id: &UserId, _name: Option<&str>, _topic: Option<&str>) -> Verdict {
+		Verdict::Allow
+	}
+
+	/// Invite of a user, by a local user or received over federation.
+	fn check_invite(&self, _inviter: &UserId, _invitee: &UserId, _room_id: &RoomId) -> Verdict { Verdict::Allow }
+
+	/// Join of a room by a local user.
+	fn check_join(&self, _user_id: &UserId, _room_id: &RoomId) -> Verdict { Verdict::Allow }
+
+	/// Event sent by a local user.
+	fn check_event(&self, _sender: &UserId, _room_id: &RoomId, _event_type: &str, _content: &RawJsonValue) -> Verdict {
+		Verdict::Allow
+	}
+
+	/// Upload of media by a local user.
+	fn check_media_upload(&self, _user_id: &UserId, _content_type: Option<&str>, _filename: Option<&str>) -> Verdict {
+		Verdict::Allow
+	}
+
+	/// Timeline event received over federation, soft failed unless allowed;
+	/// checked like a local event unless implemented.
+	fn check_federated_pdu(&self, _origin: &ServerName, pdu: &PduEvent) -> Verdict {
+		self.check_event(&pdu.sender, &pdu.room_id, &pdu.kind.to_string(), &pdu.content)
+	}
+}
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		let keyword = KeywordChecker::new(args.server.config.spam_checker_rules.clone())?;
+
+		Ok(Arc::new(Self {
+			services: Services {
+				globals: args.depend::<globals::Service>("globals"),
+			},
+			db: Data::new(args.db),
+			checkers: RwLock::new(vec![Arc::new(keyword)]),
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Adds a spam checker consulted after those already registered.
+	pub fn register(&self, checker: Arc<dyn SpamChecker>) {
+		info!("Registered spam checker {}", checker.name());
+		self.checkers.write().expect("locked").push(checker);
+	}
+
+	/// Reloads the configuration of every spam checker. Those which fail keep
+	/// their previous configuration and are reported together.
+	pub fn reload(&self) -> Result<()> {
+		let mut failed = Vec::new();
+		for checker in self.checkers().iter() {
+			if let Err(e) = checker.reload() {
+				error!(checker = checker.name(), "Failed to reload spam checker: {e}");
+				failed.push(format!("{}: {e}", checker.name()));
+			}
+		}
+
+		if !failed.is_empty() {
+			return Err!("Failed to reload spam checkers: {}", failed.join(", "));
+		}
+
+		Ok(())
+	}
+
+	pub fn check_registration(&self, username: &str) -> Verdict {
+		self.check(|checker| checker.check_registration(username))
+	}
+
+	pub fn check_room_creation(&self, user_id: &UserId, name: Option<&str>, topic: Option<&str>) -> Result<Verdict> {
+		if self.is_shadow_banned(user_id)? {
+			return Ok(Verdict::ShadowBan);
+		}
+
+		Ok(self.check(|checker| checker.check_room_creation(user_id, name, topic)))
+	}
+
+	pub fn check_invite(&self, inviter: &UserId, invitee: &UserId, room_id: &RoomId) -> Result<Verdict> {
+		if self.is_shadow_banned(inviter)? {
+			return Ok(Verdict::ShadowBan);
+		}
+
+		Ok(self.check(|checker| checker.check_invite(inviter, invitee, room_id)))
+	}
+
+	pub fn check_join(&self, user_id: &UserId, room_id: &RoomId) -> Result<Verdict> {
+		if self.is_shadow_banned(user_id)? {
+			return Ok(Verdict::ShadowBan);
+		}
+
+		Ok(self.check(|checker| checker.check_join(user_id, room_id)))
+	}
+
+	pub fn check_event(
+		&self, sender: &UserId, room_id: &RoomId, event_type: &str, content: &RawJsonValue,
+	) -> Result<Verdict> {
+		if self.is_shadow_banned(sender)? {
+			return Ok(Verdict::ShadowBan);
+		}
+
+		Ok(self.check(|checker| checker.check_event(sender, room_id, event_type, content)))
+	}
+
+	pub fn check_media_upload(
+		&self, user_id: &UserId, content_type: Option<&str>, filename: Option<&str>,
+	) -> Result<Verdict> {
+		if self.is_shadow_banned(user_id)? {
+			return Ok(Verdict::ShadowBan);
+		}
+
+		Ok(self.check(|checker| checker.check_media_upload(user_id, content_type, filename)))
+	}
+
+	pub fn check_federated_pdu(&self, origin: &ServerName, pdu: &PduEvent) -> Verdict {
+		self.check(|checker| checker.check_federated_pdu(origin, pdu))
+	}
+
+	/// Whether a local user is shadow-banned: their requests seem to succeed
+	/// but have no effect visible to others.
+	pub fn is_shadow_banned(&self, user_id: &UserId) -> Result<bool> {
+		if !self.services.globals.user_is_local(user_id) {
+			return Ok(false);
+		}
+
+		self.db.is_shadow_banned(user_id)
+	}
+
+	pub fn set_shadow_banned(&self, user_id: &UserId, shadow_banned: bool) -> Result<()> {
+		self.db.set_shadow_banned(user_id, shadow_banned)
+	}
+
+	fn check<F>(&self, check: F) -> Verdict
+	where
+		F: Fn(&dyn SpamChecker) -> Verdict,
+	{
+		for checker in self.checkers().iter() {
+			let verdict = check(checker.as_ref());
+			if verdict != Verdict::Allow {
+				debug_info!(checker = checker.name(), ?verdict, "Spam checker did not allow");
+				return verdict;
+			}
+		}
+
+		Verdict::Allow
+	}
+
+	fn checkers(&self) -> Vec<Arc<dyn SpamChecker>> { self.checkers.read().expect("locked").clone() }
+}
@@ -0,0 +1,285 @@
+mod tests;
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use conduit::{err, utils, Err, Error, Result, Server};
+use ruma::{
+	api::client::error::{ErrorKind, RetryAfter},
+	ClientSecret, OwnedClientSecret, OwnedSessionId, SessionId, UInt,
+};
+
+use crate::{globals, mailer, Dep};
+
+const SESSION_ID_LENGTH: usize = 32;
+const TOKEN_LENGTH: usize = 32;
+
+/// Path of the link in validation emails.
+pub const VALIDATE_PATH: &str = "/_conduwuit/email/validate";
+
+pub struct Service {
+	server: Arc<Server>,
+	services: Services,
+	sessions: Mutex<HashMap<OwnedSessionId, Session>>,
+	uiaa_emails: Mutex<HashMap<String, (String, Purpose, Instant)>>,
+	last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+struct Services {
+	globals: Dep<globals::Service>,
+	mailer: Dep<mailer::Service>,
+}
+
+/// What an email address is being validated for, which decides the email sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Purpose {
+	Registration,
+	AddThreepid,
+	PasswordReset,
+}
+
+/// An email address waiting for its owner to follow the validation link.
+struct Session {
+	client_secret: OwnedClientSecret,
+	email: String,
+	purpose: Purpose,
+	send_attempt: UInt,
+	token: String,
+	validated: bool,
+	created: Instant,
+}
+
+impl crate::Service for Service {
+	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		Ok(Arc::new(Self {
+			server: args.server.clone(),
+			services: Services {
+				globals: args.depend::<globals::Service>("globals"),
+				mailer: args.depend::<mailer::Service>("mailer"),
+			},
+			sessions: Mutex::new(HashMap::new()),
+			uiaa_emails: Mutex::new(HashMap::new()),
+			last_sent: Mutex::new(HashMap::new()),
+		}))
+	}
+
+	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
+}
+
+impl Service {
+	/// Whether users can attach email addresses to their accounts.
+	#[inline]
+	#[must_use]
+	pub fn email_enabled(&self) -> bool { self.services.mailer.enabled() }
+
+	/// Sends a validation link to an email address. Repeated requests with the
+	/// same client secret and send attempt return the same session without
+	/// sending another email. Only one email is sent to an address every
+	/// `smtp.request_token_interval`.
+	pub async fn request_email_token(
+		&self, client_secret: &ClientSecret, email: &str, send_attempt: UInt, purpose: Purpose,
+	) -> Result<OwnedSessionId> {
+		let email = normalize_email(email);
+
+		let (sid, token) = {
+			let mut sessions = self.sessions.lock().expect("locked");
+			sessions.retain(|_, session| session.created.elapsed() < self.lifetime());
+
+			if let Some((sid, session)) = sessions.iter_mut().find(|(_, session)| {
+				*session.client_secret == *client_secret && session.email == email && session.purpose == purpose
+			}) {
+				if session.send_attempt >= send_attempt {
+					return Ok(sid.clone());
+				}
+
+				self.throttle(&email)?;
+				session.send_attempt = send_attempt;
+				(sid.clone(), session.token.clone())
+			} else {
+				self.throttle(&email)?;
+				let sid: OwnedSessionId = utils::random_string(SESSION_ID_LENGTH)
+					.try_into()
+					.expect("random string is a valid session ID");
+				let token = utils::random_string(TOKEN_LENGTH);
+
+				sessions.insert(
+					sid.clone(),
+					Session {
+						client_secret: client_secret.to_owned(),
+						email: email.clone(),
+						purpose,
+						send_attempt,
+						token: token.clone(),
+						validated: false,
+						created: Instant::now(),
+					},
+				);
+
+				(sid, token)
+			}
+		};
+
+		let link = self.validation_link(&sid, client_secret, &token)?;
+		let server_name = self.services.globals.server_name();
+		let (subject, reason) = match purpose {
+			Purpose::Registration => (
+				format!("Validate your email address for {server_name}"),
+				"to register an account",
+			),
+			Purpose::AddThreepid => (
+				format!("Add your email address to your {server_name} account"),
+				"to add it to your account",
+			),
+			Purpose::PasswordReset => (
+				format!("Reset your {server_name} password"),
+				"to reset the password of your account",
+			),
+		};
+
+		let body = format!(
+			"Someone asked to validate this email address on {server_name} {reason}.\n\nIf this was you, open the \
+			 following link:\n\n{link}\n\nOtherwise you can ignore this email.\n"
+		);
+
+		self.services.mailer.send(&email, &subject, body).await?;
+
+		Ok(sid)
+	}
+
+	/// Marks the email address of a session as validated once its owner
+	/// followed the link.
+	pub fn validate(&self, sid: &SessionId, client_secret: &ClientSecret, token: &str) -> Result<()> {
+		let mut sessions = self.sessions.lock().expect("locked");
+		let session = sessions
+			.get_mut(sid)
+			.filter(|session| session.created.elapsed() < self.lifetime())
+			.filter(|session| *session.client_secret == *client_secret)
+			.ok_or_else(|| err!(Request(ThreepidAuthFailed("Unknown or expired validation session."))))?;
+
+		if session.token != token {
+			return Err!(Request(ThreepidAuthFailed("Invalid validation token.")));
+		}
+
+		session.validated = true;
+
+		Ok(())
+	}
+
+	/// Returns the email address of a session validated for `purpose`, which
+	/// cannot be used again afterwards.
+	pub fn take_validated_email(
+		&self, sid: &SessionId, client_secret: &ClientSecret, purpose: Purpose,
+	) -> Option<String> {
+		let mut sessions = self.sessions.lock().expect("locked");
+		take_validated(&mut sessions, sid, client_secret, self.lifetime(), |session_purpose| {
+			session_purpose == purpose
+		})
+		.map(|(email, _)| email)
+	}
+
+	/// Returns the email address and purpose of a validated session, which
+	/// cannot be used again afterwards.
+	pub fn take_validated(&self, sid: &SessionId, client_secret: &ClientSecret) -> Option<(String, Purpose)> {
+		let mut sessions = self.sessions.lock().expect("locked");
+		take_validated(&mut sessions, sid, client_secret, self.lifetime(), |_| true)
+	}
+
+	/// Remembers the email address validated during a user-interactive
+	/// authentication session, for the endpoint being authenticated.
+	pub fn set_uiaa_email(&self, uiaa_session: &str, email: String, purpose: Purpose) {
+		let mut uiaa_emails = self.uiaa_emails.lock().expect("locked");
+		uiaa_emails.retain(|_, (.., created)| created.elapsed() < self.lifetime());
+		uiaa_emails.insert(uiaa_session.to_owned(), (email, purpose, Instant::now()));
+	}
+
+	/// Returns the email address validated for `purpose` during a
+	/// user-interactive authentication session.
+	pub fn take_uiaa_email(&self, uiaa_session: &str, purpose: Purpose) -> Option<String> {
+		let mut uiaa_emails = self.uiaa_emails.lock().expect("locked");
+		uiaa_emails
+			.remove(uiaa_session)
+			.filter(|(_, session_purpose, created)| *session_purpose == purpose && created.elapsed() < self.lifetime())
+			.map(|(email, ..)| email)
+	}
+
+	/// Errors with `M_LIMIT_EXCEEDED` if an email was sent to the address less
+	/// than `smtp.request_token_interval` ago.
+	fn throttle(&self, email: &str) -> Result<()> {
+		let interval = Duration::from_secs(self.server.config.smtp.request_token_interval);
+		let now = Instant::now();
+
+		let mut last_sent = self.last_sent.lock().expect("locked");
+		last_sent.retain(|_, sent| now.saturating_duration_since(*sent) < interval);
+		if let Some(wait) = retry_after(last_sent.get(email).copied(), now, interval) {
+			return Err(Error::BadRequest(
+				ErrorKind::LimitExceeded {
+					retry_after: Some(RetryAfter::Delay(wait)),
+				},
+				"An email was recently sent to this address, please try again later.",
+			));
+		}
+
+		last_sent.insert(email.to_owned(), now);
+
+		Ok(())
+	}
+
+	fn validation_link(&self, sid: &SessionId, client_secret: &ClientSecret, token: &str) -> Result<String> {
+		let mut link = self
+			.services
+			.globals
+			.well_known_client()
+			.as_ref()
+			.ok_or_else(|| {
+				err!(Config(
+					"well_known.client",
+					"A client URL is required to send email validation links."
+				))
+			})?
+			.join(VALIDATE_PATH)
+			.map_err(|e| err!(Config("well_known.client", "Invalid validation URL: {e}")))?;
+
+		link.query_pairs_mut()
+			.append_pair("sid", sid.as_str())
+			.append_pair("client_secret", client_secret.as_str())
+			.append_pair("token", token);
+
+		Ok(link.into())
+	}
+
+	fn lifetime(&self) -> Duration { Duration::from_secs(self.server.config.smtp.validation_token_ttl) }
+}
+
+/// Email addresses are compared case-insensitively.
+#[must_use]
+pub fn normalize_email(email: &str) -> String { email.trim().to_lowercase() }
+
+/// Removes and returns the email address and purpose of a session if its owner
+/// validated it before it expired.
+fn take_validated<F>(
+	sessions: &mut HashMap<OwnedSessionId, Session>, sid: &SessionId, client_secret: &ClientSecret, lifetime: Duration,
+	purpose: F,
+) -> Option<(String, Purpose)>
+where
+	F: Fn(Purpose) -> bool,
+{
+	sessions
+		.get(sid)
+		.filter(|session| session.created.elapsed() < lifetime)
+		.filter(|session| session.validated && *session.client_secret == *client_secret)
+		.filter(|session| purpose(session.purpose))?;
+
+	sessions
+		.remove(sid)
+		.map(|session| (session.email, session.purpose))
+}
+
+/// How long to wait before sending another email to an address last emailed at
+/// `last_sent`.
+fn retry_after(last_sent: Option<Instant>, now: Instant, interval: Duration) -> Option<Duration> {
+	let wait = interval.saturating_sub(now.saturating_duration_since(last_sent?));
+	(!wait.is_zero()).then_some(wait)
+}
@@ -0,0 +1,107 @@
+#![cfg(test)]
+
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use ruma::{ClientSecret, OwnedSessionId, UInt};
+
+use super::{normalize_email, retry_after, take_validated, Purpose, Session};
+
+const LIFETIME: Duration = Duration::from_secs(3600);
+
+fn sessions(purpose: Purpose, validated: bool) -> (HashMap<OwnedSessionId, Session>, OwnedSessionId) {
+	let sid: OwnedSessionId = "session".try_into().expect("valid session ID");
+	let session = Session {
+		client_secret: "secret".try_into().expect("valid client secret"),
+		email: "alice@example.com".to_owned(),
+		purpose,
+		send_attempt: UInt::MIN,
+		token: "token".to_owned(),
+		validated,
+		created: Instant::now(),
+	};
+
+	(HashMap::from([(sid.clone(), session)]), sid)
+}
+
+fn secret(secret: &str) -> &ClientSecret { secret.try_into().expect("valid client secret") }
+
+#[test]
+fn validated_session_is_consumed() {
+	let (mut sessions, sid) = sessions(Purpose::AddThreepid, true);
+
+	assert_eq!(
+		take_validated(&mut sessions, &sid, secret("secret"), LIFETIME, |purpose| purpose
+			== Purpose::AddThreepid),
+		Some(("alice@example.com".to_owned(), Purpose::AddThreepid))
+	);
+	assert_eq!(
+		take_validated(&mut sessions, &sid, secret("secret"), LIFETIME, |_| true),
+		None,
+		"used twice"
+	);
+}
+
+#[test]
+fn session_is_bound_to_its_purpose() {
+	let (mut sessions, sid) = sessions(Purpose::AddThreepid, true);
+
+	assert_eq!(
+		take_validated(&mut sessions, &sid, secret("secret"), LIFETIME, |purpose| purpose
+			== Purpose::PasswordReset),
+		None
+	);
+	assert!(sessions.contains_key(&sid), "not consumed by another purpose");
+}
+
+#[test]
+fn unvalidated_session_is_refused() {
+	let (mut sessions, sid) = sessions(Purpose::Registration, false);
+
+	assert_eq!(take_validated(&mut sessions, &sid, secret("secret"), LIFETIME, |_| true), None);
+	assert!(sessions.contains_key(&sid), "still waiting for validation");
+}
+
+#[test]
+fn session_requires_its_client_secret() {
+	let (mut sessions, sid) = sessions(Purpose::Registration, true);
+
+	assert_eq!(take_validated(&mut sessions, &sid, secret("other"), LIFETIME, |_| true), None);
+}
+
+#[test]
+fn expired_session_is_refused() {
+	let (mut sessions, sid) = sessions(Purpose::Registration, true);
+
+	assert_eq!(
+		take_validated(&mut sessions, &sid, secret("secret"), Duration::ZERO, |_| true),
+		None
+	);
+}
+
+fn later(instant: Instant, duration: Duration) -> Instant { instant.checked_add(duration).expect("valid instant") }
+
+#[test]
+fn emails_are_throttled_per_interval() {
+	let interval = Duration::from_secs(60);
+	let sent = Instant::now();
+
+	assert_eq!(retry_after(None, sent, interval), None, "never sent");
+	assert_eq!(retry_after(Some(sent), sent, interval), Some(interval));
+	assert_eq!(
+		retry_after(Some(sent), later(sent, Duration::from_secs(45)), interval),
+		Some(Duration::from_secs(15))
+	);
+	assert_eq!(
+		retry_after(Some(sent), later(sent, interval), interval),
+		None,
+		"interval elapsed"
+	);
+}
+
+#[test]
+fn emails_are_normalized() {
+	assert_eq!(normalize_email(" Alice@Example.COM\n"), "alice@example.com");
+}
@@ -2,37 +2,39 @@ mod data;
 
 use std::sync::Arc;
 
-use conduit::{error, utils, utils::hash, Error, Result, Server};
+use conduit::{error, utils, utils::hash, Error, Result};
 use data::Data;
 use ruma::{
 	api::client::{
 		error::ErrorKind,
-		uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+		uiaa::{AuthData, AuthType, EmailIdentity, Password, UiaaInfo, UserIdentifier},
 	},
 	CanonicalJsonValue, DeviceId, UserId,
 };
 
-use crate::{globals, users, Dep};
+use crate::{globals, registration_tokens, threepid, users, Dep};
 
 pub const SESSION_ID_LENGTH: usize = 32;
 
 pub struct Service {
-	server: Arc<Server>,
 	services: Services,
 	pub db: Data,
 }
 
 struct Services {
 	globals: Dep<globals::Service>,
+	registration_tokens: Dep<registration_tokens::Service>,
+	threepid: Dep<threepid::Service>,
 	users: Dep<users::Service>,
 }
 
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
 		Ok(Arc::new(Self {
-			server: args.server.clone(),
 			services: Services {
 				globals: args.depend::<globals::Service>("globals"),
+				registration_tokens: args.depend::<registration_tokens::Service>("registration_tokens"),
+				threepid: args.depend::<threepid::Service>("threepid"),
 				users: args.depend::<users::Service>("users"),
 			},
 			db: Data::new(args.db),
@@ -117,7 +119,12 @@ impl Service {
 				uiaainfo.completed.push(AuthType::Password);
 			},
 			AuthData::RegistrationToken(t) => {
-				if Some(t.token.trim()) == self.server.config.registration_token.as_deref() {
+				let session = uiaainfo.session.as_ref().expect("session is always set");
+				if self
+					.services
+					.registration_tokens
+					.reserve(session, t.token.trim())?
+				{
 					uiaainfo.completed.push(AuthType::RegistrationToken);
 				} else {
 					uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
@@ -127,6 +134,28 @@ impl Service {
 					return Ok((false, uiaainfo));
 				}
 			},
+			AuthData::EmailIdentity(EmailIdentity {
+				thirdparty_id_creds,
+				..
+			}) => {
+				if let Some((email, purpose)) = self
+					.services
+					.threepid
+					.take_validated(&thirdparty_id_creds.sid, &thirdparty_id_creds.client_secret)
+				{
+					let session = uiaainfo.session.as_ref().expect("session is always set");
+					self.services
+						.threepid
+						.set_uiaa_email(session, email, purpose);
+					uiaainfo.completed.push(AuthType::EmailIdentity);
+				} else {
+					uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+						kind: ErrorKind::ThreepidAuthFailed,
+						message: "Email address has not been validated.".to_owned(),
+					});
+					return Ok((false, uiaainfo));
+				}
+			},
 			AuthData::Dummy(_) => {
 				uiaainfo.completed.push(AuthType::Dummy);
 			},
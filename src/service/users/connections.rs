@@ -0,0 +1,525 @@
+//! State of sliding sync connections (MSC3575 and the simplified MSC4186),
+//! kept in memory and persisted once per request so that connections survive
+//! restarts.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::{Arc, Mutex},
+};
+
+use conduit::{utils, warn, Result};
+use ruma::{
+	api::client::sync::sync_events::{
+		self,
+		v4::{ExtensionsConfig, SyncRequestList},
+	},
+	events::StateEventType,
+	DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub(super) type DbConnections<C> = Mutex<BTreeMap<DbConnectionsKey, DbConnectionsVal<C>>>;
+type DbConnectionsKey = (OwnedUserId, OwnedDeviceId, String);
+type DbConnectionsVal<C> = Arc<Mutex<Connection<C>>>;
+
+/// Known rooms of each list, with the count they were last sent at.
+pub(super) type KnownRooms = BTreeMap<String, BTreeMap<OwnedRoomId, u64>>;
+
+/// Sticky parameters of a sync connection which are persisted between
+/// requests.
+trait SyncConnection: Default + DeserializeOwned + Serialize {
+	/// Sync version, so both endpoints can reuse the same connection ids.
+	const VERSION: &'static str;
+}
+
+/// A sync connection kept in memory, with what changed since it was last
+/// persisted.
+#[derive(Default)]
+pub(super) struct Connection<C> {
+	params: C,
+	known_rooms: KnownRooms, // For every room, the roomsince number
+
+	/// Milliseconds since the unix epoch when the connection was last used.
+	last_used: u64,
+
+	/// Sticky parameters as last persisted, so they are only written again
+	/// when they change.
+	persisted_params: Option<Vec<u8>>,
+
+	/// Known rooms whose count changed since the connection was persisted.
+	changed_rooms: BTreeSet<(String, OwnedRoomId)>,
+}
+
+/// Sticky parameters of a sliding sync connection.
+#[derive(Default, Deserialize, Serialize)]
+pub(super) struct SlidingSyncCache {
+	lists: BTreeMap<String, SyncRequestList>,
+	subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
+	extensions: ExtensionsConfig,
+}
+
+/// Simplified sliding sync has no sticky parameters; besides the rooms already
+/// sent on the connection, the `required_state` they were sent with is
+/// remembered so that only what changed is sent again.
+#[derive(Default, Deserialize, Serialize)]
+pub(super) struct SimplifiedSyncCache {
+	#[serde(default)]
+	list_required_state: BTreeMap<String, RequiredState>,
+	#[serde(default)]
+	subscription_required_state: BTreeMap<OwnedRoomId, RequiredState>,
+}
+
+type RequiredState = BTreeSet<(StateEventType, String)>;
+
+impl SyncConnection for SlidingSyncCache {
+	const VERSION: &'static str = "msc3575";
+}
+
+impl SyncConnection for SimplifiedSyncCache {
+	const VERSION: &'static str = "msc4186";
+}
+
+impl super::Service {
+	pub fn remembered(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) -> bool {
+		let key = (user_id, device_id, conn_id);
+		self.remembered_sync_connection(&self.connections, &key)
+	}
+
+	pub fn forget_sync_request_connection(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
+		let key = (user_id, device_id, conn_id);
+		self.remove_sync_connection::<SlidingSyncCache>(&key);
+		self.connections.lock().unwrap().remove(&key);
+	}
+
+	pub fn update_sync_request_with_cache(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, request: &mut sync_events::v4::Request,
+	) -> KnownRooms {
+		let Some(conn_id) = request.conn_id.clone() else {
+			return BTreeMap::new();
+		};
+
+		let key = (user_id, device_id, conn_id);
+		let cached = self.sync_connection(&self.connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		for (list_id, list) in &mut request.lists {
+			if let Some(cached_list) = cached.params.lists.get(list_id) {
+				if list.sort.is_empty() {
+					list.sort.clone_from(&cached_list.sort);
+				};
+				if list.room_details.required_state.is_empty() {
+					list.room_details
+						.required_state
+						.clone_from(&cached_list.room_details.required_state);
+				};
+				list.room_details.timeline_limit = list
+					.room_details
+					.timeline_limit
+					.or(cached_list.room_details.timeline_limit);
+				list.include_old_rooms = list
+					.include_old_rooms
+					.clone()
+					.or_else(|| cached_list.include_old_rooms.clone());
+				match (&mut list.filters, cached_list.filters.clone()) {
+					(Some(list_filters), Some(cached_filters)) => {
+						list_filters.is_dm = list_filters.is_dm.or(cached_filters.is_dm);
+						if list_filters.spaces.is_empty() {
+							list_filters.spaces = cached_filters.spaces;
+						}
+						list_filters.is_encrypted = list_filters.is_encrypted.or(cached_filters.is_encrypted);
+						list_filters.is_invite = list_filters.is_invite.or(cached_filters.is_invite);
+						if list_filters.room_types.is_empty() {
+							list_filters.room_types = cached_filters.room_types;
+						}
+						if list_filters.not_room_types.is_empty() {
+							list_filters.not_room_types = cached_filters.not_room_types;
+						}
+						list_filters.room_name_like = list_filters
+							.room_name_like
+							.clone()
+							.or(cached_filters.room_name_like);
+						if list_filters.tags.is_empty() {
+							list_filters.tags = cached_filters.tags;
+						}
+						if list_filters.not_tags.is_empty() {
+							list_filters.not_tags = cached_filters.not_tags;
+						}
+					},
+					(_, Some(cached_filters)) => list.filters = Some(cached_filters),
+					(Some(list_filters), _) => list.filters = Some(list_filters.clone()),
+					(..) => {},
+				}
+				if list.bump_event_types.is_empty() {
+					list.bump_event_types
+						.clone_from(&cached_list.bump_event_types);
+				};
+			}
+			cached.params.lists.insert(list_id.clone(), list.clone());
+		}
+
+		cached
+			.params
+			.subscriptions
+			.extend(request.room_subscriptions.clone());
+		request
+			.room_subscriptions
+			.extend(cached.params.subscriptions.clone());
+
+		request.extensions.e2ee.enabled = request
+			.extensions
+			.e2ee
+			.enabled
+			.or(cached.params.extensions.e2ee.enabled);
+
+		request.extensions.to_device.enabled = request.extensions.to_device.enabled.or(cached
+			.params
+			.extensions
+			.to_device
+			.enabled);
+
+		request.extensions.account_data.enabled = request.extensions.account_data.enabled.or(cached
+			.params
+			.extensions
+			.account_data
+			.enabled);
+		request.extensions.account_data.lists = request
+			.extensions
+			.account_data
+			.lists
+			.clone()
+			.or_else(|| cached.params.extensions.account_data.lists.clone());
+		request.extensions.account_data.rooms = request
+			.extensions
+			.account_data
+			.rooms
+			.clone()
+			.or_else(|| cached.params.extensions.account_data.rooms.clone());
+
+		cached.params.extensions = request.extensions.clone();
+
+		cached.known_rooms.clone()
+	}
+
+	pub fn update_sync_subscriptions(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
+		subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
+	) {
+		let key = (user_id, device_id, conn_id);
+		let cached = self.sync_connection(&self.connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		cached.params.subscriptions = subscriptions;
+	}
+
+	pub fn update_sync_known_rooms(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String, list_id: String,
+		new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64,
+	) {
+		let key = (user_id, device_id, conn_id);
+		let cached = self.sync_connection(&self.connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		cached.update_known_rooms(list_id, new_cached_rooms, globalsince);
+	}
+
+	/// Persists what changed on a sliding sync connection during a request.
+	pub fn persist_sync_connection(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
+		let key = (user_id, device_id, conn_id);
+		self.persist_sync_connection_changes(&self.connections, &key);
+	}
+
+	/// Whether a simplified sliding sync connection is known, in memory or in
+	/// the database. A missing `conn_id` is the default connection.
+	pub fn remembered_simplified(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>,
+	) -> bool {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		self.remembered_sync_connection(&self.simplified_connections, &key)
+	}
+
+	pub fn forget_simplified_sync_connection(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>,
+	) {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		self.remove_sync_connection::<SimplifiedSyncCache>(&key);
+		self.simplified_connections.lock().unwrap().remove(&key);
+	}
+
+	/// Returns the rooms of each list already sent on a simplified sliding sync
+	/// connection, with the count they were sent at.
+	pub fn simplified_sync_known_rooms(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>,
+	) -> KnownRooms {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		let cached = self.sync_connection(&self.simplified_connections, &key);
+		let known_rooms = cached.lock().unwrap().known_rooms.clone();
+
+		known_rooms
+	}
+
+	pub fn update_simplified_sync_known_rooms(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>, list_id: String,
+		new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64,
+	) {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		let cached = self.sync_connection(&self.simplified_connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		cached.update_known_rooms(list_id, new_cached_rooms, globalsince);
+	}
+
+	/// Remembers the `required_state` of a list of a simplified sliding sync
+	/// connection, returning whether it requests state which was not sent
+	/// with the rooms of the list before.
+	pub fn update_simplified_sync_list_state(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>, list_id: &str,
+		required_state: &[(StateEventType, String)],
+	) -> bool {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		let cached = self.sync_connection(&self.simplified_connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		let sent = cached
+			.params
+			.list_required_state
+			.entry(list_id.to_owned())
+			.or_default();
+
+		update_required_state(sent, required_state)
+	}
+
+	/// Remembers the `required_state` of a room subscription of a simplified
+	/// sliding sync connection, returning whether it requests state which was
+	/// not sent with the room before.
+	pub fn update_simplified_sync_subscription_state(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>, room_id: &RoomId,
+		required_state: &[(StateEventType, String)],
+	) -> bool {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		let cached = self.sync_connection(&self.simplified_connections, &key);
+		let cached = &mut cached.lock().unwrap();
+
+		let sent = cached
+			.params
+			.subscription_required_state
+			.entry(room_id.to_owned())
+			.or_default();
+
+		update_required_state(sent, required_state)
+	}
+
+	/// Persists what changed on a simplified sliding sync connection during a
+	/// request.
+	pub fn persist_simplified_sync_connection(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: Option<String>,
+	) {
+		let key = (user_id, device_id, conn_id.unwrap_or_default());
+		self.persist_sync_connection_changes(&self.simplified_connections, &key);
+	}
+
+	/// Forgets the sync connections of a device, e.g. when it is removed.
+	pub(super) fn forget_sync_connections(&self, user_id: &UserId, device_id: &DeviceId) {
+		let other_device = |(conn_user_id, conn_device_id, _): &DbConnectionsKey| {
+			&**conn_user_id != user_id || &**conn_device_id != device_id
+		};
+
+		self.connections
+			.lock()
+			.unwrap()
+			.retain(|key, _| other_device(key));
+
+		self.simplified_connections
+			.lock()
+			.unwrap()
+			.retain(|key, _| other_device(key));
+	}
+
+	/// Whether a connection is known and has not expired, in memory or in the
+	/// database.
+	fn remembered_sync_connection<C: SyncConnection>(
+		&self, connections: &DbConnections<C>, key: &DbConnectionsKey,
+	) -> bool {
+		let (user_id, device_id, conn_id) = key;
+		let expired_before = self.connection_expired_before();
+		if let Some(cached) = connections.lock().unwrap().get(key) {
+			return cached
+				.try_lock()
+				.map_or(true, |cached| cached.last_used >= expired_before);
+		}
+
+		self.remove_expired_sync_connections(user_id, device_id, expired_before);
+		self.db
+			.sync_connection_last_used(user_id, device_id, conn_id, C::VERSION)
+			.inspect_err(|e| warn!("Failed to load sync connection {conn_id} of {user_id}: {e}"))
+			.is_ok_and(|last_used| last_used.is_some())
+	}
+
+	/// Returns the state of a connection, restoring it from the database if it
+	/// is not in memory, or starting a new one. Connections unused for longer
+	/// than `sliding_sync_connection_ttl` are dropped from memory on the way.
+	fn sync_connection<C: SyncConnection>(
+		&self, connections: &DbConnections<C>, key: &DbConnectionsKey,
+	) -> DbConnectionsVal<C> {
+		let now = utils::millis_since_unix_epoch();
+		let expired_before = self.connection_expired_before();
+
+		let mut cache = connections.lock().unwrap();
+		if let Some(cached) = cache.get(key) {
+			// A connection in use by another request is not expired.
+			let fresh = cached.try_lock().map_or(true, |mut cached| {
+				let fresh = cached.last_used >= expired_before;
+				if fresh {
+					cached.last_used = now;
+				}
+
+				fresh
+			});
+
+			if fresh {
+				return Arc::clone(cached);
+			}
+		}
+
+		cache.retain(|_, cached| {
+			cached
+				.try_lock()
+				.map_or(true, |cached| cached.last_used >= expired_before)
+		});
+
+		let mut cached = self.load_sync_connection(key).unwrap_or_default();
+		cached.last_used = now;
+
+		let cached = Arc::new(Mutex::new(cached));
+		cache.insert(key.clone(), Arc::clone(&cached));
+
+		cached
+	}
+
+	/// Reads a connection from the database unless it has expired. Expired
+	/// connections of the device are removed along the way.
+	fn load_sync_connection<C: SyncConnection>(
+		&self, (user_id, device_id, conn_id): &DbConnectionsKey,
+	) -> Option<Connection<C>> {
+		self.remove_expired_sync_connections(user_id, device_id, self.connection_expired_before());
+
+		let load = || -> Result<Option<Connection<C>>> {
+			let Some(last_used) = self
+				.db
+				.sync_connection_last_used(user_id, device_id, conn_id, C::VERSION)?
+			else {
+				return Ok(None);
+			};
+
+			let params: C = self
+				.db
+				.sync_connection(user_id, device_id, conn_id, C::VERSION)?
+				.unwrap_or_default();
+
+			Ok(Some(Connection {
+				persisted_params: Some(serde_json::to_vec(&params).expect("sync connection serializes")),
+				params,
+				known_rooms: self
+					.db
+					.sync_connection_known_rooms(user_id, device_id, conn_id, C::VERSION)?,
+				last_used,
+				changed_rooms: BTreeSet::new(),
+			}))
+		};
+
+		load()
+			.inspect_err(|e| warn!("Failed to load sync connection {conn_id} of {user_id}: {e}"))
+			.ok()
+			.flatten()
+	}
+
+	/// Writes the sticky parameters if they changed and the known rooms whose
+	/// count changed, in a single batch with the time the connection was used.
+	fn persist_sync_connection_changes<C: SyncConnection>(
+		&self, connections: &DbConnections<C>, key: &DbConnectionsKey,
+	) {
+		let (user_id, device_id, conn_id) = key;
+		let Some(cached) = connections.lock().unwrap().get(key).cloned() else {
+			return;
+		};
+
+		let cached = &mut *cached.lock().unwrap();
+		let params = serde_json::to_vec(&cached.params).expect("sync connection serializes");
+		let changed_params = (cached.persisted_params.as_ref() != Some(&params)).then_some(params.as_slice());
+
+		let known_rooms = cached
+			.changed_rooms
+			.iter()
+			.filter_map(|(list_id, room_id)| {
+				let count = cached.known_rooms.get(list_id)?.get(room_id)?;
+				Some((list_id.as_str(), &**room_id, *count))
+			});
+
+		if let Err(e) = self.db.set_sync_connection(
+			user_id,
+			device_id,
+			conn_id,
+			C::VERSION,
+			cached.last_used,
+			changed_params,
+			known_rooms,
+		) {
+			warn!("Failed to save sync connection {conn_id} of {user_id}: {e}");
+			return;
+		}
+
+		cached.persisted_params = Some(params);
+		cached.changed_rooms.clear();
+	}
+
+	fn remove_sync_connection<C: SyncConnection>(&self, (user_id, device_id, conn_id): &DbConnectionsKey) {
+		if let Err(e) = self
+			.db
+			.remove_sync_connection(user_id, device_id, conn_id, C::VERSION)
+		{
+			warn!("Failed to remove sync connection {conn_id} of {user_id}: {e}");
+		}
+	}
+
+	fn remove_expired_sync_connections(&self, user_id: &UserId, device_id: &DeviceId, expired_before: u64) {
+		if let Err(e) = self
+			.db
+			.remove_expired_sync_connections(user_id, device_id, expired_before)
+		{
+			warn!("Failed to remove expired sync connections of {user_id}: {e}");
+		}
+	}
+
+	/// Connections last used before this time, in milliseconds since the unix
+	/// epoch, have expired.
+	fn connection_expired_before(&self) -> u64 { utils::millis_since_unix_epoch().saturating_sub(self.connection_ttl) }
+}
+
+/// Replaces the `required_state` sent before, returning whether the new one
+/// requests anything more.
+fn update_required_state(sent: &mut RequiredState, required_state: &[(StateEventType, String)]) -> bool {
+	let required_state: RequiredState = required_state.iter().cloned().collect();
+	let changed = !required_state.is_subset(sent);
+	*sent = required_state;
+
+	changed
+}
+
+impl<C> Connection<C> {
+	/// Marks the rooms of a list as sent at `globalsince`; rooms which left
+	/// the list are reset so they are sent in full when they come back.
+	fn update_known_rooms(&mut self, list_id: String, new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64) {
+		let list = self.known_rooms.entry(list_id.clone()).or_default();
+		for (roomid, lastsince) in list.iter_mut() {
+			if *lastsince != 0 && !new_cached_rooms.contains(roomid) {
+				*lastsince = 0;
+				self.changed_rooms.insert((list_id.clone(), roomid.clone()));
+			}
+		}
+
+		for roomid in new_cached_rooms {
+			if list.insert(roomid.clone(), globalsince) != Some(globalsince) {
+				self.changed_rooms.insert((list_id.clone(), roomid));
+			}
+		}
+	}
+}
@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, mem::size_of, sync::Arc};
+use std::{collections::BTreeMap, mem::size_of, sync::Arc, time::Duration};
 
 use conduit::{debug_info, err, utils, warn, Err, Error, Result, Server};
 use database::Map;
@@ -7,32 +7,47 @@ use ruma::{
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	events::{AnyToDeviceEvent, StateEventType},
 	serde::Raw,
+	thirdparty::{Medium, ThirdPartyIdentifier},
 	uint, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedDeviceKeyId,
-	OwnedMxcUri, OwnedUserId, UInt, UserId,
+	OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
+use serde::de::DeserializeOwned;
 
+use super::{
+	connections::KnownRooms,
+	tokens::{token_expires_at, Tokens},
+};
 use crate::{globals, rooms, users::clean_signatures, Dep};
 
+/// Sync streams which have not synced for this long no longer hold back the
+/// deletion of to-device events (7 days, in milliseconds).
+const SYNC_STREAM_TIMEOUT: u64 = 7 * 24 * 60 * 60 * 1000;
+
 pub struct Data {
 	keychangeid_userid: Arc<Map>,
 	keyid_key: Arc<Map>,
 	onetimekeyid_onetimekeys: Arc<Map>,
 	openidtoken_expiresatuserid: Arc<Map>,
+	threepid_userid: Arc<Map>,
 	todeviceid_events: Arc<Map>,
-	token_userdeviceid: Arc<Map>,
+	tokens: Tokens,
+	userdeviceconnid_slidingsync: Arc<Map>,
 	userdeviceid_metadata: Arc<Map>,
-	userdeviceid_token: Arc<Map>,
+	userdevicestream_todevicecount: Arc<Map>,
 	userfilterid_filter: Arc<Map>,
 	userid_avatarurl: Arc<Map>,
 	userid_blurhash: Arc<Map>,
 	userid_devicelistversion: Arc<Map>,
 	userid_displayname: Arc<Map>,
 	userid_lastonetimekeyupdate: Arc<Map>,
+	userid_lockedat: Arc<Map>,
 	userid_masterkeyid: Arc<Map>,
 	userid_password: Arc<Map>,
 	userid_selfsigningkeyid: Arc<Map>,
+	userid_suspendedat: Arc<Map>,
 	userid_usersigningkeyid: Arc<Map>,
 	useridprofilekey_value: Arc<Map>,
+	userthreepid_metadata: Arc<Map>,
 	services: Services,
 }
 
@@ -51,21 +66,32 @@ impl Data {
 			keyid_key: db["keyid_key"].clone(),
 			onetimekeyid_onetimekeys: db["onetimekeyid_onetimekeys"].clone(),
 			openidtoken_expiresatuserid: db["openidtoken_expiresatuserid"].clone(),
+			threepid_userid: db["threepid_userid"].clone(),
 			todeviceid_events: db["todeviceid_events"].clone(),
-			token_userdeviceid: db["token_userdeviceid"].clone(),
+			tokens: Tokens {
+				refreshtoken_userdeviceid: db["refreshtoken_userdeviceid"].clone(),
+				token_expiresat: db["token_expiresat"].clone(),
+				token_userdeviceid: db["token_userdeviceid"].clone(),
+				userdeviceid_refreshtoken: db["userdeviceid_refreshtoken"].clone(),
+				userdeviceid_token: db["userdeviceid_token"].clone(),
+			},
+			userdeviceconnid_slidingsync: db["userdeviceconnid_slidingsync"].clone(),
 			userdeviceid_metadata: db["userdeviceid_metadata"].clone(),
-			userdeviceid_token: db["userdeviceid_token"].clone(),
+			userdevicestream_todevicecount: db["userdevicestream_todevicecount"].clone(),
 			userfilterid_filter: db["userfilterid_filter"].clone(),
 			userid_avatarurl: db["userid_avatarurl"].clone(),
 			userid_blurhash: db["userid_blurhash"].clone(),
 			userid_devicelistversion: db["userid_devicelistversion"].clone(),
 			userid_displayname: db["userid_displayname"].clone(),
 			userid_lastonetimekeyupdate: db["userid_lastonetimekeyupdate"].clone(),
+			userid_lockedat: db["userid_lockedat"].clone(),
 			userid_masterkeyid: db["userid_masterkeyid"].clone(),
 			userid_password: db["userid_password"].clone(),
 			userid_selfsigningkeyid: db["userid_selfsigningkeyid"].clone(),
+			userid_suspendedat: db["userid_suspendedat"].clone(),
 			userid_usersigningkeyid: db["userid_usersigningkeyid"].clone(),
 			useridprofilekey_value: db["useridprofilekey_value"].clone(),
+			userthreepid_metadata: db["userthreepid_metadata"].clone(),
 			services: Services {
 				server: args.server.clone(),
 				globals: args.depend::<globals::Service>("globals"),
@@ -90,33 +116,91 @@ impl Data {
 			.is_empty())
 	}
 
+	pub(super) fn set_locked(&self, user_id: &UserId, locked: bool) -> Result<()> {
+		if locked {
+			self.userid_lockedat
+				.insert(user_id.as_bytes(), &utils::millis_since_unix_epoch().to_be_bytes())
+		} else {
+			self.userid_lockedat.remove(user_id.as_bytes())
+		}
+	}
+
+	pub(super) fn is_locked(&self, user_id: &UserId) -> Result<bool> {
+		Ok(self.userid_lockedat.get(user_id.as_bytes())?.is_some())
+	}
+
+	pub(super) fn set_suspended(&self, user_id: &UserId, suspended: bool) -> Result<()> {
+		if suspended {
+			self.userid_suspendedat
+				.insert(user_id.as_bytes(), &utils::millis_since_unix_epoch().to_be_bytes())
+		} else {
+			self.userid_suspendedat.remove(user_id.as_bytes())
+		}
+	}
+
+	pub(super) fn is_suspended(&self, user_id: &UserId) -> Result<bool> {
+		Ok(self.userid_suspendedat.get(user_id.as_bytes())?.is_some())
+	}
+
 	/// Returns the number of users registered on this server.
 	#[inline]
 	pub(super) fn count(&self) -> Result<usize> { Ok(self.userid_password.iter().count()) }
 
 	/// Find out which user an access token belongs to.
 	pub(super) fn find_from_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>> {
-		self.token_userdeviceid
-			.get(token.as_bytes())?
-			.map_or(Ok(None), |bytes| {
+		self.tokens.device(token)?.map_or(Ok(None), |bytes| {
+			let mut parts = bytes.split(|&b| b == 0xFF);
+			let user_bytes = parts
+				.next()
+				.ok_or_else(|| err!(Database("User ID in token_userdeviceid is invalid.")))?;
+			let device_bytes = parts
+				.next()
+				.ok_or_else(|| err!(Database("Device ID in token_userdeviceid is invalid.")))?;
+
+			Ok(Some((
+				UserId::parse(
+					utils::string_from_bytes(user_bytes)
+						.map_err(|e| err!(Database("User ID in token_userdeviceid is invalid unicode. {e}")))?,
+				)
+				.map_err(|e| err!(Database("User ID in token_userdeviceid is invalid. {e}")))?,
+				utils::string_from_bytes(device_bytes)
+					.map_err(|e| err!(Database("Device ID in token_userdeviceid is invalid. {e}")))?,
+			)))
+		})
+	}
+
+	/// Returns whether an access token was issued with an expiry which has
+	/// passed.
+	pub(super) fn token_expired(&self, token: &str) -> Result<bool> {
+		self.tokens.expired(token, utils::millis_since_unix_epoch())
+	}
+
+	/// Find out which user and device a refresh token belongs to.
+	pub(super) fn find_from_refresh_token(&self, refresh_token: &str) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+		self.tokens
+			.refresh_device(refresh_token)?
+			.map(|bytes| {
 				let mut parts = bytes.split(|&b| b == 0xFF);
 				let user_bytes = parts
 					.next()
-					.ok_or_else(|| err!(Database("User ID in token_userdeviceid is invalid.")))?;
+					.ok_or_else(|| err!(Database("User ID in refreshtoken_userdeviceid is invalid.")))?;
 				let device_bytes = parts
 					.next()
-					.ok_or_else(|| err!(Database("Device ID in token_userdeviceid is invalid.")))?;
+					.ok_or_else(|| err!(Database("Device ID in refreshtoken_userdeviceid is invalid.")))?;
 
-				Ok(Some((
+				Ok((
 					UserId::parse(
-						utils::string_from_bytes(user_bytes)
-							.map_err(|e| err!(Database("User ID in token_userdeviceid is invalid unicode. {e}")))?,
+						utils::string_from_bytes(user_bytes).map_err(|e| {
+							err!(Database("User ID in refreshtoken_userdeviceid is invalid unicode. {e}"))
+						})?,
 					)
-					.map_err(|e| err!(Database("User ID in token_userdeviceid is invalid. {e}")))?,
+					.map_err(|e| err!(Database("User ID in refreshtoken_userdeviceid is invalid. {e}")))?,
 					utils::string_from_bytes(device_bytes)
-						.map_err(|e| err!(Database("Device ID in token_userdeviceid is invalid. {e}")))?,
-				)))
+						.map_err(|e| err!(Database("Device ID in refreshtoken_userdeviceid is invalid. {e}")))?
+						.into(),
+				))
 			})
+			.transpose()
 	}
 
 	/// Returns an iterator over all users on this homeserver.
@@ -392,19 +476,29 @@ impl Data {
 		userdeviceid.extend_from_slice(device_id.as_bytes());
 
 		// Remove tokens
-		if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
-			self.userdeviceid_token.remove(&userdeviceid)?;
-			self.token_userdeviceid.remove(&old_token)?;
-		}
+		self.remove_access_token(user_id, device_id)?;
+		self.remove_refresh_token(user_id, device_id)?;
 
 		// Remove todevice events
 		let mut prefix = userdeviceid.clone();
 		prefix.push(0xFF);
 
-		for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+		for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
 			self.todeviceid_events.remove(&key)?;
 		}
 
+		for (key, _) in self
+			.userdevicestream_todevicecount
+			.scan_prefix(prefix.clone())
+		{
+			self.userdevicestream_todevicecount.remove(&key)?;
+		}
+
+		// Remove sliding sync connections
+		for (key, _) in self.userdeviceconnid_slidingsync.scan_prefix(prefix) {
+			self.userdeviceconnid_slidingsync.remove(&key)?;
+		}
+
 		// TODO: Remove onetimekeys
 
 		self.userid_devicelistversion
@@ -451,19 +545,64 @@ impl Data {
 			)));
 		}
 
-		// Remove old token
-		if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
-			self.token_userdeviceid.remove(&old_token)?;
-			// It will be removed from userdeviceid_token by the insert later
-		}
+		// Removes the old token and the refresh token it was issued with
+		self.tokens.set_token(&userdeviceid, token)
+	}
+
+	/// Makes the current access token of a device expire after the configured
+	/// lifetime and assigns the device a refresh token. Returns the lifetime of
+	/// the access token.
+	pub(super) fn set_refresh_token(
+		&self, user_id: &UserId, device_id: &DeviceId, token: &str, refresh_token: &str,
+	) -> Result<Duration> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
 
-		// Assign token to user device combination
-		self.userdeviceid_token
-			.insert(&userdeviceid, token.as_bytes())?;
-		self.token_userdeviceid
-			.insert(token.as_bytes(), &userdeviceid)?;
+		let expires_in = self.services.server.config.access_token_ttl;
+		let expires_at = token_expires_at(utils::millis_since_unix_epoch(), expires_in);
 
-		Ok(())
+		self.tokens
+			.set_refresh_token(&userdeviceid, token, refresh_token, expires_at)?;
+
+		Ok(Duration::from_secs(expires_in))
+	}
+
+	/// Exchanges the tokens of a device for a new access token expiring after
+	/// the configured lifetime and a new refresh token. Returns the lifetime of
+	/// the access token.
+	pub(super) fn rotate_tokens(
+		&self, user_id: &UserId, device_id: &DeviceId, token: &str, refresh_token: &str,
+	) -> Result<Duration> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		let expires_in = self.services.server.config.access_token_ttl;
+		let expires_at = token_expires_at(utils::millis_since_unix_epoch(), expires_in);
+
+		self.tokens
+			.rotate(&userdeviceid, token, refresh_token, expires_at)?;
+
+		Ok(Duration::from_secs(expires_in))
+	}
+
+	/// Revokes the access token of a device, if it has one.
+	pub(super) fn remove_access_token(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		self.tokens.remove_access_token(&userdeviceid)
+	}
+
+	/// Revokes the refresh token of a device, if it has one.
+	pub(super) fn remove_refresh_token(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		self.tokens.remove_refresh_token(&userdeviceid)
 	}
 
 	pub(super) fn add_one_time_key(
@@ -885,8 +1024,10 @@ impl Data {
 		Ok(())
 	}
 
+	/// Returns the to-device events of a device which the sync stream has not
+	/// acknowledged yet.
 	pub(super) fn get_to_device_events(
-		&self, user_id: &UserId, device_id: &DeviceId,
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>,
 	) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
 		let mut events = Vec::new();
 
@@ -895,7 +1036,34 @@ impl Data {
 		prefix.extend_from_slice(device_id.as_bytes());
 		prefix.push(0xFF);
 
-		for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
+		let mut streamkey = prefix.clone();
+		streamkey.extend_from_slice(stream.unwrap_or_default().as_bytes());
+		let since = self
+			.userdevicestream_todevicecount
+			.get(&streamkey)?
+			.and_then(|value| value.get(..size_of::<u64>()).map(utils::u64_from_u8));
+
+		// A stream which has yet to acknowledge anything still holds back the
+		// deletion of every pending event.
+		let since = if let Some(since) = since {
+			since
+		} else {
+			let mut value = 0_u64.to_be_bytes().to_vec();
+			value.extend_from_slice(&utils::millis_since_unix_epoch().to_be_bytes());
+			self.userdevicestream_todevicecount
+				.insert(&streamkey, &value)?;
+
+			0
+		};
+
+		let mut first = prefix.clone();
+		first.extend_from_slice(&since.saturating_add(1).to_be_bytes());
+
+		for (_, value) in self
+			.todeviceid_events
+			.iter_from(&first, false)
+			.take_while(|(key, _)| key.starts_with(&prefix))
+		{
 			events.push(
 				serde_json::from_slice(&value)
 					.map_err(|e| err!(Database("Event in todeviceid_events is invalid. {e}")))?,
@@ -905,12 +1073,48 @@ impl Data {
 		Ok(events)
 	}
 
-	pub(super) fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
+	/// Acknowledges the to-device events up to `until` for a sync stream.
+	/// Events are deleted once every stream of the device which synced
+	/// recently acknowledged them.
+	pub(super) fn remove_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, until: u64,
+	) -> Result<()> {
 		let mut prefix = user_id.as_bytes().to_vec();
 		prefix.push(0xFF);
 		prefix.extend_from_slice(device_id.as_bytes());
 		prefix.push(0xFF);
 
+		let now = utils::millis_since_unix_epoch();
+		let mut streamkey = prefix.clone();
+		streamkey.extend_from_slice(stream.unwrap_or_default().as_bytes());
+		let mut value = until.to_be_bytes().to_vec();
+		value.extend_from_slice(&now.to_be_bytes());
+		self.userdevicestream_todevicecount
+			.insert(&streamkey, &value)?;
+
+		let mut until = until;
+		for (key, value) in self
+			.userdevicestream_todevicecount
+			.scan_prefix(prefix.clone())
+		{
+			let (Some(count), Some(last_seen)) = (
+				value.get(..size_of::<u64>()).map(utils::u64_from_u8),
+				value
+					.get(size_of::<u64>()..)
+					.map(utils::u64_from_bytes_or_zero),
+			) else {
+				self.userdevicestream_todevicecount.remove(&key)?;
+				continue;
+			};
+
+			if now.saturating_sub(last_seen) > SYNC_STREAM_TIMEOUT {
+				self.userdevicestream_todevicecount.remove(&key)?;
+				continue;
+			}
+
+			until = until.min(count);
+		}
+
 		let mut last = prefix.clone();
 		last.extend_from_slice(&until.to_be_bytes());
 
@@ -934,6 +1138,156 @@ impl Data {
 		Ok(())
 	}
 
+	/// When a sync connection was last used, in milliseconds since the unix
+	/// epoch.
+	pub(super) fn sync_connection_last_used(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str,
+	) -> Result<Option<u64>> {
+		let key = sync_connection_key(user_id, device_id, conn_id, version);
+		self.userdeviceconnid_slidingsync
+			.get(&key)?
+			.map(|bytes| utils::u64_from_bytes(&bytes).map_err(|_| err!(Database("Invalid sync connection in db."))))
+			.transpose()
+	}
+
+	/// The sticky parameters of a sync connection.
+	pub(super) fn sync_connection<T: DeserializeOwned>(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str,
+	) -> Result<Option<T>> {
+		let mut key = sync_connection_key(user_id, device_id, conn_id, version);
+		key.push(0xFF);
+
+		self.userdeviceconnid_slidingsync
+			.get(&key)?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes).map_err(|e| err!(Database("Invalid sync connection in db. {e}")))
+			})
+			.transpose()
+	}
+
+	/// The rooms of each list already sent on a sync connection, with the
+	/// count they were sent at.
+	pub(super) fn sync_connection_known_rooms(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str,
+	) -> Result<KnownRooms> {
+		let mut prefix = sync_connection_key(user_id, device_id, conn_id, version);
+		prefix.push(0xFF);
+
+		let mut known_rooms = KnownRooms::new();
+		for (key, value) in self
+			.userdeviceconnid_slidingsync
+			.scan_prefix(prefix.clone())
+		{
+			let Some(suffix) = key.get(prefix.len()..).filter(|suffix| !suffix.is_empty()) else {
+				continue; // sticky parameters
+			};
+
+			let mut parts = suffix.splitn(2, |&b| b == 0xFF);
+			let list_id = utils::string_from_bytes(parts.next().expect("splitn always returns one element"))
+				.map_err(|_| err!(Database("Invalid list id in sync connection.")))?;
+			let room_id = parts
+				.next()
+				.ok_or_else(|| err!(Database("Invalid known room in sync connection.")))
+				.and_then(|bytes| {
+					utils::string_from_bytes(bytes).map_err(|_| err!(Database("Invalid room id in sync connection.")))
+				})
+				.and_then(|room_id| {
+					OwnedRoomId::try_from(room_id).map_err(|_| err!(Database("Invalid room id in sync connection.")))
+				})?;
+			let count = utils::u64_from_bytes(&value)
+				.map_err(|_| err!(Database("Invalid known room count in sync connection.")))?;
+
+			known_rooms
+				.entry(list_id)
+				.or_default()
+				.insert(room_id, count);
+		}
+
+		Ok(known_rooms)
+	}
+
+	/// Writes what changed on a sync connection in a single batch: when it was
+	/// used, its serialized sticky parameters if they changed, and the known
+	/// rooms whose count changed.
+	#[allow(clippy::too_many_arguments)]
+	pub(super) fn set_sync_connection<'a, I>(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str, last_used: u64,
+		params: Option<&[u8]>, known_rooms: I,
+	) -> Result<()>
+	where
+		I: Iterator<Item = (&'a str, &'a RoomId, u64)>,
+	{
+		let key = sync_connection_key(user_id, device_id, conn_id, version);
+
+		let mut batch = vec![(key.clone(), last_used.to_be_bytes().to_vec())];
+		if let Some(params) = params {
+			let mut params_key = key.clone();
+			params_key.push(0xFF);
+			batch.push((params_key, params.to_vec()));
+		}
+
+		for (list_id, room_id, count) in known_rooms {
+			let mut room_key = key.clone();
+			room_key.push(0xFF);
+			room_key.extend_from_slice(list_id.as_bytes());
+			room_key.push(0xFF);
+			room_key.extend_from_slice(room_id.as_bytes());
+			batch.push((room_key, count.to_be_bytes().to_vec()));
+		}
+
+		self.userdeviceconnid_slidingsync
+			.insert_batch(batch.iter().map(database::KeyVal::from))
+	}
+
+	pub(super) fn remove_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str,
+	) -> Result<()> {
+		self.remove_sync_connection_key(sync_connection_key(user_id, device_id, conn_id, version))
+	}
+
+	/// Removes the sync connections of a device which were last used before
+	/// `before`, in milliseconds since the unix epoch.
+	pub(super) fn remove_expired_sync_connections(
+		&self, user_id: &UserId, device_id: &DeviceId, before: u64,
+	) -> Result<()> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		prefix.extend_from_slice(device_id.as_bytes());
+		prefix.push(0xFF);
+
+		// Connections are keyed by user, device, connection id and version; the
+		// sticky parameters and known rooms are stored under longer keys.
+		let expired = self
+			.userdeviceconnid_slidingsync
+			.scan_prefix(prefix)
+			.filter(|(key, _)| key.iter().filter(|&&b| b == 0xFF).count() == 3)
+			.filter(|(_, value)| utils::u64_from_bytes(value).map_or(true, |last_used| last_used < before))
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+
+		for key in expired {
+			self.remove_sync_connection_key(key)?;
+		}
+
+		Ok(())
+	}
+
+	/// Removes a sync connection with its sticky parameters and known rooms.
+	fn remove_sync_connection_key(&self, key: Vec<u8>) -> Result<()> {
+		let mut prefix = key.clone();
+		prefix.push(0xFF);
+
+		let keys = self
+			.userdeviceconnid_slidingsync
+			.scan_prefix(prefix)
+			.map(|(key, _)| key)
+			.chain(std::iter::once(key))
+			.collect::<Vec<_>>();
+
+		self.userdeviceconnid_slidingsync
+			.remove_batch(keys.iter().map(Vec::as_slice))
+	}
+
 	pub(super) fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
 		userdeviceid.push(0xFF);
@@ -1076,6 +1430,64 @@ impl Data {
 		)
 		.map_err(|e| err!(Database("User ID in openid_userid is invalid. {e}")))
 	}
+
+	/// Binds a validated third party identifier to a user.
+	pub(super) fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()> {
+		let key = threepid_key(&threepid.medium, &threepid.address);
+
+		let mut userthreepid = user_id.as_bytes().to_vec();
+		userthreepid.push(0xFF);
+		userthreepid.extend_from_slice(&key);
+
+		self.userthreepid_metadata.insert(
+			&userthreepid,
+			&serde_json::to_vec(threepid).expect("ThirdPartyIdentifier::to_vec always works"),
+		)?;
+		self.threepid_userid.insert(&key, user_id.as_bytes())
+	}
+
+	/// Unbinds a third party identifier from a user.
+	pub(super) fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+		let key = threepid_key(medium, address);
+
+		let mut userthreepid = user_id.as_bytes().to_vec();
+		userthreepid.push(0xFF);
+		userthreepid.extend_from_slice(&key);
+
+		self.userthreepid_metadata.remove(&userthreepid)?;
+		self.threepid_userid.remove(&key)
+	}
+
+	/// Returns the third party identifiers bound to a user.
+	pub(super) fn threepids<'a>(
+		&'a self, user_id: &UserId,
+	) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		Box::new(
+			self.userthreepid_metadata
+				.scan_prefix(prefix)
+				.map(|(_, bytes)| {
+					serde_json::from_slice(&bytes)
+						.map_err(|e| err!(Database("Invalid third party identifier in userthreepid_metadata. {e}")))
+				}),
+		)
+	}
+
+	/// Find out which user a third party identifier is bound to.
+	pub(super) fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+		self.threepid_userid
+			.get(&threepid_key(medium, address))?
+			.map(|bytes| {
+				UserId::parse(
+					utils::string_from_bytes(&bytes)
+						.map_err(|e| err!(Database("User ID in threepid_userid is invalid unicode. {e}")))?,
+				)
+				.map_err(|e| err!(Database("User ID in threepid_userid is invalid. {e}")))
+			})
+			.transpose()
+	}
 }
 
 /// Will only return with Some(username) if the password was not empty and the
@@ -1096,3 +1508,23 @@ pub(super) fn get_username_with_valid_password(username: &[u8], password: &[u8])
 		}
 	}
 }
+
+/// Key of a sync connection: the connection id is followed by the sync version
+/// so both sliding sync endpoints can use the same one.
+fn sync_connection_key(user_id: &UserId, device_id: &DeviceId, conn_id: &str, version: &str) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(conn_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(version.as_bytes());
+	key
+}
+
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+	let mut key = medium.as_str().as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(address.as_bytes());
+	key
+}
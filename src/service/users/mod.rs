@@ -1,49 +1,57 @@
+mod connections;
 mod data;
+mod tests;
+mod tokens;
 
 use std::{
-	collections::{BTreeMap, BTreeSet},
+	collections::BTreeMap,
 	mem,
-	sync::{Arc, Mutex, Mutex as StdMutex},
+	sync::{Arc, Mutex as StdMutex},
+	time::Duration,
 };
 
 use conduit::{Error, Result};
+use http::StatusCode;
 use ruma::{
-	api::client::{
-		device::Device,
-		filter::FilterDefinition,
-		sync::sync_events::{
-			self,
-			v4::{ExtensionsConfig, SyncRequestList},
-		},
-	},
+	api::client::{device::Device, filter::FilterDefinition},
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	events::AnyToDeviceEvent,
 	serde::Raw,
-	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
-	UInt, UserId,
+	thirdparty::{Medium, ThirdPartyIdentifier},
+	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 
-use self::data::Data;
-use crate::{admin, rooms, Dep};
+use self::{
+	connections::{DbConnections, SimplifiedSyncCache, SlidingSyncCache},
+	data::Data,
+};
+use crate::{admin, rooms, sending, sending::AppserviceEdu, Dep};
 
 pub struct Service {
-	connections: DbConnections,
+	connections: DbConnections<SlidingSyncCache>,
+	simplified_connections: DbConnections<SimplifiedSyncCache>,
+	connection_ttl: u64,
 	pub db: Data,
 	services: Services,
 }
 
 struct Services {
 	admin: Dep<admin::Service>,
+	sending: Dep<sending::Service>,
 	state_cache: Dep<rooms::state_cache::Service>,
 }
 
 impl crate::Service for Service {
 	fn build(args: crate::Args<'_>) -> Result<Arc<Self>> {
+		let config = &args.server.config;
 		Ok(Arc::new(Self {
 			connections: StdMutex::new(BTreeMap::new()),
+			simplified_connections: StdMutex::new(BTreeMap::new()),
+			connection_ttl: config.sliding_sync_connection_ttl.saturating_mul(1000),
 			db: Data::new(&args),
 			services: Services {
 				admin: args.depend::<admin::Service>("admin"),
+				sending: args.depend::<sending::Service>("sending"),
 				state_cache: args.depend::<rooms::state_cache::Service>("rooms::state_cache"),
 			},
 		}))
@@ -52,217 +60,56 @@ impl crate::Service for Service {
 	fn name(&self) -> &str { crate::service::make_name(std::module_path!()) }
 }
 
-type DbConnections = Mutex<BTreeMap<DbConnectionsKey, DbConnectionsVal>>;
-type DbConnectionsKey = (OwnedUserId, OwnedDeviceId, String);
-type DbConnectionsVal = Arc<Mutex<SlidingSyncCache>>;
-
-struct SlidingSyncCache {
-	lists: BTreeMap<String, SyncRequestList>,
-	subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
-	known_rooms: BTreeMap<String, BTreeMap<OwnedRoomId, u64>>, // For every room, the roomsince number
-	extensions: ExtensionsConfig,
-}
-
 impl Service {
 	/// Check if a user has an account on this homeserver.
 	#[inline]
 	pub fn exists(&self, user_id: &UserId) -> Result<bool> { self.db.exists(user_id) }
 
-	pub fn remembered(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) -> bool {
-		self.connections
-			.lock()
-			.unwrap()
-			.contains_key(&(user_id, device_id, conn_id))
-	}
-
-	pub fn forget_sync_request_connection(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
-		self.connections
-			.lock()
-			.unwrap()
-			.remove(&(user_id, device_id, conn_id));
-	}
-
-	pub fn update_sync_request_with_cache(
-		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, request: &mut sync_events::v4::Request,
-	) -> BTreeMap<String, BTreeMap<OwnedRoomId, u64>> {
-		let Some(conn_id) = request.conn_id.clone() else {
-			return BTreeMap::new();
-		};
-
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
-		let cached = &mut cached.lock().unwrap();
-		drop(cache);
-
-		for (list_id, list) in &mut request.lists {
-			if let Some(cached_list) = cached.lists.get(list_id) {
-				if list.sort.is_empty() {
-					list.sort.clone_from(&cached_list.sort);
-				};
-				if list.room_details.required_state.is_empty() {
-					list.room_details
-						.required_state
-						.clone_from(&cached_list.room_details.required_state);
-				};
-				list.room_details.timeline_limit = list
-					.room_details
-					.timeline_limit
-					.or(cached_list.room_details.timeline_limit);
-				list.include_old_rooms = list
-					.include_old_rooms
-					.clone()
-					.or_else(|| cached_list.include_old_rooms.clone());
-				match (&mut list.filters, cached_list.filters.clone()) {
-					(Some(list_filters), Some(cached_filters)) => {
-						list_filters.is_dm = list_filters.is_dm.or(cached_filters.is_dm);
-						if list_filters.spaces.is_empty() {
-							list_filters.spaces = cached_filters.spaces;
-						}
-						list_filters.is_encrypted = list_filters.is_encrypted.or(cached_filters.is_encrypted);
-						list_filters.is_invite = list_filters.is_invite.or(cached_filters.is_invite);
-						if list_filters.room_types.is_empty() {
-							list_filters.room_types = cached_filters.room_types;
-						}
-						if list_filters.not_room_types.is_empty() {
-							list_filters.not_room_types = cached_filters.not_room_types;
-						}
-						list_filters.room_name_like = list_filters
-							.room_name_like
-							.clone()
-							.or(cached_filters.room_name_like);
-						if list_filters.tags.is_empty() {
-							list_filters.tags = cached_filters.tags;
-						}
-						if list_filters.not_tags.is_empty() {
-							list_filters.not_tags = cached_filters.not_tags;
-						}
-					},
-					(_, Some(cached_filters)) => list.filters = Some(cached_filters),
-					(Some(list_filters), _) => list.filters = Some(list_filters.clone()),
-					(..) => {},
-				}
-				if list.bump_event_types.is_empty() {
-					list.bump_event_types
-						.clone_from(&cached_list.bump_event_types);
-				};
-			}
-			cached.lists.insert(list_id.clone(), list.clone());
-		}
+	/// Check if account is deactivated
+	pub fn is_deactivated(&self, user_id: &UserId) -> Result<bool> { self.db.is_deactivated(user_id) }
 
-		cached
-			.subscriptions
-			.extend(request.room_subscriptions.clone());
-		request
-			.room_subscriptions
-			.extend(cached.subscriptions.clone());
-
-		request.extensions.e2ee.enabled = request
-			.extensions
-			.e2ee
-			.enabled
-			.or(cached.extensions.e2ee.enabled);
-
-		request.extensions.to_device.enabled = request
-			.extensions
-			.to_device
-			.enabled
-			.or(cached.extensions.to_device.enabled);
-
-		request.extensions.account_data.enabled = request
-			.extensions
-			.account_data
-			.enabled
-			.or(cached.extensions.account_data.enabled);
-		request.extensions.account_data.lists = request
-			.extensions
-			.account_data
-			.lists
-			.clone()
-			.or_else(|| cached.extensions.account_data.lists.clone());
-		request.extensions.account_data.rooms = request
-			.extensions
-			.account_data
-			.rooms
-			.clone()
-			.or_else(|| cached.extensions.account_data.rooms.clone());
-
-		cached.extensions = request.extensions.clone();
-
-		cached.known_rooms.clone()
-	}
-
-	pub fn update_sync_subscriptions(
-		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
-		subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
-	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
-		let cached = &mut cached.lock().unwrap();
-		drop(cache);
-
-		cached.subscriptions = subscriptions;
-	}
-
-	pub fn update_sync_known_rooms(
-		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String, list_id: String,
-		new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64,
-	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
-		let cached = &mut cached.lock().unwrap();
-		drop(cache);
-
-		for (roomid, lastsince) in cached
-			.known_rooms
-			.entry(list_id.clone())
-			.or_default()
-			.iter_mut()
-		{
-			if !new_cached_rooms.contains(roomid) {
-				*lastsince = 0;
-			}
+	/// Locks or unlocks an account. Locking revokes the tokens of every device,
+	/// and locked users cannot log in until unlocked (MSC3939).
+	pub fn set_locked(&self, user_id: &UserId, locked: bool) -> Result<()> {
+		self.db.set_locked(user_id, locked)?;
+		if !locked {
+			return Ok(());
 		}
-		let list = cached.known_rooms.entry(list_id).or_default();
-		for roomid in new_cached_rooms {
-			list.insert(roomid, globalsince);
+
+		let device_ids: Vec<_> = self.all_device_ids(user_id).collect::<Result<_>>()?;
+		for device_id in device_ids {
+			self.db.remove_access_token(user_id, &device_id)?;
+			self.db.remove_refresh_token(user_id, &device_id)?;
 		}
+
+		Ok(())
 	}
 
-	/// Check if account is deactivated
-	pub fn is_deactivated(&self, user_id: &UserId) -> Result<bool> { self.db.is_deactivated(user_id) }
+	/// Check if account is locked
+	pub fn is_locked(&self, user_id: &UserId) -> Result<bool> { self.db.is_locked(user_id) }
+
+	/// Suspends or unsuspends an account. Suspended users can read but cannot
+	/// send events, join rooms or invite (MSC3823).
+	pub fn set_suspended(&self, user_id: &UserId, suspended: bool) -> Result<()> {
+		self.db.set_suspended(user_id, suspended)
+	}
+
+	/// Check if account is suspended
+	pub fn is_suspended(&self, user_id: &UserId) -> Result<bool> { self.db.is_suspended(user_id) }
+
+	/// Errors with `M_USER_SUSPENDED` if the account is suspended, for requests
+	/// suspended users cannot make.
+	pub fn check_suspended(&self, user_id: &UserId) -> Result<()> {
+		if self.is_suspended(user_id)? {
+			return Err(Error::custom(
+				"M_USER_SUSPENDED",
+				"This account has been suspended.",
+				StatusCode::FORBIDDEN,
+			));
+		}
+
+		Ok(())
+	}
 
 	/// Check if a user is an admin
 	pub fn is_admin(&self, user_id: &UserId) -> Result<bool> {
@@ -289,6 +136,15 @@ impl Service {
 		self.db.find_from_token(token)
 	}
 
+	/// Returns whether an access token has outlived its lifetime. Tokens
+	/// issued without a refresh token never expire.
+	pub fn token_expired(&self, token: &str) -> Result<bool> { self.db.token_expired(token) }
+
+	/// Find out which user and device a refresh token belongs to.
+	pub fn find_from_refresh_token(&self, refresh_token: &str) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+		self.db.find_from_refresh_token(refresh_token)
+	}
+
 	/// Returns an iterator over all users on this homeserver.
 	pub fn iter(&self) -> impl Iterator<Item = Result<OwnedUserId>> + '_ { self.db.iter() }
 
@@ -370,6 +226,7 @@ impl Service {
 
 	/// Removes a device from a user.
 	pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		self.forget_sync_connections(user_id, device_id);
 		self.db.remove_device(user_id, device_id)
 	}
 
@@ -384,12 +241,42 @@ impl Service {
 		self.db.set_token(user_id, device_id, token)
 	}
 
+	/// Limits the lifetime of the device's current access token and assigns
+	/// the device a new refresh token, replacing any previous one. Returns
+	/// the lifetime of the access token.
+	pub fn set_refresh_token(
+		&self, user_id: &UserId, device_id: &DeviceId, token: &str, refresh_token: &str,
+	) -> Result<Duration> {
+		self.db
+			.set_refresh_token(user_id, device_id, token, refresh_token)
+	}
+
+	/// Exchanges the tokens of a device for a new access token and refresh
+	/// token, revoking the previous ones. Returns the lifetime of the access
+	/// token.
+	pub fn rotate_tokens(
+		&self, user_id: &UserId, device_id: &DeviceId, token: &str, refresh_token: &str,
+	) -> Result<Duration> {
+		self.db
+			.rotate_tokens(user_id, device_id, token, refresh_token)
+	}
+
+	/// Revokes the refresh token of a device. Its current access token stays
+	/// valid until it expires.
+	pub fn remove_refresh_token(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		self.db.remove_refresh_token(user_id, device_id)
+	}
+
 	pub fn add_one_time_key(
 		&self, user_id: &UserId, device_id: &DeviceId, one_time_key_key: &DeviceKeyId,
 		one_time_key_value: &Raw<OneTimeKey>,
 	) -> Result<()> {
 		self.db
-			.add_one_time_key(user_id, device_id, one_time_key_key, one_time_key_value)
+			.add_one_time_key(user_id, device_id, one_time_key_key, one_time_key_value)?;
+
+		self.services
+			.sending
+			.send_edu_appservices(&AppserviceEdu::OneTimeKeysCount(user_id.to_owned(), device_id.to_owned()))
 	}
 
 	// TODO: use this ?
@@ -401,7 +288,16 @@ impl Service {
 	pub fn take_one_time_key(
 		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
 	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
-		self.db.take_one_time_key(user_id, device_id, key_algorithm)
+		let key = self
+			.db
+			.take_one_time_key(user_id, device_id, key_algorithm)?;
+		if key.is_some() {
+			self.services
+				.sending
+				.send_edu_appservices(&AppserviceEdu::OneTimeKeysCount(user_id.to_owned(), device_id.to_owned()))?;
+		}
+
+		Ok(key)
 	}
 
 	pub fn count_one_time_keys(
@@ -411,7 +307,8 @@ impl Service {
 	}
 
 	pub fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()> {
-		self.db.add_device_keys(user_id, device_id, device_keys)
+		self.db.add_device_keys(user_id, device_id, device_keys)?;
+		self.device_list_changed(user_id)
 	}
 
 	pub fn add_cross_signing_keys(
@@ -419,13 +316,20 @@ impl Service {
 		user_signing_key: &Option<Raw<CrossSigningKey>>, notify: bool,
 	) -> Result<()> {
 		self.db
-			.add_cross_signing_keys(user_id, master_key, self_signing_key, user_signing_key, notify)
+			.add_cross_signing_keys(user_id, master_key, self_signing_key, user_signing_key, notify)?;
+
+		if notify {
+			self.device_list_changed(user_id)?;
+		}
+
+		Ok(())
 	}
 
 	pub fn sign_key(
 		&self, target_id: &UserId, key_id: &str, signature: (String, String), sender_id: &UserId,
 	) -> Result<()> {
-		self.db.sign_key(target_id, key_id, signature, sender_id)
+		self.db.sign_key(target_id, key_id, signature, sender_id)?;
+		self.device_list_changed(target_id)
 	}
 
 	pub fn keys_changed<'a>(
@@ -434,8 +338,17 @@ impl Service {
 		self.db.keys_changed(user_or_room_id, from, to)
 	}
 
-	#[inline]
-	pub fn mark_device_key_update(&self, user_id: &UserId) -> Result<()> { self.db.mark_device_key_update(user_id) }
+	pub fn mark_device_key_update(&self, user_id: &UserId) -> Result<()> {
+		self.db.mark_device_key_update(user_id)?;
+		self.device_list_changed(user_id)
+	}
+
+	/// Queues the device list change of a user for appservices (MSC3202).
+	fn device_list_changed(&self, user_id: &UserId) -> Result<()> {
+		self.services
+			.sending
+			.send_edu_appservices(&AppserviceEdu::DeviceListUpdate(user_id.to_owned()))
+	}
 
 	pub fn get_device_keys(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Raw<DeviceKeys>>> {
 		self.db.get_device_keys(user_id, device_id)
@@ -478,16 +391,37 @@ impl Service {
 		&self, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
 		content: serde_json::Value,
 	) -> Result<()> {
+		let event = serde_json::json!({
+			"type": event_type,
+			"sender": sender,
+			"content": content,
+			"to_user_id": target_user_id,
+			"to_device_id": target_device_id,
+		});
+
 		self.db
-			.add_to_device_event(sender, target_user_id, target_device_id, event_type, content)
+			.add_to_device_event(sender, target_user_id, target_device_id, event_type, content)?;
+
+		self.services
+			.sending
+			.send_edu_appservices(&AppserviceEdu::ToDevice(target_user_id.to_owned(), event))
 	}
 
-	pub fn get_to_device_events(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
-		self.db.get_to_device_events(user_id, device_id)
+	/// Returns the to-device events not yet acknowledged by a sync stream of
+	/// the device; `stream` is None for the default stream.
+	pub fn get_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>,
+	) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
+		self.db.get_to_device_events(user_id, device_id, stream)
 	}
 
-	pub fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
-		self.db.remove_to_device_events(user_id, device_id, until)
+	/// Acknowledges to-device events for a sync stream of the device. They are
+	/// only deleted once all its active streams have received them.
+	pub fn remove_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, until: u64,
+	) -> Result<()> {
+		self.db
+			.remove_to_device_events(user_id, device_id, stream, until)
 	}
 
 	pub fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
@@ -520,10 +454,39 @@ impl Service {
 		// account is deactivated.
 		self.db.set_password(user_id, None)?;
 
-		// TODO: Unhook 3PID
+		// Deactivation supersedes locking and suspension
+		self.db.set_locked(user_id, false)?;
+		self.db.set_suspended(user_id, false)?;
+
+		// Unhook 3PIDs so they can be used by other accounts
+		let threepids: Vec<_> = self.threepids(user_id).collect::<Result<_>>()?;
+		for threepid in threepids {
+			self.remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+		}
+
 		Ok(())
 	}
 
+	/// Binds a validated third party identifier to a user.
+	pub fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()> {
+		self.db.add_threepid(user_id, threepid)
+	}
+
+	/// Unbinds a third party identifier from a user.
+	pub fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+		self.db.remove_threepid(user_id, medium, address)
+	}
+
+	/// Returns an iterator over the third party identifiers bound to a user.
+	pub fn threepids<'a>(&'a self, user_id: &UserId) -> impl Iterator<Item = Result<ThirdPartyIdentifier>> + 'a {
+		self.db.threepids(user_id)
+	}
+
+	/// Find out which user a third party identifier is bound to.
+	pub fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+		self.db.find_from_threepid(medium, address)
+	}
+
 	/// Creates a new sync filter. Returns the filter id.
 	pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
 		self.db.create_filter(user_id, filter)
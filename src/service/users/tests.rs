@@ -0,0 +1,195 @@
+#![cfg(test)]
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+};
+
+use conduit::Result;
+
+use super::tokens::{token_expired_at, token_expires_at, Column, Tokens};
+
+const ISSUED: u64 = 1_700_000_000_000;
+
+const DEVICE: &[u8] = b"@alice:example.com\xFFDEVICE";
+
+#[derive(Default)]
+struct Memory(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl Column for Memory {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { Ok(self.0.lock().expect("locked").get(key).cloned()) }
+
+	fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+		self.0
+			.lock()
+			.expect("locked")
+			.insert(key.to_vec(), value.to_vec());
+		Ok(())
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<()> {
+		self.0.lock().expect("locked").remove(key);
+		Ok(())
+	}
+}
+
+fn tokens() -> Tokens<Memory> {
+	Tokens {
+		refreshtoken_userdeviceid: Arc::default(),
+		token_expiresat: Arc::default(),
+		token_userdeviceid: Arc::default(),
+		userdeviceid_refreshtoken: Arc::default(),
+		userdeviceid_token: Arc::default(),
+	}
+}
+
+/// A device logged in with a refresh token.
+fn logged_in() -> Tokens<Memory> {
+	let tokens = tokens();
+	tokens.set_token(DEVICE, "access").expect("token set");
+	tokens
+		.set_refresh_token(DEVICE, "access", "refresh", token_expires_at(ISSUED, 300))
+		.expect("refresh token set");
+
+	tokens
+}
+
+#[test]
+fn access_token_expires_after_ttl() {
+	let expires_at = token_expires_at(ISSUED, 300);
+	assert_eq!(expires_at, ISSUED.saturating_add(300_000));
+
+	assert!(!token_expired_at(expires_at, ISSUED), "just issued");
+	assert!(!token_expired_at(expires_at, expires_at), "valid until the end of its lifetime");
+	assert!(token_expired_at(expires_at, expires_at.saturating_add(1)), "expired");
+}
+
+#[test]
+fn access_token_expiry_saturates() {
+	let expires_at = token_expires_at(ISSUED, u64::MAX);
+	assert_eq!(expires_at, u64::MAX);
+	assert!(!token_expired_at(expires_at, u64::MAX), "never expires");
+}
+
+#[test]
+fn refreshed_access_token_expires_later() {
+	let first = token_expires_at(ISSUED, 300);
+	let refreshed = token_expires_at(first.saturating_add(1), 300);
+
+	assert!(
+		token_expired_at(first, first.saturating_add(1)),
+		"first token expired when refreshed"
+	);
+	assert!(!token_expired_at(refreshed, first.saturating_add(1)), "refreshed token valid");
+}
+
+#[test]
+fn only_tokens_issued_with_a_refresh_token_expire() {
+	let tokens = tokens();
+	tokens.set_token(DEVICE, "access").expect("token set");
+	assert!(!tokens.expired("access", u64::MAX).expect("expiry read"));
+
+	let tokens = logged_in();
+	assert!(!tokens
+		.expired("access", 1_700_000_300_000)
+		.expect("expiry read"));
+	assert!(tokens
+		.expired("access", 1_700_000_300_001)
+		.expect("expiry read"));
+	assert_eq!(tokens.device("access").expect("device read").as_deref(), Some(DEVICE));
+}
+
+#[test]
+fn refresh_token_is_only_assigned_for_the_current_access_token() {
+	let tokens = logged_in();
+	tokens.set_token(DEVICE, "newer").expect("token set");
+
+	assert!(tokens
+		.set_refresh_token(DEVICE, "access", "refresh", u64::MAX)
+		.is_err());
+}
+
+#[test]
+fn refresh_rotates_the_token_pair() {
+	let tokens = logged_in();
+	assert_eq!(
+		tokens
+			.refresh_device("refresh")
+			.expect("device read")
+			.as_deref(),
+		Some(DEVICE)
+	);
+
+	tokens
+		.rotate(DEVICE, "access2", "refresh2", token_expires_at(1_700_000_300_001, 300))
+		.expect("tokens rotated");
+
+	assert_eq!(tokens.device("access").expect("device read"), None, "old access token revoked");
+	assert_eq!(
+		tokens.refresh_device("refresh").expect("device read"),
+		None,
+		"old refresh token revoked"
+	);
+	assert!(
+		!tokens.expired("access", u64::MAX).expect("expiry read"),
+		"old expiry forgotten"
+	);
+
+	assert_eq!(tokens.device("access2").expect("device read").as_deref(), Some(DEVICE));
+	assert_eq!(
+		tokens
+			.refresh_device("refresh2")
+			.expect("device read")
+			.as_deref(),
+		Some(DEVICE)
+	);
+	assert!(!tokens
+		.expired("access2", 1_700_000_300_001)
+		.expect("expiry read"));
+	assert!(tokens
+		.expired("access2", 1_700_000_600_002)
+		.expect("expiry read"));
+}
+
+#[test]
+fn logout_revokes_the_token_chain() {
+	let tokens = logged_in();
+	tokens
+		.rotate(DEVICE, "access2", "refresh2", u64::MAX)
+		.expect("tokens rotated");
+
+	// As done when a device is removed on logout
+	tokens
+		.remove_access_token(DEVICE)
+		.expect("access token removed");
+	tokens
+		.remove_refresh_token(DEVICE)
+		.expect("refresh token removed");
+
+	for token in ["access", "access2"] {
+		assert_eq!(tokens.device(token).expect("device read"), None, "{token} revoked");
+	}
+	for token in ["refresh", "refresh2"] {
+		assert_eq!(tokens.refresh_device(token).expect("device read"), None, "{token} revoked");
+	}
+}
+
+#[test]
+fn password_change_revokes_the_refresh_token() {
+	let tokens = logged_in();
+
+	// As done for the other devices when they are not logged out
+	tokens
+		.remove_refresh_token(DEVICE)
+		.expect("refresh token removed");
+
+	assert_eq!(tokens.refresh_device("refresh").expect("device read"), None);
+	assert_eq!(
+		tokens.device("access").expect("device read").as_deref(),
+		Some(DEVICE),
+		"access token valid until it expires"
+	);
+	assert!(tokens
+		.expired("access", 1_700_000_300_001)
+		.expect("expiry read"));
+}
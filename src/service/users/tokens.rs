@@ -0,0 +1,136 @@
+//! Access and refresh tokens of devices. Devices are keyed by the user ID and
+//! device ID joined by 0xFF.
+
+use std::sync::Arc;
+
+use conduit::{err, utils, Err, Result};
+use database::Map;
+
+/// The columns holding tokens, so their bookkeeping can be tested in memory.
+pub(super) trait Column: Send + Sync {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+	fn insert(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+	fn remove(&self, key: &[u8]) -> Result<()>;
+}
+
+impl Column for Map {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { Ok(Self::get(self, key)?.map(|value| value.to_vec())) }
+
+	fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> { Self::insert(self, key, value) }
+
+	fn remove(&self, key: &[u8]) -> Result<()> { Self::remove(self, key) }
+}
+
+pub(super) struct Tokens<C: Column + ?Sized = Map> {
+	pub(super) refreshtoken_userdeviceid: Arc<C>,
+	pub(super) token_expiresat: Arc<C>,
+	pub(super) token_userdeviceid: Arc<C>,
+	pub(super) userdeviceid_refreshtoken: Arc<C>,
+	pub(super) userdeviceid_token: Arc<C>,
+}
+
+impl<C: Column + ?Sized> Tokens<C> {
+	/// Returns the device an access token belongs to.
+	pub(super) fn device(&self, token: &str) -> Result<Option<Vec<u8>>> {
+		self.token_userdeviceid.get(token.as_bytes())
+	}
+
+	/// Returns the device a refresh token belongs to.
+	pub(super) fn refresh_device(&self, refresh_token: &str) -> Result<Option<Vec<u8>>> {
+		self.refreshtoken_userdeviceid.get(refresh_token.as_bytes())
+	}
+
+	/// Returns whether an access token was issued with an expiry which has
+	/// passed at `now`.
+	pub(super) fn expired(&self, token: &str, now: u64) -> Result<bool> {
+		let Some(bytes) = self.token_expiresat.get(token.as_bytes())? else {
+			return Ok(false);
+		};
+
+		let expires_at = utils::u64_from_bytes(&bytes)
+			.map_err(|e| err!(Database("expires_at in token_expiresat is invalid u64. {e}")))?;
+
+		Ok(token_expired_at(expires_at, now))
+	}
+
+	/// Replaces the access token of a device, revoking the previous one and
+	/// the refresh token it was issued with.
+	pub(super) fn set_token(&self, userdeviceid: &[u8], token: &str) -> Result<()> {
+		if let Some(old_token) = self.userdeviceid_token.get(userdeviceid)? {
+			self.token_userdeviceid.remove(&old_token)?;
+			self.token_expiresat.remove(&old_token)?;
+			// It will be removed from userdeviceid_token by the insert later
+		}
+
+		self.remove_refresh_token(userdeviceid)?;
+
+		self.userdeviceid_token
+			.insert(userdeviceid, token.as_bytes())?;
+		self.token_userdeviceid
+			.insert(token.as_bytes(), userdeviceid)?;
+
+		Ok(())
+	}
+
+	/// Makes the current access token of a device expire at `expires_at` and
+	/// assigns the device a refresh token, replacing any previous one.
+	pub(super) fn set_refresh_token(
+		&self, userdeviceid: &[u8], token: &str, refresh_token: &str, expires_at: u64,
+	) -> Result<()> {
+		if self.userdeviceid_token.get(userdeviceid)?.as_deref() != Some(token.as_bytes()) {
+			return Err!(Database("Access token is not the current token of the device."));
+		}
+
+		self.remove_refresh_token(userdeviceid)?;
+
+		self.token_expiresat
+			.insert(token.as_bytes(), &expires_at.to_be_bytes())?;
+		self.userdeviceid_refreshtoken
+			.insert(userdeviceid, refresh_token.as_bytes())?;
+		self.refreshtoken_userdeviceid
+			.insert(refresh_token.as_bytes(), userdeviceid)?;
+
+		Ok(())
+	}
+
+	/// Exchanges the tokens of a device for a new pair, revoking the previous
+	/// access token and refresh token.
+	pub(super) fn rotate(&self, userdeviceid: &[u8], token: &str, refresh_token: &str, expires_at: u64) -> Result<()> {
+		self.set_token(userdeviceid, token)?;
+		self.set_refresh_token(userdeviceid, token, refresh_token, expires_at)
+	}
+
+	/// Revokes the access token of a device, if it has one.
+	pub(super) fn remove_access_token(&self, userdeviceid: &[u8]) -> Result<()> {
+		if let Some(old_token) = self.userdeviceid_token.get(userdeviceid)? {
+			self.userdeviceid_token.remove(userdeviceid)?;
+			self.token_userdeviceid.remove(&old_token)?;
+			self.token_expiresat.remove(&old_token)?;
+		}
+
+		Ok(())
+	}
+
+	/// Revokes the refresh token of a device, if it has one.
+	pub(super) fn remove_refresh_token(&self, userdeviceid: &[u8]) -> Result<()> {
+		if let Some(old_refresh_token) = self.userdeviceid_refreshtoken.get(userdeviceid)? {
+			self.userdeviceid_refreshtoken.remove(userdeviceid)?;
+			self.refreshtoken_userdeviceid.remove(&old_refresh_token)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// When an access token issued at `now` with a lifetime of `ttl` seconds
+/// expires, in milliseconds since the unix epoch.
+pub(super) fn token_expires_at(now: u64, ttl: u64) -> u64 {
+	use std::num::Saturating as Sat;
+
+	(Sat(now) + Sat(ttl) * Sat(1000)).0
+}
+
+/// Whether an access token expiring at `expires_at` has expired at `now`.
+pub(super) fn token_expired_at(expires_at: u64, now: u64) -> bool { expires_at < now }